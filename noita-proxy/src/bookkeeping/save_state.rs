@@ -1,5 +1,7 @@
 use std::{
-    fs, io,
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    marker::PhantomData,
     path::{Path, PathBuf},
     sync::{
         Arc,
@@ -13,6 +15,58 @@ pub trait SaveStateEntry: bitcode::Encode + bitcode::DecodeOwned {
     const FILENAME: &'static str;
 }
 
+/// Like `SaveStateEntry`, but saved and loaded one item at a time.
+pub trait StreamedSaveEntry: bitcode::Encode + bitcode::DecodeOwned {
+    const FILENAME: &'static str;
+}
+
+/// Streamed saves start with this instead of an entry length, followed by a crc32 of the rest of the file.
+/// Older saves without checksums start with the length of the first entry right away.
+const CHECKSUMMED_SAVE_MARKER: u32 = u32::MAX;
+
+/// Writes a save item by item, each one compressed and prefixed by its length and crc32,
+/// so that the whole save never has to be encoded in memory at once.
+/// Save is only replaced once `finish` is called.
+pub(crate) struct StreamingSave<E> {
+    file: BufWriter<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    /// Checksum of everything after the header.
+    checksum: crc32fast::Hasher,
+    _entry: PhantomData<fn(&E)>,
+}
+
+impl<E: StreamedSaveEntry> StreamingSave<E> {
+    pub(crate) fn write(&mut self, entry: &E) -> io::Result<()> {
+        let compressed = lz4_flex::compress_prepend_size(&bitcode::encode(entry));
+        let len = (compressed.len() as u32).to_le_bytes();
+        let crc = crc32fast::hash(&compressed).to_le_bytes();
+        for bytes in [&len[..], &crc, &compressed] {
+            self.checksum.update(bytes);
+            self.file.write_all(bytes)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.file.flush()?;
+        // Whole file checksum is only known now, fill it in the header.
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&self.checksum.clone().finalize().to_le_bytes())?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        info!("Saved {}", self.path.display());
+        Ok(())
+    }
+}
+
+impl<E> Drop for StreamingSave<E> {
+    fn drop(&mut self) {
+        // Only still there if save wasn't finished.
+        fs::remove_file(&self.tmp_path).ok();
+    }
+}
+
 struct SaveStateInner {
     game_started: AtomicBool,
 }
@@ -61,25 +115,165 @@ impl SaveState {
         info!("Saved {}", path.display());
     }
 
-    pub(crate) fn load<D: SaveStateEntry>(&self) -> Option<D> {
-        let path = self.path_for_filename(D::FILENAME);
-        let data = fs::read(&path)
+    /// Starts a save that is written item by item, see `StreamingSave`.
+    pub(crate) fn start_streaming_save<E: StreamedSaveEntry>(&self) -> Option<StreamingSave<E>> {
+        if !self.inner.game_started.load(atomic::Ordering::SeqCst) {
+            info!("Skipping save of {}, game not started yet", E::FILENAME);
+            return None;
+        }
+
+        let path = self.path_for_filename(E::FILENAME);
+        let tmp_path = path.with_extension("bit.tmp");
+        let file = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(&CHECKSUMMED_SAVE_MARKER.to_le_bytes())?;
+                // Placeholder for whole file checksum.
+                file.write_all(&0u32.to_le_bytes())?;
+                Ok(file)
+            })
+            .inspect_err(|err| error!("Error while saving to {:?}: {err}", E::FILENAME))
+            .ok()?;
+        Some(StreamingSave {
+            file: BufWriter::new(file),
+            tmp_path,
+            path,
+            checksum: crc32fast::Hasher::new(),
+            _entry: PhantomData,
+        })
+    }
+
+    /// Entries with a wrong checksum are skipped and the rest of the save is still loaded,
+    /// damaged save is backed up anyway. Older saves without checksums are loaded without verification.
+    pub(crate) fn load_streamed<E: StreamedSaveEntry>(&self) -> Option<Vec<E>> {
+        let path = self.path_for_filename(E::FILENAME);
+        let data = Self::read(&path, E::FILENAME)?;
+        let mut rest = data.as_slice();
+        let checksummed = rest
+            .split_first_chunk::<4>()
+            .is_some_and(|(marker, _)| u32::from_le_bytes(*marker) == CHECKSUMMED_SAVE_MARKER);
+        let mut damaged = false;
+        if checksummed {
+            let Some((file_crc, tail)) = rest[4..].split_first_chunk::<4>() else {
+                error!("Could not load {:?}: truncated header", E::FILENAME);
+                self.back_up_corrupt(&path);
+                return None;
+            };
+            rest = tail;
+            if crc32fast::hash(rest) != u32::from_le_bytes(*file_crc) {
+                error!(
+                    "Save {:?} doesn't match its checksum, loading what's intact",
+                    E::FILENAME
+                );
+                damaged = true;
+            }
+        }
+        let mut entries = Vec::new();
+        while !rest.is_empty() {
+            match Self::next_streamed_entry(&mut rest, checksummed) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => {
+                    warn!("Skipping damaged entry of {:?}", E::FILENAME);
+                    damaged = true;
+                }
+                Err(err) if checksummed && damaged => {
+                    // Likely truncated, everything before that was checked to be fine.
+                    error!("Could not load rest of {:?}: {err}", E::FILENAME);
+                    break;
+                }
+                Err(err) => {
+                    error!("Could not load {:?}: {err}", E::FILENAME);
+                    self.back_up_corrupt(&path);
+                    return None;
+                }
+            }
+        }
+        if damaged {
+            self.back_up_corrupt(&path);
+        }
+        Some(entries)
+    }
+
+    /// Whether there is a streamed save of `E`, loadable or not.
+    pub(crate) fn has_streamed<E: StreamedSaveEntry>(&self) -> bool {
+        self.path_for_filename(E::FILENAME).exists()
+    }
+
+    /// Returns None if the entry doesn't match its checksum.
+    fn next_streamed_entry<E: StreamedSaveEntry>(
+        rest: &mut &[u8],
+        checksummed: bool,
+    ) -> Result<Option<E>, String> {
+        let (len, tail) = rest
+            .split_first_chunk::<4>()
+            .ok_or("truncated entry length")?;
+        let len = u32::from_le_bytes(*len) as usize;
+        let (crc, tail) = if checksummed {
+            let (crc, tail) = tail
+                .split_first_chunk::<4>()
+                .ok_or("truncated entry checksum")?;
+            (Some(u32::from_le_bytes(*crc)), tail)
+        } else {
+            (None, tail)
+        };
+        if tail.len() < len {
+            return Err("truncated entry".into());
+        }
+        let (compressed, tail) = tail.split_at(len);
+        *rest = tail;
+        if crc.is_some_and(|crc| crc32fast::hash(compressed) != crc) {
+            return Ok(None);
+        }
+        let data =
+            lz4_flex::decompress_size_prepended(compressed).map_err(|err| err.to_string())?;
+        bitcode::decode(&data)
+            .map_err(|err| err.to_string())
+            .map(Some)
+    }
+
+    fn read(path: &Path, filename: &str) -> Option<Vec<u8>> {
+        fs::read(path)
             .inspect_err(|err| {
                 if err.kind() == io::ErrorKind::NotFound {
-                    info!("No save for {}, no need to load anything", D::FILENAME)
+                    info!("No save for {filename}, no need to load anything")
                 } else {
-                    warn!("Could not read {:?}: {err}", D::FILENAME)
+                    warn!("Could not read {filename:?}: {err}")
                 }
             })
-            .ok()?;
+            .ok()
+    }
+
+    pub(crate) fn load<D: SaveStateEntry>(&self) -> Option<D> {
+        let path = self.path_for_filename(D::FILENAME);
+        let data = Self::read(&path, D::FILENAME)?;
         let data = lz4_flex::decompress_size_prepended(&data)
-            .inspect_err(|err| warn!("Could not decompress {:?}: {err}", D::FILENAME))
+            .inspect_err(|err| {
+                error!("Could not decompress {:?}: {err}", D::FILENAME);
+                self.back_up_corrupt(&path);
+            })
             .ok()?;
         bitcode::decode(&data)
-            .inspect_err(|err| error!("Could not decode {:?}: {err}", D::FILENAME))
+            .inspect_err(|err| {
+                error!("Could not decode {:?}: {err}", D::FILENAME);
+                self.back_up_corrupt(&path);
+            })
             .ok()
     }
 
+    /// Moves a save that failed to load out of the way, so that it doesn't get overwritten
+    /// and can be recovered manually.
+    fn back_up_corrupt(&self, path: &Path) {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".corrupt");
+        match fs::rename(path, &backup) {
+            Ok(()) => error!(
+                "Save {} is corrupt and was not loaded, moved it to {}",
+                path.display(),
+                Path::new(&backup).display()
+            ),
+            Err(err) => error!("Could not back up corrupt save {}: {err}", path.display()),
+        }
+    }
+
     pub(crate) fn mark_game_started(&self) {
         self.inner
             .game_started
@@ -11,7 +11,7 @@ use shared::message_socket::MessageSocket;
 use shared::{Destination, NoitaInbound, NoitaOutbound, RemoteMessage, WorldPos};
 use socket2::{Domain, Socket, Type};
 use std::collections::HashMap;
-use std::fs::{File, create_dir, remove_dir_all};
+use std::fs::{self, File, create_dir, remove_dir_all};
 use std::io::Write;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU16, Ordering};
@@ -25,11 +25,17 @@ use std::{
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
-use world::WorldManager;
+use world::{
+    CutClamp, ExplosionMask, ExportedRegion, ImportPolicy, RecordedMessage, TerraformOp,
+    TerraformUndo, UntrackedTakeAuth, WorldManager,
+};
 
 use crate::lobby_code::LobbyKind;
 use crate::mod_manager::{ModmanagerSettings, get_mods};
-use crate::net::world::world_model::ChunkData;
+use crate::net::world::world_model::compression::{
+    CompressionDict, CompressionLevel, train_dictionary,
+};
+use crate::net::world::world_model::{ChunkData, ChunkTransform};
 use crate::player_cosmetics::{PlayerPngDesc, create_player_png, get_player_skin};
 use crate::steam_helper::LobbyExtraData;
 use crate::{
@@ -37,7 +43,7 @@ use crate::{
     bookkeeping::save_state::{SaveState, SaveStateEntry},
 };
 use shared::des::ProxyToDes;
-use shared::world_sync::{ChunkCoord, PixelFlags, ProxyToWorldSync, RawPixel};
+use shared::world_sync::{CHUNK_SIZE, ChunkCoord, PixelFlags, ProxyToWorldSync, RawPixel};
 use tangled::Reliability;
 use tracing::{error, info, warn};
 mod audio;
@@ -62,6 +68,43 @@ pub fn ws_encode_proxy_bin(key: u8, data: &[u8]) -> NoitaInbound {
     NoitaInbound::RawMessage(buf)
 }
 
+/// Parses `x y r [mat] [chance] [seed] [power]` of a circle cut.
+/// Seed derived from the parameters is passed through `default_seed` if none is given.
+fn parse_circle_cut<'a>(
+    msg: &mut impl Iterator<Item = &'a str>,
+    default_seed: impl FnOnce(u64) -> u64,
+) -> Option<TerraformOp> {
+    let x: i32 = msg.next().and_then(|s| s.parse().ok())?;
+    let y: i32 = msg.next().and_then(|s| s.parse().ok())?;
+    let r: i32 = msg.next().and_then(|s| s.parse().ok())?;
+    let mat: Option<u16> = msg.next().and_then(|s| s.parse().ok());
+    let chance = msg
+        .next()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(100)
+        .min(100) as u8;
+    let seed: u64 = msg
+        .next()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| default_seed(world::operation_seed((x, y, r, mat, chance))));
+    let power: Option<u32> = msg.next().and_then(|s| s.parse().ok());
+    let density = msg
+        .next()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(100)
+        .min(100) as u8;
+    Some(TerraformOp::Circle {
+        x,
+        y,
+        r,
+        mat,
+        density,
+        chance,
+        seed,
+        power,
+    })
+}
+
 pub(crate) fn ws_encode_mod(peer: OmniPeerId, data: &[u8]) -> NoitaInbound {
     let mut buf = Vec::new();
     buf.push(1u8);
@@ -85,6 +128,12 @@ pub(crate) struct NetInnerState {
     des: DesManager,
     audio: AudioManager,
     explosion_data: Vec<ExplosionData>,
+    /// Masks of repeated explosions, keyed by radius, durability and ray strength.
+    explosion_masks: FxHashMap<(u64, u32, u64, Option<u64>), ExplosionMask>,
+    /// Terraforming that will be applied as a single batch, once the batch is ended.
+    terraform_batch: Option<Vec<TerraformOp>>,
+    /// Undo of the last terraforming batch.
+    terraform_undo: Option<TerraformUndo>,
     had_a_disconnect: bool,
     flags: FxHashSet<String>,
 }
@@ -99,6 +148,13 @@ impl NetInnerState {
             self.had_a_disconnect = true;
         };
     }
+    /// Applies terraforming right away, unless a batch is open.
+    fn terraform(&mut self, op: TerraformOp) {
+        match &mut self.terraform_batch {
+            Some(batch) => batch.push(op),
+            None => self.world.queue_terraform(op),
+        }
+    }
     pub(crate) fn try_ws_write_option(&mut self, key: &str, value: impl ProxyOpt) {
         let mut buf = Vec::new();
         buf.push(2);
@@ -385,21 +441,30 @@ impl NetManager {
         let audio_settings = self.audio.lock().unwrap().clone();
         let audio_state = AudioManager::new(audio_settings);
 
-        let (world, rx, recv, sendm, tx) = WorldManager::new(
+        let (mut world, rx, recv, sendm, tx) = WorldManager::new(
             is_host,
             self.peer.my_id(),
             self.init_settings.save_state.clone(),
         );
+        world.compression_dict = fs::read(self.compression_dict_path())
+            .ok()
+            .map(CompressionDict::new);
+        // Timeouts in updates were tuned for the game running at 60 fps.
+        world.tick_duration = Some(Duration::from_secs(1) / 60);
         let mut state = NetInnerState {
             ms: None,
             world,
             explosion_data: Vec::new(),
+            explosion_masks: FxHashMap::default(),
+            terraform_batch: None,
+            terraform_undo: None,
             des: DesManager::new(is_host, self.init_settings.save_state.clone()),
             had_a_disconnect: false,
             flags: self.init_settings.save_state.load().unwrap_or_default(),
             audio: audio_state,
         };
         let mut last_iter = Instant::now();
+        let mut last_world_update = Instant::now();
         let path = crate::player_path(self.init_settings.modmanager_settings.mod_path());
         let player_image = if path.exists() {
             image::open(path)
@@ -490,7 +555,11 @@ impl NetManager {
             for peer in to_kick.iter() {
                 info!("player kicked: {}", peer);
                 state.try_ms_write(&ws_encode_proxy("leave", peer.as_hex()));
-                state.world.handle_peer_left(*peer);
+                if self.is_host() {
+                    state.world.evict_peer(*peer);
+                } else {
+                    state.world.handle_peer_left(*peer);
+                }
                 self.send(*peer, &NetMsg::Kick, Reliability::Reliable);
                 self.broadcast(
                     &NetMsg::PeerDisconnected { id: *peer },
@@ -532,10 +601,16 @@ impl NetManager {
                     }
                 }
             }
+            // Clean up after peers that left without us getting notified about it.
+            if state.world.drop_missing_peers {
+                state.world.check_missing_peers(&self.peer.iter_peer_ids());
+            }
+            state.world.set_expected_host(self.peer.host_id());
             for msg in state.world.get_emitted_msgs() {
                 self.do_message_request(msg)
             }
-            let updates = state.world.update();
+            let updates = state.world.update(last_world_update.elapsed());
+            last_world_update = Instant::now();
             if !updates.is_empty() {
                 state.try_ms_write(&NoitaInbound::ProxyToWorldSync(ProxyToWorldSync::Updates(
                     updates,
@@ -795,7 +870,7 @@ impl NetManager {
                     state.try_ms_write(&ws_encode_mod(src, &decompressed));
                 }
             }
-            NetMsg::WorldMessage(msg) => state.world.handle_msg(src, msg),
+            NetMsg::WorldMessage(envelope) => state.world.handle_envelope(src, envelope),
             NetMsg::PlayerColor(rgb, host, pong, name) => {
                 info!("Player appearance created for {}", src);
                 // Create proper appearance files for new player.
@@ -1248,6 +1323,11 @@ impl NetManager {
         self.peer.is_host()
     }
 
+    /// Precomputed dictionary for chunk compression, shipped next to the mod.
+    fn compression_dict_path(&self) -> PathBuf {
+        self.init_settings.mod_path.join("compression_dict.bin")
+    }
+
     pub(crate) fn handle_message_to_proxy(
         &self,
         msg: &[u8],
@@ -1282,6 +1362,25 @@ impl NetManager {
                     .map(|s| s.parse().ok() == Some(1))
                     .unwrap_or(false);
                 if let (Some(x), Some(y)) = (x, y) {
+                    let (cx, cy) = (
+                        self.camera_pos.0.load(Ordering::Relaxed),
+                        self.camera_pos.1.load(Ordering::Relaxed),
+                    );
+                    state.world.set_movement_hint((
+                        x - self.player_pos.0.load(Ordering::Relaxed),
+                        y - self.player_pos.1.load(Ordering::Relaxed),
+                    ));
+                    state.world.set_position(
+                        (
+                            x.div_euclid(CHUNK_SIZE as i32),
+                            y.div_euclid(CHUNK_SIZE as i32),
+                        ),
+                        (
+                            cx.div_euclid(CHUNK_SIZE as i32),
+                            cy.div_euclid(CHUNK_SIZE as i32),
+                        ),
+                        b,
+                    );
                     self.player_pos.0.store(x, Ordering::Relaxed);
                     self.player_pos.1.store(y, Ordering::Relaxed);
                     self.broadcast(&NetMsg::PlayerPosition(x, y, b, d), Reliability::Reliable);
@@ -1334,6 +1433,7 @@ impl NetManager {
                             h,
                             CellType::new(cell_type, liquid_static, liquid_sand),
                             wang_color,
+                            0,
                         ),
                     );
                     colors.insert(i, wang_color);
@@ -1348,6 +1448,62 @@ impl NetManager {
                     error!("bad materials data {}", c);
                 }
             }
+            Some("material_vulnerabilities") => {
+                while let (Some(i), Some(damage_types)) = (
+                    msg.next().and_then(|s| s.parse::<u16>().ok()),
+                    msg.next().and_then(|s| s.parse().ok()),
+                ) {
+                    match state.world.materials.get_mut(&i) {
+                        Some(material) => material.4 = damage_types,
+                        None => warn!("Vulnerabilities for unknown material {i}"),
+                    }
+                }
+            }
+            Some("effective_durability") => {
+                let Some(material) = msg.next().and_then(|s| s.parse().ok()) else {
+                    error!("Missing arguments in effective_durability message");
+                    return;
+                };
+                let damage_type = msg.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+                let mult = msg.next().and_then(|s| s.parse().ok()).unwrap_or(1.0);
+                let reply = state
+                    .world
+                    .effective_durability(material, damage_type, mult)
+                    .map(|stats| {
+                        format!(
+                            "{} {} {}",
+                            stats.durability, stats.ray_cost, stats.vulnerable
+                        )
+                    })
+                    .unwrap_or_default();
+                state.try_ms_write(&ws_encode_proxy("effective_durability", reply));
+            }
+            Some("minimap_block") => {
+                let block = msg.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+                if !state.world.set_minimap_block(block) {
+                    error!("Minimap block size {block} doesn't divide chunk size");
+                }
+            }
+            Some("minimap_tile") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y)) = (x, y) else {
+                    error!("Missing arguments in minimap_tile message");
+                    return;
+                };
+                let chunk = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                let tile: Vec<String> = state
+                    .world
+                    .minimap_tile(chunk)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(u16::to_string)
+                    .collect();
+                state.try_ms_write(&ws_encode_proxy("minimap_tile", tile.join(" ")));
+            }
             Some("cut_through_world") => {
                 let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
                 let y_min: Option<i32> = msg.next().and_then(|s| s.parse().ok());
@@ -1370,33 +1526,52 @@ impl NetManager {
                     error!("Missing arguments in cut_through_world_line message");
                     return;
                 };
-                let r: Option<i32> = msg.next().and_then(|s| s.parse().ok());
-                let chance: Option<u64> = msg.next().and_then(|s| s.parse().ok());
-                state.world.cut_through_world_line(
+                let r: i32 = msg.next().and_then(|s| s.parse().ok()).unwrap_or(12);
+                let chance = msg
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or(100)
+                    .min(100) as u8;
+                let seed: u64 = msg.next().and_then(|s| s.parse().ok()).unwrap_or_else(|| {
+                    let seed = world::operation_seed((x, y, lx, ly, r, chance));
+                    state.world.vary_operation_seed(seed)
+                });
+                let power: Option<u32> = msg.next().and_then(|s| s.parse().ok());
+                state.terraform(TerraformOp::Line {
                     x,
                     y,
                     lx,
                     ly,
-                    r.unwrap_or(12),
-                    chance.unwrap_or(100).min(100) as u8,
-                );
+                    r,
+                    chance,
+                    seed,
+                    power,
+                });
             }
             Some("cut_through_world_circle") => {
-                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
-                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
-                let r: Option<i32> = msg.next().and_then(|s| s.parse().ok());
-                let mat: Option<u16> = msg.next().and_then(|s| s.parse().ok());
-                let chance: u64 = msg
-                    .next()
-                    .and_then(|s| s.parse::<u64>().ok())
-                    .unwrap_or(100);
-                let (Some(x), Some(y), Some(r)) = (x, y, r) else {
+                let Some(op) =
+                    parse_circle_cut(&mut msg, |seed| state.world.vary_operation_seed(seed))
+                else {
                     error!("Missing arguments in cut_through_world_circle message");
                     return;
                 };
-                state
-                    .world
-                    .cut_through_world_circle(x, y, r, mat, chance.min(100) as u8);
+                state.terraform(op);
+            }
+            Some("preview_cut_circle") => {
+                let Some(op) =
+                    parse_circle_cut(&mut msg, |seed| state.world.peek_operation_seed(seed))
+                else {
+                    error!("Missing arguments in preview_cut_circle message");
+                    return;
+                };
+                let Some(preview) = state.world.preview_cut(&op) else {
+                    return;
+                };
+                let chunks: Vec<String> = preview
+                    .keys()
+                    .map(|chunk| format!("{} {}", chunk.0, chunk.1))
+                    .collect();
+                state.try_ms_write(&ws_encode_proxy("preview_cut", chunks.join(" ")));
             }
             Some("cut_through_world_explosion") => {
                 let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
@@ -1423,19 +1598,625 @@ impl NetManager {
                     error!("Missing arguments in cut_through_world_expl message");
                     return;
                 };
-                state.explosion_data.push(ExplosionData::new(
-                    x,
-                    y,
-                    r,
-                    d,
-                    ray,
-                    hole,
-                    liquid,
-                    mat,
-                    prob.min(100) as u8,
+                let mut explosion =
+                    ExplosionData::new(x, y, r, d, ray, hole, liquid, mat, prob.min(100) as u8);
+                if let Some(seed) = msg.next().and_then(|s| s.parse().ok()) {
+                    explosion.seed = seed;
+                }
+                if let Some(jitter) = msg.next().and_then(|s| s.parse().ok()) {
+                    explosion.jitter = jitter;
+                }
+                if let Some(damage_type) = msg.next().and_then(|s| s.parse().ok()) {
+                    explosion.damage_type = damage_type;
+                }
+                match &mut state.terraform_batch {
+                    Some(batch) => batch.push(TerraformOp::Explosion(explosion)),
+                    None => state.explosion_data.push(explosion),
+                }
+            }
+            Some("cut_through_world_explosion_mask") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let r: Option<u64> = msg.next().and_then(|s| s.parse().ok());
+                let d: Option<u32> = msg.next().and_then(|s| s.parse().ok());
+                let ray: Option<u64> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y), Some(r), Some(d), Some(ray)) = (x, y, r, d, ray) else {
+                    error!("Missing arguments in cut_through_world_explosion_mask message");
+                    return;
+                };
+                let max_rays = state.world.max_explosion_rays;
+                let mask = state
+                    .explosion_masks
+                    .entry((r, d, ray, max_rays))
+                    .or_insert_with(|| world::compute_explosion_mask(r, d, ray, max_rays));
+                state.world.apply_explosion_mask(x, y, mask);
+            }
+            Some(cmd @ ("protect_region" | "unprotect_region")) => {
+                let x1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let x2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x1), Some(y1), Some(x2), Some(y2)) = (x1, y1, x2, y2) else {
+                    error!("Missing arguments in {cmd} message");
+                    return;
+                };
+                let min = ChunkCoord(
+                    x1.min(x2).div_euclid(CHUNK_SIZE as i32),
+                    y1.min(y2).div_euclid(CHUNK_SIZE as i32),
+                );
+                let max = ChunkCoord(
+                    x1.max(x2).div_euclid(CHUNK_SIZE as i32),
+                    y1.max(y2).div_euclid(CHUNK_SIZE as i32),
+                );
+                if cmd == "protect_region" {
+                    state.world.protect_region(min, max)
+                } else {
+                    state.world.unprotect_region(min, max)
+                }
+            }
+            Some("export_region") => {
+                let x1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let x2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x1), Some(y1), Some(x2), Some(y2)) = (x1, y1, x2, y2) else {
+                    error!("Missing arguments in export_region message");
+                    return;
+                };
+                let min = ChunkCoord(
+                    x1.min(x2).div_euclid(CHUNK_SIZE as i32),
+                    y1.min(y2).div_euclid(CHUNK_SIZE as i32),
+                );
+                let max = ChunkCoord(
+                    x1.max(x2).div_euclid(CHUNK_SIZE as i32),
+                    y1.max(y2).div_euclid(CHUNK_SIZE as i32),
+                );
+                let region = state.world.export_region(min, max);
+                self.init_settings.save_state.save(&region);
+            }
+            Some("prefetch_region") => {
+                let x1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let x2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let priority: Option<u8> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x1), Some(y1), Some(x2), Some(y2), Some(priority)) =
+                    (x1, y1, x2, y2, priority)
+                else {
+                    error!("Missing arguments in prefetch_region message");
+                    return;
+                };
+                let min = ChunkCoord(
+                    x1.min(x2).div_euclid(CHUNK_SIZE as i32),
+                    y1.min(y2).div_euclid(CHUNK_SIZE as i32),
+                );
+                let max = ChunkCoord(
+                    x1.max(x2).div_euclid(CHUNK_SIZE as i32),
+                    y1.max(y2).div_euclid(CHUNK_SIZE as i32),
+                );
+                state.world.prefetch_region(min, max, priority);
+            }
+            Some("render_region") => {
+                let x1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let x2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x1), Some(y1), Some(x2), Some(y2)) = (x1, y1, x2, y2) else {
+                    error!("Missing arguments in render_region message");
+                    return;
+                };
+                let min = ChunkCoord(
+                    x1.min(x2).div_euclid(CHUNK_SIZE as i32),
+                    y1.min(y2).div_euclid(CHUNK_SIZE as i32),
+                );
+                let max = ChunkCoord(
+                    x1.max(x2).div_euclid(CHUNK_SIZE as i32),
+                    y1.max(y2).div_euclid(CHUNK_SIZE as i32),
+                );
+                let colors = self.colors.lock().unwrap().clone();
+                if let Some(image) = state.world.render_region(min, max, &colors) {
+                    match image.save("world_region.png") {
+                        Ok(()) => info!("Rendered {min:?}..={max:?} to world_region.png"),
+                        Err(err) => error!("Could not save rendered region: {err}"),
+                    }
+                }
+            }
+            Some("default_chunk_material") => {
+                state.world.default_chunk =
+                    msg.next().and_then(|s| s.parse().ok()).map(ChunkData::new)
+            }
+            Some("start_terraform_log") => {
+                match state.world.start_terraform_log("terraform_log.bin") {
+                    Ok(()) => info!("Logging terraforming to terraform_log.bin"),
+                    Err(err) => error!("Could not start terraform log: {err}"),
+                }
+            }
+            Some("stop_terraform_log") => state.world.stop_terraform_log(),
+            Some("replay_terraform_log") => {
+                if let Err(err) = state.world.replay_terraform_log("terraform_log.bin") {
+                    error!("Could not replay terraform log: {err}");
+                }
+            }
+            Some("start_noita_update_log") => {
+                match state.world.start_noita_update_log("noita_updates.bin") {
+                    Ok(()) => info!("Logging noita updates to noita_updates.bin"),
+                    Err(err) => error!("Could not start noita update log: {err}"),
+                }
+            }
+            Some("stop_noita_update_log") => state.world.stop_noita_update_log(),
+            Some("replay_noita_updates") => {
+                if let Err(err) = state.world.replay_noita_updates("noita_updates.bin") {
+                    error!("Could not replay noita updates: {err}");
+                }
+            }
+            Some("boost_priority") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let priority: Option<u8> = msg.next().and_then(|s| s.parse().ok());
+                let duration: Option<u64> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y), Some(priority), Some(duration)) = (x, y, priority, duration)
+                else {
+                    error!("Missing arguments in boost_priority message");
+                    return;
+                };
+                let chunk = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                state.world.boost_chunk_priority(chunk, priority, duration);
+            }
+            Some("import_region") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y)) = (x, y) else {
+                    error!("Missing arguments in import_region message");
+                    return;
+                };
+                let policy = match msg.next() {
+                    Some("skip") => ImportPolicy::SkipExisting,
+                    Some("merge") => ImportPolicy::MergeNonAir,
+                    _ => ImportPolicy::Overwrite,
+                };
+                let Some(region) = self.init_settings.save_state.load::<ExportedRegion>() else {
+                    return;
+                };
+                let origin = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                let imported = state.world.import_region(&region, origin, policy);
+                info!("Imported {imported} chunks");
+            }
+            Some("merge_save") => {
+                let Some(path) = msg.next() else {
+                    error!("Missing arguments in merge_save message");
+                    return;
+                };
+                let policy = match msg.next() {
+                    Some("skip") => ImportPolicy::SkipExisting,
+                    Some("merge") => ImportPolicy::MergeNonAir,
+                    _ => ImportPolicy::Overwrite,
+                };
+                let (chunks, _) = world::load_saved_chunks(&SaveState::new(path));
+                let merged = state.world.merge_storage(chunks, policy);
+                info!("Merged {merged} chunks from {path}");
+            }
+            Some("fill_rect") => {
+                let x1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y1: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let x2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y2: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let material: Option<u16> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x1), Some(y1), Some(x2), Some(y2), Some(material)) =
+                    (x1, y1, x2, y2, material)
+                else {
+                    error!("Missing arguments in fill_rect message");
+                    return;
+                };
+                state.terraform(TerraformOp::Rect {
+                    min: (x1, y1),
+                    max: (x2, y2),
+                    material,
+                })
+            }
+            Some("storage_send_attempts") => {
+                let Some(attempts) = msg.next().and_then(|s| s.parse().ok()) else {
+                    error!("Missing arguments in storage_send_attempts message");
+                    return;
+                };
+                state.world.storage_send_attempts = attempts;
+            }
+            Some("can_terraform") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y)) = (x, y) else {
+                    error!("Missing arguments in can_terraform message");
+                    return;
+                };
+                let power: Option<u32> = msg.next().and_then(|s| s.parse().ok());
+                let permission = state.world.can_terraform(x, y, power);
+                state.try_ms_write(&ws_encode_proxy(
+                    "can_terraform",
+                    format!("{x} {y} {}", permission.name()),
+                ));
+            }
+            Some("max_cut_depth") => {
+                // Anything that isn't a number lifts the limit.
+                state.world.max_cut_depth = msg.next().and_then(|s| s.parse().ok());
+                state.world.cut_clamp = match msg.next() {
+                    Some("centered") => CutClamp::Centered,
+                    _ => CutClamp::FromTop,
+                };
+            }
+            Some("delta_ack_interval") => {
+                // Anything that isn't a number stops acknowledging deltas.
+                state.world.delta_ack_interval = msg.next().and_then(|s| s.parse().ok());
+            }
+            Some("world_bounds") => {
+                let bounds: Vec<i32> = msg.by_ref().filter_map(|s| s.parse().ok()).collect();
+                let chunk = |x: i32, y: i32| {
+                    ChunkCoord(
+                        x.div_euclid(CHUNK_SIZE as i32),
+                        y.div_euclid(CHUNK_SIZE as i32),
+                    )
+                };
+                // Anything but an inclusive pixel rectangle goes back to default bounds.
+                let bounds = match bounds[..] {
+                    [min_x, min_y, max_x, max_y] => {
+                        Some((chunk(min_x, min_y), chunk(max_x, max_y)))
+                    }
+                    _ => Some(world::DEFAULT_WORLD_BOUNDS),
+                };
+                state.world.set_world_bounds(bounds);
+            }
+            Some("coalesce_terraform") => {
+                let Some(enabled) = msg.next().and_then(|s| s.parse().ok()) else {
+                    error!("Missing arguments in coalesce_terraform message");
+                    return;
+                };
+                state.world.coalesce_terraform = enabled;
+                if !enabled {
+                    state.world.flush_terraform();
+                }
+            }
+            Some("begin_terraform_batch") => state.terraform_batch = Some(Vec::new()),
+            Some("end_terraform_batch") => {
+                let Some(batch) = state.terraform_batch.take() else {
+                    error!("end_terraform_batch without begin_terraform_batch");
+                    return;
+                };
+                // Anything queued before the batch goes first, to keep the order.
+                state.world.flush_terraform();
+                state.terraform_undo = Some(state.world.terraform_batch(&batch));
+            }
+            Some("undo_terraform") => {
+                if let Some(undo) = state.terraform_undo.take() {
+                    state.world.undo_terraform(undo);
+                }
+            }
+            Some("record_world_history") => {
+                state.world.record_history = msg.next().and_then(|s| s.parse().ok());
+            }
+            Some("start_world_trace") => state.world.start_trace(),
+            Some("save_world_trace") => {
+                if let Some(trace) = state.world.take_trace() {
+                    self.init_settings.save_state.save(&trace);
+                }
+            }
+            Some("dump_world_history") => {
+                for message in state.world.recent_messages() {
+                    match message {
+                        RecordedMessage::Handled { source, msg } => info!("from {source}: {msg:?}"),
+                        RecordedMessage::Emitted { dst, msg } => info!("to {dst:?}: {msg:?}"),
+                    }
+                }
+            }
+            Some("begin_transition") => state.world.begin_transition(),
+            Some("end_transition") => state.world.end_transition(),
+            Some("suspend_sending") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y)) = (x, y) else {
+                    error!("Missing arguments in suspend_sending message");
+                    return;
+                };
+                let chunk = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                if !state.world.suspend_sending(chunk) {
+                    warn!("Can't suspend sending of {chunk:?}, not its authority");
+                }
+            }
+            Some("resume_sending") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y)) = (x, y) else {
+                    error!("Missing arguments in resume_sending message");
+                    return;
+                };
+                let chunk = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                state.world.resume_sending(chunk);
+            }
+            Some("authority_of") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y)) = (x, y) else {
+                    error!("Missing arguments in authority_of message");
+                    return;
+                };
+                let chunk = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                let authority = state
+                    .world
+                    .authority_of(chunk)
+                    .map(|peer| peer.as_hex())
+                    .unwrap_or_else(|| "none".to_string());
+                state.try_ms_write(&ws_encode_proxy(
+                    "authority_of",
+                    format!("{} {} {authority}", chunk.0, chunk.1),
+                ));
+            }
+            Some("listeners_of") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y)) = (x, y) else {
+                    error!("Missing arguments in listeners_of message");
+                    return;
+                };
+                let chunk = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                let listeners = match state.world.listeners_of(chunk) {
+                    Some(listeners) => listeners
+                        .iter()
+                        .map(|peer| peer.as_hex())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    None => "none".to_string(),
+                };
+                state.try_ms_write(&ws_encode_proxy(
+                    "listeners_of",
+                    format!("{} {} {listeners}", chunk.0, chunk.1),
+                ));
+            }
+            Some("rebroadcast_authority_state") => {
+                if !state.world.rebroadcast_authority_state() {
+                    warn!("Authority state wasn't rebroadcast, not a host or too soon");
+                }
+            }
+            Some("authority_priorities") => {
+                let priorities: Vec<String> = state
+                    .world
+                    .authority_priorities()
+                    .iter()
+                    .map(|(chunk, peer, priority)| {
+                        format!("{} {} {} {priority}", chunk.0, chunk.1, peer.as_hex())
+                    })
+                    .collect();
+                state.try_ms_write(&ws_encode_proxy(
+                    "authority_priorities",
+                    priorities.join(" "),
+                ));
+            }
+            Some("max_explosion_rays") => {
+                // Anything that isn't a number lifts the cap.
+                state.world.max_explosion_rays = msg.next().and_then(|s| s.parse().ok());
+                state.explosion_masks.clear();
+            }
+            Some("max_authority_changes") => {
+                // Anything that isn't a number never locks authority.
+                state.world.max_authority_changes = msg.next().and_then(|s| s.parse().ok());
+            }
+            Some("notplayer_sync_radius") => {
+                // Anything that isn't a number keeps as much as around a player.
+                state.world.notplayer_sync_radius = msg.next().and_then(|s| s.parse().ok());
+            }
+            Some("sync_bounds") => {
+                let (min, max) = state.world.active_sync_bounds();
+                state.try_ms_write(&ws_encode_proxy(
+                    "sync_bounds",
+                    format!("{} {} {} {}", min.0, min.1, max.0, max.1),
                 ));
             }
+            Some("neighbors_loaded") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let (Some(x), Some(y)) = (x, y) else {
+                    error!("Missing arguments in neighbors_loaded message");
+                    return;
+                };
+                let chunk = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                let loaded: String = state
+                    .world
+                    .neighbors_loaded(chunk)
+                    .map(|loaded| if loaded { '1' } else { '0' })
+                    .into_iter()
+                    .collect();
+                state.try_ms_write(&ws_encode_proxy("neighbors_loaded", loaded));
+            }
+            Some("chunks_changed_since") => {
+                let Some(since) = msg.next().and_then(|s| s.parse().ok()) else {
+                    error!("Missing arguments in chunks_changed_since message");
+                    return;
+                };
+                let include_unloaded = msg.next().and_then(|s| s.parse().ok()).unwrap_or(false);
+                // Current update goes first, to be passed as `since` in the next poll.
+                let mut reply = vec![state.world.current_update().to_string()];
+                for chunk in state.world.chunks_changed_since(since, include_unloaded) {
+                    reply.push(format!("{} {}", chunk.0, chunk.1));
+                }
+                state.try_ms_write(&ws_encode_proxy("chunks_changed_since", reply.join(" ")));
+            }
+            Some("initial_priority_by_distance") => {
+                let Some(enabled) = msg.next().and_then(|s| s.parse::<bool>().ok()) else {
+                    error!("Missing arguments in initial_priority_by_distance message");
+                    return;
+                };
+                state.world.initial_priority_fn =
+                    enabled.then_some(world::distance_initial_priority);
+            }
+            Some("drop_missing_peers") => {
+                let Some(enabled) = msg.next().and_then(|s| s.parse().ok()) else {
+                    error!("Missing arguments in drop_missing_peers message");
+                    return;
+                };
+                state.world.drop_missing_peers = enabled;
+            }
+            Some("reacquire_relinquished") => {
+                let Some(enabled) = msg.next().and_then(|s| s.parse().ok()) else {
+                    error!("Missing arguments in reacquire_relinquished message");
+                    return;
+                };
+                state.world.reacquire_relinquished = enabled;
+            }
+            Some("replicate_terraform") => {
+                let Some(enabled) = msg.next().and_then(|s| s.parse().ok()) else {
+                    error!("Missing arguments in replicate_terraform message");
+                    return;
+                };
+                state.world.replicate_terraform = enabled;
+            }
+            Some("prioritize_control") => {
+                let Some(enabled) = msg.next().and_then(|s| s.parse().ok()) else {
+                    error!("Missing arguments in prioritize_control message");
+                    return;
+                };
+                state.world.prioritize_control = enabled;
+            }
+            Some("warm_up_priority") => {
+                // Anything that isn't a number turns it off.
+                state.world.warm_up_priority = msg.next().and_then(|s| s.parse().ok());
+            }
+            Some("replicate_only") => {
+                let Some(replicate_only) = msg.next().and_then(|s| s.parse().ok()) else {
+                    error!("Missing arguments in replicate_only message");
+                    return;
+                };
+                state.world.set_replicate_only(replicate_only)
+            }
+            Some("cold_storage_after") => {
+                let updates = msg.next().and_then(|s| s.parse().ok());
+                state.world.set_cold_storage_after(updates)
+            }
+            Some("idle_unload_after") => {
+                state.world.idle_unload_after = msg.next().and_then(|s| s.parse().ok())
+            }
+            Some("max_requests_per_update") => {
+                state.world.max_requests_per_update = msg.next().and_then(|s| s.parse().ok())
+            }
+            Some("pending_by_destination") => {
+                for (dst, (count, bytes)) in state.world.pending_by_destination() {
+                    info!("{count} world messages ({bytes} bytes) waiting to be sent to {dst:?}");
+                }
+            }
+            Some("record_compression_stats") => {
+                let record = msg.next().and_then(|s| s.parse().ok()).unwrap_or(true);
+                state.world.record_compression_stats = record;
+            }
+            Some("compression_stats") => {
+                let stats = state.world.compression_stats();
+                info!(
+                    "Compression: mean ratio {:.3}, worst ratio {:.3}, {} bytes saved over {} samples",
+                    stats.mean_ratio(),
+                    stats.worst_ratio,
+                    stats.bytes_saved,
+                    stats.samples
+                );
+                state.world.reset_compression_stats();
+            }
+            Some("copy_chunk") => {
+                let x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let offset_x: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let offset_y: Option<i32> = msg.next().and_then(|s| s.parse().ok());
+                let mirror_x: Option<bool> = msg.next().and_then(|s| s.parse().ok());
+                let mirror_y: Option<bool> = msg.next().and_then(|s| s.parse().ok());
+                let (
+                    Some(x),
+                    Some(y),
+                    Some(offset_x),
+                    Some(offset_y),
+                    Some(mirror_x),
+                    Some(mirror_y),
+                ) = (x, y, offset_x, offset_y, mirror_x, mirror_y)
+                else {
+                    error!("Missing arguments in copy_chunk message");
+                    return;
+                };
+                let chunk = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                state.world.copy_chunk_transformed(
+                    chunk,
+                    ChunkTransform {
+                        offset: (offset_x, offset_y),
+                        mirror_x,
+                        mirror_y,
+                    },
+                );
+            }
+            Some("world_hash") => {
+                let hash = state.world.world_hash();
+                state.try_ms_write(&ws_encode_proxy("world_hash", format!("{hash:016x}")));
+            }
+            Some("memory_estimate") => {
+                let stats = state.world.memory_estimate();
+                info!("World uses about {} bytes: {stats:?}", stats.total());
+            }
+            Some("save_world") => state.world.start_background_save(),
+            Some("validate_world") => {
+                let issues = state.world.validate_consistency();
+                for issue in &issues {
+                    warn!("World sync inconsistency: {issue:?}");
+                }
+                info!("Found {} world sync inconsistencies", issues.len());
+                for (peer, rejected) in state.world.suspicious_peers() {
+                    warn!("{peer} had {rejected} authority relinquishes rejected");
+                }
+            }
+            Some("compression_level") => {
+                state.world.compression_level = match msg.next() {
+                    Some("fast") => CompressionLevel::Fast,
+                    Some("balanced") => CompressionLevel::Balanced,
+                    Some("max") => CompressionLevel::Max,
+                    _ => {
+                        error!("Missing arguments in compression_level message");
+                        return;
+                    }
+                }
+            }
+            Some("untracked_take_auth") => {
+                state.world.untracked_take_auth = match msg.next() {
+                    Some("ignore") => UntrackedTakeAuth::Ignore,
+                    Some("unload") => UntrackedTakeAuth::Unload,
+                    Some("take") => UntrackedTakeAuth::Take,
+                    _ => {
+                        error!("Missing arguments in untracked_take_auth message");
+                        return;
+                    }
+                }
+            }
+            Some("save_compression_dict") => {
+                let chunks: Vec<ChunkData> = state.world.get_chunks().into_values().collect();
+                let dict = train_dictionary(&chunks);
+                if let Err(err) = fs::write(self.compression_dict_path(), &dict) {
+                    error!("Could not save compression dictionary: {err}");
+                }
+                state.world.compression_dict = Some(CompressionDict::new(dict));
+            }
             Some("flush_exp") => {
+                state.world.flush_terraform();
                 state
                     .world
                     .cut_through_world_explosion(std::mem::take(&mut state.explosion_data));
@@ -1476,7 +2257,7 @@ impl NetManager {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Encode, Decode)]
 pub struct ExplosionData {
     x: i32,
     y: i32,
@@ -1487,6 +2268,12 @@ pub struct ExplosionData {
     liquid: bool,
     mat: RawPixel,
     prob: u8,
+    /// Seed for pixels removed with `prob`, same on every peer.
+    seed: u64,
+    /// How much rays are randomly turned, as a fraction of angle between rays.
+    jitter: f32,
+    /// Bitmask, materials vulnerable to any of these are destroyed regardless of `d`.
+    damage_type: u32,
 }
 impl ExplosionData {
     #[allow(clippy::too_many_arguments)]
@@ -1514,6 +2301,9 @@ impl ExplosionData {
                 material: mat,
             },
             prob,
+            seed: world::operation_seed((x, y, r, d, ray, hole, liquid, mat, prob)),
+            jitter: 0.0,
+            damage_type: 0,
         }
     }
 }
@@ -1,4 +1,7 @@
-use super::{omni::OmniPeerId, world::WorldNetMessage};
+use super::{
+    omni::OmniPeerId,
+    world::{WorldEnvelope, WorldNetMessage},
+};
 use crate::net::world::world_model::ChunkData;
 use crate::{GameSettings, player_cosmetics::PlayerPngDesc};
 use bitcode::{Decode, Encode};
@@ -23,7 +26,7 @@ pub(crate) enum NetMsg {
     StartGame { settings: GameSettings, init: bool },
     ModRaw { data: Vec<u8> },
     ModCompressed { data: Vec<u8> },
-    WorldMessage(WorldNetMessage),
+    WorldMessage(WorldEnvelope),
     PlayerColor(PlayerPngDesc, bool, Option<OmniPeerId>, String),
     RemoteMsg(shared::RemoteMessage),
     ForwardDesToProxy(shared::des::DesToProxy),
@@ -45,7 +48,7 @@ pub(crate) enum NetMsg {
 impl From<MessageRequest<WorldNetMessage>> for MessageRequest<NetMsg> {
     fn from(value: MessageRequest<WorldNetMessage>) -> Self {
         Self {
-            msg: NetMsg::WorldMessage(value.msg),
+            msg: NetMsg::WorldMessage(WorldEnvelope::wrap(&value.msg)),
             reliability: value.reliability,
             dst: value.dst,
         }
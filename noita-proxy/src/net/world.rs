@@ -1,20 +1,33 @@
 use bitcode::{Decode, Encode};
 use image::RgbaImage;
-use rand::{Rng, rng};
 use rayon::iter::IntoParallelIterator;
+use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
-use rustc_hash::{FxHashMap, FxHashSet};
-use std::collections::HashMap;
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::f32::consts::TAU;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::Path;
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
-use std::time::Duration;
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use std::{cmp, mem, thread};
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 use wide::f32x8;
-use world_model::{ChunkData, ChunkDelta, WorldModel, chunk::Chunk};
+use world_model::{
+    ChunkData, ChunkDelta, ChunkTransform, WorldModel,
+    chunk::Chunk,
+    compression::{
+        CompressedChunkData, CompressionDict, CompressionLevel, CompressionStats, SavedChunkData,
+    },
+    storage::ChunkStorage,
+};
 
-use crate::bookkeeping::save_state::{SaveState, SaveStateEntry};
+use crate::bookkeeping::save_state::{SaveState, SaveStateEntry, StreamedSaveEntry};
 
 use super::{
     CellType, ExplosionData,
@@ -31,6 +44,8 @@ pub(crate) enum WorldNetMessage {
         chunk: ChunkCoord,
         priority: u8,
         can_wait: bool,
+        // Direction the requester is moving in, if known
+        hint: Option<(i32, i32)>,
     },
     // have peer make Authority request
     AskForAuthority {
@@ -56,8 +71,10 @@ pub(crate) enum WorldNetMessage {
     // When got authority
     GotAuthority {
         chunk: ChunkCoord,
-        chunk_data: Option<ChunkData>,
+        chunk_data: Option<CompressedChunkData>,
         priority: u8,
+        /// Peers that were waiting for the chunk to have an authority, new authority sends them the chunk right away.
+        listeners: FxHashSet<OmniPeerId>,
     },
     // Tell host that someone is losing authority
     RelinquishAuthority {
@@ -89,7 +106,7 @@ pub(crate) enum WorldNetMessage {
     // Listen responses/messages
     ListenInitialResponse {
         chunk: ChunkCoord,
-        chunk_data: Option<ChunkData>,
+        chunk_data: Option<CompressedChunkData>,
         priority: u8,
     },
     ListenUpdate {
@@ -99,6 +116,8 @@ pub(crate) enum WorldNetMessage {
     },
     ChunkPacket {
         chunkpacket: Vec<(ChunkDelta, u8)>,
+        /// Deltas that are the same in several chunks, like when a long band is carved out.
+        templated: Vec<TemplatedDeltas>,
     },
     ListenAuthorityRelinquished {
         chunk: ChunkCoord,
@@ -113,7 +132,7 @@ pub(crate) enum WorldNetMessage {
     },
     TransferOk {
         chunk: ChunkCoord,
-        chunk_data: Option<ChunkData>,
+        chunk_data: Option<CompressedChunkData>,
         listeners: FxHashSet<OmniPeerId>,
     },
     TransferFailed {
@@ -122,6 +141,484 @@ pub(crate) enum WorldNetMessage {
     NotifyNewAuthority {
         chunk: ChunkCoord,
     },
+    // Which compression dictionary the sender has, if any
+    DictionaryVersion {
+        version: Option<u64>,
+    },
+    // Edit made by host to a chunk we're an authority of
+    ApplyDelta {
+        delta: ChunkDelta,
+    },
+    // Tell host to never give us authority
+    RegisterReplicaOnly {
+        replicate_only: bool,
+    },
+    // Sent by host periodically, so that split brain can be detected
+    HostAssertion {
+        host: OmniPeerId,
+        epoch: u64,
+    },
+    // Host got an UpdateStorage with no priority, it doesn't have to be resent
+    StorageAck {
+        chunk: ChunkCoord,
+        world_num: u8,
+    },
+    // How many deltas of a chunk a listener got since it got the chunk whole
+    DeltaAck {
+        chunk: ChunkCoord,
+        seq: u32,
+    },
+    // Host thinks we're the authority, see `rebroadcast_authority_state`
+    ConfirmAuthority {
+        chunk: ChunkCoord,
+    },
+}
+
+impl WorldNetMessage {
+    /// Chunk this message is about, if it's about a single chunk.
+    fn chunk(&self) -> Option<ChunkCoord> {
+        match self {
+            WorldNetMessage::RequestAuthority { chunk, .. }
+            | WorldNetMessage::AskForAuthority { chunk, .. }
+            | WorldNetMessage::GetChunk { chunk, .. }
+            | WorldNetMessage::LoseAuthority { chunk, .. }
+            | WorldNetMessage::ChangePriority { chunk, .. }
+            | WorldNetMessage::GotAuthority { chunk, .. }
+            | WorldNetMessage::RelinquishAuthority { chunk, .. }
+            | WorldNetMessage::UpdateStorage { chunk, .. }
+            | WorldNetMessage::AuthorityAlreadyTaken { chunk, .. }
+            | WorldNetMessage::ListenRequest { chunk }
+            | WorldNetMessage::ListenStopRequest { chunk }
+            | WorldNetMessage::UnloadChunk { chunk }
+            | WorldNetMessage::ListenInitialResponse { chunk, .. }
+            | WorldNetMessage::ListenAuthorityRelinquished { chunk }
+            | WorldNetMessage::GetAuthorityFrom { chunk, .. }
+            | WorldNetMessage::RequestAuthorityTransfer { chunk }
+            | WorldNetMessage::TransferOk { chunk, .. }
+            | WorldNetMessage::TransferFailed { chunk }
+            | WorldNetMessage::NotifyNewAuthority { chunk }
+            | WorldNetMessage::StorageAck { chunk, .. }
+            | WorldNetMessage::DeltaAck { chunk, .. }
+            | WorldNetMessage::ConfirmAuthority { chunk } => Some(*chunk),
+            WorldNetMessage::ListenUpdate { delta, .. } | WorldNetMessage::ApplyDelta { delta } => {
+                Some(delta.chunk_coord)
+            }
+            WorldNetMessage::ChunkPacket { .. }
+            | WorldNetMessage::DictionaryVersion { .. }
+            | WorldNetMessage::RegisterReplicaOnly { .. }
+            | WorldNetMessage::HostAssertion { .. } => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            WorldNetMessage::RequestAuthority { .. } => "RequestAuthority",
+            WorldNetMessage::AskForAuthority { .. } => "AskForAuthority",
+            WorldNetMessage::GetChunk { .. } => "GetChunk",
+            WorldNetMessage::LoseAuthority { .. } => "LoseAuthority",
+            WorldNetMessage::ChangePriority { .. } => "ChangePriority",
+            WorldNetMessage::GotAuthority { .. } => "GotAuthority",
+            WorldNetMessage::RelinquishAuthority { .. } => "RelinquishAuthority",
+            WorldNetMessage::UpdateStorage { .. } => "UpdateStorage",
+            WorldNetMessage::AuthorityAlreadyTaken { .. } => "AuthorityAlreadyTaken",
+            WorldNetMessage::ListenRequest { .. } => "ListenRequest",
+            WorldNetMessage::ListenStopRequest { .. } => "ListenStopRequest",
+            WorldNetMessage::UnloadChunk { .. } => "UnloadChunk",
+            WorldNetMessage::ListenInitialResponse { .. } => "ListenInitialResponse",
+            WorldNetMessage::ListenUpdate { .. } => "ListenUpdate",
+            WorldNetMessage::ChunkPacket { .. } => "ChunkPacket",
+            WorldNetMessage::ListenAuthorityRelinquished { .. } => "ListenAuthorityRelinquished",
+            WorldNetMessage::GetAuthorityFrom { .. } => "GetAuthorityFrom",
+            WorldNetMessage::RequestAuthorityTransfer { .. } => "RequestAuthorityTransfer",
+            WorldNetMessage::TransferOk { .. } => "TransferOk",
+            WorldNetMessage::TransferFailed { .. } => "TransferFailed",
+            WorldNetMessage::NotifyNewAuthority { .. } => "NotifyNewAuthority",
+            WorldNetMessage::DictionaryVersion { .. } => "DictionaryVersion",
+            WorldNetMessage::ApplyDelta { .. } => "ApplyDelta",
+            WorldNetMessage::RegisterReplicaOnly { .. } => "RegisterReplicaOnly",
+            WorldNetMessage::HostAssertion { .. } => "HostAssertion",
+            WorldNetMessage::StorageAck { .. } => "StorageAck",
+            WorldNetMessage::DeltaAck { .. } => "DeltaAck",
+            WorldNetMessage::ConfirmAuthority { .. } => "ConfirmAuthority",
+        }
+    }
+
+    /// Messages that only host is supposed to send.
+    fn is_from_host_only(&self) -> bool {
+        matches!(
+            self,
+            WorldNetMessage::GotAuthority { .. }
+                | WorldNetMessage::GetChunk { .. }
+                | WorldNetMessage::AuthorityAlreadyTaken { .. }
+                | WorldNetMessage::GetAuthorityFrom { .. }
+                | WorldNetMessage::ListenAuthorityRelinquished { .. }
+                | WorldNetMessage::StorageAck { .. }
+                | WorldNetMessage::ConfirmAuthority { .. }
+                | WorldNetMessage::ApplyDelta { .. }
+        )
+    }
+
+    /// Priority of delta messages, which are fine to drop as authority will resend the chunk later anyway.
+    /// None for everything else.
+    fn delta_priority(&self) -> Option<u8> {
+        match self {
+            WorldNetMessage::ListenUpdate { priority, .. } => Some(*priority),
+            WorldNetMessage::ChunkPacket {
+                chunkpacket,
+                templated,
+            } => chunkpacket
+                .iter()
+                .map(|(_, priority)| *priority)
+                .chain(
+                    templated
+                        .iter()
+                        .flat_map(|deltas| deltas.chunks.iter().map(|(_, priority)| *priority)),
+                )
+                .max(),
+            _ => None,
+        }
+    }
+
+    /// Priority that a chunk sent whole in this message is wanted with, None for other messages.
+    fn chunk_data_priority(&self) -> Option<u8> {
+        match self {
+            WorldNetMessage::ListenInitialResponse { priority, .. }
+            | WorldNetMessage::GotAuthority { priority, .. } => Some(*priority),
+            _ => None,
+        }
+    }
+
+    /// Chunks a delta message carries changes for, None for everything else.
+    fn delta_chunks(&self) -> Option<Vec<ChunkCoord>> {
+        match self {
+            WorldNetMessage::ListenUpdate { delta, .. } => Some(vec![delta.chunk_coord]),
+            WorldNetMessage::ChunkPacket {
+                chunkpacket,
+                templated,
+            } => Some(
+                chunkpacket
+                    .iter()
+                    .map(|(delta, _)| delta.chunk_coord)
+                    .chain(
+                        templated
+                            .iter()
+                            .flat_map(|deltas| deltas.chunks.iter().map(|(chunk, _)| *chunk)),
+                    )
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Variant index, has to match declaration order. New variants go at the end.
+    fn tag(&self) -> u8 {
+        match self {
+            WorldNetMessage::RequestAuthority { .. } => 0,
+            WorldNetMessage::AskForAuthority { .. } => 1,
+            WorldNetMessage::GetChunk { .. } => 2,
+            WorldNetMessage::LoseAuthority { .. } => 3,
+            WorldNetMessage::ChangePriority { .. } => 4,
+            WorldNetMessage::GotAuthority { .. } => 5,
+            WorldNetMessage::RelinquishAuthority { .. } => 6,
+            WorldNetMessage::UpdateStorage { .. } => 7,
+            WorldNetMessage::AuthorityAlreadyTaken { .. } => 8,
+            WorldNetMessage::ListenRequest { .. } => 9,
+            WorldNetMessage::ListenStopRequest { .. } => 10,
+            WorldNetMessage::UnloadChunk { .. } => 11,
+            WorldNetMessage::ListenInitialResponse { .. } => 12,
+            WorldNetMessage::ListenUpdate { .. } => 13,
+            WorldNetMessage::ChunkPacket { .. } => 14,
+            WorldNetMessage::ListenAuthorityRelinquished { .. } => 15,
+            WorldNetMessage::GetAuthorityFrom { .. } => 16,
+            WorldNetMessage::RequestAuthorityTransfer { .. } => 17,
+            WorldNetMessage::TransferOk { .. } => 18,
+            WorldNetMessage::TransferFailed { .. } => 19,
+            WorldNetMessage::NotifyNewAuthority { .. } => 20,
+            WorldNetMessage::DictionaryVersion { .. } => 21,
+            WorldNetMessage::ApplyDelta { .. } => 22,
+            WorldNetMessage::RegisterReplicaOnly { .. } => 23,
+            WorldNetMessage::HostAssertion { .. } => 24,
+            WorldNetMessage::StorageAck { .. } => 25,
+            WorldNetMessage::DeltaAck { .. } => 26,
+            WorldNetMessage::ConfirmAuthority { .. } => 27,
+        }
+    }
+
+    /// Messages that can't be skipped without chunk state going out of sync between peers.
+    fn is_mandatory(&self) -> bool {
+        matches!(
+            self,
+            WorldNetMessage::RequestAuthority { .. }
+                | WorldNetMessage::LoseAuthority { .. }
+                | WorldNetMessage::GotAuthority { .. }
+                | WorldNetMessage::RelinquishAuthority { .. }
+                | WorldNetMessage::UpdateStorage { .. }
+                | WorldNetMessage::AuthorityAlreadyTaken { .. }
+                | WorldNetMessage::ListenInitialResponse { .. }
+                | WorldNetMessage::GetAuthorityFrom { .. }
+                | WorldNetMessage::RequestAuthorityTransfer { .. }
+                | WorldNetMessage::TransferOk { .. }
+                | WorldNetMessage::TransferFailed { .. }
+        )
+    }
+}
+
+/// Number of WorldNetMessage variants this version knows about.
+const KNOWN_WORLD_MESSAGE_TAGS: u8 = 28;
+
+/// WorldNetMessage encoded separately, so that peers on an older version can skip variants they don't know
+/// instead of failing to decode the whole NetMsg.
+#[derive(Debug, Decode, Encode, Clone)]
+pub(crate) struct WorldEnvelope {
+    tag: u8,
+    mandatory: bool,
+    payload: Vec<u8>,
+}
+
+impl WorldEnvelope {
+    pub(crate) fn wrap(msg: &WorldNetMessage) -> Self {
+        Self {
+            tag: msg.tag(),
+            mandatory: msg.is_mandatory(),
+            payload: bitcode::encode(msg),
+        }
+    }
+
+    /// None if the message is of an unknown variant or couldn't be decoded.
+    pub(crate) fn open(&self, source: OmniPeerId) -> Option<WorldNetMessage> {
+        if self.tag >= KNOWN_WORLD_MESSAGE_TAGS {
+            if self.mandatory {
+                error!(
+                    "{source} sent mandatory world message with unknown tag {}, they're likely on a newer version and world might desync",
+                    self.tag
+                );
+            } else {
+                debug!(
+                    "Skipping world message with unknown tag {} from {source}",
+                    self.tag
+                );
+            }
+            return None;
+        }
+        bitcode::decode(&self.payload)
+            .inspect_err(|err| warn!("Could not decode world message from {source}: {err}"))
+            .ok()
+    }
+}
+
+/// Same delta applied to several chunks, sent only once.
+#[derive(Debug, Decode, Encode, Clone)]
+pub(crate) struct TemplatedDeltas {
+    /// Only pixels are used, chunk it's for doesn't matter.
+    template: ChunkDelta,
+    chunks: Vec<(ChunkCoord, u8)>,
+}
+
+impl TemplatedDeltas {
+    fn deltas(self) -> impl Iterator<Item = (ChunkDelta, u8)> {
+        self.chunks
+            .into_iter()
+            .map(move |(chunk, priority)| (self.template.moved_to(chunk), priority))
+    }
+}
+
+/// Pixels of `imported` to write over `existing` according to `policy`, None if there are none.
+fn import_delta(
+    chunk: ChunkCoord,
+    existing: Option<&ChunkData>,
+    imported: &ChunkData,
+    policy: ImportPolicy,
+) -> Option<ChunkDelta> {
+    let mut new = Chunk::default();
+    imported.apply_to_chunk(&mut new);
+    let (Some(existing), ImportPolicy::MergeNonAir) = (existing, policy) else {
+        return Some(ChunkDelta::new(
+            chunk,
+            (0..CHUNK_SIZE * CHUNK_SIZE).map(|i| Some(new.compact_pixel(i))),
+        ));
+    };
+    let mut old = Chunk::default();
+    existing.apply_to_chunk(&mut old);
+    // Unknown pixels are neither air nor something to stamp.
+    let is_air = |pixel: RawPixel| pixel.flags != PixelFlags::Unknown && pixel.material == 0;
+    let is_solid = |pixel: RawPixel| pixel.flags != PixelFlags::Unknown && pixel.material != 0;
+    let mut any = false;
+    let pixels: Vec<_> = (0..CHUNK_SIZE * CHUNK_SIZE)
+        .map(|i| {
+            let fill = is_solid(new.pixel(i)) && is_air(old.pixel(i));
+            any |= fill;
+            fill.then(|| new.compact_pixel(i))
+        })
+        .collect();
+    any.then(|| ChunkDelta::new(chunk, pixels))
+}
+
+/// Chunks kept in a save, along with when they were last modified if the save has that.
+pub(crate) fn load_saved_chunks(
+    save_state: &SaveState,
+) -> (FxHashMap<ChunkCoord, ChunkData>, FxHashMap<ChunkCoord, u64>) {
+    let mut chunk_modified = FxHashMap::default();
+    // Saves made before chunks were streamed have all of them in one entry,
+    // and ones made before modification times were tracked don't have those.
+    // Older formats are only used when there's no newer save at all, a newer save that failed
+    // to load was backed up, and an outdated world shouldn't be saved over it.
+    let chunks = if save_state.has_streamed::<(ChunkCoord, SavedChunkData, u64)>() {
+        save_state
+            .load_streamed::<(ChunkCoord, SavedChunkData, u64)>()
+            .map(|chunks| {
+                chunks
+                    .into_iter()
+                    .map(|(chunk, data, modified_at)| {
+                        chunk_modified.insert(chunk, modified_at);
+                        (chunk, data.into_chunk_data())
+                    })
+                    .collect()
+            })
+    } else if save_state.has_streamed::<(ChunkCoord, SavedChunkData)>() {
+        save_state
+            .load_streamed::<(ChunkCoord, SavedChunkData)>()
+            .map(|chunks| {
+                chunks
+                    .into_iter()
+                    .map(|(chunk, data)| (chunk, data.into_chunk_data()))
+                    .collect()
+            })
+    } else {
+        save_state.load()
+    }
+    .unwrap_or_default();
+    (chunks, chunk_modified)
+}
+
+/// Picks save encoding of a chunk, recording how much smaller it got if `stats` are given.
+fn saved_chunk_data(data: ChunkData, stats: Option<&Mutex<CompressionStats>>) -> SavedChunkData {
+    let Some(stats) = stats else {
+        return SavedChunkData::new(data);
+    };
+    let original = bitcode::encode(&data).len();
+    let saved = SavedChunkData::new(data);
+    stats
+        .lock()
+        .unwrap()
+        .record(original, bitcode::encode(&saved).len());
+    saved
+}
+
+/// Appends a length-prefixed entry to a log, to be read back with `read_log`.
+fn write_log_entry<T: Encode + ?Sized>(file: &mut File, entry: &T) -> io::Result<()> {
+    let encoded = bitcode::encode(entry);
+    let mut buf = Vec::with_capacity(4 + encoded.len());
+    buf.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&encoded);
+    file.write_all(&buf)
+}
+
+fn read_log<T: bitcode::DecodeOwned>(path: impl AsRef<Path>) -> io::Result<Vec<T>> {
+    let data = fs::read(path)?;
+    let mut entries = Vec::new();
+    let mut rest = data.as_slice();
+    while !rest.is_empty() {
+        let (len, tail) = rest
+            .split_first_chunk::<4>()
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if tail.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (encoded, tail) = tail.split_at(len);
+        entries.push(
+            bitcode::decode(encoded)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        );
+        rest = tail;
+    }
+    Ok(entries)
+}
+
+/// Moves whole chunks wanted with at least `warm_up` priority ahead of everything else,
+/// keeping the order within each group. As in `prioritize_control`, they don't overtake
+/// earlier messages about the same chunk.
+fn prioritize_warm_up(
+    requests: Vec<MessageRequest<WorldNetMessage>>,
+    warm_up: u8,
+) -> Vec<MessageRequest<WorldNetMessage>> {
+    let mut warm = Vec::new();
+    let mut rest = Vec::with_capacity(requests.len());
+    let mut pinned: FxHashSet<ChunkCoord> = FxHashSet::default();
+    for request in requests {
+        let is_warm = request
+            .msg
+            .chunk_data_priority()
+            .is_some_and(|priority| priority <= warm_up)
+            && request
+                .msg
+                .chunk()
+                .is_some_and(|chunk| !pinned.contains(&chunk));
+        if is_warm {
+            warm.push(request);
+        } else {
+            pinned.extend(request.msg.chunk());
+            pinned.extend(request.msg.delta_chunks().into_iter().flatten());
+            rest.push(request);
+        }
+    }
+    warm.extend(rest);
+    warm
+}
+
+/// Moves control messages ahead of deltas, keeping the order within each group.
+/// Messages about a chunk that a delta before them was for stay behind that delta,
+/// so that e.g. relinquishing a chunk doesn't overtake its last changes.
+fn prioritize_control(
+    requests: Vec<MessageRequest<WorldNetMessage>>,
+) -> Vec<MessageRequest<WorldNetMessage>> {
+    let mut control = Vec::with_capacity(requests.len());
+    let mut deltas = Vec::new();
+    let mut pinned: FxHashSet<ChunkCoord> = FxHashSet::default();
+    for request in requests {
+        if let Some(chunks) = request.msg.delta_chunks() {
+            pinned.extend(chunks);
+            deltas.push(request);
+        } else if request
+            .msg
+            .chunk()
+            .is_some_and(|chunk| pinned.contains(&chunk))
+        {
+            deltas.push(request);
+        } else {
+            control.push(request);
+        }
+    }
+    control.extend(deltas);
+    control
+}
+
+/// Packs deltas into a ChunkPacket, with consecutive deltas that are the same in several chunks sent once.
+fn build_chunk_packet(mut deltas: Vec<(ChunkDelta, u8)>) -> WorldNetMessage {
+    // Chunks of a horizontal band carved through the world end up next to each other.
+    deltas.sort_by_key(|(delta, _)| (delta.chunk_coord.1, delta.chunk_coord.0));
+    let mut groups: Vec<(ChunkDelta, Vec<(ChunkCoord, u8)>)> = Vec::new();
+    for (delta, priority) in deltas {
+        match groups.last_mut() {
+            Some((template, chunks)) if template.same_pixels(&delta) => {
+                chunks.push((delta.chunk_coord, priority))
+            }
+            _ => groups.push((delta.clone(), vec![(delta.chunk_coord, priority)])),
+        }
+    }
+    let mut chunkpacket = Vec::new();
+    let mut templated = Vec::new();
+    for (template, chunks) in groups {
+        if chunks.len() > 1 {
+            templated.push(TemplatedDeltas { template, chunks });
+        } else {
+            chunkpacket.push((template, chunks[0].1));
+        }
+    }
+    WorldNetMessage::ChunkPacket {
+        chunkpacket,
+        templated,
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -142,7 +639,7 @@ enum ChunkState {
     /// Chunk is to be cleaned up.
     UnloadPending,
     /// We've requested to take authority from someone else, and waiting for transfer to complete.
-    Transfer,
+    Transfer { authority: OmniPeerId },
     /// Has higher priority and is waiting for next chunk update
     WantToGetAuth {
         authority: OmniPeerId,
@@ -163,10 +660,169 @@ impl ChunkState {
 // TODO handle exits.
 pub(crate) struct WorldManager {
     pub nice_terraforming: bool,
+    /// Used in place of chunks we know nothing about when cutting, so that terraforming can carve
+    /// into space that wasn't generated yet. Such chunks are kept in `synthetic_chunks`, apart
+    /// from real data, and are dropped as soon as real data for them gets stored.
+    pub default_chunk: Option<ChunkData>,
+    /// Cut chunks that started as `default_chunk`. Never sent to anyone.
+    synthetic_chunks: FxHashMap<ChunkCoord, ChunkData>,
+    /// Terraforming passed to `queue_terraform` is held until the next `update` and applied as a
+    /// batch, so that rapid digging updates each chunk once per tick instead of once per operation.
+    pub coalesce_terraform: bool,
+    queued_terraform: Vec<TerraformOp>,
+    /// How many times operations with a given default seed were done, see `vary_operation_seed`.
+    operation_repeats: FxHashMap<u64, u64>,
+    /// Host sends its result of terraforming to authorities of the changed chunks, which pass it
+    /// on to their listeners. Everyone still carves on their own right away, but ends up with
+    /// exactly what host got, instead of what their own chunk data gave them.
+    pub replicate_terraform: bool,
     pub is_host: bool,
+    /// Who we think the host is. Host-only messages from other peers are refused.
+    expected_host: Option<OmniPeerId>,
+    /// When two peers think they're the host, the one with higher epoch wins.
+    pub host_epoch: u64,
+    last_host_assertion: Option<u64>,
+    /// Update of the last `rebroadcast_authority_state`.
+    last_authority_rebroadcast: Option<u64>,
+    /// Peers that stay out of the lobby for `MISSING_PEER_GRACE` updates are treated as if they
+    /// left, for when we never got notified about it. Off by default, as lobby lists can lag.
+    pub drop_missing_peers: bool,
+    /// When peers we know of were first seen missing from the lobby, see `drop_missing_peers`.
+    missing_peers: FxHashMap<OmniPeerId, u64>,
+    last_missing_peer_check: Option<u64>,
+    /// Inclusive (min, max) chunk coordinates that are accepted from the network and terraformed.
+    /// Protects against corrupt messages making us iterate over absurd chunk ranges, and can be
+    /// narrowed down to confine the synced world to an arena, see `set_world_bounds`.
+    pub world_bounds: Option<(ChunkCoord, ChunkCoord)>,
+    /// Chunks that can't be terraformed by cut functions. They are still synced normally.
+    protected_chunks: FxHashSet<ChunkCoord>,
+    /// Explosions that would carve through more chunks than this get their radius scaled down
+    /// until they fit, so that bad durability data can't make one explosion change half the world.
+    pub max_affected_chunks: Option<usize>,
+    /// Upper bound on rays cast by an explosion, for hosts that can't keep up with barrages.
+    /// Big explosions get less round, but cost less. Never goes below 8 rays.
+    pub max_explosion_rays: Option<u64>,
+    /// Rays of explosions up to this radius are cast on the calling thread, as for small
+    /// explosions handing them out to rayon takes longer than casting them.
+    pub serial_explosion_radius: u64,
+    /// Vertical cuts deeper than this many pixels are shortened according to `cut_clamp`,
+    /// so that a script passing a huge range can't make a cut go through every chunk row.
+    pub max_cut_depth: Option<i32>,
+    pub cut_clamp: CutClamp,
+    /// Max time to spend on sending local chunk updates per frame.
+    /// Chunks that didn't fit stay marked as updated and are sent next frame, as do packets
+    /// that were put together but not encoded in time.
+    pub end_time_budget: Option<Duration>,
+    /// Chunk packets that didn't fit in `end_time_budget`, sent before anything else next frame.
+    unsent_packets: Vec<(OmniPeerId, Vec<(ChunkDelta, u8)>)>,
+    /// Max number of unloaded chunks removed from models per update, to spread out mass unloads.
+    /// Chunks are unloaded right away either way, this only defers the cleanup.
+    pub unload_per_update: Option<usize>,
+    /// Unloaded chunks that still have to be removed from models.
+    pending_forget: Vec<ChunkCoord>,
+    /// Min number of updates between pushes of a chunk we're authority of to host's storage.
+    pub storage_push_interval: u64,
+    /// How many pixels have to change before a chunk is pushed to host's storage.
+    pub storage_push_min_pixels: usize,
+    /// Pixels changed in chunks we're authority of since they were last pushed to host's storage,
+    /// along with update in which that happened.
+    unpushed_changes: FxHashMap<ChunkCoord, (usize, u64)>,
+    /// Dictionary for compressing chunk data sent to peers.
+    /// Only used for peers that reported having the same dictionary.
+    pub compression_dict: Option<CompressionDict>,
+    /// Effort spent on compressing chunk data sent to others.
+    pub compression_level: CompressionLevel,
+    /// Whether sizes before and after compression are recorded into `compression_stats`.
+    pub record_compression_stats: bool,
+    /// Shared with the background save thread.
+    compression_stats: Arc<Mutex<CompressionStats>>,
+    /// Version of `compression_dict` we've told others about.
+    announced_dict_version: Option<u64>,
+    peer_dict_versions: FxHashMap<OmniPeerId, u64>,
+    /// Only listen to chunks, never become an authority.
+    replicate_only: bool,
+    /// Which direction we're moving in, sent along with authority requests.
+    movement_hint: Option<(i32, i32)>,
+    /// Chunks that host granted us ahead of time, and update in which that happened.
+    speculative_chunks: FxHashMap<ChunkCoord, u64>,
+    /// Temporary priority overrides of chunks, see `boost_chunk_priority`.
+    priority_boosts: FxHashMap<ChunkCoord, PriorityBoost>,
+    /// Chunks prefetched ahead of the player, and update in which that happened.
+    prefetched_chunks: FxHashMap<ChunkCoord, u64>,
+    /// Peers that registered as replicate-only. Host never grants them authority.
+    replica_only_peers: FxHashSet<OmniPeerId>,
+    /// Replica-only peers waiting for chunks nobody has authority of, handed over to whoever gets authority next.
+    /// Also keeps the update in which each of them started waiting.
+    pending_listeners: FxHashMap<ChunkCoord, FxHashMap<OmniPeerId, u64>>,
+    /// How many RelinquishAuthority messages from each peer were rejected, as it wasn't the authority.
+    rejected_relinquishes: FxHashMap<OmniPeerId, u32>,
+    /// Peers with this many rejected relinquishes are considered suspicious.
+    pub relinquish_rejection_limit: u32,
+    /// How much better our priority has to be than authority's before we try to take a chunk over.
+    /// Should be at least 1.
+    pub min_takeover_priority_gap: u8,
+    /// Priority a chunk we just started updating requests authority with, given the chunk, our
+    /// position in chunks and priority the game gave it. None uses the game's priority as is.
+    /// Lower is better, see `distance_initial_priority`.
+    pub initial_priority_fn: Option<InitialPriorityFn>,
+    /// Host stops transferring authority of a chunk for `AUTHORITY_LOCK_COOLDOWN` updates once it
+    /// was transferred more than this many times within `AUTHORITY_CHANGE_WINDOW` updates, so that
+    /// peers with rotating priorities can't pass a chunk around forever. None to never lock.
+    pub max_authority_changes: Option<u32>,
+    /// Updates in which host transferred authority of a chunk, within the last window.
+    authority_changes: FxHashMap<ChunkCoord, VecDeque<u64>>,
+    /// Chunks whose authority can't be transferred, and update until which.
+    authority_locks: FxHashMap<ChunkCoord, u64>,
+    /// How many pending authority requests are sent per update, closest to the player first.
+    /// The rest are sent in later updates. No limit if None.
+    pub max_requests_per_update: Option<usize>,
+    /// How to handle ListenUpdate with take_auth for chunks we don't listen to or use.
+    pub untracked_take_auth: UntrackedTakeAuth,
+    /// Peers that left and weren't heard from since. Only used for consistency checks.
+    departed_peers: FxHashSet<OmniPeerId>,
+    /// For how many updates host keeps authority of a peer that left, in case it comes back.
+    /// Anyone else requesting those chunks gets them right away. Released immediately if 0.
+    pub peer_left_grace: u64,
+    /// Peers that left but keep their authority, and update until which they keep it.
+    orphaning_peers: FxHashMap<OmniPeerId, u64>,
+    /// Authority chunks whose changes are held back from listeners, see `suspend_sending`.
+    suspended_sending: FxHashSet<ChunkCoord>,
+    /// Set between `begin_transition` and `end_transition`, with arguments of the last frame end
+    /// Noita sent meanwhile, if any.
+    transition: Option<Option<TransitionEnd>>,
+    /// Failed authority transfers of a chunk in a row, and update until which authority isn't requested again.
+    transfer_backoff: FxHashMap<ChunkCoord, (u32, u64)>,
+    /// Update in which chunks went into Transfer state.
+    transfer_started: FxHashMap<ChunkCoord, u64>,
+    /// Last UpdateStorage sent for a chunk that host hasn't acknowledged yet,
+    /// with the update it was sent in and how many times it was sent.
+    unacked_storage: FxHashMap<ChunkCoord, (Option<ChunkData>, u64, u32)>,
+    /// How many times an UpdateStorage is sent before giving up on host acknowledging it.
+    /// Hosts on older versions never do.
+    pub storage_send_attempts: u32,
+    /// Messages we've sent to ourselves, when they are to be handled like ones that came over
+    /// the network instead of right away, see `assert_self_handling_matches`.
+    #[cfg(test)]
+    loopback: Option<Vec<WorldEnvelope>>,
+    /// As a listener, tell authorities how many deltas we got this often, in updates. Authorities
+    /// send the whole chunk again to listeners that turn out to have missed some. None to never tell.
+    pub delta_ack_interval: Option<u64>,
+    /// Deltas of chunks we listen to that we got since getting them whole, and how many of those we acknowledged.
+    deltas_received: FxHashMap<ChunkCoord, (u32, u32)>,
+    last_delta_ack: u64,
+    /// Deltas sent to listeners of our chunks since they were sent the whole chunk.
+    deltas_sent: FxHashMap<(ChunkCoord, OmniPeerId), SentDeltas>,
+    /// Updates to wait before requesting authority again after a failed transfer, doubled with each failure in a row,
+    /// up to `MAX_TRANSFER_RETRY_DELAY`.
+    pub transfer_retry_delay: u64,
+    /// After this many failed transfers in a row we only listen to the chunk, until we get its authority some other way.
+    pub max_transfer_retries: u32,
     my_pos: (i32, i32),
     cam_pos: (i32, i32),
     is_notplayer: bool,
+    /// Radius of chunks kept around us while not being a player, e.g. polymorphed or spectating.
+    /// None to keep as much as around a player.
+    pub notplayer_sync_radius: Option<i32>,
     my_peer_id: OmniPeerId,
     pub save_state: SaveState,
     /// We receive changes from other clients here, intending to send them to Noita.
@@ -174,22 +830,75 @@ pub(crate) struct WorldManager {
     /// We use that to create changes to be sent to other clients.
     outbound_model: WorldModel,
     /// Stores chunks that aren't under any authority.
-    chunk_storage: FxHashMap<ChunkCoord, ChunkData>,
+    chunk_storage: ChunkStorage,
+    /// When chunks in `chunk_storage` were last modified, see `chunk_modified_at`.
+    chunk_modified: FxHashMap<ChunkCoord, u64>,
+    /// Save of `chunk_storage` that is being written on another thread.
+    background_save: Option<BackgroundSave>,
     /// Who is the current chunk authority.
     authority_map: FxHashMap<ChunkCoord, (OmniPeerId, u8)>,
+    /// Previous authority of a chunk, whose storage can still arrive after authority moved on.
+    /// Cleared once anything newer is stored, so that a late retry of it doesn't overwrite that.
+    storage_handoff: FxHashMap<ChunkCoord, OmniPeerId>,
     /// Chunk states, according to docs/distributed_world_sync.drawio
     chunk_state: FxHashMap<ChunkCoord, ChunkState>,
     emitted_messages: Vec<MessageRequest<WorldNetMessage>>,
+    /// If set, emitted messages are pushed here instead of waiting for `get_emitted_msgs`.
+    /// Messages are still buffered in `emitted_messages` when the channel is full.
+    msg_sink: Option<SyncSender<MessageRequest<WorldNetMessage>>>,
+    /// Warn about possible runaway emission when this many messages are waiting to be sent.
+    pub emitted_soft_cap: usize,
+    /// Start dropping delta messages when this many messages are waiting to be sent.
+    pub emitted_hard_cap: usize,
+    /// Makes `get_emitted_msgs` return control messages before deltas, so that authority changes
+    /// aren't held up by a frame with lots of destruction. Doesn't apply with a message sink,
+    /// as messages aren't held back then.
+    pub prioritize_control: bool,
+    /// Makes `get_emitted_msgs` return chunks for those that want them with at least this priority
+    /// before anything else, so that chunks a player is waiting for don't queue behind background
+    /// transfers. Same as with `prioritize_control`, doesn't apply with a message sink.
+    pub warm_up_priority: Option<u8>,
+    last_emitted_cap_warning: Option<Instant>,
+    /// If set, this many of the last handled and emitted messages are kept in `history`.
+    pub record_history: Option<usize>,
+    /// Can hold up to twice the amount of messages, so that trimming doesn't happen on every message.
+    history: Vec<RecordedMessage>,
+    /// Terraforming operations are appended here, see `start_terraform_log`.
+    terraform_log: Option<File>,
+    /// Messages from Noita are appended here, see `start_noita_update_log`.
+    noita_update_log: Option<File>,
+    /// Handled messages are recorded here while tracing, see `start_trace`.
+    trace: Option<MessageTrace>,
+    /// Set while handling a traced message, so that messages we send to ourselves aren't traced separately.
+    in_traced_msg: bool,
     /// Which update it is?
-    /// Incremented every time `add_end()` gets called.
+    /// Incremented with every end of a game frame, or follows `clock` if `tick_duration` is set.
     current_update: u64,
+    /// How much real time an update is worth. When set, `current_update` follows time passed to `update`
+    /// instead of counting game frames, so that timeouts measured in updates don't depend on frame rate.
+    pub tick_duration: Option<Duration>,
+    /// Real time passed to `update` so far.
+    clock: Duration,
     /// Update number in which chunk has been updated locally.
     /// Used to track which chunks can be unloaded.
     chunk_last_update: FxHashMap<ChunkCoord, u64>,
+    /// Chunks that weren't updated locally for this many updates get unloaded even when they're
+    /// close, unless they're prefetched, boosted or suspended. Trims memory on low-end setups,
+    /// such chunks are requested again as soon as the game changes them.
+    pub idle_unload_after: Option<u64>,
+    /// When authority of a chunk we listen to is relinquished and the chunk is still around us,
+    /// request its authority right away instead of unloading it and waiting for the game to
+    /// change it again.
+    pub reacquire_relinquished: bool,
+    /// Our own priority of chunks we listen to as of their last local update, to request them at
+    /// with `reacquire_relinquished`.
+    listen_priority: FxHashMap<ChunkCoord, u8>,
     /// Stores last priority we used for that chunk, in case transfer fails and we'll need to request authority normally.
     last_request_priority: FxHashMap<ChunkCoord, u8>,
     world_num: u8,
-    pub materials: FxHashMap<u16, (u32, u32, CellType, u32)>,
+    /// Durability, hardness, cell type, color, and damage types (as a bitmask) that destroy the material
+    /// no matter its durability.
+    pub materials: FxHashMap<u16, (u32, u32, CellType, u32, u32)>,
     is_storage_recent: FxHashSet<ChunkCoord>,
     explosion_pointer: FxHashMap<ChunkCoord, Vec<usize>>,
     explosion_data: Vec<(usize, usize, ExTarget, u64)>,
@@ -197,6 +906,260 @@ pub(crate) struct WorldManager {
     tx: Sender<(ChunkCoord, ChunkData)>,
 }
 
+/// Message that went through the world manager, kept for debugging.
+#[derive(Debug, Clone)]
+pub(crate) enum RecordedMessage {
+    Handled {
+        source: OmniPeerId,
+        msg: WorldNetMessage,
+    },
+    Emitted {
+        dst: Destination,
+        msg: WorldNetMessage,
+    },
+}
+
+/// Approximate memory used by the world, in bytes, as returned by `WorldManager::memory_estimate`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct WorldMemoryStats {
+    pub(crate) chunk_storage: usize,
+    pub(crate) inbound_model: usize,
+    pub(crate) outbound_model: usize,
+    pub(crate) authority_map: usize,
+    pub(crate) chunk_state: usize,
+}
+
+impl WorldMemoryStats {
+    pub(crate) fn total(&self) -> usize {
+        self.chunk_storage
+            + self.inbound_model
+            + self.outbound_model
+            + self.authority_map
+            + self.chunk_state
+    }
+}
+
+/// Problem found by `WorldManager::validate_consistency`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ConsistencyIssue {
+    /// Host thinks this peer is the authority, but it doesn't act like one.
+    UnclaimedAuthority {
+        chunk: ChunkCoord,
+        authority: OmniPeerId,
+    },
+    /// We're acting as authority, but host has someone else (or nobody) listed.
+    UnlistedAuthority {
+        chunk: ChunkCoord,
+        listed: Option<OmniPeerId>,
+    },
+    /// Chunk updates are sent to a peer that has left.
+    DepartedListener {
+        chunk: ChunkCoord,
+        listener: OmniPeerId,
+    },
+}
+
+/// Messages handled by a world, along with messages each of them caused to be sent.
+/// Replaying it against a fresh world shows whether protocol behavior changed.
+#[derive(Debug, Encode, Decode, Clone)]
+pub(crate) struct MessageTrace {
+    is_host: bool,
+    my_peer_id: OmniPeerId,
+    steps: Vec<TraceStep>,
+}
+
+#[derive(Debug, Encode, Decode, Clone)]
+struct TraceStep {
+    source: OmniPeerId,
+    msg: WorldNetMessage,
+    emitted: Vec<(Destination, WorldNetMessage)>,
+}
+
+impl MessageTrace {
+    /// Handles traced messages again, returns index of the first one that caused different messages to be sent.
+    /// `world` has to be a fresh one, with the same peer id and host status as the traced one.
+    #[cfg(test)]
+    pub(crate) fn replay(&self, world: &mut WorldManager) -> Result<(), usize> {
+        assert_eq!(
+            (world.is_host, world.my_peer_id),
+            (self.is_host, self.my_peer_id),
+            "trace replayed on a different peer"
+        );
+        world.start_trace();
+        for (i, step) in self.steps.iter().enumerate() {
+            world.handle_msg(step.source, step.msg.clone());
+            let replayed = world.trace.as_ref().and_then(|trace| trace.steps.last());
+            if replayed.map(|replayed| bitcode::encode(&replayed.emitted))
+                != Some(bitcode::encode(&step.emitted))
+            {
+                world.trace = None;
+                return Err(i);
+            }
+        }
+        world.trace = None;
+        Ok(())
+    }
+}
+
+/// What to do with imported chunks that already exist at the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ImportPolicy {
+    Overwrite,
+    SkipExisting,
+    /// Only imported non-air pixels are written, and only over existing air.
+    /// Useful for stamping prefabs without erasing what's around them.
+    MergeNonAir,
+}
+
+/// Which part of a too deep vertical cut is kept, see `WorldManager::max_cut_depth`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CutClamp {
+    /// Starting at the top of the cut.
+    FromTop,
+    /// Around the middle of the cut.
+    Centered,
+}
+
+/// Whether a pixel can be dug out right now, see `WorldManager::can_terraform`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TerraformPermission {
+    Allowed,
+    /// In a chunk of `protect_region`.
+    Protected,
+    /// Outside of `world_bounds`.
+    OutOfBounds,
+    /// Material that cuts leave as it is.
+    TooHard,
+}
+
+impl TerraformPermission {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            TerraformPermission::Allowed => "allowed",
+            TerraformPermission::Protected => "protected",
+            TerraformPermission::OutOfBounds => "out_of_bounds",
+            TerraformPermission::TooHard => "too_hard",
+        }
+    }
+}
+
+/// How a material holds up against an explosion ray, see `WorldManager::effective_durability`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct EffectiveDurability {
+    pub durability: u32,
+    /// Ray strength used up per pixel, after the ray's multiplier.
+    pub ray_cost: u64,
+    /// Whether the damage type destroys it regardless of durability.
+    pub vulnerable: bool,
+}
+
+impl EffectiveDurability {
+    /// Whether an explosion of durability `d` can't destroy this material, no matter the ray strength.
+    pub(crate) fn resists(&self, d: u32) -> bool {
+        self.durability > d && !self.vulnerable
+    }
+
+    /// Whether an explosion of durability `d` with `ray` strength left stops at this material.
+    pub(crate) fn stops(&self, d: u32, ray: u64) -> bool {
+        self.resists(d) || ray < self.ray_cost
+    }
+}
+
+/// Single terraforming operation, see `WorldManager::terraform_batch`.
+#[derive(Clone, Copy, Encode, Decode)]
+pub(crate) enum TerraformOp {
+    Circle {
+        x: i32,
+        y: i32,
+        r: i32,
+        mat: Option<u16>,
+        /// Percent of filled pixels that get `mat`, the rest are carved to air.
+        density: u8,
+        chance: u8,
+        seed: u64,
+        power: Option<u32>,
+    },
+    Line {
+        x: i32,
+        y: i32,
+        lx: i32,
+        ly: i32,
+        r: i32,
+        chance: u8,
+        seed: u64,
+        power: Option<u32>,
+    },
+    /// Inclusive pixel rectangle filled with a material.
+    Rect {
+        min: (i32, i32),
+        max: (i32, i32),
+        material: u16,
+    },
+    Explosion(ExplosionData),
+}
+
+impl TerraformOp {
+    /// Pixel bounding box of everything the operation can change.
+    fn bounds(&self) -> ((i32, i32), (i32, i32)) {
+        let around = |(min_x, min_y): (i32, i32), (max_x, max_y): (i32, i32), r: i32| {
+            (
+                (min_x.saturating_sub(r), min_y.saturating_sub(r)),
+                (max_x.saturating_add(r), max_y.saturating_add(r)),
+            )
+        };
+        match *self {
+            TerraformOp::Circle { x, y, r, .. } => around((x, y), (x, y), r),
+            TerraformOp::Line {
+                x, y, lx, ly, r, ..
+            } => around((x.min(lx), y.min(ly)), (x.max(lx), y.max(ly)), r),
+            TerraformOp::Rect { min, max, .. } => (
+                (min.0.min(max.0), min.1.min(max.1)),
+                (min.0.max(max.0), min.1.max(max.1)),
+            ),
+            TerraformOp::Explosion(ex) => {
+                around((ex.x, ex.y), (ex.x, ex.y), ex.r.min(i32::MAX as u64) as i32)
+            }
+        }
+    }
+}
+
+/// Stored chunks as they were before a terraforming batch, see `WorldManager::terraform_batch`.
+pub(crate) struct TerraformUndo {
+    /// Chunks that the batch changed, None for ones that weren't in storage.
+    chunks: Vec<(ChunkCoord, Option<ChunkData>)>,
+    /// Encoded storage of changed chunks right after the batch, to tell if they changed since.
+    results: FxHashMap<ChunkCoord, Option<Vec<u8>>>,
+}
+
+/// What a cut changed a chunk from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CutBase {
+    Storage,
+    /// Live data of a chunk we're an authority of or listen to, which storage is up to date with.
+    Model,
+    /// `WorldManager::default_chunk`, as nothing is known about the chunk.
+    Synthetic,
+}
+
+/// What to do when someone wants to give us authority of a chunk we don't care about.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UntrackedTakeAuth {
+    /// Ignore the update, sender keeps the authority.
+    #[default]
+    Ignore,
+    /// Sender keeps the authority, and stops sending us updates of the chunk.
+    Unload,
+    /// Take authority anyway.
+    Take,
+}
+
+/// Chunks of an exported region, with coordinates relative to the region's origin,
+/// so that it can be imported anywhere.
+#[derive(Debug, Default, Encode, Decode, Clone)]
+pub(crate) struct ExportedRegion {
+    chunks: Vec<(ChunkCoord, ChunkData)>,
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub(crate) enum ExTarget {
     Ray(u64),
@@ -204,6 +1167,139 @@ pub(crate) enum ExTarget {
     RayRad((u64, u64)),
 }
 
+/// Explosion rays can't be jittered further than this, or they'd leave their sector.
+const MAX_RAY_JITTER: f32 = 0.99;
+
+/// How often host reminds everyone that it's the host, in updates.
+const HOST_ASSERTION_INTERVAL: u64 = 300;
+/// Updates over which authority transfers of a chunk are counted, see `max_authority_changes`.
+const AUTHORITY_CHANGE_WINDOW: u64 = 600;
+/// For how many updates authority of a chunk that changed hands too often can't be transferred.
+const AUTHORITY_LOCK_COOLDOWN: u64 = 1200;
+/// How often authority state can be rebroadcast, see `rebroadcast_authority_state`.
+const AUTHORITY_REBROADCAST_INTERVAL: u64 = 600;
+/// Tracked default seeds are forgotten all at once past this many, see `vary_operation_seed`.
+const MAX_TRACKED_OPERATIONS: usize = 4096;
+/// How often known peers are compared with the lobby, see `WorldManager::drop_missing_peers`.
+const MISSING_PEER_CHECK_INTERVAL: u64 = 60;
+/// For how many updates a peer has to be missing from the lobby before its chunks are dropped.
+const MISSING_PEER_GRACE: u64 = 600;
+
+/// Max number of chunks requested by a single prefetch.
+const MAX_PREFETCH_CHUNKS: usize = 256;
+/// For how many updates prefetched chunks are kept, if the player doesn't get to them.
+const PREFETCH_TIMEOUT: u64 = 300;
+/// Max number of chunks protected by `protect_region` at once.
+const MAX_PROTECTED_CHUNKS: usize = 65536;
+/// Max number of chunks in a rendered region, a single chunk is 64KiB of RGBA.
+const MAX_RENDERED_CHUNKS: usize = 4096;
+/// Color of chunks we don't know anything about in rendered regions.
+const UNKNOWN_CHUNK_COLOR: image::Rgba<u8> = image::Rgba([255, 0, 255, 255]);
+
+/// How many chunks can wait for background save writer at once.
+const BACKGROUND_SAVE_QUEUE: usize = 64;
+
+/// Priority of chunks granted ahead of a moving player. Any actual request takes them over.
+const SPECULATIVE_PRIORITY: u8 = u8::MAX;
+/// How many chunks ahead of a moving player are granted.
+const SPECULATIVE_DISTANCE: i32 = 2;
+/// For how many updates speculatively granted chunks are kept, if they're out of range.
+const SPECULATIVE_TIMEOUT: u64 = 60;
+
+/// Offsets of the 8 surrounding chunks, row by row from top left.
+const NEIGHBOR_OFFSETS: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// For how many updates a replica waits for somebody to get authority of a chunk, before it has to ask again.
+const PENDING_LISTENER_TIMEOUT: u64 = 600;
+
+/// Longest delay before requesting authority again after failed transfers, in updates.
+const MAX_TRANSFER_RETRY_DELAY: u64 = 36000;
+
+/// After how many updates a transfer with no reply is considered lost.
+const TRANSFER_TIMEOUT: u64 = 300;
+
+/// After how many updates an UpdateStorage that host didn't acknowledge is sent again.
+const STORAGE_ACK_TIMEOUT: u64 = 120;
+
+/// Deltas a listener hasn't acknowledged this many updates after they were sent are considered lost.
+const DELTA_ACK_TIMEOUT: u64 = 300;
+
+/// Listeners that are this many deltas behind get the whole chunk instead of waiting for them.
+/// Also bounds how many deltas are remembered per listener of a chunk.
+const MAX_UNACKED_DELTAS: u32 = 128;
+
+/// Modification time of chunks that came from somewhere else, like imported regions or old saves.
+pub(crate) const UNKNOWN_MODIFICATION_TIME: u64 = 0;
+
+/// Way bigger than anything reachable in Noita, parallel worlds included.
+pub(crate) const DEFAULT_WORLD_BOUNDS: (ChunkCoord, ChunkCoord) = (
+    ChunkCoord(-(1 << 16), -(1 << 16)),
+    ChunkCoord(1 << 16, 1 << 16),
+);
+
+/// Chunk data queries of at least this many chunks are resolved in parallel by host.
+const PARALLEL_QUERY_MIN_CHUNKS: usize = 64;
+
+/// Radius of chunks kept around the player when the camera is close to them.
+const PLAYER_SYNC_RADIUS: i32 = 3;
+
+/// Inclusive chunk boxes that are kept alive around the player, and around the camera if it's far from the player.
+fn sync_bounds(
+    my_pos: (i32, i32),
+    cam_pos: (i32, i32),
+    player_radius: i32,
+) -> ((ChunkCoord, ChunkCoord), Option<(ChunkCoord, ChunkCoord)>) {
+    let around = |(x, y): (i32, i32), radius: i32| {
+        (
+            ChunkCoord(x - radius, y - radius),
+            ChunkCoord(x + radius, y + radius),
+        )
+    };
+    let (x, y) = my_pos;
+    let (cx, cy) = cam_pos;
+    if (x - cx).abs() > 2 || (y - cy).abs() > 2 {
+        (around(my_pos, 2), Some(around(cam_pos, 2)))
+    } else {
+        (around(my_pos, player_radius), None)
+    }
+}
+
+fn bounds_contain((min, max): (ChunkCoord, ChunkCoord), chunk: ChunkCoord) -> bool {
+    (min.0..=max.0).contains(&chunk.0) && (min.1..=max.1).contains(&chunk.1)
+}
+
+/// See `WorldManager::initial_priority_fn`.
+pub(crate) type InitialPriorityFn = fn(ChunkCoord, (i32, i32), u8) -> u8;
+
+/// `WorldManager::initial_priority_fn` that makes chunks worse the further they're from us,
+/// so that players close to a chunk get it first.
+pub(crate) fn distance_initial_priority(chunk: ChunkCoord, (x, y): (i32, i32), priority: u8) -> u8 {
+    let distance = chunk.0.abs_diff(x).max(chunk.1.abs_diff(y));
+    priority.saturating_add(distance.min(u8::MAX as u32) as u8)
+}
+
+fn should_kill(
+    my_pos: (i32, i32),
+    cam_pos: (i32, i32),
+    chx: i32,
+    chy: i32,
+    player_radius: i32,
+) -> bool {
+    let (player_bounds, camera_bounds) = sync_bounds(my_pos, cam_pos, player_radius);
+    let chunk = ChunkCoord(chx, chy);
+    !(bounds_contain(player_bounds, chunk)
+        || camera_bounds.is_some_and(|bounds| bounds_contain(bounds, chunk)))
+}
+
 impl WorldManager {
     #[allow(clippy::type_complexity)]
     pub(crate) fn new(
@@ -216,6 +1312,24 @@ impl WorldManager {
         Receiver<(ChunkCoord, ChunkData)>,
         Sender<FxHashMap<u16, u32>>,
         Sender<(ChunkCoord, ChunkData)>,
+    ) {
+        Self::new_with_sink(is_host, my_peer_id, save_state, None)
+    }
+
+    /// Same as `new`, but emitted messages are pushed into `msg_sink` instead of waiting for `get_emitted_msgs`.
+    /// `get_emitted_msgs` still returns whatever didn't fit into the channel.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn new_with_sink(
+        is_host: bool,
+        my_peer_id: OmniPeerId,
+        save_state: SaveState,
+        msg_sink: Option<SyncSender<MessageRequest<WorldNetMessage>>>,
+    ) -> (
+        Self,
+        Receiver<(ChunkCoord, RgbaImage)>,
+        Receiver<(ChunkCoord, ChunkData)>,
+        Sender<FxHashMap<u16, u32>>,
+        Sender<(ChunkCoord, ChunkData)>,
     ) {
         let (send, rx) = mpsc::channel::<(ChunkCoord, RgbaImage)>();
         let (sendm, rxm) = mpsc::channel::<FxHashMap<u16, u32>>();
@@ -242,7 +1356,7 @@ impl WorldManager {
                 thread::sleep(Duration::from_millis(16));
             }
         });
-        let chunk_storage: FxHashMap<ChunkCoord, ChunkData> = save_state.load().unwrap_or_default();
+        let (chunk_storage, chunk_modified) = load_saved_chunks(&save_state);
         for (ch, c) in chunk_storage.iter() {
             let _ = tx.send((*ch, c.clone()));
         }
@@ -251,20 +1365,107 @@ impl WorldManager {
             (
                 WorldManager {
                     nice_terraforming: true,
+                    default_chunk: None,
+                    synthetic_chunks: Default::default(),
+                    coalesce_terraform: false,
+                    replicate_terraform: false,
+                    queued_terraform: Vec::new(),
+                    operation_repeats: Default::default(),
                     is_host,
+                    expected_host: is_host.then_some(my_peer_id),
+                    host_epoch: 0,
+                    last_host_assertion: None,
+                    last_authority_rebroadcast: None,
+                    drop_missing_peers: false,
+                    missing_peers: Default::default(),
+                    last_missing_peer_check: None,
+                    world_bounds: Some(DEFAULT_WORLD_BOUNDS),
+                    protected_chunks: Default::default(),
+                    end_time_budget: None,
+                    unsent_packets: Vec::new(),
+                    max_affected_chunks: None,
+                    max_explosion_rays: None,
+                    serial_explosion_radius: 16,
+                    max_cut_depth: None,
+                    cut_clamp: CutClamp::FromTop,
+                    unload_per_update: None,
+                    pending_forget: Vec::new(),
+                    storage_push_interval: 600,
+                    storage_push_min_pixels: 1024,
+                    unpushed_changes: Default::default(),
+                    compression_dict: None,
+                    compression_level: CompressionLevel::default(),
+                    record_compression_stats: false,
+                    compression_stats: Default::default(),
+                    announced_dict_version: None,
+                    peer_dict_versions: Default::default(),
+                    replicate_only: false,
+                    movement_hint: None,
+                    speculative_chunks: Default::default(),
+                    priority_boosts: Default::default(),
+                    prefetched_chunks: Default::default(),
+                    replica_only_peers: Default::default(),
+                    pending_listeners: Default::default(),
+                    rejected_relinquishes: Default::default(),
+                    relinquish_rejection_limit: 16,
+                    min_takeover_priority_gap: 1,
+                    initial_priority_fn: None,
+                    max_authority_changes: Some(8),
+                    authority_changes: Default::default(),
+                    authority_locks: Default::default(),
+                    max_requests_per_update: None,
+                    untracked_take_auth: UntrackedTakeAuth::default(),
+                    departed_peers: Default::default(),
+                    peer_left_grace: 0,
+                    orphaning_peers: Default::default(),
+                    suspended_sending: Default::default(),
+                    transition: None,
+                    transfer_backoff: Default::default(),
+                    transfer_started: Default::default(),
+                    unacked_storage: Default::default(),
+                    storage_send_attempts: 5,
+                    #[cfg(test)]
+                    loopback: None,
+                    delta_ack_interval: None,
+                    deltas_received: Default::default(),
+                    last_delta_ack: 0,
+                    deltas_sent: Default::default(),
+                    transfer_retry_delay: 1,
+                    max_transfer_retries: 5,
                     my_pos: (i32::MIN / 2, i32::MIN / 2),
                     cam_pos: (i32::MIN / 2, i32::MIN / 2),
                     is_notplayer: false,
+                    notplayer_sync_radius: Some(PLAYER_SYNC_RADIUS - 1),
                     my_peer_id,
                     save_state,
                     inbound_model: Default::default(),
                     outbound_model: Default::default(),
                     authority_map: Default::default(),
-                    chunk_storage,
+                    storage_handoff: Default::default(),
+                    chunk_storage: chunk_storage.into_iter().collect(),
+                    chunk_modified: chunk_modified.clone(),
+                    background_save: None,
                     chunk_state: Default::default(),
                     emitted_messages: Default::default(),
+                    msg_sink,
+                    emitted_soft_cap: 10_000,
+                    emitted_hard_cap: 100_000,
+                    prioritize_control: false,
+                    warm_up_priority: None,
+                    last_emitted_cap_warning: None,
+                    record_history: None,
+                    terraform_log: None,
+                    noita_update_log: None,
+                    history: Vec::new(),
+                    trace: None,
+                    in_traced_msg: false,
                     current_update: 0,
+                    tick_duration: None,
+                    clock: Duration::ZERO,
                     chunk_last_update: Default::default(),
+                    idle_unload_after: None,
+                    reacquire_relinquished: false,
+                    listen_priority: Default::default(),
                     last_request_priority: Default::default(),
                     world_num: 0,
                     materials: Default::default(),
@@ -283,20 +1484,107 @@ impl WorldManager {
             (
                 WorldManager {
                     nice_terraforming: true,
+                    default_chunk: None,
+                    synthetic_chunks: Default::default(),
+                    coalesce_terraform: false,
+                    replicate_terraform: false,
+                    queued_terraform: Vec::new(),
+                    operation_repeats: Default::default(),
                     is_host,
+                    expected_host: is_host.then_some(my_peer_id),
+                    host_epoch: 0,
+                    last_host_assertion: None,
+                    last_authority_rebroadcast: None,
+                    drop_missing_peers: false,
+                    missing_peers: Default::default(),
+                    last_missing_peer_check: None,
+                    world_bounds: Some(DEFAULT_WORLD_BOUNDS),
+                    protected_chunks: Default::default(),
+                    end_time_budget: None,
+                    unsent_packets: Vec::new(),
+                    max_affected_chunks: None,
+                    max_explosion_rays: None,
+                    serial_explosion_radius: 16,
+                    max_cut_depth: None,
+                    cut_clamp: CutClamp::FromTop,
+                    unload_per_update: None,
+                    pending_forget: Vec::new(),
+                    storage_push_interval: 600,
+                    storage_push_min_pixels: 1024,
+                    unpushed_changes: Default::default(),
+                    compression_dict: None,
+                    compression_level: CompressionLevel::default(),
+                    record_compression_stats: false,
+                    compression_stats: Default::default(),
+                    announced_dict_version: None,
+                    peer_dict_versions: Default::default(),
+                    replicate_only: false,
+                    movement_hint: None,
+                    speculative_chunks: Default::default(),
+                    priority_boosts: Default::default(),
+                    prefetched_chunks: Default::default(),
+                    replica_only_peers: Default::default(),
+                    pending_listeners: Default::default(),
+                    rejected_relinquishes: Default::default(),
+                    relinquish_rejection_limit: 16,
+                    min_takeover_priority_gap: 1,
+                    initial_priority_fn: None,
+                    max_authority_changes: Some(8),
+                    authority_changes: Default::default(),
+                    authority_locks: Default::default(),
+                    max_requests_per_update: None,
+                    untracked_take_auth: UntrackedTakeAuth::default(),
+                    departed_peers: Default::default(),
+                    peer_left_grace: 0,
+                    orphaning_peers: Default::default(),
+                    suspended_sending: Default::default(),
+                    transition: None,
+                    transfer_backoff: Default::default(),
+                    transfer_started: Default::default(),
+                    unacked_storage: Default::default(),
+                    storage_send_attempts: 5,
+                    #[cfg(test)]
+                    loopback: None,
+                    delta_ack_interval: None,
+                    deltas_received: Default::default(),
+                    last_delta_ack: 0,
+                    deltas_sent: Default::default(),
+                    transfer_retry_delay: 1,
+                    max_transfer_retries: 5,
                     my_pos: (i32::MIN / 2, i32::MIN / 2),
                     cam_pos: (i32::MIN / 2, i32::MIN / 2),
                     is_notplayer: false,
+                    notplayer_sync_radius: Some(PLAYER_SYNC_RADIUS - 1),
                     my_peer_id,
                     save_state,
                     inbound_model: Default::default(),
                     outbound_model: Default::default(),
                     authority_map: Default::default(),
-                    chunk_storage,
+                    storage_handoff: Default::default(),
+                    chunk_storage: chunk_storage.into_iter().collect(),
+                    chunk_modified: chunk_modified.clone(),
+                    background_save: None,
                     chunk_state: Default::default(),
                     emitted_messages: Default::default(),
+                    msg_sink,
+                    emitted_soft_cap: 10_000,
+                    emitted_hard_cap: 100_000,
+                    prioritize_control: false,
+                    warm_up_priority: None,
+                    last_emitted_cap_warning: None,
+                    record_history: None,
+                    terraform_log: None,
+                    noita_update_log: None,
+                    history: Vec::new(),
+                    trace: None,
+                    in_traced_msg: false,
                     current_update: 0,
+                    tick_duration: None,
+                    clock: Duration::ZERO,
                     chunk_last_update: Default::default(),
+                    idle_unload_after: None,
+                    reacquire_relinquished: false,
+                    listen_priority: Default::default(),
                     last_request_priority: Default::default(),
                     world_num: 0,
                     materials: Default::default(),
@@ -314,3086 +1602,11080 @@ impl WorldManager {
         }
     }
 
-    pub(crate) fn get_chunks(&self) -> FxHashMap<ChunkCoord, ChunkData> {
-        self.chunk_storage.clone()
+    /// Makes this peer a passive replica, that only listens to chunks and never gets authority over them.
+    pub(crate) fn set_replicate_only(&mut self, replicate_only: bool) {
+        self.replicate_only = replicate_only;
+        self.emit_msg(
+            Destination::Host,
+            WorldNetMessage::RegisterReplicaOnly { replicate_only },
+        );
     }
 
-    fn chunk_updated_locally(
-        &mut self,
-        chunk: ChunkCoord,
-        priority: u8,
-        pos: Option<(i32, i32, i32, i32, bool)>,
-        world_num: u8,
-    ) -> Vec<(OmniPeerId, u8)> {
-        if let Some((px, py, cx, cy, is_not)) = pos {
-            self.my_pos = (px, py);
-            self.cam_pos = (cx, cy);
-            self.is_notplayer = is_not;
-            if self.world_num != world_num {
-                self.world_num = world_num;
-                self.reset();
-            }
-        } else if self.world_num != world_num {
-            self.world_num = world_num;
-            self.reset();
+    /// Keeps stored chunks that weren't changed for `updates` compressed in memory.
+    /// Saves memory in big worlds, at the cost of decompressing those chunks whenever they're used.
+    pub(crate) fn set_cold_storage_after(&mut self, updates: Option<u64>) {
+        self.chunk_storage.cold_after = updates;
+    }
+
+    /// Should be called whenever we learn who the host is.
+    pub(crate) fn set_expected_host(&mut self, host: OmniPeerId) {
+        if !self.is_host {
+            self.expected_host = Some(host);
         }
-        let entry = self.chunk_state.entry(chunk).or_insert_with(|| {
-            debug!("Created entry for {chunk:?}");
-            ChunkState::RequestAuthority {
-                priority,
-                can_wait: true,
-            }
-        });
-        let mut emit_queue = Vec::new();
-        self.chunk_last_update.insert(chunk, self.current_update);
-        let mut chunks_to_send = Vec::new();
-        match entry {
-            ChunkState::Listening {
-                authority,
-                priority: pri,
-            } => {
-                if *pri > priority {
-                    let cs = ChunkState::WantToGetAuth {
-                        authority: *authority,
-                        auth_priority: *pri,
-                        my_priority: priority,
-                    };
-                    emit_queue.push((
-                        Destination::Peer(*authority),
-                        WorldNetMessage::LoseAuthority {
-                            chunk,
-                            new_priority: priority,
-                            new_authority: self.my_peer_id,
-                        },
-                    ));
-                    self.chunk_state.insert(chunk, cs);
-                }
-            }
-            ChunkState::WantToGetAuth {
-                authority,
-                auth_priority: auth_pri,
-                my_priority: my_pri,
-            } => {
-                if *my_pri != priority {
-                    *my_pri = priority;
-                    if *auth_pri <= priority {
-                        let cs = ChunkState::Listening {
-                            authority: *authority,
-                            priority: *auth_pri,
-                        };
-                        self.chunk_state.insert(chunk, cs);
-                    } else {
-                        emit_queue.push((
-                            Destination::Peer(*authority),
-                            WorldNetMessage::LoseAuthority {
-                                chunk,
-                                new_priority: priority,
-                                new_authority: self.my_peer_id,
-                            },
-                        ));
-                    }
-                }
-            }
-            ChunkState::Authority {
-                listeners,
-                priority: pri,
-                new_authority,
-                stop_sending,
-            } => {
-                let Some(delta) = self.outbound_model.get_chunk_delta(chunk, false) else {
-                    return Vec::new();
-                };
-                if *pri != priority {
-                    *pri = priority;
-                    emit_queue.push((
-                        Destination::Host,
-                        WorldNetMessage::ChangePriority { chunk, priority },
-                    ));
-                }
-                let mut new_auth = None;
-                if let Some(new) = new_authority {
-                    if new.1 >= priority {
-                        *new_authority = None;
-                        *stop_sending = false
-                    } else {
-                        new_auth = Some(new.0)
-                    }
-                } else {
-                    *stop_sending = false
-                }
-                let mut new_auth_got = false;
-                if !*stop_sending {
-                    for &listener in listeners.iter() {
-                        let take_auth = new_auth == Some(listener);
-                        if take_auth {
-                            new_auth_got = true
-                        }
-                        if take_auth {
-                            emit_queue.push((
-                                Destination::Peer(listener),
-                                WorldNetMessage::ListenUpdate {
-                                    delta: delta.clone(),
-                                    priority,
-                                    take_auth,
-                                },
-                            ));
-                            chunks_to_send = Vec::new()
-                        } else {
-                            chunks_to_send.push((listener, priority));
-                        }
-                    }
-                }
-                if new_auth_got && new_auth.is_some() {
-                    *stop_sending = true
-                }
-            }
-            _ => {}
+    }
+
+    /// Stops acting as a host after losing a split brain resolution to `host`.
+    /// Everything host-specific is dropped, chunks will be requested from the new host.
+    /// Stored chunks are kept, as the rest of the proxy can still ask us for them.
+    fn demote_from_host(&mut self, host: OmniPeerId) {
+        warn!("{host} is the actual host, demoting");
+        self.is_host = false;
+        self.expected_host = Some(host);
+        self.replica_only_peers.clear();
+        self.reset_sync_state();
+    }
+
+    /// Velocity of the player, used by host to grant authority of chunks ahead of time.
+    pub(crate) fn set_movement_hint(&mut self, velocity: (i32, i32)) {
+        self.movement_hint = (velocity != (0, 0)).then_some(velocity);
+    }
+
+    /// Grants authority of free chunks in the direction peer is moving in, at a priority
+    /// that any actual request beats.
+    fn pre_grant_authority(&mut self, chunk: ChunkCoord, peer: OmniPeerId, velocity: (i32, i32)) {
+        let direction = (velocity.0.signum(), velocity.1.signum());
+        if direction == (0, 0) {
+            return;
         }
-        for (dst, msg) in emit_queue {
-            self.emit_msg(dst, msg)
+        for distance in 1..=SPECULATIVE_DISTANCE {
+            let ahead = ChunkCoord(
+                chunk.0 + direction.0 * distance,
+                chunk.1 + direction.1 * distance,
+            );
+            if !self.chunk_in_bounds(ahead) || self.authority_map.contains_key(&ahead) {
+                continue;
+            }
+            debug!("Granting {peer} authority of {ahead:?} ahead of time");
+            self.emit_got_authority(ahead, peer, SPECULATIVE_PRIORITY);
         }
-        chunks_to_send
     }
 
-    pub(crate) fn update(&mut self) -> Vec<NoitaWorldUpdate> {
-        fn should_kill(
-            my_pos: (i32, i32),
-            cam_pos: (i32, i32),
-            chx: i32,
-            chy: i32,
-            is_notplayer: bool,
-        ) -> bool {
-            let (x, y) = my_pos;
-            let (cx, cy) = cam_pos;
-            if (x - cx).abs() > 2 || (y - cy).abs() > 2 {
-                !(chx <= x + 2 && chx >= x - 2 && chy <= y + 2 && chy >= y - 2
-                    || chx <= cx + 2 && chx >= cx - 2 && chy <= cy + 2 && chy >= cy - 2)
-            } else if is_notplayer {
-                !(chx <= x + 2 && chx >= x - 2 && chy <= y + 2 && chy >= y - 2)
-            } else {
-                !(chx <= x + 3 && chx >= x - 3 && chy <= y + 3 && chy >= y - 3)
-            }
+    fn chunk_in_bounds(&self, chunk: ChunkCoord) -> bool {
+        self.world_bounds
+            .map(|(min, max)| {
+                (min.0..=max.0).contains(&chunk.0) && (min.1..=max.1).contains(&chunk.1)
+            })
+            .unwrap_or(true)
+    }
+
+    /// Changes `world_bounds`. Host drops stored chunks that are out of new bounds.
+    pub(crate) fn set_world_bounds(&mut self, bounds: Option<(ChunkCoord, ChunkCoord)>) {
+        self.world_bounds = bounds;
+        if !self.is_host {
+            return;
         }
-        let mut emit_queue = Vec::new();
-        for (&chunk, state) in self.chunk_state.iter_mut() {
-            let chunk_last_update = self
-                .chunk_last_update
-                .get(&chunk)
-                .copied()
-                .unwrap_or_default();
-            match state {
-                ChunkState::RequestAuthority { priority, can_wait } => {
-                    let priority = *priority;
-                    emit_queue.push((
-                        Destination::Host,
-                        WorldNetMessage::RequestAuthority {
-                            chunk,
-                            priority,
-                            can_wait: *can_wait,
-                        },
-                    ));
-                    *state = ChunkState::WaitingForAuthority;
-                    self.last_request_priority.insert(chunk, priority);
-                    debug!("Requested authority for {chunk:?}")
-                }
-                // This state doesn't have much to do.
-                ChunkState::WaitingForAuthority => {
-                    if should_kill(
-                        self.my_pos,
-                        self.cam_pos,
-                        chunk.0,
-                        chunk.1,
-                        self.is_notplayer,
-                    ) {
-                        *state = ChunkState::UnloadPending;
-                    }
-                }
-                ChunkState::Listening { authority, .. } => {
-                    if should_kill(
-                        self.my_pos,
-                        self.cam_pos,
-                        chunk.0,
-                        chunk.1,
-                        self.is_notplayer,
-                    ) {
-                        debug!("Unloading [listening] chunk {chunk:?}");
-                        emit_queue.push((
-                            Destination::Peer(*authority),
-                            WorldNetMessage::ListenStopRequest { chunk },
-                        ));
-                        *state = ChunkState::UnloadPending;
-                    }
-                }
-                ChunkState::Authority { new_authority, .. } => {
-                    if should_kill(
-                        self.my_pos,
-                        self.cam_pos,
-                        chunk.0,
-                        chunk.1,
-                        self.is_notplayer,
-                    ) {
-                        if let Some(new) = new_authority {
-                            emit_queue.push((
-                                Destination::Peer(new.0),
-                                WorldNetMessage::AskForAuthority {
-                                    chunk,
-                                    priority: new.1,
-                                },
-                            ));
-                        }
-                        debug!(
-                            "Unloading [authority] chunk {chunk:?} (updates: {chunk_last_update} {})",
-                            self.current_update
-                        );
-                        emit_queue.push((
-                            Destination::Host,
-                            WorldNetMessage::RelinquishAuthority {
-                                chunk,
-                                chunk_data: self.outbound_model.get_chunk_data(chunk),
-                                world_num: self.world_num,
-                            },
-                        ));
-                        *state = ChunkState::UnloadPending;
-                    }
-                }
-                ChunkState::WantToGetAuth { .. } => {
-                    if should_kill(
-                        self.my_pos,
-                        self.cam_pos,
-                        chunk.0,
-                        chunk.1,
-                        self.is_notplayer,
-                    ) {
-                        debug!("Unloading [want to get auth] chunk {chunk:?}");
-                        *state = ChunkState::UnloadPending;
-                    }
-                }
-                ChunkState::UnloadPending => {}
-                ChunkState::Transfer => {}
-            }
+        let outside: Vec<ChunkCoord> = self
+            .chunk_storage
+            .keys()
+            .copied()
+            .filter(|chunk| !self.chunk_in_bounds(*chunk))
+            .collect();
+        if !outside.is_empty() {
+            info!(
+                "Dropping {} stored chunks out of world bounds",
+                outside.len()
+            );
+        }
+        for chunk in outside {
+            self.chunk_storage.remove(&chunk);
+            self.chunk_modified.remove(&chunk);
         }
+    }
 
-        for (dst, msg) in emit_queue {
-            self.emit_msg(dst, msg)
+    /// Clamps a chunk range of a cut to `world_bounds`.
+    /// Returns None if nothing is left to iterate over.
+    fn clamp_chunk_range(
+        &self,
+        (min_cx, max_cx): (i32, i32),
+        (min_cy, max_cy): (i32, i32),
+    ) -> Option<((i32, i32), (i32, i32))> {
+        let Some((min, max)) = self.world_bounds else {
+            return Some(((min_cx, max_cx), (min_cy, max_cy)));
+        };
+        let clamped = (
+            (min_cx.max(min.0), max_cx.min(max.0)),
+            (min_cy.max(min.1), max_cy.min(max.1)),
+        );
+        if clamped != ((min_cx, max_cx), (min_cy, max_cy)) {
+            warn!(
+                "Cut over chunks {min_cx}..={max_cx} {min_cy}..={max_cy} is out of world bounds, clamping"
+            );
         }
-        self.chunk_state.retain(|chunk, state| {
-            let retain = *state != ChunkState::UnloadPending;
-            if !retain {
-                // Models are basically caches, no need to keep the chunk around in them.
-                self.inbound_model.forget_chunk(*chunk);
-                self.outbound_model.forget_chunk(*chunk);
-            }
-            retain
-        });
-        self.get_noita_updates()
+        (clamped.0.0 <= clamped.0.1 && clamped.1.0 <= clamped.1.1).then_some(clamped)
     }
 
-    pub(crate) fn get_noita_updates(&mut self) -> Vec<NoitaWorldUpdate> {
-        self.inbound_model.get_all_noita_updates()
+    /// Keeps authority of the chunk, but holds back its changes from listeners until `resume_sending`,
+    /// so that a big change can go out as one delta. Unrelated to `stop_sending` used by transfers.
+    /// Returns false if we aren't the authority of the chunk.
+    pub(crate) fn suspend_sending(&mut self, chunk: ChunkCoord) -> bool {
+        if !matches!(
+            self.chunk_state.get(&chunk),
+            Some(ChunkState::Authority { .. })
+        ) {
+            return false;
+        }
+        self.suspended_sending.insert(chunk);
+        true
     }
 
-    pub(crate) fn reset(&mut self) {
-        self.inbound_model.reset();
-        self.outbound_model.reset();
-        self.chunk_storage.clear();
-        self.authority_map.clear();
-        self.chunk_last_update.clear();
-        self.chunk_state.clear();
-        self.is_storage_recent.clear();
+    /// Changes made while sending was suspended go to listeners as a single delta with the next update.
+    pub(crate) fn resume_sending(&mut self, chunk: ChunkCoord) {
+        self.suspended_sending.remove(&chunk);
     }
 
-    pub(crate) fn get_emitted_msgs(&mut self) -> Vec<MessageRequest<WorldNetMessage>> {
-        mem::take(&mut self.emitted_messages)
+    /// Holds back all changes and authority requests during a level transition, so that terrain
+    /// about to be replaced isn't synced. Unlike `suspend_sending` it covers chunks we aren't
+    /// the authority of yet.
+    pub(crate) fn begin_transition(&mut self) {
+        if self.transition.is_none() {
+            debug!("Beginning world transition");
+            self.transition = Some(None);
+        }
     }
 
-    fn emit_msg(&mut self, dst: Destination, msg: WorldNetMessage) {
-        // Short-circuit for messages intended for myself
-        if (self.is_host && dst == Destination::Host) || dst == Destination::Peer(self.my_peer_id) {
-            self.handle_msg(self.my_peer_id, msg);
+    /// Syncs the final state of every chunk changed during the transition at once.
+    pub(crate) fn end_transition(&mut self) {
+        let Some(last_end) = self.transition.take() else {
             return;
+        };
+        debug!("Ending world transition");
+        if let Some((pos, priority, world_num)) = last_end {
+            self.handle_frame_end(pos, priority, world_num);
         }
-        // Also handle broadcast messages this way.
-        if dst == Destination::Broadcast {
-            self.handle_msg(self.my_peer_id, msg.clone());
-        }
-
-        self.emitted_messages.push(MessageRequest {
-            reliability: tangled::Reliability::Reliable,
-            dst,
-            msg,
-        })
     }
 
-    fn emit_got_authority(&mut self, chunk: ChunkCoord, source: OmniPeerId, priority: u8) {
-        let auth = self.authority_map.get(&chunk);
-        let chunk_data = if auth
-            .map(|a| a.0 != source) //TODO doesn't work
-            .unwrap_or(self.chunk_storage.contains_key(&chunk))
-        {
-            if self.explosion_pointer.contains_key(&chunk) {
-                self.cut_through_world_explosion_chunk(chunk);
-            }
-            self.chunk_storage.get(&chunk).cloned()
-        } else if self.explosion_pointer.contains_key(&chunk) {
-            if !self.chunk_storage.contains_key(&chunk) {
-                self.emit_msg(
-                    Destination::Peer(source),
-                    WorldNetMessage::GetChunk { chunk, priority },
-                );
-                return;
-            } else {
-                self.cut_through_world_explosion_chunk(chunk);
-            }
-            self.chunk_storage.get(&chunk).cloned()
-        } else {
-            None
-        };
-        self.authority_map.insert(chunk, (source, priority));
-        self.emit_msg(
-            Destination::Peer(source),
-            WorldNetMessage::GotAuthority {
-                chunk,
-                chunk_data,
-                priority,
-            },
-        );
-    }
-
-    fn emit_transfer_authority(
+    /// Temporarily raises the priority of a chunk, like around a fight, so that it's synced faster.
+    /// Priority goes back to normal after `duration_ticks` updates. Overlapping boosts use the strongest one.
+    pub(crate) fn boost_chunk_priority(
         &mut self,
         chunk: ChunkCoord,
-        source: OmniPeerId,
         priority: u8,
-        current_authority: OmniPeerId,
+        duration_ticks: u64,
     ) {
-        self.authority_map.insert(chunk, (source, priority));
-        self.emit_msg(
-            Destination::Peer(source),
-            WorldNetMessage::GetAuthorityFrom {
-                chunk,
-                current_authority,
-            },
-        );
+        let base = match self.chunk_state.get(&chunk) {
+            Some(ChunkState::Authority { priority, .. }) => Some(*priority),
+            _ => None,
+        };
+        let boost = self
+            .priority_boosts
+            .entry(chunk)
+            .or_insert_with(|| PriorityBoost {
+                boosts: Vec::new(),
+                base,
+            });
+        boost
+            .boosts
+            .push((priority, self.current_update + duration_ticks));
+        let strongest = boost.strongest();
+        let priority = base.map_or(strongest, |base| base.min(strongest));
+        self.set_authority_priority(chunk, priority);
     }
 
-    pub(crate) fn handle_msg(&mut self, source: OmniPeerId, msg: WorldNetMessage) {
-        match msg {
-            WorldNetMessage::RequestAuthority {
-                chunk,
-                priority,
-                can_wait,
-            } => {
-                if !self.is_host {
-                    warn!("{} sent RequestAuthority to not-host.", source);
-                    return;
-                }
-                let current_authority = self.authority_map.get(&chunk).copied();
-                match current_authority {
-                    Some((authority, priority_state)) => {
-                        if source == authority {
-                            debug!("{source} already has authority of {chunk:?}");
-                            self.emit_got_authority(chunk, source, priority);
-                        } else if priority_state > priority && !can_wait {
-                            debug!("{source} is gaining priority over {chunk:?} from {authority}");
-                            self.emit_transfer_authority(chunk, source, priority, authority);
-                        } else {
-                            debug!(
-                                "{source} requested authority for {chunk:?}, but it's already taken by {authority}"
-                            );
-                            self.emit_msg(
-                                Destination::Peer(source),
-                                WorldNetMessage::AuthorityAlreadyTaken { chunk, authority },
-                            );
-                        }
-                    }
-                    None => {
-                        debug!("Granting {source} authority of {chunk:?}");
-                        self.emit_got_authority(chunk, source, priority);
-                    }
-                }
+    fn expire_priority_boosts(&mut self) {
+        let current_update = self.current_update;
+        let mut changed = Vec::new();
+        self.priority_boosts.retain(|chunk, boost| {
+            let count = boost.boosts.len();
+            boost.boosts.retain(|(_, until)| *until > current_update);
+            if boost.boosts.len() != count
+                && let Some(base) = boost.base
+            {
+                changed.push((*chunk, base.min(boost.strongest())));
             }
-            WorldNetMessage::GetChunk { chunk, priority } => self.emit_msg(
+            !boost.boosts.is_empty()
+        });
+        for (chunk, priority) in changed {
+            self.set_authority_priority(chunk, priority);
+        }
+    }
+
+    fn set_authority_priority(&mut self, chunk: ChunkCoord, priority: u8) {
+        if let Some(ChunkState::Authority { priority: pri, .. }) = self.chunk_state.get_mut(&chunk)
+            && *pri != priority
+        {
+            *pri = priority;
+            self.emit_msg(
                 Destination::Host,
-                WorldNetMessage::UpdateStorage {
+                WorldNetMessage::ChangePriority { chunk, priority },
+            );
+        }
+    }
+
+    /// Starts syncing chunks of the inclusive region ahead of time, like a teleport destination,
+    /// so that they're there when the player arrives. Returns how many chunks were requested.
+    /// Prefetched chunks aren't unloaded for a while, then they're unloaded as usual if nobody came.
+    pub(crate) fn prefetch_region(
+        &mut self,
+        min: ChunkCoord,
+        max: ChunkCoord,
+        priority: u8,
+    ) -> usize {
+        let Some(((min_cx, max_cx), (min_cy, max_cy))) =
+            self.clamp_chunk_range((min.0, max.0), (min.1, max.1))
+        else {
+            return 0;
+        };
+        let mut prefetched = 0;
+        for cx in min_cx..=max_cx {
+            for cy in min_cy..=max_cy {
+                if prefetched == MAX_PREFETCH_CHUNKS {
+                    warn!(
+                        "Prefetch region is too big, only {MAX_PREFETCH_CHUNKS} chunks requested"
+                    );
+                    return prefetched;
+                }
+                let chunk = ChunkCoord(cx, cy);
+                if self.chunk_state.contains_key(&chunk) {
+                    continue;
+                }
+                self.chunk_state.insert(
                     chunk,
-                    chunk_data: self.outbound_model.get_chunk_data(chunk),
-                    world_num: self.world_num,
-                    priority: Some(priority),
-                },
-            ),
-            WorldNetMessage::AskForAuthority { chunk, priority } => {
-                self.emit_msg(
-                    Destination::Host,
-                    WorldNetMessage::RequestAuthority {
-                        chunk,
+                    ChunkState::RequestAuthority {
                         priority,
-                        can_wait: false,
+                        can_wait: true,
                     },
                 );
-                self.chunk_state
-                    .insert(chunk, ChunkState::WaitingForAuthority);
-            }
-            WorldNetMessage::LoseAuthority {
-                chunk,
-                new_authority,
-                new_priority,
-            } => {
-                if let Some(ChunkState::Authority {
-                    new_authority: new_auth,
-                    ..
-                }) = self.chunk_state.get_mut(&chunk)
-                {
-                    if new_authority == self.my_peer_id {
-                        *new_auth = None;
-                    } else if let Some(new) = new_auth {
-                        if new.1 > new_priority {
-                            *new_auth = Some((new_authority, new_priority));
-                        }
-                    } else {
-                        *new_auth = Some((new_authority, new_priority))
-                    }
-                }
+                self.prefetched_chunks.insert(chunk, self.current_update);
+                prefetched += 1;
             }
-            WorldNetMessage::ChangePriority { chunk, priority } => {
-                if !self.is_host {
-                    warn!("{} sent RequestAuthority to not-host.", source);
+        }
+        prefetched
+    }
+
+    /// Prevents chunks in the inclusive region from being terraformed.
+    /// Region is clamped to `world_bounds`, and at most `MAX_PROTECTED_CHUNKS` chunks are protected in total.
+    pub(crate) fn protect_region(&mut self, min: ChunkCoord, max: ChunkCoord) {
+        let Some(((min_cx, max_cx), (min_cy, max_cy))) =
+            self.clamp_chunk_range((min.0, max.0), (min.1, max.1))
+        else {
+            return;
+        };
+        for x in min_cx..=max_cx {
+            for y in min_cy..=max_cy {
+                if self.protected_chunks.len() == MAX_PROTECTED_CHUNKS {
+                    warn!("Too many protected chunks, only {MAX_PROTECTED_CHUNKS} are protected");
                     return;
                 }
-                let current_authority = self.authority_map.get(&chunk).copied();
-                match current_authority {
-                    Some((authority, _)) => {
-                        if source == authority {
-                            self.authority_map.insert(chunk, (source, priority));
-                        } else {
-                            debug!(
-                                "{source} requested authority for {chunk:?}, but it's already taken by {authority}"
-                            );
-                        }
-                    }
-                    None => {
-                        debug!("Granting {source} authority of {chunk:?}");
-                    }
-                }
+                self.protected_chunks.insert(ChunkCoord(x, y));
             }
-            WorldNetMessage::GotAuthority {
-                chunk,
-                chunk_data,
-                priority,
-            } => {
-                self.chunk_state
-                    .insert(chunk, ChunkState::authority(priority));
-                self.last_request_priority.remove(&chunk);
-                if let Some(chunk_data) = chunk_data {
-                    self.inbound_model.apply_chunk_data(chunk, &chunk_data);
-                    self.outbound_model.apply_chunk_data(chunk, &chunk_data);
-                } else {
-                    self.emit_msg(
-                        Destination::Host,
-                        WorldNetMessage::UpdateStorage {
-                            chunk,
-                            chunk_data: self.outbound_model.get_chunk_data(chunk),
-                            world_num: self.world_num,
-                            priority: None,
-                        },
-                    )
-                }
+        }
+    }
+
+    /// Whether a cut with `power` would remove the pixel, going through the same checks as cuts do.
+    /// Pixels of chunks we know nothing about are allowed.
+    pub(crate) fn can_terraform(&self, x: i32, y: i32, power: Option<u32>) -> TerraformPermission {
+        let chunk = ChunkCoord(
+            x.div_euclid(CHUNK_SIZE as i32),
+            y.div_euclid(CHUNK_SIZE as i32),
+        );
+        if !self.chunk_in_bounds(chunk) {
+            return TerraformPermission::OutOfBounds;
+        }
+        if self.protected_chunks.contains(&chunk) {
+            return TerraformPermission::Protected;
+        }
+        let Some(chunk_data) = self.latest_chunk_data(chunk) else {
+            return TerraformPermission::Allowed;
+        };
+        let mut pixels = Chunk::default();
+        chunk_data.apply_to_chunk(&mut pixels);
+        let pixel = pixels.pixel(
+            y.rem_euclid(CHUNK_SIZE as i32) as usize * CHUNK_SIZE
+                + x.rem_euclid(CHUNK_SIZE as i32) as usize,
+        );
+        let removable = pixel.flags == PixelFlags::Unknown
+            || self
+                .materials
+                .get(&pixel.material)
+                .is_none_or(|(_, _, cell, _, _)| cell.can_remove(true, false));
+        if !removable || self.too_hard(pixel, power) {
+            return TerraformPermission::TooHard;
+        }
+        TerraformPermission::Allowed
+    }
+
+    pub(crate) fn unprotect_region(&mut self, min: ChunkCoord, max: ChunkCoord) {
+        self.protected_chunks.retain(|chunk| {
+            !((min.0..=max.0).contains(&chunk.0) && (min.1..=max.1).contains(&chunk.1))
+        });
+    }
+
+    pub(crate) fn is_authority(&self, chunk: ChunkCoord) -> bool {
+        matches!(
+            self.chunk_state.get(&chunk),
+            Some(ChunkState::Authority { .. })
+        )
+    }
+
+    /// Who is the authority of this chunk, as far as we know.
+    /// While authority is being transferred, reports the previous authority.
+    pub(crate) fn authority_of(&self, chunk: ChunkCoord) -> Option<OmniPeerId> {
+        match self.chunk_state.get(&chunk) {
+            Some(ChunkState::Authority { .. }) => Some(self.my_peer_id),
+            Some(
+                ChunkState::Listening { authority, .. }
+                | ChunkState::WantToGetAuth { authority, .. }
+                | ChunkState::Transfer { authority },
+            ) => Some(*authority),
+            _ => self
+                .authority_map
+                .get(&chunk)
+                .map(|(authority, _)| *authority),
+        }
+    }
+
+    /// Peers that get updates of a chunk we're an authority of, sorted. None if we aren't its authority.
+    pub(crate) fn listeners_of(&self, chunk: ChunkCoord) -> Option<Vec<OmniPeerId>> {
+        let Some(ChunkState::Authority { listeners, .. }) = self.chunk_state.get(&chunk) else {
+            return None;
+        };
+        let mut listeners: Vec<OmniPeerId> = listeners.iter().copied().collect();
+        listeners.sort_unstable_by_key(|peer| peer.0);
+        Some(listeners)
+    }
+
+    /// Has every authority host knows of tell its listeners that it's their authority, and give up
+    /// chunks it doesn't have anymore, so that everyone agrees with `authority_map` again after
+    /// lost messages. Host only, and at most once per `AUTHORITY_REBROADCAST_INTERVAL` updates.
+    /// Returns false if nothing was sent.
+    pub(crate) fn rebroadcast_authority_state(&mut self) -> bool {
+        if !self.is_host {
+            return false;
+        }
+        if self
+            .last_authority_rebroadcast
+            .is_some_and(|last| self.current_update < last + AUTHORITY_REBROADCAST_INTERVAL)
+        {
+            return false;
+        }
+        self.last_authority_rebroadcast = Some(self.current_update);
+        let mut authorities: Vec<(ChunkCoord, OmniPeerId)> = self
+            .authority_map
+            .iter()
+            .map(|(&chunk, &(peer, _))| (chunk, peer))
+            .collect();
+        authorities.sort_unstable_by_key(|(chunk, _)| (chunk.0, chunk.1));
+        info!("Rebroadcasting {} authorities", authorities.len());
+        for (chunk, peer) in authorities {
+            self.emit_msg(
+                Destination::Peer(peer),
+                WorldNetMessage::ConfirmAuthority { chunk },
+            );
+        }
+        true
+    }
+
+    /// Authority and its priority of every chunk as host has them recorded, sorted by chunk.
+    /// Empty if we aren't the host.
+    pub(crate) fn authority_priorities(&self) -> Vec<(ChunkCoord, OmniPeerId, u8)> {
+        let mut priorities: Vec<_> = self
+            .authority_map
+            .iter()
+            .map(|(&chunk, &(peer, priority))| (chunk, peer, priority))
+            .collect();
+        priorities.sort_unstable_by_key(|(chunk, _, _)| (chunk.0, chunk.1));
+        priorities
+    }
+
+    /// Applies an edit to a chunk without taking authority over it. Host only.
+    /// Current authority of the chunk, if any, gets the edit as well, and passes it to its listeners.
+    /// Returns false if there was nothing to apply the delta to.
+    pub(crate) fn apply_delta_to_storage(&mut self, delta: &ChunkDelta) -> bool {
+        if !self.is_host {
+            warn!("Only host can apply deltas to storage");
+            return false;
+        }
+        let chunk = delta.chunk_coord;
+        if !self.chunk_in_bounds(chunk) {
+            return false;
+        }
+        let authority = self.authority_map.get(&chunk).map(|(peer, _)| *peer);
+        match self.chunk_storage.get_mut(&chunk) {
+            Some(chunk_data) => {
+                chunk_data.apply_chunk_delta(delta);
+                self.mark_modified(chunk);
             }
-            WorldNetMessage::UpdateStorage {
-                chunk,
-                chunk_data,
-                world_num,
-                priority,
-            } => {
-                if !self.is_host {
-                    warn!("{} sent RelinquishAuthority to not-host.", source);
-                    return;
-                }
-                if world_num != self.world_num {
-                    return;
-                }
-                if let Some(chunk_data) = chunk_data {
-                    let _ = self.tx.send((chunk, chunk_data.clone()));
+            None => match delta.to_chunk_data() {
+                Some(chunk_data) => {
                     self.chunk_storage.insert(chunk, chunk_data);
-                    if let Some(p) = priority {
-                        self.cut_through_world_explosion_chunk(chunk);
-                        self.emit_got_authority(chunk, source, p)
-                    }
-                } else if priority.is_some() {
-                    warn!("{} sent give auth without chunk", source)
-                }
-            }
-            WorldNetMessage::RelinquishAuthority {
-                chunk,
-                chunk_data,
-                world_num,
-            } => {
-                if !self.is_host {
-                    warn!("{} sent RelinquishAuthority to not-host.", source);
-                    return;
-                }
-                if world_num != self.world_num {
-                    return;
+                    self.mark_modified(chunk);
                 }
-                if let Some(state) = self.authority_map.get(&chunk)
-                    && state.0 != source
-                {
-                    debug!(
-                        "{source} sent RelinquishAuthority for {chunk:?}, but isn't currently an authority"
-                    );
-                    return;
+                None if authority.is_some() => {}
+                None => {
+                    warn!("Can't apply a partial delta to {chunk:?}, as it isn't in storage");
+                    return false;
                 }
-                self.authority_map.remove(&chunk);
-                if let Some(chunk_data) = chunk_data {
-                    let _ = self.tx.send((chunk, chunk_data.clone()));
-                    self.chunk_storage.insert(chunk, chunk_data);
-                    self.emit_msg(
-                        Destination::Broadcast,
-                        WorldNetMessage::ListenAuthorityRelinquished { chunk },
-                    )
-                }
-            }
-            WorldNetMessage::UnloadChunk { chunk } => {
-                self.chunk_state.insert(chunk, ChunkState::UnloadPending {});
-            }
+            },
+        }
+        if let Some(chunk_data) = self.chunk_storage.get(&chunk) {
+            let _ = self.tx.send((chunk, chunk_data.into_owned()));
+        }
+        if let Some(authority) = authority {
+            self.emit_msg(
+                Destination::Peer(authority),
+                WorldNetMessage::ApplyDelta {
+                    delta: delta.clone(),
+                },
+            );
+        }
+        true
+    }
 
-            WorldNetMessage::AuthorityAlreadyTaken { chunk, authority } => {
-                self.emit_msg(
-                    Destination::Peer(authority),
-                    WorldNetMessage::ListenRequest { chunk },
-                );
-                self.last_request_priority.remove(&chunk);
+    /// Same as `apply_delta_to_storage`, but with the delta moved and mirrored, e.g. to build symmetric levels.
+    pub(crate) fn apply_chunk_delta_transformed(
+        &mut self,
+        delta: &ChunkDelta,
+        transform: ChunkTransform,
+    ) -> bool {
+        self.apply_delta_to_storage(&delta.transformed(transform))
+    }
+
+    /// Copies whole contents of a chunk elsewhere, see `apply_chunk_delta_transformed`.
+    pub(crate) fn copy_chunk_transformed(
+        &mut self,
+        chunk: ChunkCoord,
+        transform: ChunkTransform,
+    ) -> bool {
+        let Some(data) = self.latest_chunk_data(chunk) else {
+            warn!("Nothing to copy from {chunk:?}");
+            return false;
+        };
+        let mut pixels = Chunk::default();
+        data.apply_to_chunk(&mut pixels);
+        let delta = ChunkDelta::new(
+            chunk,
+            (0..CHUNK_SIZE * CHUNK_SIZE).map(|i| Some(pixels.compact_pixel(i))),
+        );
+        self.apply_chunk_delta_transformed(&delta, transform)
+    }
+
+    pub(crate) fn apply_terraform(&mut self, op: &TerraformOp) {
+        if !matches!(op, TerraformOp::Explosion(_)) {
+            // Explosions get logged in cut_through_world_explosion.
+            self.log_terraform(op);
+        }
+        match *op {
+            TerraformOp::Circle {
+                x,
+                y,
+                r,
+                mat,
+                density,
+                chance,
+                seed,
+                power,
+            } => self.cut_through_world_circle(x, y, r, mat, density, chance, seed, power),
+            TerraformOp::Line {
+                x,
+                y,
+                lx,
+                ly,
+                r,
+                chance,
+                seed,
+                power,
+            } => self.cut_through_world_line(x, y, lx, ly, r, chance, seed, power),
+            TerraformOp::Rect { min, max, material } => self.fill_rect(min, max, material),
+            TerraformOp::Explosion(ex) => self.cut_through_world_explosion(vec![ex]),
+        }
+    }
+
+    /// Appends every following terraforming operation to a file, so that it can be replayed with
+    /// `replay_terraform_log`. Undoing a batch isn't logged.
+    pub(crate) fn start_terraform_log(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.terraform_log = Some(File::create(path)?);
+        Ok(())
+    }
+
+    pub(crate) fn stop_terraform_log(&mut self) {
+        self.terraform_log = None;
+    }
+
+    fn log_terraform(&mut self, op: &TerraformOp) {
+        let Some(file) = &mut self.terraform_log else {
+            return;
+        };
+        if let Err(err) = write_log_entry(file, op) {
+            warn!("Could not write to terraform log, stopping: {err}");
+            self.terraform_log = None;
+        }
+    }
+
+    /// Applies every operation from a log made with `start_terraform_log`, in order.
+    /// Ends up with the same terrain as the logged world if started from the same one.
+    /// Replayed operations aren't logged again.
+    pub(crate) fn replay_terraform_log(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let ops: Vec<TerraformOp> = read_log(path)?;
+        let log = self.terraform_log.take();
+        for op in &ops {
+            self.apply_terraform(op);
+        }
+        self.terraform_log = log;
+        Ok(())
+    }
+
+    /// Appends every following message from Noita to a file, so that a session can be replayed
+    /// with `replay_noita_updates`.
+    pub(crate) fn start_noita_update_log(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.noita_update_log = Some(File::create(path)?);
+        Ok(())
+    }
+
+    pub(crate) fn stop_noita_update_log(&mut self) {
+        self.noita_update_log = None;
+    }
+
+    fn log_noita_update(&mut self, msg: &WorldSyncToProxy) {
+        let Some(file) = &mut self.noita_update_log else {
+            return;
+        };
+        if let Err(err) = write_log_entry(file, msg) {
+            warn!("Could not write to noita update log, stopping: {err}");
+            self.noita_update_log = None;
+        }
+    }
+
+    /// Feeds every message from a log made with `start_noita_update_log` through `handle_noita_msg`,
+    /// with the recorded positions and priorities. Replayed messages aren't logged again.
+    pub(crate) fn replay_noita_updates(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let msgs: Vec<WorldSyncToProxy> = read_log(path)?;
+        let log = self.noita_update_log.take();
+        for msg in msgs {
+            self.handle_noita_msg(OmniPeerId(0), msg);
+        }
+        self.noita_update_log = log;
+        Ok(())
+    }
+
+    /// Applies terraforming right away, or on the next `update` if `coalesce_terraform` is set.
+    /// Result is the same either way, as queued operations are applied in order.
+    pub(crate) fn queue_terraform(&mut self, op: TerraformOp) {
+        if self.coalesce_terraform {
+            self.queued_terraform.push(op);
+        } else {
+            self.apply_terraform(&op);
+        }
+    }
+
+    /// Applies queued terraforming, with a single map update per changed chunk.
+    pub(crate) fn flush_terraform(&mut self) {
+        if self.queued_terraform.is_empty() {
+            return;
+        }
+        let ops = mem::take(&mut self.queued_terraform);
+        self.terraform_batch(&ops);
+    }
+
+    /// Default seeds come from operation's parameters, so the same operation done twice would pick
+    /// the same pixels both times. Each repeat gets a different seed instead, which is still the
+    /// same on every peer that got the same operations.
+    pub(crate) fn vary_operation_seed(&mut self, seed: u64) -> u64 {
+        let varied = self.peek_operation_seed(seed);
+        if self.operation_repeats.len() >= MAX_TRACKED_OPERATIONS
+            && !self.operation_repeats.contains_key(&seed)
+        {
+            self.operation_repeats.clear();
+        }
+        *self.operation_repeats.entry(seed).or_default() += 1;
+        varied
+    }
+
+    /// Seed `vary_operation_seed` would give next, without counting it as done.
+    pub(crate) fn peek_operation_seed(&self, seed: u64) -> u64 {
+        match self.operation_repeats.get(&seed) {
+            Some(&repeats) => operation_seed((seed, repeats)),
+            None => seed,
+        }
+    }
+
+    /// Applies operations in order, as a single unit that can be undone with `undo_terraform`.
+    /// Map gets a single update per changed chunk, no matter how many operations changed it.
+    /// Explosion rays that reach chunks which aren't in storage yet can't be undone.
+    pub(crate) fn terraform_batch(&mut self, ops: &[TerraformOp]) -> TerraformUndo {
+        let ranges: Vec<_> = ops
+            .iter()
+            .filter_map(|op| {
+                let ((min_x, min_y), (max_x, max_y)) = op.bounds();
+                self.clamp_chunk_range(
+                    (
+                        min_x.div_euclid(CHUNK_SIZE as i32),
+                        max_x.div_euclid(CHUNK_SIZE as i32),
+                    ),
+                    (
+                        min_y.div_euclid(CHUNK_SIZE as i32),
+                        max_y.div_euclid(CHUNK_SIZE as i32),
+                    ),
+                )
+            })
+            .collect();
+        // Only stored chunks can change, ones that get stored by the batch weren't there before.
+        let mut before: FxHashMap<ChunkCoord, Option<ChunkData>> = FxHashMap::default();
+        for &range in &ranges {
+            for chunk in self.stored_chunks_in(range) {
+                before
+                    .entry(chunk)
+                    .or_insert_with(|| self.chunk_storage.get(&chunk).map(Cow::into_owned));
             }
-            WorldNetMessage::ListenRequest { chunk } => {
-                let Some(ChunkState::Authority {
-                    listeners,
-                    priority,
-                    ..
-                }) = self.chunk_state.get_mut(&chunk)
-                else {
-                    self.emit_msg(
-                        Destination::Peer(source),
-                        WorldNetMessage::UnloadChunk { chunk },
-                    );
-                    //warn!("Can't listen for {chunk:?} - not an authority");
-                    return;
-                };
-                listeners.insert(source);
-                let chunk_data = self.outbound_model.get_chunk_data(chunk);
-                let priority = *priority;
-                self.emit_msg(
-                    Destination::Peer(source),
-                    WorldNetMessage::ListenInitialResponse {
-                        chunk,
-                        chunk_data,
-                        priority,
-                    },
-                );
+        }
+        for op in ops {
+            self.apply_terraform(op);
+        }
+        for &range in &ranges {
+            for chunk in self.stored_chunks_in(range) {
+                before.entry(chunk).or_insert(None);
             }
-            WorldNetMessage::ListenStopRequest { chunk } => {
-                let Some(ChunkState::Authority { listeners, .. }) =
-                    self.chunk_state.get_mut(&chunk)
-                else {
-                    //warn!("Can't stop listen for {chunk:?} - not an authority");
-                    return;
-                };
-                listeners.remove(&source);
+        }
+        let encode = |data: Option<&ChunkData>| data.map(bitcode::encode);
+        let chunks: Vec<(ChunkCoord, Option<ChunkData>)> = before
+            .into_iter()
+            .filter(|(chunk, data)| {
+                encode(self.chunk_storage.get(chunk).as_deref()) != encode(data.as_ref())
+            })
+            .collect();
+        for (chunk, _) in &chunks {
+            if let Some(data) = self.chunk_storage.get(chunk) {
+                let _ = self.tx.send((*chunk, data.into_owned()));
             }
-            WorldNetMessage::ListenInitialResponse {
-                chunk,
-                chunk_data,
-                priority,
-            } => {
-                self.chunk_state.insert(
-                    chunk,
-                    ChunkState::Listening {
-                        authority: source,
-                        priority,
-                    },
-                );
-                if let Some(chunk_data) = chunk_data {
-                    self.inbound_model.apply_chunk_data(chunk, &chunk_data);
-                } else {
-                    warn!(
-                        "Initial listen response has None chunk_data. It's generally supposed to have some."
-                    );
-                }
+        }
+        if self.is_host && self.replicate_terraform {
+            self.replicate_terraform_result(&chunks);
+        }
+        let results = chunks
+            .iter()
+            .map(|(chunk, _)| (*chunk, encode(self.chunk_storage.get(chunk).as_deref())))
+            .collect();
+        TerraformUndo { chunks, results }
+    }
+
+    /// Stored chunks in an inclusive chunk range, going through either the range or the storage,
+    /// whichever is smaller.
+    fn stored_chunks_in(
+        &self,
+        ((min_cx, max_cx), (min_cy, max_cy)): ((i32, i32), (i32, i32)),
+    ) -> Vec<ChunkCoord> {
+        let area = (max_cx.abs_diff(min_cx) as u64 + 1) * (max_cy.abs_diff(min_cy) as u64 + 1);
+        if area > self.chunk_storage.len() as u64 {
+            return self
+                .chunk_storage
+                .keys()
+                .filter(|chunk| {
+                    (min_cx..=max_cx).contains(&chunk.0) && (min_cy..=max_cy).contains(&chunk.1)
+                })
+                .copied()
+                .collect();
+        }
+        (min_cx..=max_cx)
+            .flat_map(|cx| (min_cy..=max_cy).map(move |cy| ChunkCoord(cx, cy)))
+            .filter(|chunk| self.chunk_storage.contains_key(chunk))
+            .collect()
+    }
+
+    /// Sends pixels that the batch changed to authorities of their chunks, so that their own
+    /// prediction of it gets overwritten. Chunks whose storage might be outdated are skipped, as
+    /// the batch was applied to data the authority doesn't have anymore.
+    fn replicate_terraform_result(&mut self, chunks: &[(ChunkCoord, Option<ChunkData>)]) {
+        for (chunk, before) in chunks {
+            let Some(&(authority, _)) = self.authority_map.get(chunk) else {
+                continue;
+            };
+            if !self.is_storage_recent.contains(chunk) {
+                continue;
             }
-            WorldNetMessage::ListenUpdate {
-                delta,
-                priority,
-                take_auth,
-            } => {
-                match self.chunk_state.get_mut(&delta.chunk_coord) {
-                    Some(ChunkState::Listening { priority: pri, .. }) => {
-                        *pri = priority;
-                        if take_auth {
-                            self.emit_msg(
-                                Destination::Peer(source),
-                                WorldNetMessage::LoseAuthority {
-                                    chunk: delta.chunk_coord,
-                                    new_priority: priority,
-                                    new_authority: source,
-                                },
-                            );
-                        }
-                    }
-                    Some(ChunkState::WantToGetAuth {
-                        authority,
-                        my_priority,
-                        ..
-                    }) => {
-                        if priority > *my_priority {
-                            if take_auth {
-                                let rq = WorldNetMessage::RequestAuthority {
-                                    chunk: delta.chunk_coord,
-                                    priority: *my_priority,
-                                    can_wait: false,
-                                };
-                                self.emit_msg(Destination::Host, rq);
-                                self.chunk_state
-                                    .insert(delta.chunk_coord, ChunkState::WaitingForAuthority);
-                            }
-                        } else {
-                            let cs = ChunkState::Listening {
-                                authority: *authority,
-                                priority,
-                            };
-                            self.chunk_state.insert(delta.chunk_coord, cs);
-                        }
-                    }
-                    _ if take_auth => {
-                        self.emit_msg(
-                            Destination::Peer(source),
-                            WorldNetMessage::LoseAuthority {
-                                chunk: delta.chunk_coord,
-                                new_priority: priority,
-                                new_authority: source,
-                            },
-                        );
-                    }
-                    _ => return,
-                }
-                self.inbound_model.apply_chunk_delta(&delta);
-                self.is_storage_recent.remove(&delta.chunk_coord);
+            let (Some(before), Some(after)) = (before, self.chunk_storage.get(chunk)) else {
+                continue;
+            };
+            let (mut old, mut new) = (Chunk::default(), Chunk::default());
+            before.apply_to_chunk(&mut old);
+            after.apply_to_chunk(&mut new);
+            let delta = ChunkDelta::new(
+                *chunk,
+                (0..CHUNK_SIZE * CHUNK_SIZE).map(|i| {
+                    let pixel = new.compact_pixel(i);
+                    (pixel != old.compact_pixel(i)).then_some(pixel)
+                }),
+            );
+            self.emit_msg(
+                Destination::Peer(authority),
+                WorldNetMessage::ApplyDelta { delta },
+            );
+        }
+    }
+
+    /// Puts chunks changed by a terraforming batch back into storage as they were before it.
+    /// Chunks that changed again since the batch are left alone, so that later changes aren't lost.
+    pub(crate) fn undo_terraform(&mut self, undo: TerraformUndo) {
+        for (chunk, data) in undo.chunks {
+            let current = self
+                .chunk_storage
+                .get(&chunk)
+                .map(|data| bitcode::encode(&*data));
+            if undo.results.get(&chunk) != Some(&current) {
+                warn!("{chunk:?} changed since terraforming, not undoing it");
+                continue;
             }
-            WorldNetMessage::ChunkPacket { chunkpacket } => {
-                for (delta, priority) in chunkpacket {
-                    match self.chunk_state.get_mut(&delta.chunk_coord) {
-                        Some(ChunkState::Listening { priority: pri, .. }) => {
-                            *pri = priority;
-                        }
-                        Some(ChunkState::WantToGetAuth {
-                            authority,
-                            my_priority,
-                            ..
-                        }) => {
-                            if priority <= *my_priority {
-                                let cs = ChunkState::Listening {
-                                    authority: *authority,
-                                    priority,
-                                };
-                                self.chunk_state.insert(delta.chunk_coord, cs);
-                            }
-                        }
-                        _ => continue,
-                    }
-                    self.inbound_model.apply_chunk_delta(&delta);
-                    self.is_storage_recent.remove(&delta.chunk_coord);
+            match data {
+                Some(data) => {
+                    let _ = self.tx.send((chunk, data.clone()));
+                    self.chunk_storage.insert(chunk, data);
+                    self.mark_modified(chunk);
                 }
-            }
-            WorldNetMessage::ListenAuthorityRelinquished { chunk } => {
-                self.chunk_state.insert(chunk, ChunkState::UnloadPending);
-            }
-            WorldNetMessage::GetAuthorityFrom {
-                chunk,
-                current_authority,
-            } => {
-                if self.chunk_state.get(&chunk) != Some(&ChunkState::UnloadPending) {
-                    debug!("Will request authority transfer");
-                    self.chunk_state.insert(chunk, ChunkState::Transfer);
-                    self.emit_msg(
-                        Destination::Peer(current_authority),
-                        WorldNetMessage::RequestAuthorityTransfer { chunk },
-                    );
-                } else {
-                    self.emit_msg(
-                        Destination::Host,
-                        WorldNetMessage::RelinquishAuthority {
-                            chunk,
-                            chunk_data: None,
-                            world_num: self.world_num,
-                        },
-                    );
+                None => {
+                    self.chunk_storage.remove(&chunk);
+                    self.chunk_modified.remove(&chunk);
                 }
             }
-            WorldNetMessage::RequestAuthorityTransfer { chunk } => {
-                debug!("Got a request for authority transfer");
-                let state = self.chunk_state.get(&chunk);
-                if let Some(ChunkState::Authority { listeners, .. }) = state {
-                    let chunk_data = self.outbound_model.get_chunk_data(chunk);
-                    self.emit_msg(
-                        Destination::Peer(source),
-                        WorldNetMessage::TransferOk {
-                            chunk,
-                            chunk_data,
-                            listeners: listeners.clone(),
-                        },
-                    );
-                    self.chunk_state.insert(chunk, ChunkState::UnloadPending);
-                    let chunk_data = self.outbound_model.get_chunk_data(chunk);
-                    self.emit_msg(
-                        Destination::Host,
-                        WorldNetMessage::UpdateStorage {
-                            chunk,
-                            chunk_data,
-                            world_num: self.world_num,
-                            priority: None,
-                        },
-                    );
-                } else {
-                    self.emit_msg(
-                        Destination::Peer(source),
-                        WorldNetMessage::TransferFailed { chunk },
-                    );
-                }
-            }
-            WorldNetMessage::TransferOk {
-                chunk,
-                chunk_data,
-                listeners,
-            } => {
-                debug!("Transfer ok");
-                if let Some(chunk_data) = chunk_data {
-                    self.inbound_model.apply_chunk_data(chunk, &chunk_data);
-                    self.outbound_model.apply_chunk_data(chunk, &chunk_data);
-                }
-                for listener in listeners.iter() {
-                    self.emit_msg(
-                        Destination::Peer(*listener),
-                        WorldNetMessage::NotifyNewAuthority { chunk },
-                    );
-                }
-                self.chunk_state.insert(
-                    chunk,
-                    ChunkState::Authority {
-                        listeners,
-                        priority: self.last_request_priority.remove(&chunk).unwrap_or(0),
-                        new_authority: None,
-                        stop_sending: false,
-                    },
-                );
-            }
-            WorldNetMessage::TransferFailed { chunk } => {
-                debug!("Transfer failed, requesting authority normally");
-                let priority = self
-                    .last_request_priority
-                    .get(&chunk)
-                    .copied()
-                    .unwrap_or(255);
-                self.chunk_state.insert(
-                    chunk,
-                    ChunkState::RequestAuthority {
-                        priority,
-                        can_wait: true,
-                    },
-                );
-                self.emit_msg(
-                    Destination::Host,
-                    WorldNetMessage::RelinquishAuthority {
-                        chunk,
-                        chunk_data: None,
-                        world_num: self.world_num,
-                    },
-                );
-            }
-            WorldNetMessage::NotifyNewAuthority { chunk } => {
-                debug!("Notified of new authority");
-                let state = self.chunk_state.get_mut(&chunk);
-                if let Some(ChunkState::Listening { authority, .. }) = state {
-                    *authority = source;
-                } else {
-                    debug!("Got notified of new authority, but not a listener");
-                }
+        }
+    }
+
+    /// Sets pixels in the inclusive pixel rectangle to a material.
+    pub(crate) fn fill_rect(&mut self, (x1, y1): (i32, i32), (x2, y2): (i32, i32), material: u16) {
+        let (min_x, max_x) = (x1.min(x2), x1.max(x2));
+        let (min_y, max_y) = (y1.min(y2), y1.max(y2));
+        let Some(((min_cx, max_cx), (min_cy, max_cy))) = self.clamp_chunk_range(
+            (
+                min_x.div_euclid(CHUNK_SIZE as i32),
+                max_x.div_euclid(CHUNK_SIZE as i32),
+            ),
+            (
+                min_y.div_euclid(CHUNK_SIZE as i32),
+                max_y.div_euclid(CHUNK_SIZE as i32),
+            ),
+        ) else {
+            return;
+        };
+        let pixel = RawPixel {
+            material,
+            flags: PixelFlags::Normal,
+        }
+        .to_compact();
+        for cx in min_cx..=max_cx {
+            for cy in min_cy..=max_cy {
+                let pixels = (0..CHUNK_SIZE as i32).flat_map(|y| {
+                    (0..CHUNK_SIZE as i32).map(move |x| {
+                        let x = cx * CHUNK_SIZE as i32 + x;
+                        let y = cy * CHUNK_SIZE as i32 + y;
+                        ((min_x..=max_x).contains(&x) && (min_y..=max_y).contains(&y))
+                            .then_some(pixel)
+                    })
+                });
+                self.apply_delta_to_storage(&ChunkDelta::new(ChunkCoord(cx, cy), pixels));
             }
         }
     }
 
-    /// Should be called when player disconnects.
-    /// This frees up any authority that player had.
-    pub(crate) fn handle_peer_left(&mut self, source: OmniPeerId) {
-        let mut to_remove = Vec::new();
-        for (c, chunk) in self.chunk_state.iter_mut() {
-            match chunk {
-                ChunkState::RequestAuthority { .. } => {}
-                ChunkState::WaitingForAuthority => {}
-                ChunkState::Listening { authority, .. } => {
-                    if authority == &source {
-                        to_remove.push(*c)
-                    }
-                }
-                ChunkState::Authority {
-                    listeners,
-                    new_authority,
-                    ..
-                } => {
-                    if new_authority.map(|(p, _)| p == source).unwrap_or(false) {
-                        *new_authority = None;
+    /// Most recent contents of a chunk we know of, be it from the game, from its authority or from storage.
+    fn latest_chunk_data(&self, chunk: ChunkCoord) -> Option<ChunkData> {
+        self.outbound_model
+            .get_chunk_data(chunk)
+            .or_else(|| self.inbound_model.get_chunk_data(chunk))
+            .or_else(|| self.chunk_storage.get(&chunk).map(Cow::into_owned))
+    }
+
+    /// `latest_chunk_data` of many chunks at once, in the same order as `coords`.
+    /// Host resolves big batches in parallel, as most of them come from possibly compressed storage.
+    pub(crate) fn get_chunks_data(
+        &self,
+        coords: &[ChunkCoord],
+    ) -> Vec<(ChunkCoord, Option<ChunkData>)> {
+        if self.is_host && coords.len() >= PARALLEL_QUERY_MIN_CHUNKS {
+            coords
+                .par_iter()
+                .map(|&chunk| (chunk, self.latest_chunk_data(chunk)))
+                .collect()
+        } else {
+            coords
+                .iter()
+                .map(|&chunk| (chunk, self.latest_chunk_data(chunk)))
+                .collect()
+        }
+    }
+
+    /// Checksum of a chunk as we currently see it, same on every peer that sees the same pixels.
+    pub(crate) fn chunk_checksum(&self, chunk: ChunkCoord) -> Option<u64> {
+        let data = self.latest_chunk_data(chunk)?;
+        let mut pixels = Chunk::default();
+        data.apply_to_chunk(&mut pixels);
+        let mut hasher = FxHasher::default();
+        chunk.hash(&mut hasher);
+        for offset in 0..CHUNK_SIZE * CHUNK_SIZE {
+            pixels.compact_pixel(offset).raw().hash(&mut hasher);
+        }
+        Some(hasher.finish())
+    }
+
+    /// Single hash of every chunk we know about, so that peers can tell if they diverged without
+    /// exchanging per-chunk checksums. Doesn't depend on the order chunks are kept in.
+    pub(crate) fn world_hash(&self) -> u64 {
+        let chunks: FxHashSet<ChunkCoord> = self
+            .outbound_model
+            .chunk_coords()
+            .chain(self.inbound_model.chunk_coords())
+            .chain(self.chunk_storage.keys())
+            .copied()
+            .collect();
+        chunks
+            .into_iter()
+            .filter_map(|chunk| self.chunk_checksum(chunk))
+            .fold(0, u64::wrapping_add)
+    }
+
+    /// Starts keeping a minimap of loaded chunks, with the most common material of each
+    /// `block` by `block` pixel square. Stops if `block` is 0. Returns false if `block`
+    /// doesn't divide chunk size.
+    pub(crate) fn set_minimap_block(&mut self, block: usize) -> bool {
+        if block != 0 && !CHUNK_SIZE.is_multiple_of(block) {
+            return false;
+        }
+        self.inbound_model.set_minimap_block(block);
+        self.outbound_model.set_minimap_block(block);
+        true
+    }
+
+    /// Minimap blocks of a loaded chunk, row by row. Kept up to date as the chunk changes,
+    /// so it's cheap to call every frame.
+    pub(crate) fn minimap_tile(&self, chunk: ChunkCoord) -> Option<Vec<u16>> {
+        self.outbound_model
+            .minimap_tile(chunk)
+            .or_else(|| self.inbound_model.minimap_tile(chunk))
+            .map(<[u16]>::to_vec)
+    }
+
+    /// Chunks that both worlds know about, but have different pixels in.
+    /// Chunks only one of them has are ignored, as host keeps much more than others do.
+    #[cfg(test)]
+    pub(crate) fn sync_diff(&self, other: &WorldManager) -> Vec<ChunkCoord> {
+        let known = |world: &WorldManager| -> FxHashSet<ChunkCoord> {
+            world
+                .outbound_model
+                .chunk_coords()
+                .chain(world.inbound_model.chunk_coords())
+                .chain(world.chunk_storage.keys())
+                .copied()
+                .collect()
+        };
+        let pixels = |data: ChunkData| {
+            let mut chunk = Chunk::default();
+            data.apply_to_chunk(&mut chunk);
+            (0..CHUNK_SIZE * CHUNK_SIZE)
+                .map(|i| chunk.compact_pixel(i))
+                .collect::<Vec<_>>()
+        };
+        let theirs = known(other);
+        let mut diff: Vec<ChunkCoord> = known(self)
+            .into_iter()
+            .filter(|chunk| theirs.contains(chunk))
+            .filter(|chunk| {
+                let mine = self.latest_chunk_data(*chunk).map(pixels);
+                mine != other.latest_chunk_data(*chunk).map(pixels)
+            })
+            .collect();
+        diff.sort_unstable_by_key(|chunk| (chunk.0, chunk.1));
+        diff
+    }
+
+    /// Whether both worlds have the same pixels in chunks they both know about.
+    #[cfg(test)]
+    pub(crate) fn sync_equal(&self, other: &WorldManager) -> bool {
+        self.sync_diff(other).is_empty()
+    }
+
+    /// Renders the inclusive region as we currently see it, using ARGB material colors.
+    /// Meant for debugging, chunks we don't know about are magenta. None if the region is too big.
+    pub(crate) fn render_region(
+        &self,
+        min: ChunkCoord,
+        max: ChunkCoord,
+        colors: &FxHashMap<u16, u32>,
+    ) -> Option<RgbaImage> {
+        let (width, height) = (
+            (max.0 - min.0 + 1).max(0) as usize,
+            (max.1 - min.1 + 1).max(0) as usize,
+        );
+        if width * height > MAX_RENDERED_CHUNKS {
+            warn!("Region of {width}x{height} chunks is too big to render");
+            return None;
+        }
+        let mut image = RgbaImage::new((width * CHUNK_SIZE) as u32, (height * CHUNK_SIZE) as u32);
+        let mut working_chunk = Chunk::default();
+        let coords: Vec<ChunkCoord> = (min.0..=max.0)
+            .flat_map(|cx| (min.1..=max.1).map(move |cy| ChunkCoord(cx, cy)))
+            .collect();
+        for (chunk, data) in self.get_chunks_data(&coords) {
+            let (x0, y0) = (
+                ((chunk.0 - min.0) as usize * CHUNK_SIZE) as u32,
+                ((chunk.1 - min.1) as usize * CHUNK_SIZE) as u32,
+            );
+            let Some(data) = data else {
+                for y in 0..CHUNK_SIZE as u32 {
+                    for x in 0..CHUNK_SIZE as u32 {
+                        image.put_pixel(x0 + x, y0 + y, UNKNOWN_CHUNK_COLOR);
                     }
-                    listeners.retain(|p| p != &source);
                 }
-                ChunkState::UnloadPending => {}
-                ChunkState::Transfer => {}
-                ChunkState::WantToGetAuth { authority, .. } => {
-                    if authority == &source {
-                        to_remove.push(*c)
+                continue;
+            };
+            data.apply_to_chunk(&mut working_chunk);
+            for y in 0..CHUNK_SIZE {
+                for x in 0..CHUNK_SIZE {
+                    if let Some(color) =
+                        pixel_color(working_chunk.pixel(y * CHUNK_SIZE + x), colors)
+                    {
+                        image.put_pixel(x0 + x as u32, y0 + y as u32, color);
                     }
                 }
             }
         }
-        for c in to_remove {
-            self.chunk_state.remove(&c);
+        Some(image)
+    }
+
+    /// Collects chunks we know about in the inclusive region, relative to `min`.
+    pub(crate) fn export_region(&self, min: ChunkCoord, max: ChunkCoord) -> ExportedRegion {
+        let in_region = |chunk: &ChunkCoord| {
+            (min.0..=max.0).contains(&chunk.0) && (min.1..=max.1).contains(&chunk.1)
+        };
+        let mut chunks: FxHashMap<ChunkCoord, ChunkData> = self
+            .chunk_storage
+            .iter()
+            .filter(|(chunk, _)| in_region(chunk))
+            .map(|(chunk, data)| (*chunk, data.into_owned()))
+            .collect();
+        // Our own authority chunks are more recent than whatever is in storage.
+        for (chunk, state) in &self.chunk_state {
+            if in_region(chunk)
+                && matches!(state, ChunkState::Authority { .. })
+                && let Some(data) = self.outbound_model.get_chunk_data(*chunk)
+            {
+                chunks.insert(*chunk, data);
+            }
         }
-        if !self.is_host {
-            return;
+        ExportedRegion {
+            chunks: chunks
+                .into_iter()
+                .map(|(chunk, data)| (ChunkCoord(chunk.0 - min.0, chunk.1 - min.1), data))
+                .collect(),
         }
-        let mut pending_messages = Vec::new();
+    }
 
-        for (&chunk, peer) in self.authority_map.iter() {
-            if peer.0 == source {
-                info!("Removing authority from disconnected peer: {chunk:?}");
-                pending_messages.push(WorldNetMessage::ListenAuthorityRelinquished { chunk });
+    /// Puts chunks of a region into storage, translated so that region's origin ends up at `origin`.
+    /// Chunks we're an authority of are updated in place, so that changes reach the game and listeners.
+    /// Chunks of other authorities are edited through them, as they'd overwrite storage otherwise.
+    /// Returns the amount of chunks imported.
+    pub(crate) fn import_region(
+        &mut self,
+        region: &ExportedRegion,
+        origin: ChunkCoord,
+        policy: ImportPolicy,
+    ) -> usize {
+        if !self.is_host {
+            warn!("Only host can import regions");
+            return 0;
+        }
+        let mut imported = 0;
+        for (offset, data) in &region.chunks {
+            let chunk = ChunkCoord(origin.0 + offset.0, origin.1 + offset.1);
+            if !self.chunk_in_bounds(chunk) {
+                warn!("Skipping import of out of bounds chunk {chunk:?}");
+                continue;
+            }
+            let exists =
+                self.chunk_storage.contains_key(&chunk) || self.authority_map.contains_key(&chunk);
+            if exists && policy == ImportPolicy::SkipExisting {
+                continue;
+            }
+            let is_authority = self.is_authority(chunk);
+            let existing = if is_authority {
+                self.outbound_model.get_chunk_data(chunk)
+            } else {
+                self.chunk_storage.get(&chunk).map(Cow::into_owned)
+            };
+            let Some(delta) = import_delta(chunk, existing.as_ref(), data, policy) else {
+                continue;
+            };
+            imported += 1;
+            if !is_authority && self.authority_map.contains_key(&chunk) {
+                self.apply_delta_to_storage(&delta);
+                continue;
+            }
+            if is_authority {
+                self.inbound_model.apply_chunk_delta(&delta);
+                self.outbound_model.apply_chunk_delta(&delta);
             }
+            let data = match existing {
+                Some(mut existing) => {
+                    existing.apply_chunk_delta(&delta);
+                    existing
+                }
+                None => data.clone(),
+            };
+            let _ = self.tx.send((chunk, data.clone()));
+            self.chunk_storage.insert(chunk, data);
+            self.chunk_modified.insert(chunk, UNKNOWN_MODIFICATION_TIME);
         }
-        self.authority_map.retain(|_, peer| peer.0 != source);
+        imported
+    }
 
-        for message in pending_messages {
-            self.emit_msg(Destination::Broadcast, message)
+    /// Merges chunks of another world into ours, e.g. regions explored in another session.
+    /// Works like importing them as a region at the origin, see `import_region`: `Overwrite` prefers
+    /// their chunks, `SkipExisting` prefers ours, and `MergeNonAir` fills our air with their pixels.
+    pub(crate) fn merge_storage(
+        &mut self,
+        other: FxHashMap<ChunkCoord, ChunkData>,
+        policy: ImportPolicy,
+    ) -> usize {
+        let region = ExportedRegion {
+            chunks: other.into_iter().collect(),
+        };
+        self.import_region(&region, ChunkCoord(0, 0), policy)
+    }
+
+    fn compress_for(&self, peer: OmniPeerId, chunk_data: &ChunkData) -> CompressedChunkData {
+        let dict = self.compression_dict.as_ref().filter(|dict| {
+            peer == self.my_peer_id || self.peer_dict_versions.get(&peer) == Some(&dict.version())
+        });
+        let compressed = chunk_data.compress(dict, self.compression_level);
+        if self.record_compression_stats {
+            self.compression_stats
+                .lock()
+                .unwrap()
+                .record(bitcode::encode(chunk_data).len(), compressed.len());
         }
+        compressed
     }
 
-    pub(crate) fn cut_through_world(&mut self, x: i32, y_min: i32, y_max: i32, radius: i32) {
-        let max_wiggle = 5;
-        let interval = 300.0;
+    /// Compression stats since recording was enabled or last reset.
+    pub(crate) fn compression_stats(&self) -> CompressionStats {
+        *self.compression_stats.lock().unwrap()
+    }
 
-        let min_cx = (x - radius - max_wiggle).div_euclid(CHUNK_SIZE as i32);
-        let max_cx = (x + radius + max_wiggle).div_euclid(CHUNK_SIZE as i32);
-        let max_cy = y_max.div_euclid(CHUNK_SIZE as i32);
-        let min_cy = y_min.div_euclid(CHUNK_SIZE as i32);
-        let start = x - radius;
-        let end = x + radius;
+    pub(crate) fn reset_compression_stats(&mut self) {
+        *self.compression_stats.lock().unwrap() = CompressionStats::default();
+    }
 
-        let air_pixel = RawPixel {
-            flags: PixelFlags::Normal,
-            material: 0,
-        };
-        let chunk_storage: Vec<(ChunkCoord, ChunkData)> = self
-            .chunk_storage
-            .clone()
-            .into_par_iter()
-            .filter(|(coord, _)| {
-                min_cx <= coord.0 && max_cx >= coord.0 && coord.1 <= max_cy && coord.1 >= min_cy
-            })
-            .map(|(chunk_coord, chunk_encoded)| {
-                let chunk_start_x = chunk_coord.0 * CHUNK_SIZE as i32;
-                let chunk_end_x = chunk_start_x + CHUNK_SIZE as i32;
-                let chunk_start_y = chunk_coord.1 * CHUNK_SIZE as i32;
-                let mut chunk = Chunk::default();
-                chunk_encoded.apply_to_chunk(&mut chunk);
-                for in_chunk_y in 0..(CHUNK_SIZE as i32) {
-                    let global_y = in_chunk_y + chunk_start_y;
-                    let wiggle = -(global_y as f32 / interval * TAU).cos() * max_wiggle as f32;
-                    let wiggle = wiggle.round() as i32;
-                    let in_chunk_x_range = ((start + wiggle).clamp(chunk_start_x, chunk_end_x)
-                        - chunk_start_x)
-                        ..((end + wiggle).clamp(chunk_start_x, chunk_end_x) - chunk_start_x);
-                    for in_chunk_x in in_chunk_x_range {
-                        chunk.set_pixel(
-                            (in_chunk_y as usize) * CHUNK_SIZE + (in_chunk_x as usize),
-                            air_pixel,
-                        );
-                    }
-                }
-                (chunk_coord, chunk.to_chunk_data())
-            })
-            .collect();
-        for entry in chunk_storage.into_iter() {
-            self.chunk_storage.insert(entry.0, entry.1);
+    /// Stats to record into, if recording is enabled.
+    fn stats_sink(&self) -> Option<Arc<Mutex<CompressionStats>>> {
+        self.record_compression_stats
+            .then(|| self.compression_stats.clone())
+    }
+
+    fn decompress(&self, chunk_data: &CompressedChunkData) -> Option<ChunkData> {
+        chunk_data.decompress(self.compression_dict.as_ref())
+    }
+
+    /// Milliseconds since unix epoch at which stored chunk was last changed by terraforming
+    /// or by a storage update. Chunks that were imported or come from old saves
+    /// have `UNKNOWN_MODIFICATION_TIME`. None if chunk isn't in storage.
+    pub(crate) fn chunk_modified_at(&self, chunk: ChunkCoord) -> Option<u64> {
+        self.chunk_storage.contains_key(&chunk).then(|| {
+            self.chunk_modified
+                .get(&chunk)
+                .copied()
+                .unwrap_or(UNKNOWN_MODIFICATION_TIME)
+        })
+    }
+
+    fn mark_modified(&mut self, chunk: ChunkCoord) {
+        if self.chunk_storage.contains_key(&chunk) {
+            self.synthetic_chunks.remove(&chunk);
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(UNKNOWN_MODIFICATION_TIME, |time| time.as_millis() as u64);
+            self.chunk_modified.insert(chunk, now);
         }
     }
-    pub(crate) fn cut_through_world_line(
+
+    pub(crate) fn get_chunks(&self) -> FxHashMap<ChunkCoord, ChunkData> {
+        self.chunk_storage.to_map()
+    }
+
+    /// Updates the positions (in chunk coordinates) used to decide which chunks to unload.
+    /// Should be called every frame, as chunk updates alone don't happen while standing still.
+    pub(crate) fn set_position(
         &mut self,
-        x: i32,
-        y: i32,
-        lx: i32,
-        ly: i32,
-        r: i32,
-        chance: u8,
+        my_pos: (i32, i32),
+        cam_pos: (i32, i32),
+        is_notplayer: bool,
     ) {
-        if chance == 0 {
+        self.my_pos = my_pos;
+        self.cam_pos = cam_pos;
+        self.is_notplayer = is_notplayer;
+    }
+
+    /// Pending authority requests that can be sent this update, see `max_requests_per_update`.
+    /// None if all of them can.
+    fn requests_allowed_this_update(&self) -> Option<FxHashSet<ChunkCoord>> {
+        let max = self.max_requests_per_update?;
+        let mut pending: Vec<ChunkCoord> = self
+            .chunk_state
+            .iter()
+            .filter(|(_, state)| matches!(state, ChunkState::RequestAuthority { .. }))
+            .map(|(chunk, _)| *chunk)
+            .collect();
+        if pending.len() <= max {
+            return None;
+        }
+        let (x, y) = self.my_pos;
+        pending.sort_by_key(|chunk| {
+            let (dx, dy) = ((chunk.0 - x) as i64, (chunk.1 - y) as i64);
+            (dx * dx + dy * dy, chunk.0, chunk.1)
+        });
+        pending.truncate(max);
+        Some(pending.into_iter().collect())
+    }
+
+    /// Whether transfers of the chunk failed too many times in a row, see `max_transfer_retries`.
+    fn gave_up_transfers(&self, chunk: ChunkCoord) -> bool {
+        self.transfer_backoff
+            .get(&chunk)
+            .is_some_and(|(attempts, _)| *attempts > self.max_transfer_retries)
+    }
+
+    fn advance_clock(&mut self, dt: Duration) {
+        let Some(tick_duration) = self.tick_duration.filter(|tick| !tick.is_zero()) else {
             return;
+        };
+        self.clock += dt;
+        let ticks = (self.clock.as_nanos() / tick_duration.as_nanos()) as u64;
+        // Could've been ahead if tick duration was changed.
+        self.current_update = self.current_update.max(ticks);
+    }
+
+    /// Inclusive box of chunks around the player that aren't unloaded, as of last `set_position`.
+    /// When the camera is far away from the player, chunks around the camera are kept as well,
+    /// which isn't included here.
+    pub(crate) fn active_sync_bounds(&self) -> (ChunkCoord, ChunkCoord) {
+        sync_bounds(self.my_pos, self.cam_pos, self.player_sync_radius()).0
+    }
+
+    /// Radius of chunks kept around us when the camera is close, see `notplayer_sync_radius`.
+    fn player_sync_radius(&self) -> i32 {
+        match self.notplayer_sync_radius {
+            Some(radius) if self.is_notplayer => radius,
+            _ => PLAYER_SYNC_RADIUS,
         }
-        let (min_cx, max_cx) = if x < lx {
-            (
-                (x - r).div_euclid(CHUNK_SIZE as i32),
-                (lx + r).div_euclid(CHUNK_SIZE as i32),
-            )
-        } else {
-            (
-                (lx - r).div_euclid(CHUNK_SIZE as i32),
-                (x + r).div_euclid(CHUNK_SIZE as i32),
-            )
-        };
-        let (min_cy, max_cy) = if y < ly {
-            (
-                (y - r).div_euclid(CHUNK_SIZE as i32),
-                (ly + r).div_euclid(CHUNK_SIZE as i32),
-            )
-        } else {
-            (
-                (ly - r).div_euclid(CHUNK_SIZE as i32),
-                (y + r).div_euclid(CHUNK_SIZE as i32),
-            )
-        };
+    }
 
-        let dmx = lx - x;
-        let dmy = ly - y;
-        if dmx == 0 && dmy == 0 {
-            self.cut_through_world_circle(x, y, r, None, chance);
-            return;
+    pub(crate) fn current_update(&self) -> u64 {
+        self.current_update
+    }
+
+    /// Chunks updated by the game after update `since`, so that something outside can follow
+    /// changes by polling with the `current_update` of its last poll.
+    /// Chunks that were unloaded since are left out, unless `include_unloaded` is set.
+    pub(crate) fn chunks_changed_since(
+        &self,
+        since: u64,
+        include_unloaded: bool,
+    ) -> Vec<ChunkCoord> {
+        self.chunk_last_update
+            .iter()
+            .filter(|(chunk, last_update)| {
+                **last_update > since
+                    && (include_unloaded
+                        || !matches!(
+                            self.chunk_state.get(chunk),
+                            None | Some(ChunkState::UnloadPending)
+                        ))
+            })
+            .map(|(chunk, _)| *chunk)
+            .collect()
+    }
+
+    /// Which of the surrounding chunks we have any data for, in `NEIGHBOR_OFFSETS` order.
+    /// Lets operations that read across chunk boundaries decide whether to go on or wait.
+    pub(crate) fn neighbors_loaded(&self, chunk: ChunkCoord) -> [bool; 8] {
+        NEIGHBOR_OFFSETS.map(|(dx, dy)| {
+            let neighbor = ChunkCoord(chunk.0 + dx, chunk.1 + dy);
+            self.outbound_model.has_chunk(neighbor)
+                || self.inbound_model.has_chunk(neighbor)
+                || self.chunk_storage.contains_key(&neighbor)
+        })
+    }
+
+    fn chunk_updated_locally(
+        &mut self,
+        chunk: ChunkCoord,
+        mut priority: u8,
+        pos: Option<(i32, i32, i32, i32, bool)>,
+        world_num: u8,
+    ) -> Vec<(OmniPeerId, u8)> {
+        if let Some(boost) = self.priority_boosts.get_mut(&chunk) {
+            boost.base = Some(priority);
+            priority = priority.min(boost.strongest());
         }
-        if dmx.abs() > 8 * 512 || dmy.abs() > 8 * 512 {
-            warn!("unusually large cut, {} {} {} {} {}", x, y, lx, ly, r)
+        if let Some((px, py, cx, cy, is_not)) = pos {
+            self.set_position((px, py), (cx, cy), is_not);
         }
-        let dm2 = ((dmx.unsigned_abs() as u64 * dmx.unsigned_abs() as u64
-            + dmy.unsigned_abs() as u64 * dmy.unsigned_abs() as u64) as f64)
-            .recip();
-        let air_pixel = RawPixel {
-            flags: PixelFlags::Normal,
-            material: 0,
+        if self.world_num != world_num {
+            self.world_num = world_num;
+            self.reset();
+        }
+        let gave_up_transfers = self.gave_up_transfers(chunk);
+        let initial_priority = match self.initial_priority_fn {
+            Some(initial_priority) => initial_priority(chunk, self.my_pos, priority),
+            None => priority,
         };
-        let close_check = max_cx == min_cx || max_cy == min_cy;
-        let iter_check = [
-            (x + r, y),
-            (x - r, y),
-            (x, y + r),
-            (x, y - r),
-            (lx + r, ly),
-            (lx - r, ly),
-            (lx, ly + r),
-            (lx, ly - r),
-        ]
-        .into_iter();
-        let r = r as u64 * r as u64;
-        let chunk_storage: Vec<(ChunkCoord, ChunkData, bool)> = (min_cx..=max_cx)
-            .into_par_iter()
-            .flat_map(|chunk_x| {
-                (min_cy..=max_cy)
-                    .into_par_iter()
-                    .map(move |chunk_y| (chunk_x, chunk_y))
-            })
-            .filter(|&(chunk_x, chunk_y)| {
-                let chunk_start_x = chunk_x * CHUNK_SIZE as i32;
-                let chunk_start_y = chunk_y * CHUNK_SIZE as i32;
-                close_check
-                    || [
-                        (chunk_start_x, chunk_start_y),
-                        (
-                            chunk_start_x + CHUNK_SIZE as i32 - 1,
-                            chunk_start_y + CHUNK_SIZE as i32 - 1,
-                        ),
-                        (chunk_start_x + CHUNK_SIZE as i32 - 1, chunk_start_y),
-                        (chunk_start_x, chunk_start_y + CHUNK_SIZE as i32 - 1),
-                    ]
-                    .iter()
-                    .any(|(cx, cy)| {
-                        let dcx = cx - x;
-                        let dcy = cy - y;
-                        let m = ((dcx.unsigned_abs() as u64 * dmx.unsigned_abs() as u64
-                            + dcy.unsigned_abs() as u64 * dmy.unsigned_abs() as u64)
-                            as f64
-                            * dm2)
-                            .clamp(0.0, 1.0);
-                        let dx = dcx.abs_diff((m * dmx as f64) as i32) as u64;
-                        let dy = dcy.abs_diff((m * dmy as f64) as i32) as u64;
-                        dx * dx + dy * dy <= r
-                    })
-                    || {
-                        let (end_x, end_y) = (
-                            chunk_start_x + CHUNK_SIZE as i32 - 1,
-                            chunk_start_y + CHUNK_SIZE as i32 - 1,
-                        );
-                        iter_check.clone().any(|(x, y)| {
-                            end_x >= x && x >= chunk_start_x && end_y >= y && y >= chunk_start_y
-                        })
+        let entry = self.chunk_state.entry(chunk).or_insert_with(|| {
+            debug!("Created entry for {chunk:?}");
+            ChunkState::RequestAuthority {
+                priority: initial_priority,
+                can_wait: true,
+            }
+        });
+        let mut emit_queue = Vec::new();
+        self.chunk_last_update.insert(chunk, self.current_update);
+        self.speculative_chunks.remove(&chunk);
+        self.prefetched_chunks.remove(&chunk);
+        let mut chunks_to_send = Vec::new();
+        if matches!(
+            entry,
+            ChunkState::Listening { .. } | ChunkState::WantToGetAuth { .. }
+        ) {
+            self.listen_priority.insert(chunk, priority);
+        }
+        match entry {
+            ChunkState::Listening {
+                authority,
+                priority: pri,
+            } => {
+                if *pri > priority
+                    && *pri - priority >= self.min_takeover_priority_gap
+                    && !self.replicate_only
+                    && !gave_up_transfers
+                {
+                    let cs = ChunkState::WantToGetAuth {
+                        authority: *authority,
+                        auth_priority: *pri,
+                        my_priority: priority,
+                    };
+                    emit_queue.push((
+                        Destination::Peer(*authority),
+                        WorldNetMessage::LoseAuthority {
+                            chunk,
+                            new_priority: priority,
+                            new_authority: self.my_peer_id,
+                        },
+                    ));
+                    self.chunk_state.insert(chunk, cs);
+                }
+            }
+            ChunkState::WantToGetAuth {
+                authority,
+                auth_priority: auth_pri,
+                my_priority: my_pri,
+            } => {
+                if *my_pri != priority {
+                    *my_pri = priority;
+                    if *auth_pri <= priority {
+                        let cs = ChunkState::Listening {
+                            authority: *authority,
+                            priority: *auth_pri,
+                        };
+                        self.chunk_state.insert(chunk, cs);
+                    } else {
+                        emit_queue.push((
+                            Destination::Peer(*authority),
+                            WorldNetMessage::LoseAuthority {
+                                chunk,
+                                new_priority: priority,
+                                new_authority: self.my_peer_id,
+                            },
+                        ));
                     }
-            })
-            .filter_map(|(chunk_x, chunk_y)| {
-                let chunk_start_x = chunk_x * CHUNK_SIZE as i32;
-                let chunk_start_y = chunk_y * CHUNK_SIZE as i32;
-                let mut chunk = Chunk::default();
-                let coord = ChunkCoord(chunk_x, chunk_y);
-                let mut del = false;
-                let mut no_info = false;
-                if self.is_storage_recent.contains(&coord) {
-                    if let Some(chunk_encoded) = self.chunk_storage.get(&coord) {
-                        chunk_encoded.apply_to_chunk(&mut chunk)
+                }
+            }
+            ChunkState::Authority {
+                listeners,
+                priority: pri,
+                new_authority,
+                stop_sending,
+            } => {
+                // Without a delta listeners still get full chunk data, see `send_full_chunk`.
+                let delta = self.outbound_model.get_chunk_delta(chunk, false);
+                if *pri != priority {
+                    *pri = priority;
+                    emit_queue.push((
+                        Destination::Host,
+                        WorldNetMessage::ChangePriority { chunk, priority },
+                    ));
+                }
+                let mut new_auth = None;
+                if let Some(new) = new_authority {
+                    if new.1 >= priority {
+                        *new_authority = None;
+                        *stop_sending = false
+                    } else {
+                        new_auth = Some(new.0)
                     }
-                } else if let Some(chunk_encoded) = self
-                    .outbound_model
-                    .get_chunk_data(coord)
-                    .or(self.inbound_model.get_chunk_data(coord))
-                {
-                    del = true;
-                    chunk_encoded.apply_to_chunk(&mut chunk);
-                } else if let Some(chunk_encoded) = self.chunk_storage.get(&coord) {
-                    chunk_encoded.apply_to_chunk(&mut chunk)
-                } else if !self.nice_terraforming {
-                    return None;
                 } else {
-                    no_info = true;
+                    *stop_sending = false
                 }
-                let mut changed = false;
-                let mut rng = rng();
-                for icx in 0..CHUNK_SIZE as i32 {
-                    let cx = chunk_start_x + icx;
-                    let dcx = cx - x;
-                    let dx2 = dcx.unsigned_abs() as u64 * dmx.unsigned_abs() as u64;
-                    for icy in 0..CHUNK_SIZE as i32 {
-                        let cy = chunk_start_y + icy;
-                        let dcy = cy - y;
-                        let m = ((dx2 + dcy.unsigned_abs() as u64 * dmy.unsigned_abs() as u64)
-                            as f64
-                            * dm2)
-                            .clamp(0.0, 1.0);
-                        let dx = dcx.abs_diff((m * dmx as f64) as i32) as u64;
-                        let dy = dcy.abs_diff((m * dmy as f64) as i32) as u64;
-                        if dx * dx + dy * dy <= r {
-                            let px = icy as usize * CHUNK_SIZE + icx as usize;
-                            if (no_info
-                                || chunk.pixel(px).flags == PixelFlags::Unknown
-                                || self
-                                    .materials
-                                    .get(&chunk.pixel(px).material)
-                                    .map(|(_, _, cell, _)| cell.can_remove(true, false))
-                                    .unwrap_or(true))
-                                && (chance == 100
-                                    || rng.random_bool((chance as f64 / 100.0).clamp(0.0, 1.0)))
-                            {
-                                changed = true;
-                                chunk.set_pixel(px, air_pixel);
+                let mut new_auth_got = false;
+                if !*stop_sending {
+                    for &listener in listeners.iter() {
+                        if listener == self.my_peer_id {
+                            continue;
+                        }
+                        let take_auth = new_auth == Some(listener);
+                        match &delta {
+                            Some(delta) if take_auth => {
+                                new_auth_got = true;
+                                emit_queue.push((
+                                    Destination::Peer(listener),
+                                    WorldNetMessage::ListenUpdate {
+                                        delta: delta.clone(),
+                                        priority,
+                                        take_auth,
+                                    },
+                                ));
+                                chunks_to_send = Vec::new()
                             }
+                            _ => chunks_to_send.push((listener, priority)),
                         }
                     }
                 }
-                if changed {
-                    Some((coord, chunk.to_chunk_data(), del))
-                } else {
-                    None
+                if new_auth_got && new_auth.is_some() {
+                    *stop_sending = true
                 }
-            })
-            .collect();
-        for entry in chunk_storage.into_iter() {
-            self.chunk_storage.insert(entry.0, entry.1);
-            if entry.2 {
-                self.is_storage_recent.insert(entry.0);
             }
+            _ => {}
+        }
+        for (dst, msg) in emit_queue {
+            self.emit_msg(dst, msg)
         }
+        chunks_to_send
     }
-    pub(crate) fn cut_through_world_circle(
-        &mut self,
-        x: i32,
-        y: i32,
-        r: i32,
-        mat: Option<u16>,
-        chance: u8,
-    ) {
-        if chance == 0 {
-            return;
+
+    /// `dt` is the real time since last call, only used if `tick_duration` is set.
+    pub(crate) fn update(&mut self, dt: Duration) -> Vec<NoitaWorldUpdate> {
+        self.advance_clock(dt);
+        self.advance_background_save();
+        self.expire_priority_boosts();
+        self.expire_orphaned_authority();
+        self.expire_authority_locks();
+        self.expire_pending_listeners();
+        self.flush_terraform();
+        self.resend_unacked_storage();
+        self.send_delta_acks();
+        self.chunk_storage.update(self.current_update);
+        let mut emit_queue = Vec::new();
+        if self.is_host
+            && self
+                .last_host_assertion
+                .is_none_or(|last| self.current_update >= last + HOST_ASSERTION_INTERVAL)
+        {
+            self.last_host_assertion = Some(self.current_update);
+            emit_queue.push((
+                Destination::Broadcast,
+                WorldNetMessage::HostAssertion {
+                    host: self.my_peer_id,
+                    epoch: self.host_epoch,
+                },
+            ));
         }
-        let (min_cx, max_cx) = (
-            (x - r).div_euclid(CHUNK_SIZE as i32),
-            (x + r).div_euclid(CHUNK_SIZE as i32),
-        );
-        let (min_cy, max_cy) = (
-            (y - r).div_euclid(CHUNK_SIZE as i32),
-            (y + r).div_euclid(CHUNK_SIZE as i32),
-        );
-        let air_pixel = RawPixel {
-            flags: PixelFlags::Normal,
-            material: mat.unwrap_or(0),
-        };
-        let (chunkx, chunky) = (
-            x.div_euclid(CHUNK_SIZE as i32),
-            y.div_euclid(CHUNK_SIZE as i32),
-        );
-        let do_continue = mat.unwrap_or(0) != 0;
-        let rs = r as u64 * r as u64;
-        let chunk_storage: Vec<(ChunkCoord, ChunkData, bool)> = (min_cx..=max_cx)
-            .into_par_iter()
-            .flat_map(|chunk_x| {
-                (min_cy..=max_cy)
-                    .into_par_iter()
-                    .map(move |chunk_y| (chunk_x, chunk_y))
-            })
-            .filter(|&(chunk_x, chunk_y)| {
-                r <= CHUNK_SIZE as i32 || min_dist(x, y, chunkx, chunky, chunk_x, chunk_y) <= rs
-            })
-            .filter_map(|(chunk_x, chunk_y)| {
-                let coord = ChunkCoord(chunk_x, chunk_y);
-                let chunk_start_x = chunk_x * CHUNK_SIZE as i32;
-                let chunk_start_y = chunk_y * CHUNK_SIZE as i32;
-                let mut chunk = Chunk::default();
-                let mut del = false;
-                let mut no_info = false;
-                if self.is_storage_recent.contains(&coord) {
-                    if let Some(chunk_encoded) = self.chunk_storage.get(&coord) {
-                        chunk_encoded.apply_to_chunk(&mut chunk)
+        let dict_version = self.compression_dict.as_ref().map(|dict| dict.version());
+        if dict_version != self.announced_dict_version {
+            self.announced_dict_version = dict_version;
+            emit_queue.push((
+                Destination::Broadcast,
+                WorldNetMessage::DictionaryVersion {
+                    version: dict_version,
+                },
+            ));
+        }
+        let requests_allowed = self.requests_allowed_this_update();
+        let player_sync_radius = self.player_sync_radius();
+        let mut lost_transfers = Vec::new();
+        for (&chunk, state) in self.chunk_state.iter_mut() {
+            let chunk_last_update = self
+                .chunk_last_update
+                .get(&chunk)
+                .copied()
+                .unwrap_or_default();
+            let is_prefetched = self
+                .prefetched_chunks
+                .get(&chunk)
+                .is_some_and(|prefetched| self.current_update < prefetched + PREFETCH_TIMEOUT);
+            // Chunk we're standing in stays, even if nothing in it changes.
+            let is_idle = self.idle_unload_after.is_some_and(|after| {
+                self.current_update >= chunk_last_update.saturating_add(after)
+            }) && chunk != ChunkCoord(self.my_pos.0, self.my_pos.1)
+                && !self.priority_boosts.contains_key(&chunk)
+                && !self.suspended_sending.contains(&chunk);
+            let kill = !is_prefetched
+                && self.transition.is_none()
+                && (is_idle
+                    || should_kill(
+                        self.my_pos,
+                        self.cam_pos,
+                        chunk.0,
+                        chunk.1,
+                        player_sync_radius,
+                    ));
+            match state {
+                ChunkState::RequestAuthority { priority, can_wait } => {
+                    if self.transition.is_some()
+                        || requests_allowed
+                            .as_ref()
+                            .is_some_and(|allowed| !allowed.contains(&chunk))
+                    {
+                        continue;
                     }
-                } else if let Some(chunk_encoded) = self
-                    .outbound_model
-                    .get_chunk_data(coord)
-                    .or(self.inbound_model.get_chunk_data(coord))
-                {
-                    del = true;
-                    chunk_encoded.apply_to_chunk(&mut chunk);
-                } else if let Some(chunk_encoded) = self.chunk_storage.get(&coord) {
-                    chunk_encoded.apply_to_chunk(&mut chunk)
-                } else if do_continue || !self.nice_terraforming {
-                    return None;
-                } else {
-                    no_info = true;
+                    if self
+                        .transfer_backoff
+                        .get(&chunk)
+                        .is_some_and(|(_, next_try)| self.current_update < *next_try)
+                    {
+                        continue;
+                    }
+                    let priority = *priority;
+                    emit_queue.push((
+                        Destination::Host,
+                        WorldNetMessage::RequestAuthority {
+                            chunk,
+                            priority,
+                            can_wait: *can_wait,
+                            hint: self.movement_hint,
+                        },
+                    ));
+                    *state = ChunkState::WaitingForAuthority;
+                    self.last_request_priority.insert(chunk, priority);
+                    debug!("Requested authority for {chunk:?}")
                 }
-                let mut changed = false;
-                let mut rng = rng();
-                for icx in 0..CHUNK_SIZE as i32 {
-                    let cx = chunk_start_x + icx;
-                    let dx = cx.abs_diff(x) as u64;
-                    let dd = dx * dx;
-                    for icy in 0..CHUNK_SIZE as i32 {
-                        let cy = chunk_start_y + icy;
-                        let dy = cy.abs_diff(y) as u64;
-                        if dd + dy * dy <= rs {
-                            let px = icy as usize * CHUNK_SIZE + icx as usize;
-                            if (no_info
-                                || chunk.pixel(px).flags == PixelFlags::Unknown
-                                || self
-                                    .materials
-                                    .get(&chunk.pixel(px).material)
-                                    .map(|(_, _, cell, _)| cell.can_remove(true, false))
-                                    .unwrap_or(true))
-                                && (chance == 100
-                                    || rng.random_bool((chance as f64 / 100.0).clamp(0.0, 1.0)))
-                            {
-                                changed = true;
-                                chunk.set_pixel(px, air_pixel);
-                            }
-                        }
+                // This state doesn't have much to do.
+                ChunkState::WaitingForAuthority => {
+                    if kill {
+                        *state = ChunkState::UnloadPending;
                     }
                 }
-                if changed {
-                    Some((coord, chunk.to_chunk_data(), del))
-                } else {
-                    None
+                ChunkState::Listening { authority, .. } => {
+                    if kill {
+                        debug!("Unloading [listening] chunk {chunk:?}");
+                        emit_queue.push((
+                            Destination::Peer(*authority),
+                            WorldNetMessage::ListenStopRequest { chunk },
+                        ));
+                        *state = ChunkState::UnloadPending;
+                    }
                 }
-            })
-            .collect();
-        for entry in chunk_storage.into_iter() {
-            self.chunk_storage.insert(entry.0, entry.1);
-            if entry.2 {
-                self.is_storage_recent.insert(entry.0);
-            }
-        }
-    }
-    #[allow(clippy::too_many_arguments)]
-    #[allow(clippy::type_complexity)]
-    fn do_ray(
-        &self,
-        mut x: i32,
-        mut y: i32,
-        end_x: i32,
-        end_y: i32,
-        mut ray: u64,
-        d: u32,
-        mult: f32,
-    ) -> (Option<(i32, i32)>, u64, Option<ChunkCoord>) {
-        //Bresenham's line algorithm
-        let dx = (end_x - x).abs();
-        let dy = (end_y - y).abs();
-        if (dx == 0 && dy == 0) || ray == 0 {
-            return (None, 0, None);
-        }
-        let sx = if x < end_x { 1 } else { -1 };
-        let sy = if y < end_y { 1 } else { -1 };
-        let mut err = if dx > dy { dx } else { -dy } / 2;
-        let mut e2;
-        let mut working_chunk = Chunk::default();
-        let mut last_co = ChunkCoord(
-            x.div_euclid(CHUNK_SIZE as i32),
-            y.div_euclid(CHUNK_SIZE as i32),
-        );
-        if self.is_storage_recent.contains(&last_co) {
-            if let Some(c) = self.chunk_storage.get(&last_co) {
-                c.apply_to_chunk(&mut working_chunk);
-            } else {
-                return (None, 0, None);
-            }
-        } else if let Some(c) = self
-            .outbound_model
-            .get_chunk_data(last_co)
-            .or(self.inbound_model.get_chunk_data(last_co))
-        {
-            c.apply_to_chunk(&mut working_chunk);
-        } else if let Some(c) = self.chunk_storage.get(&last_co) {
-            c.apply_to_chunk(&mut working_chunk);
-        } else {
-            return (None, ray, None);
-        };
-        let mut last_coord = None;
-        let mut ret = 0;
-        while x != end_x || y != end_y {
-            if ret == 0 {
-                let co = ChunkCoord(
-                    x.div_euclid(CHUNK_SIZE as i32),
-                    y.div_euclid(CHUNK_SIZE as i32),
-                );
-                if co != last_co {
-                    if self.is_storage_recent.contains(&co) {
-                        if let Some(c) = self.chunk_storage.get(&co) {
-                            c.apply_to_chunk(&mut working_chunk);
-                        } else {
-                            return (None, 0, None);
+                ChunkState::Authority { new_authority, .. } => {
+                    let is_speculative = self
+                        .speculative_chunks
+                        .get(&chunk)
+                        .is_some_and(|granted| self.current_update < granted + SPECULATIVE_TIMEOUT);
+                    if !is_speculative && kill {
+                        if let Some(new) = new_authority {
+                            emit_queue.push((
+                                Destination::Peer(new.0),
+                                WorldNetMessage::AskForAuthority {
+                                    chunk,
+                                    priority: new.1,
+                                },
+                            ));
                         }
-                    } else if let Some(c) = self
-                        .outbound_model
-                        .get_chunk_data(co)
-                        .or(self.inbound_model.get_chunk_data(co))
-                    {
-                        c.apply_to_chunk(&mut working_chunk)
-                    } else if let Some(c) = self.chunk_storage.get(&co) {
-                        c.apply_to_chunk(&mut working_chunk)
-                    } else {
-                        ret = 17;
-                        continue;
-                    };
-                    last_co = co;
+                        debug!(
+                            "Unloading [authority] chunk {chunk:?} (updates: {chunk_last_update} {})",
+                            self.current_update
+                        );
+                        emit_queue.push((
+                            Destination::Host,
+                            WorldNetMessage::RelinquishAuthority {
+                                chunk,
+                                chunk_data: self.outbound_model.get_chunk_data(chunk),
+                                world_num: self.world_num,
+                            },
+                        ));
+                        // Relinquished data is newer than anything we're still resending.
+                        self.unacked_storage.remove(&chunk);
+                        *state = ChunkState::UnloadPending;
+                    }
                 }
-                let icx = x.rem_euclid(CHUNK_SIZE as i32);
-                let icy = y.rem_euclid(CHUNK_SIZE as i32);
-                let px = icy as usize * CHUNK_SIZE + icx as usize;
-                let pixel = working_chunk.pixel(px);
-                if let Some(stats) = self.materials.get(&pixel.material) {
-                    let h = (stats.1 as f64 * mult as f64) as u64;
-                    if stats.0 > d || ray < h {
-                        return (last_coord, 0, None);
+                ChunkState::WantToGetAuth { .. } => {
+                    if kill {
+                        debug!("Unloading [want to get auth] chunk {chunk:?}");
+                        *state = ChunkState::UnloadPending;
+                    }
+                }
+                ChunkState::UnloadPending => {}
+                ChunkState::Transfer { authority } => {
+                    if self
+                        .transfer_started
+                        .get(&chunk)
+                        .is_some_and(|started| self.current_update >= started + TRANSFER_TIMEOUT)
+                    {
+                        warn!("Transfer of {chunk:?} from {authority} got no reply");
+                        lost_transfers.push(chunk);
                     }
-                    ray = ray.saturating_sub(h);
                 }
-                last_coord = Some((x, y));
-            } else if ret != 1 {
-                ret -= 1;
-            } else {
-                return (Some((x, y)), ray, Some(last_co));
-            }
-
-            e2 = err;
-            if e2 > -dx {
-                err -= dy;
-                x += sx;
-            }
-            if e2 < dy {
-                err += dx;
-                y += sy;
             }
         }
-        (Some((x, y)), 0, None)
-    }
 
-    #[allow(clippy::type_complexity)]
-    fn interior_iter(&self, ex: ExplosionData) -> (Vec<ExRet>, Vec<u64>) {
-        let ExplosionData {
-            x,
-            y,
-            r,
-            d,
-            ray,
-            hole,
-            liquid,
-            mat,
-            prob,
-        } = ex;
-        let rays = get_ray(r);
-        let t = TAU / rays as f32;
-        let results: Vec<(u64, u64, Option<ChunkCoord>)> = (0..rays)
-            .into_par_iter()
-            .map(|n| {
-                let theta = t * (n as f32 + 0.5);
-                let end_x = x + (r as f64 * theta.cos() as f64) as i32;
-                let end_y = y + (r as f64 * theta.sin() as f64) as i32;
-                let mult = (((theta + TAU / 8.0) % (TAU / 4.0)) - TAU / 8.0)
-                    .cos()
-                    .recip();
-                let (u, v, c) = self.do_ray(x, y, end_x, end_y, ray, d, mult);
-                (
-                    if let Some((ex, ey)) = u {
-                        let dx = ex.abs_diff(x) as u64;
-                        let dy = ey.abs_diff(y) as u64;
-                        if dx != 0 || dy != 0 {
-                            dx * dx + dy * dy
-                        } else {
-                            0
-                        }
-                    } else {
-                        0
-                    },
-                    v,
-                    c,
-                )
-            })
-            .collect();
-        let lst = results.iter().map(|(_, b, _)| *b).collect();
-        (
-            self.cut_through_world_explosion_list(
-                x, y, d, rays, results, hole, liquid, mat, prob, r,
-            ),
-            lst,
-        )
+        for (dst, msg) in emit_queue {
+            self.emit_msg(dst, msg)
+        }
+        for chunk in lost_transfers {
+            self.transfer_started.remove(&chunk);
+            self.transfer_failed(chunk);
+        }
+        self.chunk_state.retain(|chunk, state| {
+            let retain = *state != ChunkState::UnloadPending;
+            if !retain {
+                self.speculative_chunks.remove(chunk);
+                self.prefetched_chunks.remove(chunk);
+                self.pending_forget.push(*chunk);
+            }
+            retain
+        });
+        self.transfer_backoff
+            .retain(|chunk, _| self.chunk_state.contains_key(chunk));
+        self.listen_priority
+            .retain(|chunk, _| self.chunk_state.contains_key(chunk));
+        self.transfer_started.retain(|chunk, _| {
+            matches!(
+                self.chunk_state.get(chunk),
+                Some(ChunkState::Transfer { .. })
+            )
+        });
+        let forget_count = self
+            .unload_per_update
+            .unwrap_or(usize::MAX)
+            .min(self.pending_forget.len());
+        for chunk in self.pending_forget.drain(..forget_count) {
+            // Could've been loaded again while waiting.
+            if self.chunk_state.contains_key(&chunk) {
+                continue;
+            }
+            // Models are basically caches, no need to keep the chunk around in them.
+            self.inbound_model.forget_chunk(chunk);
+            self.outbound_model.forget_chunk(chunk);
+        }
+        self.get_noita_updates()
     }
 
-    #[allow(clippy::type_complexity)]
-    pub(crate) fn cut_through_world_explosion(&mut self, exp: Vec<ExplosionData>) {
-        let resres: Vec<((Vec<ExRet>, Vec<u64>), ExplosionData)> = exp
-            .into_par_iter()
-            .map(|ex| (self.interior_iter(ex), ex))
-            .collect();
-        for ((chunks, raydata), ex) in resres {
-            let m = self.explosion_heap.len();
-            self.explosion_heap.push(ex);
-            let mut data = FxHashMap::default();
-            let mut exists = false;
-            for entry in chunks {
-                if let Some(entry) = entry.loaded {
-                    if entry.3 {
-                        self.chunk_storage.insert(entry.0, entry.1);
-                    } else {
-                        self.chunk_storage
-                            .entry(entry.0)
-                            .and_modify(|c| c.apply_delta(entry.1));
-                    }
-                    if entry.2 {
-                        self.is_storage_recent.insert(entry.0);
-                    }
+    pub(crate) fn get_noita_updates(&mut self) -> Vec<NoitaWorldUpdate> {
+        self.inbound_model.get_all_noita_updates()
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.chunk_storage.clear();
+        self.chunk_modified.clear();
+        self.synthetic_chunks.clear();
+        self.reset_sync_state();
+    }
+
+    /// Forgets everything about who syncs which chunk, but keeps stored chunks.
+    fn reset_sync_state(&mut self) {
+        self.inbound_model.reset();
+        self.outbound_model.reset();
+        self.authority_map.clear();
+        self.storage_handoff.clear();
+        self.chunk_last_update.clear();
+        self.chunk_state.clear();
+        self.is_storage_recent.clear();
+        self.priority_boosts.clear();
+        self.pending_forget.clear();
+        self.orphaning_peers.clear();
+        self.suspended_sending.clear();
+        self.transfer_backoff.clear();
+        self.listen_priority.clear();
+        self.authority_changes.clear();
+        self.authority_locks.clear();
+        self.transfer_started.clear();
+        self.unacked_storage.clear();
+        self.deltas_received.clear();
+        self.deltas_sent.clear();
+        self.pending_listeners.clear();
+        self.queued_terraform.clear();
+        self.unsent_packets.clear();
+        self.operation_repeats.clear();
+    }
+
+    pub(crate) fn get_emitted_msgs(&mut self) -> Vec<MessageRequest<WorldNetMessage>> {
+        let mut msgs = mem::take(&mut self.emitted_messages);
+        if let Some(warm_up) = self.warm_up_priority {
+            msgs = prioritize_warm_up(msgs, warm_up);
+        }
+        if self.prioritize_control {
+            prioritize_control(msgs)
+        } else {
+            msgs
+        }
+    }
+
+    /// Number of queued messages and their estimated encoded size in bytes, per destination.
+    pub(crate) fn pending_by_destination(&self) -> FxHashMap<Destination, (usize, usize)> {
+        let mut pending: FxHashMap<Destination, (usize, usize)> = FxHashMap::default();
+        for request in &self.emitted_messages {
+            let (count, bytes) = pending.entry(request.dst.clone()).or_default();
+            *count += 1;
+            *bytes += bitcode::encode(&request.msg).len();
+        }
+        pending
+    }
+
+    fn push_emitted(&mut self, request: MessageRequest<WorldNetMessage>) {
+        let Some(sink) = &self.msg_sink else {
+            self.emitted_messages.push(request);
+            self.check_emitted_caps();
+            return;
+        };
+        // Older buffered messages go first to keep ordering.
+        let mut pending = mem::take(&mut self.emitted_messages)
+            .into_iter()
+            .chain(std::iter::once(request));
+        let mut disconnected = false;
+        for request in pending.by_ref() {
+            match sink.try_send(request) {
+                Ok(()) => {}
+                Err(TrySendError::Full(request)) => {
+                    self.emitted_messages.push(request);
+                    break;
                 }
-                if let Some((coord, rays)) = entry.unloaded
-                    && self.nice_terraforming
-                {
-                    exists = true;
-                    let lst = rays
-                        .iter()
-                        .filter_map(|i| {
-                            if raydata[*i] == 0 {
-                                None
-                            } else if let Some(n) = data.get(i) {
-                                Some(*n)
-                            } else {
-                                let n = self.explosion_data.len();
-                                self.explosion_data
-                                    .push((m, *i, ExTarget::Ray(raydata[*i]), 0));
-                                data.insert(*i, n);
-                                Some(n)
-                            }
-                        })
-                        .collect::<Vec<usize>>();
-                    if !lst.is_empty() {
-                        self.explosion_pointer.entry(coord).or_default().extend(lst)
-                    }
+                Err(TrySendError::Disconnected(request)) => {
+                    self.emitted_messages.push(request);
+                    disconnected = true;
+                    break;
                 }
             }
-            if !exists {
-                self.explosion_heap.pop();
+        }
+        self.emitted_messages.extend(pending);
+        if disconnected {
+            warn!("World message sink disconnected, falling back to polling");
+            self.msg_sink = None;
+        }
+        self.check_emitted_caps();
+    }
+
+    fn check_emitted_caps(&mut self) {
+        let pending = self.emitted_messages.len();
+        if pending <= self.emitted_soft_cap {
+            return;
+        }
+        if self
+            .last_emitted_cap_warning
+            .is_none_or(|last| last.elapsed() > Duration::from_secs(5))
+        {
+            self.last_emitted_cap_warning = Some(Instant::now());
+            let mut counts: FxHashMap<&str, usize> = FxHashMap::default();
+            for request in &self.emitted_messages {
+                *counts.entry(request.msg.name()).or_default() += 1;
+            }
+            let mut counts: Vec<_> = counts.into_iter().collect();
+            counts.sort_unstable_by_key(|(_, count)| std::cmp::Reverse(*count));
+            warn!("{pending} world messages are waiting to be sent: {counts:?}");
+        }
+        if pending <= self.emitted_hard_cap {
+            return;
+        }
+        // Drop deltas with worst (highest) priority first, control messages are kept no matter what.
+        // Going down to soft cap, so that this doesn't have to happen on every message.
+        let mut deltas: Vec<(usize, u8)> = self
+            .emitted_messages
+            .iter()
+            .enumerate()
+            .filter_map(|(i, request)| Some((i, request.msg.delta_priority()?)))
+            .collect();
+        deltas.sort_unstable_by_key(|(i, priority)| (std::cmp::Reverse(*priority), *i));
+        let to_drop: FxHashSet<usize> = deltas
+            .into_iter()
+            .take(pending - self.emitted_soft_cap)
+            .map(|(i, _)| i)
+            .collect();
+        warn!("Dropping {} delta messages", to_drop.len());
+        let mut i = 0;
+        self.emitted_messages.retain(|_| {
+            i += 1;
+            !to_drop.contains(&(i - 1))
+        });
+    }
+
+    fn record(&mut self, message: impl FnOnce() -> RecordedMessage) {
+        let Some(limit) = self.record_history else {
+            return;
+        };
+        if self.history.len() >= limit.max(1) * 2 {
+            self.history.drain(..self.history.len() - limit);
+        }
+        self.history.push(message());
+    }
+
+    /// Last messages handled and emitted, oldest first. Empty unless `record_history` is set.
+    pub(crate) fn recent_messages(&self) -> &[RecordedMessage] {
+        let limit = self.record_history.unwrap_or(0);
+        &self.history[self.history.len().saturating_sub(limit)..]
+    }
+
+    /// Starts recording every handled message along with what it caused us to send.
+    /// Should be started on a fresh world, so that the trace can be replayed on another fresh one.
+    pub(crate) fn start_trace(&mut self) {
+        self.trace = Some(MessageTrace {
+            is_host: self.is_host,
+            my_peer_id: self.my_peer_id,
+            steps: Vec::new(),
+        });
+    }
+
+    pub(crate) fn take_trace(&mut self) -> Option<MessageTrace> {
+        self.trace.take()
+    }
+
+    fn emit_msg(&mut self, dst: Destination, msg: WorldNetMessage) {
+        self.record(|| RecordedMessage::Emitted {
+            dst: dst.clone(),
+            msg: msg.clone(),
+        });
+        if self.in_traced_msg
+            && let Some(step) = self.trace.as_mut().and_then(|trace| trace.steps.last_mut())
+        {
+            step.emitted.push((dst.clone(), msg.clone()));
+        }
+        // Short-circuit for messages intended for myself
+        if (self.is_host && dst == Destination::Host) || dst == Destination::Peer(self.my_peer_id) {
+            self.handle_own_msg(msg);
+            return;
+        }
+        // Also handle broadcast messages this way.
+        if dst == Destination::Broadcast {
+            self.handle_own_msg(msg.clone());
+        }
+        if let Destination::Peer(peer) = dst {
+            self.track_sent_deltas(peer, &msg);
+        }
+
+        self.push_emitted(MessageRequest {
+            reliability: tangled::Reliability::Reliable,
+            dst,
+            msg,
+        })
+    }
+
+    /// Counts deltas sent to a listener, see `DeltaAck`.
+    fn track_sent_deltas(&mut self, peer: OmniPeerId, msg: &WorldNetMessage) {
+        if let WorldNetMessage::ListenInitialResponse { chunk, .. } = msg {
+            self.deltas_sent.remove(&(*chunk, peer));
+            return;
+        }
+        for chunk in msg.delta_chunks().into_iter().flatten() {
+            let sent = self.deltas_sent.entry((chunk, peer)).or_default();
+            sent.count += 1;
+            sent.unacked.push_back((sent.count, self.current_update));
+            if sent.unacked.len() > MAX_UNACKED_DELTAS as usize {
+                sent.unacked.pop_front();
             }
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub(crate) fn cut_through_world_explosion_list(
-        &self,
-        x: i32,
-        y: i32,
-        d: u32,
-        rays: u64,
-        list: Vec<(u64, u64, Option<ChunkCoord>)>,
-        hole: bool,
-        liquid: bool,
-        mat: RawPixel,
-        prob: u8,
-        r: u64,
-    ) -> Vec<ExRet> {
-        let rads = list.iter().map(|(a, _, _)| *a).collect::<Vec<u64>>();
-        let rs = *rads.iter().max().unwrap_or(&0);
-        if r == 0 {
-            return Vec::new();
+    /// Sends whole chunks to listeners that missed some deltas, or are too far behind.
+    fn handle_delta_ack(&mut self, source: OmniPeerId, chunk: ChunkCoord, seq: u32) {
+        let Some(ChunkState::Authority {
+            listeners,
+            priority,
+            ..
+        }) = self.chunk_state.get(&chunk)
+        else {
+            return;
+        };
+        if !listeners.contains(&source) {
+            return;
         }
-        let (min_cx, max_cx) = (
-            (x - r as i32).div_euclid(CHUNK_SIZE as i32),
-            (x + r as i32).div_euclid(CHUNK_SIZE as i32),
+        let priority = *priority;
+        let Some(sent) = self.deltas_sent.get_mut(&(chunk, source)) else {
+            return;
+        };
+        // Sent before it got the chunk whole again.
+        if seq > sent.count {
+            return;
+        }
+        sent.unacked.retain(|&(unacked, _)| unacked > seq);
+        let missed = sent
+            .unacked
+            .front()
+            .is_some_and(|&(_, sent_at)| self.current_update >= sent_at + DELTA_ACK_TIMEOUT);
+        if !missed && sent.count - seq <= MAX_UNACKED_DELTAS {
+            return;
+        }
+        let Some(chunk_data) = self.outbound_model.get_chunk_data(chunk) else {
+            return;
+        };
+        info!(
+            "{source} is {} deltas behind on {chunk:?}, sending it whole",
+            sent.count - seq
         );
-        let (min_cy, max_cy) = (
-            (y - r as i32).div_euclid(CHUNK_SIZE as i32),
-            (y + r as i32).div_euclid(CHUNK_SIZE as i32),
+        let chunk_data = Some(self.compress_for(source, &chunk_data));
+        self.emit_msg(
+            Destination::Peer(source),
+            WorldNetMessage::ListenInitialResponse {
+                chunk,
+                chunk_data,
+                priority,
+            },
         );
-        let air_pixel = RawPixel {
-            flags: PixelFlags::Normal,
-            material: 0,
+    }
+
+    fn count_received_delta(&mut self, chunk: ChunkCoord) {
+        if let Some((received, _)) = self.deltas_received.get_mut(&chunk) {
+            *received += 1;
+        }
+    }
+
+    /// Tells authorities how many deltas we got since last time, see `delta_ack_interval`.
+    /// Also forgets delta counts of chunks we don't listen to or serve to a listener anymore.
+    fn send_delta_acks(&mut self) {
+        let chunk_state = &self.chunk_state;
+        self.deltas_received.retain(|chunk, _| {
+            matches!(chunk_state.get(chunk), Some(ChunkState::Listening { .. }))
+        });
+        self.deltas_sent.retain(|(chunk, peer), _| {
+            matches!(chunk_state.get(chunk), Some(ChunkState::Authority { listeners, .. }) if listeners.contains(peer))
+        });
+        let Some(interval) = self.delta_ack_interval else {
+            return;
         };
-        let (chunkx, chunky) = (
-            x.div_euclid(CHUNK_SIZE as i32),
-            y.div_euclid(CHUNK_SIZE as i32),
-        );
-        fn is_contained(sx: i32, sy: i32, ix: i32, iy: i32, cx: i32, cy: i32) -> bool {
-            if ix == cx && iy == cy {
-                false
-            } else {
-                let a = if ix > sx { cx >= ix } else { cx <= ix };
-                let b = if iy > sy { cy >= iy } else { cy <= iy };
-                a && b
+        if self.current_update < self.last_delta_ack + interval {
+            return;
+        }
+        self.last_delta_ack = self.current_update;
+        let mut acks = Vec::new();
+        for (chunk, (received, acked)) in &mut self.deltas_received {
+            if received == acked {
+                continue;
+            }
+            if let Some(ChunkState::Listening { authority, .. }) = self.chunk_state.get(chunk) {
+                *acked = *received;
+                acks.push((*authority, *chunk, *received));
             }
         }
-        (min_cx..=max_cx)
-            .into_par_iter()
-            .flat_map(|chunk_x| {
-                (min_cy..=max_cy)
-                    .into_par_iter()
-                    .map(move |chunk_y| (chunk_x, chunk_y))
-            })
-            .filter_map(|(chunk_x, chunk_y)| {
-                let coord = ChunkCoord(chunk_x, chunk_y);
-                let storage = if let Some(s) = self.chunk_storage.get(&coord) {
-                    s
-                } else if !self.nice_terraforming
-                    || min_dist(x, y, chunkx, chunky, chunk_x, chunk_y) > r * r
-                {
-                    return None;
-                } else {
-                    let lst: Vec<usize> = if (chunkx, chunky) == (chunk_x, chunk_y) {
-                        (0..list.len()).collect()
-                    } else {
-                        let (i, j) = find_rays(x, y, chunkx, chunky, chunk_x, chunk_y, rays);
-                        list.iter()
-                            .enumerate()
-                            .filter_map(|(n, (_, r, _))| {
-                                if (i..=j).contains(&n) && r != &0 {
-                                    Some(n)
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect()
-                    };
-                    return if lst.is_empty() {
-                        None
-                    } else {
-                        Some(ExRet {
-                            loaded: None,
-                            unloaded: Some((coord, lst)),
-                        })
-                    };
-                };
-                if !should_process_chunk(chunk_x, chunk_y, x, y, r, &rads, chunkx, chunky, rays, rs)
-                {
-                    return if !self.nice_terraforming {
-                        None
-                    } else {
-                        let (i, j) = find_rays(x, y, chunkx, chunky, chunk_x, chunk_y, rays);
-                        let lst: Vec<usize> = list
-                            .iter()
-                            .enumerate()
-                            .filter_map(|(n, (_, r, _))| {
-                                if (i..=j).contains(&n) && r != &0 {
-                                    Some(n)
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect();
-                        if lst.is_empty() {
-                            None
-                        } else {
-                            Some(ExRet {
-                                loaded: None,
-                                unloaded: Some((coord, lst)),
-                            })
-                        }
-                    };
-                }
-                let unloaded = if self.nice_terraforming && (chunkx, chunky) != (chunk_x, chunk_y) {
-                    let (i, j) = find_rays(x, y, chunkx, chunky, chunk_x, chunk_y, rays);
-                    let lst: Vec<usize> = list
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(n, (_, r, c))| {
-                            if let Some(c) = c {
-                                if (i..=j).contains(&n)
-                                    && r != &0
-                                    && is_contained(chunkx, chunky, c.0, c.1, chunk_x, chunk_y)
-                                {
-                                    Some(n)
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        })
-                        .collect();
-                    if lst.is_empty() {
-                        None
-                    } else {
-                        Some((coord, lst))
-                    }
-                } else {
-                    None
-                };
-                let mut chunk = Chunk::default();
-                let mut chunk_delta = Chunk::default();
-                let mut del = false;
-                if self.is_storage_recent.contains(&coord) {
-                    storage.apply_to_chunk(&mut chunk);
-                } else if let Some(chunk_encoded) = self
-                    .outbound_model
-                    .get_chunk_data(coord)
-                    .or(self.inbound_model.get_chunk_data(coord))
-                {
-                    del = true;
-                    chunk_encoded.apply_to_chunk(&mut chunk);
-                } else {
-                    storage.apply_to_chunk(&mut chunk);
-                }
-                let chunk_start_x = chunk_x * CHUNK_SIZE as i32;
-                let chunk_start_y = chunk_y * CHUNK_SIZE as i32;
-                let mut all = true;
-                let mut none = true;
-                let mut rng = rng();
-                let atan: Vec<f32> = compute_atans(chunk_start_x, chunk_start_y, rays as f32, x, y);
-                for icx in 0..CHUNK_SIZE as i32 {
-                    let cx = chunk_start_x + icx;
-                    let dx = cx.abs_diff(x) as u64;
-                    let dd = dx * dx;
-                    for icy in 0..CHUNK_SIZE as i32 {
-                        let cy = chunk_start_y + icy;
-                        let dy = cy.abs_diff(y) as u64;
-                        let px = icy as usize * CHUNK_SIZE + icx as usize;
-                        if (dx == 0 && dy == 0) || {
-                            let i = (atan[px] % rays as f32) as usize;
-                            dd + dy * dy <= list[i].0
-                        } {
-                            if self
-                                .materials
-                                .get(&chunk.pixel(px).material)
-                                .map(|(dur, _, cell, _)| *dur <= d && cell.can_remove(hole, liquid))
-                                .unwrap_or(true)
-                            {
-                                if prob != 0
-                                    && (prob == 100
-                                        || rng.random_bool((prob as f64 / 100.0).clamp(0.0, 1.0)))
-                                {
-                                    chunk_delta.set_pixel(px, mat);
-                                } else {
-                                    chunk_delta.set_pixel(px, air_pixel);
-                                }
-                                none = false;
-                            } else {
-                                all = false
-                            }
-                        } else {
-                            all = false
-                        }
-                    }
-                }
-                if none {
-                    unloaded.map(|unloaded| ExRet {
-                        loaded: None,
-                        unloaded: Some(unloaded),
-                    })
-                } else {
-                    Some(ExRet {
-                        loaded: Some((coord, chunk_delta.to_chunk_data(), del, all)),
-                        unloaded,
-                    })
-                }
-            })
-            .collect()
+        for (authority, chunk, seq) in acks {
+            self.emit_msg(
+                Destination::Peer(authority),
+                WorldNetMessage::DeltaAck { chunk, seq },
+            );
+        }
     }
 
-    #[allow(clippy::type_complexity)]
-    fn interior_iter_chunk(
-        &self,
-        ex: ExplosionData,
-        data: (usize, usize, ExTarget, u64),
-        chunk: ChunkCoord,
-        a: f32,
-    ) -> Option<(Option<u64>, ExTarget, u64)> {
-        let ExplosionData {
-            x,
-            y,
-            mut r,
-            d,
-            ray: _,
-            hole: _,
-            liquid: _,
-            mat: _,
-            prob: _,
-        } = ex;
-        let rays = get_ray(r);
-        if let ExTarget::Radius(p) = data.2 {
-            r = p
-        } else if let ExTarget::RayRad((_, p)) = data.2 {
-            r = p
-        }
-        if r == 0 {
-            return None;
+    fn handle_own_msg(&mut self, msg: WorldNetMessage) {
+        #[cfg(test)]
+        if let Some(loopback) = &mut self.loopback {
+            loopback.push(WorldEnvelope::wrap(&msg));
+            return;
         }
-        let t = TAU / rays as f32;
-        let theta = t * (data.1 as f32 + a);
-        let end_x = x + (r as f64 * theta.cos() as f64) as i32;
-        let end_y = y + (r as f64 * theta.sin() as f64) as i32;
-        let mult = (((theta + TAU / 8.0) % (TAU / 4.0)) - TAU / 8.0)
-            .cos()
-            .recip();
-        if let Some((enx, eny, ur, dd)) =
-            self.do_ray_chunk(x, y, end_x, end_y, data.2, d, mult, chunk, data.3, r)
-        {
-            let dx = enx.abs_diff(x) as u64;
-            let dy = eny.abs_diff(y) as u64;
-            if dx != 0 || dy != 0 {
-                Some((Some(dx * dx + dy * dy), ur, dd))
-            } else {
-                Some((None, ur, dd))
+        self.handle_msg(self.my_peer_id, msg);
+    }
+
+    /// Handles messages we've sent to ourselves the way remote ones are handled: encoded,
+    /// and only after whatever sent them is done.
+    #[cfg(test)]
+    fn deliver_loopback(&mut self) {
+        loop {
+            let envelopes = mem::take(self.loopback.as_mut().unwrap());
+            if envelopes.is_empty() {
+                break;
+            }
+            for envelope in envelopes {
+                self.handle_envelope(self.my_peer_id, envelope);
             }
-        } else if a != 0.5 || (end_x.abs_diff(x) == 0 && end_y.abs_diff(y) == 0) {
-            None
-        } else {
-            self.interior_iter_chunk(ex, data, chunk, 1.0)
-                .or(self.interior_iter_chunk(ex, data, chunk, 0.0))
-                .or(self.interior_iter_chunk(ex, data, chunk, 0.75))
-                .or(self.interior_iter_chunk(ex, data, chunk, 0.25))
-                .or(Some((None, data.2, 0)))
         }
     }
 
-    #[allow(clippy::type_complexity)]
-    pub(crate) fn cut_through_world_explosion_chunk(&mut self, chunk: ChunkCoord) {
-        let exp: Vec<(usize, (usize, usize, ExTarget, u64))> = self
-            .explosion_pointer
-            .remove(&chunk)
-            .unwrap_or_default()
-            .iter()
-            .map(|i| (*i, self.explosion_data[*i]))
-            .collect();
-        let data: Vec<(usize, Option<(Option<u64>, ExTarget, u64)>)> = exp
-            .into_par_iter()
-            .map(|ex| {
-                (
-                    ex.0,
-                    self.interior_iter_chunk(self.explosion_heap[ex.1.0], ex.1, chunk, 0.5),
-                )
-            })
-            .collect();
-        let ch = self.explosion_chunk(&data, chunk);
-        if let Some(ch) = ch {
-            if ch.1 {
-                self.chunk_storage.insert(chunk, ch.0);
-            } else {
-                self.chunk_storage
-                    .entry(chunk)
-                    .and_modify(|c| c.apply_delta(ch.0));
+    /// Sends chunks we've been authority of for a while to host, so that its storage isn't too far behind.
+    /// Bounds how much of the world is lost if we crash.
+    fn push_changed_storage(&mut self) {
+        let chunk_state = &self.chunk_state;
+        self.unpushed_changes.retain(|chunk, _| {
+            matches!(chunk_state.get(chunk), Some(ChunkState::Authority { .. }))
+        });
+        let mut to_push = Vec::new();
+        for (chunk, (changed, last_push)) in self.unpushed_changes.iter_mut() {
+            if *changed >= self.storage_push_min_pixels
+                && self.current_update >= *last_push + self.storage_push_interval
+            {
+                *changed = 0;
+                *last_push = self.current_update;
+                to_push.push(*chunk);
             }
-            self.is_storage_recent.insert(chunk);
         }
-        for (i, ch) in data {
-            if let Some((_, u, dd)) = ch {
-                self.explosion_data[i].2 = u;
-                if dd > self.explosion_data[i].3 {
-                    self.explosion_data[i].3 = dd
-                }
-            } else {
-                self.explosion_data[i].2 = ExTarget::Radius(0)
-            }
+        for chunk in to_push {
+            let chunk_data = self.outbound_model.get_chunk_data(chunk);
+            self.send_storage(chunk, chunk_data);
         }
     }
-    #[allow(clippy::too_many_arguments)]
-    #[allow(clippy::type_complexity)]
-    pub(crate) fn explosion_chunk(
-        &self,
-        data: &[(usize, Option<(Option<u64>, ExTarget, u64)>)],
-        coord: ChunkCoord,
-    ) -> Option<(ChunkData, bool)> {
-        let data: Vec<(usize, usize, u64)> = data
-            .iter()
-            .filter_map(|(i, a)| {
-                if let Some(a) = a {
-                    if let Some(b) = a.0 {
-                        let ex = self.explosion_data[*i];
-                        Some((ex.0, ex.1, b))
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect();
-        let mut grouped: HashMap<usize, Vec<(usize, u64)>> = HashMap::new();
-        for (key, a, b) in data {
-            grouped.entry(key).or_default().push((a, b));
+
+    /// Sends final contents of a chunk to host's storage, and keeps sending them until host
+    /// acknowledges, so that they aren't lost along with a dropped message.
+    fn send_storage(&mut self, chunk: ChunkCoord, chunk_data: Option<ChunkData>) {
+        if !self.is_host {
+            self.unacked_storage
+                .insert(chunk, (chunk_data.clone(), self.current_update, 1));
         }
-        let data: Vec<(usize, Vec<(usize, u64)>)> = grouped.into_iter().collect();
-        let air_pixel = RawPixel {
-            flags: PixelFlags::Normal,
-            material: 0,
-        };
-        let mut chunk = Chunk::default();
-        let mut chunk_delta = Chunk::default();
-        self.chunk_storage.get(&coord)?.apply_to_chunk(&mut chunk);
-        let chunk_start_x = coord.0 * CHUNK_SIZE as i32;
-        let chunk_start_y = coord.1 * CHUNK_SIZE as i32;
-        let mut all = true;
-        let mut none = true;
-        let mut rng = rng();
-        let data: Vec<(usize, &Vec<(usize, u64)>, Vec<f32>)> = data
-            .iter()
-            .map(|(i, data)| {
-                let ex = self.explosion_heap[*i];
-                let ExplosionData {
-                    x,
-                    y,
-                    r,
-                    d: _,
-                    ray: _,
-                    hole: _,
-                    liquid: _,
-                    mat: _,
-                    prob: _,
-                } = ex;
-                let rays = get_ray(r);
-                (
-                    *i,
-                    data,
-                    compute_atans(chunk_start_x, chunk_start_y, rays as f32, x, y),
-                )
-            })
-            .collect();
-        for icx in 0..CHUNK_SIZE as i32 {
-            let cx = chunk_start_x + icx;
-            'up: for icy in 0..CHUNK_SIZE as i32 {
-                let cy = chunk_start_y + icy;
-                let px = icy as usize * CHUNK_SIZE + icx as usize;
-                for (i, data, atan) in &data {
-                    let ex = self.explosion_heap[*i];
-                    let ExplosionData {
-                        x,
-                        y,
-                        r,
-                        d,
-                        ray: _,
-                        hole,
-                        liquid,
-                        mat,
-                        prob,
-                    } = ex;
-                    let dx = cx.abs_diff(x) as u64;
-                    let dy = cy.abs_diff(y) as u64;
-                    if ((dx == 0 && dy == 0) || {
-                        let rays = get_ray(r);
-                        let j = (atan[px] % rays as f32) as usize;
-                        let dd = dx * dx + dy * dy;
-                        data.iter().any(|(i, r)| j == *i && dd <= *r)
-                    }) && self
-                        .materials
-                        .get(&chunk.pixel(px).material)
-                        .map(|(dur, _, cell, _)| *dur <= d && cell.can_remove(hole, liquid))
-                        .unwrap_or(true)
-                    {
-                        if prob != 0
-                            && (prob == 100
-                                || rng.random_bool((prob as f64 / 100.0).clamp(0.0, 1.0)))
-                        {
-                            chunk_delta.set_pixel(px, mat);
-                        } else {
-                            chunk_delta.set_pixel(px, air_pixel);
-                        }
-                        none = false;
-                        continue 'up;
-                    }
+        self.emit_msg(
+            Destination::Host,
+            WorldNetMessage::UpdateStorage {
+                chunk,
+                chunk_data,
+                world_num: self.world_num,
+                priority: None,
+            },
+        );
+    }
+
+    fn resend_unacked_storage(&mut self) {
+        let mut resend = Vec::new();
+        let (current_update, attempts) = (self.current_update, self.storage_send_attempts);
+        self.unacked_storage
+            .retain(|chunk, (chunk_data, sent, sent_times)| {
+                if current_update < *sent + STORAGE_ACK_TIMEOUT {
+                    return true;
                 }
-                all = false
-            }
-        }
-        if none {
-            None
-        } else {
-            Some((chunk_delta.to_chunk_data(), all))
+                if *sent_times >= attempts {
+                    warn!("Host didn't acknowledge storage of {chunk:?}, giving up");
+                    return false;
+                }
+                *sent = current_update;
+                *sent_times += 1;
+                resend.push((*chunk, chunk_data.clone()));
+                true
+            });
+        for (chunk, chunk_data) in resend {
+            debug!("Resending storage of {chunk:?}");
+            self.emit_msg(
+                Destination::Host,
+                WorldNetMessage::UpdateStorage {
+                    chunk,
+                    chunk_data,
+                    world_num: self.world_num,
+                    priority: None,
+                },
+            );
         }
     }
-    #[allow(clippy::too_many_arguments)]
-    #[allow(clippy::type_complexity)]
-    fn do_ray_chunk(
-        &self,
-        stx: i32,
-        sty: i32,
-        end_x: i32,
-        end_y: i32,
-        rayn: ExTarget,
-        d: u32,
-        mult: f32,
-        chunk: ChunkCoord,
-        sd: u64,
-        r: u64,
-    ) -> Option<(i32, i32, ExTarget, u64)> {
-        //Bresenham's line algorithm
-        if r == 0
-            || min_dist(
-                stx,
-                sty,
-                stx.div_euclid(CHUNK_SIZE as i32),
-                sty.div_euclid(CHUNK_SIZE as i32),
-                chunk.0,
-                chunk.1,
-            ) > r * r
-        {
-            return None;
+
+    /// Fallback for when there is no delta for a locally updated chunk, as outbound model doesn't have it.
+    /// Listeners get whole chunk data instead, so that they don't silently miss an update.
+    /// Stored data is only used if it's known to be up to date, sending older data would roll
+    /// listeners back.
+    fn send_full_chunk(&mut self, chunk: ChunkCoord, listeners: &[(OmniPeerId, u8)]) {
+        if listeners.is_empty() {
+            return;
         }
-        let mut ray = match rayn {
-            ExTarget::Ray(ray) => ray,
-            ExTarget::Radius(_) => u64::MAX,
-            ExTarget::RayRad((ray, _)) => ray,
+        let Some(chunk_data) = self
+            .outbound_model
+            .get_chunk_data(chunk)
+            .or_else(|| self.inbound_model.get_chunk_data(chunk))
+            .or_else(|| {
+                self.is_storage_recent
+                    .contains(&chunk)
+                    .then(|| self.chunk_storage.get(&chunk).map(Cow::into_owned))
+                    .flatten()
+            })
+        else {
+            warn!(
+                "No delta or up to date data for {chunk:?}, {} listeners miss an update",
+                listeners.len()
+            );
+            return;
         };
-        if ray == 0 {
-            return None;
+        info!("No delta for {chunk:?}, sending whole chunk data to listeners instead");
+        for &(peer, priority) in listeners {
+            let chunk_data = Some(self.compress_for(peer, &chunk_data));
+            self.emit_msg(
+                Destination::Peer(peer),
+                WorldNetMessage::ListenInitialResponse {
+                    chunk,
+                    chunk_data,
+                    priority,
+                },
+            );
         }
-        let mut x = stx;
-        let mut y = sty;
-        let dx = end_x.abs_diff(x) as i32;
-        let dy = end_y.abs_diff(y) as i32;
-        if (dx == 0 && dy == 0) || ray == 0 {
-            return None;
+    }
+
+    /// Whether `source` can update storage of a chunk without being granted authority by it.
+    /// That's the current authority, or the previous one until the current one stores anything.
+    fn is_storage_writer(&mut self, chunk: ChunkCoord, source: OmniPeerId) -> bool {
+        let authority = self.authority_map.get(&chunk).map(|(peer, _)| *peer);
+        let handoff = self.storage_handoff.get(&chunk).copied();
+        if authority == Some(source) {
+            self.storage_handoff.remove(&chunk);
+            true
+        } else {
+            handoff == Some(source) || (authority.is_none() && handoff.is_none())
         }
-        let sx = if x < end_x { 1 } else { -1 };
-        let sy = if y < end_y { 1 } else { -1 };
-        let mut err = if dx > dy { dx } else { -dy } / 2;
-        let mut e2;
-        let mut working_chunk = Chunk::default();
-        self.chunk_storage
-            .get(&chunk)?
-            .apply_to_chunk(&mut working_chunk);
-        let mut count = 0;
-        let mut avg = 0;
-        let mut count2 = 0;
-        let mut last_dd = None;
-        while x != end_x || y != end_y {
-            let co = ChunkCoord(
-                x.div_euclid(CHUNK_SIZE as i32),
-                y.div_euclid(CHUNK_SIZE as i32),
-            );
-            if co == chunk {
-                let dx = stx.abs_diff(x) as u64;
-                let dy = sty.abs_diff(y) as u64;
-                let dd = dx * dx + dy * dy;
-                if sd < dd {
-                    let icx = x.rem_euclid(CHUNK_SIZE as i32);
-                    let icy = y.rem_euclid(CHUNK_SIZE as i32);
-                    let px = icy as usize * CHUNK_SIZE + icx as usize;
-                    let pixel = working_chunk.pixel(px);
-                    if let Some(stats) = self.materials.get(&pixel.material) {
-                        let h = (stats.1 as f64 * mult as f64) as u64;
-                        avg += h;
-                        count2 += 1;
-                        if stats.0 > d || ray < h + ((count * avg) / count2) {
-                            let nr = (dx as f64).hypot(dy as f64) as u64;
-                            return if count2 == 1 {
-                                Some((0, 0, ExTarget::RayRad((ray, nr)), 0))
-                            } else {
-                                Some((x, y, ExTarget::Radius(nr), dd))
-                            };
-                        };
-                        ray = ray.saturating_sub(h);
-                    }
-                }
-                last_dd = Some(dd)
-            } else if let Some(dd) = last_dd {
-                return if let ExTarget::Ray(_) = rayn {
-                    Some((
-                        i32::MAX / 4,
-                        i32::MAX / 4,
-                        ExTarget::Ray(ray.saturating_sub((count * avg) / count2.max(1))),
-                        dd,
-                    ))
-                } else if let ExTarget::RayRad((_, r)) = rayn {
-                    Some((
-                        i32::MAX / 4,
-                        i32::MAX / 4,
-                        ExTarget::RayRad((ray.saturating_sub((count * avg) / count2.max(1)), r)),
-                        dd,
-                    ))
-                } else {
-                    Some((i32::MAX / 4, i32::MAX / 4, rayn, dd))
-                };
-            } else if !self.chunk_storage.contains_key(&co) {
-                let dx = stx.abs_diff(x) as u64;
-                let dy = sty.abs_diff(y) as u64;
-                let dd = dx * dx + dy * dy;
-                if sd < dd {
-                    count += 1
-                }
-            }
-            e2 = err;
-            if e2 > -dx {
-                err -= dy;
-                x += sx;
-            }
-            if e2 < dy {
-                err += dx;
-                y += sy;
+    }
+
+    fn emit_got_authority(&mut self, chunk: ChunkCoord, source: OmniPeerId, priority: u8) {
+        let auth = self.authority_map.get(&chunk);
+        let chunk_data = if auth
+            .map(|a| a.0 != source) //TODO doesn't work
+            .unwrap_or(self.chunk_storage.contains_key(&chunk))
+        {
+            if self.explosion_pointer.contains_key(&chunk) {
+                self.cut_through_world_explosion_chunk(chunk);
             }
-        }
-        if let Some(dd) = last_dd {
-            if let ExTarget::Ray(_) = rayn {
-                Some((
-                    x,
-                    y,
-                    ExTarget::Ray(ray.saturating_sub((count * avg) / count2.max(1))),
-                    dd,
-                ))
-            } else if let ExTarget::RayRad((_, r)) = rayn {
-                Some((
-                    x,
-                    y,
-                    ExTarget::RayRad((ray.saturating_sub((count * avg) / count2.max(1)), r)),
-                    dd,
-                ))
+            self.chunk_storage.get(&chunk).map(Cow::into_owned)
+        } else if self.explosion_pointer.contains_key(&chunk) {
+            if !self.chunk_storage.contains_key(&chunk) {
+                self.emit_msg(
+                    Destination::Peer(source),
+                    WorldNetMessage::GetChunk { chunk, priority },
+                );
+                return;
             } else {
-                Some((x, y, rayn, dd))
+                self.cut_through_world_explosion_chunk(chunk);
             }
+            self.chunk_storage.get(&chunk).map(Cow::into_owned)
         } else {
             None
+        };
+        self.authority_map.insert(chunk, (source, priority));
+        self.storage_handoff.remove(&chunk);
+        let chunk_data = chunk_data.map(|chunk_data| self.compress_for(source, &chunk_data));
+        let mut listeners: FxHashSet<_> = self
+            .pending_listeners
+            .remove(&chunk)
+            .map(|listeners| listeners.into_keys().collect())
+            .unwrap_or_default();
+        listeners.remove(&source);
+        self.emit_msg(
+            Destination::Peer(source),
+            WorldNetMessage::GotAuthority {
+                chunk,
+                chunk_data,
+                priority,
+                listeners,
+            },
+        );
+    }
+
+    fn emit_transfer_authority(
+        &mut self,
+        chunk: ChunkCoord,
+        source: OmniPeerId,
+        priority: u8,
+        current_authority: OmniPeerId,
+    ) {
+        self.record_authority_change(chunk);
+        self.authority_map.insert(chunk, (source, priority));
+        self.storage_handoff.insert(chunk, current_authority);
+        self.emit_msg(
+            Destination::Peer(source),
+            WorldNetMessage::GetAuthorityFrom {
+                chunk,
+                current_authority,
+            },
+        );
+    }
+
+    pub(crate) fn handle_envelope(&mut self, source: OmniPeerId, envelope: WorldEnvelope) {
+        if let Some(msg) = envelope.open(source) {
+            self.handle_msg(source, msg)
+        }
+    }
+
+    pub(crate) fn handle_msg(&mut self, source: OmniPeerId, msg: WorldNetMessage) {
+        let Some(trace) = self.trace.as_mut().filter(|_| !self.in_traced_msg) else {
+            self.handle_msg_inner(source, msg);
+            return;
+        };
+        trace.steps.push(TraceStep {
+            source,
+            msg: msg.clone(),
+            emitted: Vec::new(),
+        });
+        self.in_traced_msg = true;
+        self.handle_msg_inner(source, msg);
+        self.in_traced_msg = false;
+    }
+
+    fn handle_msg_inner(&mut self, source: OmniPeerId, msg: WorldNetMessage) {
+        self.record(|| RecordedMessage::Handled {
+            source,
+            msg: msg.clone(),
+        });
+        if let Some(chunk) = msg.chunk()
+            && !self.chunk_in_bounds(chunk)
+        {
+            warn!("{source} sent a message for out of bounds chunk {chunk:?}");
+            // Requester would keep waiting for authority otherwise.
+            if self.is_host && matches!(msg, WorldNetMessage::RequestAuthority { .. }) {
+                if source == self.my_peer_id {
+                    self.chunk_state.insert(chunk, ChunkState::UnloadPending);
+                } else {
+                    self.emit_msg(
+                        Destination::Peer(source),
+                        WorldNetMessage::UnloadChunk { chunk },
+                    );
+                }
+            }
+            return;
+        }
+        self.departed_peers.remove(&source);
+        if self.orphaning_peers.remove(&source).is_some() {
+            info!("{source} came back, it keeps its authority");
+        }
+        if msg.is_from_host_only()
+            && source != self.my_peer_id
+            && self.expected_host.is_some_and(|host| host != source)
+        {
+            warn!("{source} sent {} but isn't the host, ignoring", msg.name());
+            return;
+        }
+        match msg {
+            WorldNetMessage::RequestAuthority {
+                chunk,
+                priority,
+                can_wait,
+                hint,
+            } => {
+                if !self.is_host {
+                    warn!("{} sent RequestAuthority to not-host.", source);
+                    return;
+                }
+                let mut current_authority = self.authority_map.get(&chunk).copied();
+                if let Some((authority, _)) = current_authority
+                    && authority != source
+                    && self.orphaning_peers.contains_key(&authority)
+                {
+                    debug!("{source} takes {chunk:?} over from departed {authority}");
+                    self.authority_map.remove(&chunk);
+                    self.emit_msg(
+                        Destination::Broadcast,
+                        WorldNetMessage::ListenAuthorityRelinquished { chunk },
+                    );
+                    current_authority = None;
+                }
+                if self.replica_only_peers.contains(&source) {
+                    match current_authority {
+                        Some((authority, _)) if authority != source => {
+                            debug!("Replica {source} will listen to {chunk:?} from {authority}");
+                            self.emit_msg(
+                                Destination::Peer(source),
+                                WorldNetMessage::AuthorityAlreadyTaken { chunk, authority },
+                            );
+                        }
+                        Some(_) => {
+                            debug!("Not granting replica {source} authority of {chunk:?}");
+                            self.emit_msg(
+                                Destination::Peer(source),
+                                WorldNetMessage::UnloadChunk { chunk },
+                            );
+                        }
+                        None => {
+                            debug!(
+                                "Replica {source} will listen to {chunk:?} once it has an authority"
+                            );
+                            self.pending_listeners
+                                .entry(chunk)
+                                .or_default()
+                                .insert(source, self.current_update);
+                        }
+                    }
+                    return;
+                }
+                match current_authority {
+                    Some((authority, priority_state)) => {
+                        if source == authority {
+                            debug!("{source} already has authority of {chunk:?}");
+                            self.emit_got_authority(chunk, source, priority);
+                        } else if priority_state > priority
+                            && (!can_wait || priority_state == SPECULATIVE_PRIORITY)
+                            && !self.authority_locks.contains_key(&chunk)
+                        {
+                            debug!("{source} is gaining priority over {chunk:?} from {authority}");
+                            self.emit_transfer_authority(chunk, source, priority, authority);
+                        } else {
+                            debug!(
+                                "{source} requested authority for {chunk:?}, but it's already taken by {authority}"
+                            );
+                            self.emit_msg(
+                                Destination::Peer(source),
+                                WorldNetMessage::AuthorityAlreadyTaken { chunk, authority },
+                            );
+                        }
+                    }
+                    None => {
+                        debug!("Granting {source} authority of {chunk:?}");
+                        self.emit_got_authority(chunk, source, priority);
+                    }
+                }
+                if let Some(velocity) = hint {
+                    self.pre_grant_authority(chunk, source, velocity);
+                }
+            }
+            WorldNetMessage::GetChunk { chunk, priority } => self.emit_msg(
+                Destination::Host,
+                WorldNetMessage::UpdateStorage {
+                    chunk,
+                    chunk_data: self.outbound_model.get_chunk_data(chunk),
+                    world_num: self.world_num,
+                    priority: Some(priority),
+                },
+            ),
+            WorldNetMessage::AskForAuthority { chunk, priority } => {
+                self.emit_msg(
+                    Destination::Host,
+                    WorldNetMessage::RequestAuthority {
+                        chunk,
+                        priority,
+                        can_wait: false,
+                        hint: None,
+                    },
+                );
+                self.chunk_state
+                    .insert(chunk, ChunkState::WaitingForAuthority);
+            }
+            WorldNetMessage::LoseAuthority {
+                chunk,
+                new_authority,
+                new_priority,
+            } => {
+                if let Some(ChunkState::Authority {
+                    new_authority: new_auth,
+                    ..
+                }) = self.chunk_state.get_mut(&chunk)
+                {
+                    if new_authority == self.my_peer_id {
+                        *new_auth = None;
+                    } else if let Some(new) = new_auth {
+                        if new.1 > new_priority {
+                            *new_auth = Some((new_authority, new_priority));
+                        }
+                    } else {
+                        *new_auth = Some((new_authority, new_priority))
+                    }
+                }
+            }
+            WorldNetMessage::ChangePriority { chunk, priority } => {
+                if !self.is_host {
+                    warn!("{} sent RequestAuthority to not-host.", source);
+                    return;
+                }
+                let current_authority = self.authority_map.get(&chunk).copied();
+                match current_authority {
+                    Some((authority, _)) => {
+                        if source == authority {
+                            self.authority_map.insert(chunk, (source, priority));
+                        } else {
+                            debug!(
+                                "{source} requested authority for {chunk:?}, but it's already taken by {authority}"
+                            );
+                        }
+                    }
+                    None => {
+                        debug!("Granting {source} authority of {chunk:?}");
+                    }
+                }
+            }
+            WorldNetMessage::GotAuthority {
+                chunk,
+                chunk_data,
+                priority,
+                listeners,
+            } => {
+                self.chunk_state
+                    .insert(chunk, ChunkState::authority(priority));
+                self.unacked_storage.remove(&chunk);
+                self.last_request_priority.remove(&chunk);
+                self.transfer_backoff.remove(&chunk);
+                if priority == SPECULATIVE_PRIORITY {
+                    self.speculative_chunks.insert(chunk, self.current_update);
+                } else {
+                    self.speculative_chunks.remove(&chunk);
+                }
+                let chunk_data = chunk_data.and_then(|c| self.decompress(&c));
+                if !self.apply_received_chunk_data(source, chunk, chunk_data.as_ref()) {
+                    // Whatever we had might be stale, start blank and let Noita fill it in.
+                    self.inbound_model.forget_chunk(chunk);
+                    self.outbound_model.forget_chunk(chunk);
+                }
+                for &listener in &listeners {
+                    let chunk_data = self
+                        .outbound_model
+                        .get_chunk_data(chunk)
+                        .map(|chunk_data| self.compress_for(listener, &chunk_data));
+                    self.emit_msg(
+                        Destination::Peer(listener),
+                        WorldNetMessage::ListenInitialResponse {
+                            chunk,
+                            chunk_data,
+                            priority,
+                        },
+                    );
+                }
+                if let Some(ChunkState::Authority {
+                    listeners: current, ..
+                }) = self.chunk_state.get_mut(&chunk)
+                {
+                    current.extend(listeners);
+                }
+            }
+            WorldNetMessage::UpdateStorage {
+                chunk,
+                chunk_data,
+                world_num,
+                priority,
+            } => {
+                if !self.is_host {
+                    warn!("{} sent RelinquishAuthority to not-host.", source);
+                    return;
+                }
+                if world_num != self.world_num {
+                    return;
+                }
+                if priority.is_none() && source != self.my_peer_id {
+                    self.emit_msg(
+                        Destination::Peer(source),
+                        WorldNetMessage::StorageAck { chunk, world_num },
+                    );
+                }
+                if priority.is_none() && !self.is_storage_writer(chunk, source) {
+                    debug!("{source} sent outdated storage of {chunk:?}, ignoring it");
+                    return;
+                }
+                if let Some(chunk_data) = chunk_data {
+                    let _ = self.tx.send((chunk, chunk_data.clone()));
+                    self.chunk_storage.insert(chunk, chunk_data);
+                    self.mark_modified(chunk);
+                    if let Some(p) = priority {
+                        self.cut_through_world_explosion_chunk(chunk);
+                        self.emit_got_authority(chunk, source, p)
+                    }
+                } else if priority.is_some() {
+                    warn!("{} sent give auth without chunk", source)
+                }
+            }
+            WorldNetMessage::RelinquishAuthority {
+                chunk,
+                chunk_data,
+                world_num,
+            } => {
+                if !self.is_host {
+                    warn!("{} sent RelinquishAuthority to not-host.", source);
+                    return;
+                }
+                if world_num != self.world_num {
+                    return;
+                }
+                if self.authority_map.get(&chunk).map(|(peer, _)| *peer) != Some(source) {
+                    debug!(
+                        "{source} sent RelinquishAuthority for {chunk:?}, but isn't currently an authority"
+                    );
+                    self.reject_relinquish(source);
+                    return;
+                }
+                self.authority_map.remove(&chunk);
+                self.storage_handoff.insert(chunk, source);
+                if let Some(chunk_data) = chunk_data {
+                    let _ = self.tx.send((chunk, chunk_data.clone()));
+                    self.chunk_storage.insert(chunk, chunk_data);
+                    self.mark_modified(chunk);
+                    self.emit_msg(
+                        Destination::Broadcast,
+                        WorldNetMessage::ListenAuthorityRelinquished { chunk },
+                    )
+                }
+            }
+            WorldNetMessage::UnloadChunk { chunk } => {
+                self.chunk_state.insert(chunk, ChunkState::UnloadPending {});
+            }
+
+            WorldNetMessage::AuthorityAlreadyTaken { chunk, authority } => {
+                self.emit_msg(
+                    Destination::Peer(authority),
+                    WorldNetMessage::ListenRequest { chunk },
+                );
+                self.last_request_priority.remove(&chunk);
+            }
+            WorldNetMessage::ListenRequest { chunk } => {
+                // We already have the chunk if we're its authority, and there's nobody to listen to otherwise.
+                if source == self.my_peer_id {
+                    warn!("Ignoring request to listen to our own chunk {chunk:?}");
+                    return;
+                }
+                let Some(ChunkState::Authority {
+                    listeners,
+                    priority,
+                    ..
+                }) = self.chunk_state.get_mut(&chunk)
+                else {
+                    self.emit_msg(
+                        Destination::Peer(source),
+                        WorldNetMessage::UnloadChunk { chunk },
+                    );
+                    //warn!("Can't listen for {chunk:?} - not an authority");
+                    return;
+                };
+                listeners.insert(source);
+                let priority = *priority;
+                let chunk_data = self
+                    .outbound_model
+                    .get_chunk_data(chunk)
+                    .map(|chunk_data| self.compress_for(source, &chunk_data));
+                self.emit_msg(
+                    Destination::Peer(source),
+                    WorldNetMessage::ListenInitialResponse {
+                        chunk,
+                        chunk_data,
+                        priority,
+                    },
+                );
+            }
+            WorldNetMessage::ListenStopRequest { chunk } => {
+                let Some(ChunkState::Authority { listeners, .. }) =
+                    self.chunk_state.get_mut(&chunk)
+                else {
+                    //warn!("Can't stop listen for {chunk:?} - not an authority");
+                    return;
+                };
+                listeners.remove(&source);
+                self.deltas_sent.remove(&(chunk, source));
+            }
+            WorldNetMessage::ListenInitialResponse {
+                chunk,
+                chunk_data,
+                priority,
+            } => {
+                self.deltas_received.insert(chunk, (0, 0));
+                self.unacked_storage.remove(&chunk);
+                self.chunk_state.insert(
+                    chunk,
+                    ChunkState::Listening {
+                        authority: source,
+                        priority,
+                    },
+                );
+                if let Some(chunk_data) = chunk_data.and_then(|c| self.decompress(&c)) {
+                    if let Err(err) = self.inbound_model.apply_chunk_data(chunk, &chunk_data) {
+                        warn!(
+                            "Corrupt chunk data for {chunk:?} from {source}, covers {} pixels",
+                            err.covered
+                        );
+                        // Requested again on the next local update.
+                        self.inbound_model.forget_chunk(chunk);
+                        self.chunk_state.remove(&chunk);
+                    }
+                } else {
+                    warn!(
+                        "Initial listen response has None chunk_data. It's generally supposed to have some."
+                    );
+                }
+            }
+            WorldNetMessage::ListenUpdate {
+                delta,
+                priority,
+                take_auth,
+            } => {
+                match self.chunk_state.get_mut(&delta.chunk_coord) {
+                    Some(ChunkState::Listening { priority: pri, .. }) => {
+                        *pri = priority;
+                        if take_auth && !self.replicate_only {
+                            self.emit_msg(
+                                Destination::Peer(source),
+                                WorldNetMessage::LoseAuthority {
+                                    chunk: delta.chunk_coord,
+                                    new_priority: priority,
+                                    new_authority: source,
+                                },
+                            );
+                        }
+                    }
+                    Some(ChunkState::WantToGetAuth {
+                        authority,
+                        my_priority,
+                        ..
+                    }) => {
+                        if priority > *my_priority {
+                            if take_auth {
+                                let rq = WorldNetMessage::RequestAuthority {
+                                    chunk: delta.chunk_coord,
+                                    priority: *my_priority,
+                                    can_wait: false,
+                                    hint: None,
+                                };
+                                self.emit_msg(Destination::Host, rq);
+                                self.chunk_state
+                                    .insert(delta.chunk_coord, ChunkState::WaitingForAuthority);
+                            }
+                        } else {
+                            let cs = ChunkState::Listening {
+                                authority: *authority,
+                                priority,
+                            };
+                            self.chunk_state.insert(delta.chunk_coord, cs);
+                        }
+                    }
+                    _ if take_auth && !self.replicate_only => {
+                        let chunk = delta.chunk_coord;
+                        // Sender stops updating its listeners until it hears back, so always answer.
+                        self.emit_msg(
+                            Destination::Peer(source),
+                            WorldNetMessage::LoseAuthority {
+                                chunk,
+                                new_priority: priority,
+                                new_authority: source,
+                            },
+                        );
+                        if !self.is_chunk_tracked(chunk) {
+                            match self.untracked_take_auth {
+                                UntrackedTakeAuth::Ignore => return,
+                                UntrackedTakeAuth::Unload => {
+                                    self.emit_msg(
+                                        Destination::Peer(source),
+                                        WorldNetMessage::ListenStopRequest { chunk },
+                                    );
+                                    return;
+                                }
+                                UntrackedTakeAuth::Take => {}
+                            }
+                        }
+                    }
+                    _ => return,
+                }
+                self.inbound_model.apply_chunk_delta(&delta);
+                self.is_storage_recent.remove(&delta.chunk_coord);
+                self.count_received_delta(delta.chunk_coord);
+            }
+            WorldNetMessage::ChunkPacket {
+                chunkpacket,
+                templated,
+            } => {
+                let templated = templated.into_iter().flat_map(TemplatedDeltas::deltas);
+                for (delta, priority) in chunkpacket.into_iter().chain(templated) {
+                    if !self.chunk_in_bounds(delta.chunk_coord) {
+                        warn!(
+                            "{source} sent a delta for out of bounds chunk {:?}",
+                            delta.chunk_coord
+                        );
+                        continue;
+                    }
+                    match self.chunk_state.get_mut(&delta.chunk_coord) {
+                        Some(ChunkState::Listening { priority: pri, .. }) => {
+                            *pri = priority;
+                        }
+                        Some(ChunkState::WantToGetAuth {
+                            authority,
+                            my_priority,
+                            ..
+                        }) => {
+                            if priority <= *my_priority {
+                                let cs = ChunkState::Listening {
+                                    authority: *authority,
+                                    priority,
+                                };
+                                self.chunk_state.insert(delta.chunk_coord, cs);
+                            }
+                        }
+                        _ => continue,
+                    }
+                    self.inbound_model.apply_chunk_delta(&delta);
+                    self.is_storage_recent.remove(&delta.chunk_coord);
+                    self.count_received_delta(delta.chunk_coord);
+                }
+            }
+            WorldNetMessage::ListenAuthorityRelinquished { chunk } => {
+                let priority = self.listen_priority.remove(&chunk);
+                let wanted = self.reacquire_relinquished
+                    && matches!(
+                        self.chunk_state.get(&chunk),
+                        Some(ChunkState::Listening { .. } | ChunkState::WantToGetAuth { .. })
+                    )
+                    && !should_kill(
+                        self.my_pos,
+                        self.cam_pos,
+                        chunk.0,
+                        chunk.1,
+                        self.player_sync_radius(),
+                    );
+                if wanted {
+                    debug!("Authority of {chunk:?} relinquished, requesting it");
+                    self.chunk_state.insert(
+                        chunk,
+                        ChunkState::RequestAuthority {
+                            priority: priority.unwrap_or(255),
+                            can_wait: true,
+                        },
+                    );
+                } else {
+                    self.chunk_state.insert(chunk, ChunkState::UnloadPending);
+                }
+            }
+            WorldNetMessage::GetAuthorityFrom {
+                chunk,
+                current_authority,
+            } => {
+                if self.chunk_state.get(&chunk) != Some(&ChunkState::UnloadPending) {
+                    debug!("Will request authority transfer");
+                    self.transfer_started.insert(chunk, self.current_update);
+                    self.chunk_state.insert(
+                        chunk,
+                        ChunkState::Transfer {
+                            authority: current_authority,
+                        },
+                    );
+                    self.emit_msg(
+                        Destination::Peer(current_authority),
+                        WorldNetMessage::RequestAuthorityTransfer { chunk },
+                    );
+                } else {
+                    self.emit_msg(
+                        Destination::Host,
+                        WorldNetMessage::RelinquishAuthority {
+                            chunk,
+                            chunk_data: None,
+                            world_num: self.world_num,
+                        },
+                    );
+                }
+            }
+            WorldNetMessage::RequestAuthorityTransfer { chunk } => {
+                debug!("Got a request for authority transfer");
+                let state = self.chunk_state.get(&chunk);
+                if let Some(ChunkState::Authority { listeners, .. }) = state {
+                    let chunk_data = self
+                        .outbound_model
+                        .get_chunk_data(chunk)
+                        .map(|chunk_data| self.compress_for(source, &chunk_data));
+                    self.emit_msg(
+                        Destination::Peer(source),
+                        WorldNetMessage::TransferOk {
+                            chunk,
+                            chunk_data,
+                            listeners: listeners.clone(),
+                        },
+                    );
+                    self.chunk_state.insert(chunk, ChunkState::UnloadPending);
+                    let chunk_data = self.outbound_model.get_chunk_data(chunk);
+                    self.send_storage(chunk, chunk_data);
+                } else {
+                    self.emit_msg(
+                        Destination::Peer(source),
+                        WorldNetMessage::TransferFailed { chunk },
+                    );
+                }
+            }
+            WorldNetMessage::TransferOk {
+                chunk,
+                chunk_data,
+                listeners,
+            } => {
+                debug!("Transfer ok");
+                self.transfer_backoff.remove(&chunk);
+                let chunk_data = chunk_data.and_then(|c| self.decompress(&c));
+                if chunk_data.is_some()
+                    && !self.apply_received_chunk_data(source, chunk, chunk_data.as_ref())
+                {
+                    self.inbound_model.forget_chunk(chunk);
+                    self.outbound_model.forget_chunk(chunk);
+                }
+                for listener in listeners.iter() {
+                    self.emit_msg(
+                        Destination::Peer(*listener),
+                        WorldNetMessage::NotifyNewAuthority { chunk },
+                    );
+                }
+                self.unacked_storage.remove(&chunk);
+                self.chunk_state.insert(
+                    chunk,
+                    ChunkState::Authority {
+                        listeners,
+                        priority: self.last_request_priority.remove(&chunk).unwrap_or(0),
+                        new_authority: None,
+                        stop_sending: false,
+                    },
+                );
+            }
+            WorldNetMessage::TransferFailed { chunk } => self.transfer_failed(chunk),
+            WorldNetMessage::DictionaryVersion { version } => {
+                if source == self.my_peer_id {
+                    return;
+                }
+                let changed = match version {
+                    Some(version) => {
+                        self.peer_dict_versions.insert(source, version) != Some(version)
+                    }
+                    None => self.peer_dict_versions.remove(&source).is_some(),
+                };
+                // Let them know which one we have in return.
+                if changed {
+                    self.announced_dict_version =
+                        self.compression_dict.as_ref().map(|dict| dict.version());
+                    self.emit_msg(
+                        Destination::Peer(source),
+                        WorldNetMessage::DictionaryVersion {
+                            version: self.announced_dict_version,
+                        },
+                    );
+                }
+            }
+            WorldNetMessage::ApplyDelta { delta } => {
+                if !self.is_authority(delta.chunk_coord) {
+                    debug!(
+                        "Got a delta for {:?}, but not an authority",
+                        delta.chunk_coord
+                    );
+                    return;
+                }
+                // Goes to listeners with the next update, as if it was a local change.
+                self.inbound_model.apply_chunk_delta(&delta);
+                self.outbound_model.apply_chunk_delta(&delta);
+            }
+            WorldNetMessage::RegisterReplicaOnly { replicate_only } => {
+                if !self.is_host {
+                    warn!("{} sent RegisterReplicaOnly to not-host.", source);
+                    return;
+                }
+                if replicate_only {
+                    info!("{source} registered as replicate-only");
+                    self.replica_only_peers.insert(source);
+                } else {
+                    self.replica_only_peers.remove(&source);
+                }
+            }
+            WorldNetMessage::HostAssertion { host, epoch } => {
+                if host == self.my_peer_id {
+                    return;
+                }
+                if self.is_host {
+                    // Higher epoch wins, lower id breaks ties.
+                    if (epoch, cmp::Reverse(host.0))
+                        > (self.host_epoch, cmp::Reverse(self.my_peer_id.0))
+                    {
+                        warn!("Split brain: {host} also thinks it's the host");
+                        self.host_epoch = epoch;
+                        self.demote_from_host(host);
+                    } else {
+                        warn!("Split brain: {host} also thinks it's the host, asserting ours");
+                        self.emit_msg(
+                            Destination::Broadcast,
+                            WorldNetMessage::HostAssertion {
+                                host: self.my_peer_id,
+                                epoch: self.host_epoch,
+                            },
+                        );
+                    }
+                } else if epoch >= self.host_epoch {
+                    self.host_epoch = epoch;
+                    self.expected_host = Some(host);
+                } else {
+                    warn!("{host} asserted being host with outdated epoch {epoch}");
+                }
+            }
+            WorldNetMessage::DeltaAck { chunk, seq } => self.handle_delta_ack(source, chunk, seq),
+            WorldNetMessage::StorageAck { chunk, world_num } => {
+                // Acks from before a world change are for storage that doesn't matter anymore.
+                if world_num == self.world_num {
+                    self.unacked_storage.remove(&chunk);
+                }
+            }
+            WorldNetMessage::ConfirmAuthority { chunk } => match self.chunk_state.get(&chunk) {
+                Some(ChunkState::Authority { listeners, .. }) => {
+                    let listeners: Vec<OmniPeerId> = listeners
+                        .iter()
+                        .copied()
+                        .filter(|listener| *listener != self.my_peer_id)
+                        .collect();
+                    for listener in listeners {
+                        self.emit_msg(
+                            Destination::Peer(listener),
+                            WorldNetMessage::NotifyNewAuthority { chunk },
+                        );
+                    }
+                }
+                None | Some(ChunkState::Listening { .. } | ChunkState::UnloadPending) => {
+                    warn!("Host thinks we're the authority of {chunk:?}, giving it up");
+                    self.emit_msg(
+                        Destination::Host,
+                        WorldNetMessage::RelinquishAuthority {
+                            chunk,
+                            chunk_data: None,
+                            world_num: self.world_num,
+                        },
+                    );
+                }
+                // Still being sorted out.
+                Some(_) => {}
+            },
+            WorldNetMessage::NotifyNewAuthority { chunk } => {
+                debug!("Notified of new authority");
+                let state = self.chunk_state.get_mut(&chunk);
+                if let Some(ChunkState::Listening { authority, .. }) = state {
+                    *authority = source;
+                    // New authority counts deltas it sends from scratch.
+                    self.deltas_received.insert(chunk, (0, 0));
+                } else {
+                    debug!("Got notified of new authority, but not a listener");
+                }
+            }
+        }
+    }
+
+    /// Drops chunks of peers that have been missing from `in_lobby` for a while,
+    /// see `drop_missing_peers`.
+    pub(crate) fn check_missing_peers(&mut self, in_lobby: &[OmniPeerId]) {
+        if !self.drop_missing_peers
+            || self
+                .last_missing_peer_check
+                .is_some_and(|last| self.current_update < last + MISSING_PEER_CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.last_missing_peer_check = Some(self.current_update);
+        let missing: FxHashSet<OmniPeerId> = self
+            .known_peers()
+            .into_iter()
+            .filter(|peer| *peer != self.my_peer_id && !in_lobby.contains(peer))
+            .collect();
+        self.missing_peers.retain(|peer, _| missing.contains(peer));
+        for peer in missing {
+            let since = *self
+                .missing_peers
+                .entry(peer)
+                .or_insert(self.current_update);
+            if self.current_update >= since + MISSING_PEER_GRACE {
+                warn!("Peer {peer} is no longer in lobby, dropping its chunks");
+                self.missing_peers.remove(&peer);
+                self.handle_peer_left(peer);
+            }
+        }
+    }
+
+    /// Peers we currently consider active, either as authorities or as listeners.
+    pub(crate) fn known_peers(&self) -> FxHashSet<OmniPeerId> {
+        let mut peers: FxHashSet<OmniPeerId> =
+            self.authority_map.values().map(|(peer, _)| *peer).collect();
+        for state in self.chunk_state.values() {
+            match state {
+                ChunkState::Listening { authority, .. } => {
+                    peers.insert(*authority);
+                }
+                ChunkState::Authority { listeners, .. } => {
+                    peers.extend(listeners.iter().copied());
+                }
+                _ => {}
+            }
+        }
+        peers
+    }
+
+    /// Should be called when player disconnects.
+    /// This frees up any authority that player had.
+    pub(crate) fn handle_peer_left(&mut self, source: OmniPeerId) {
+        let mut to_remove = Vec::new();
+        for (c, chunk) in self.chunk_state.iter_mut() {
+            match chunk {
+                ChunkState::RequestAuthority { .. } => {}
+                ChunkState::WaitingForAuthority => {}
+                ChunkState::Listening { authority, .. } => {
+                    if authority == &source {
+                        to_remove.push(*c)
+                    }
+                }
+                ChunkState::Authority {
+                    listeners,
+                    new_authority,
+                    ..
+                } => {
+                    if new_authority.map(|(p, _)| p == source).unwrap_or(false) {
+                        *new_authority = None;
+                    }
+                    listeners.retain(|p| p != &source);
+                }
+                ChunkState::UnloadPending => {}
+                ChunkState::Transfer { .. } => {}
+                ChunkState::WantToGetAuth { authority, .. } => {
+                    if authority == &source {
+                        to_remove.push(*c)
+                    }
+                }
+            }
+        }
+        for c in to_remove {
+            self.chunk_state.remove(&c);
+        }
+        self.peer_dict_versions.remove(&source);
+        self.replica_only_peers.remove(&source);
+        self.pending_listeners.retain(|_, listeners| {
+            listeners.remove(&source);
+            !listeners.is_empty()
+        });
+        if source != self.my_peer_id {
+            self.departed_peers.insert(source);
+        }
+        if !self.is_host {
+            return;
+        }
+        if self.peer_left_grace > 0 && source != self.my_peer_id {
+            // Peers that are gone for good keep getting reported, grace period shouldn't restart.
+            let until = self.current_update + self.peer_left_grace;
+            self.orphaning_peers.entry(source).or_insert(until);
+            return;
+        }
+        self.release_authority_of(source);
+    }
+
+    fn release_authority_of(&mut self, source: OmniPeerId) {
+        self.orphaning_peers.remove(&source);
+        let mut pending_messages = Vec::new();
+
+        for (&chunk, peer) in self.authority_map.iter() {
+            if peer.0 == source {
+                info!("Removing authority from disconnected peer: {chunk:?}");
+                pending_messages.push(WorldNetMessage::ListenAuthorityRelinquished { chunk });
+            }
+        }
+        self.authority_map.retain(|_, peer| peer.0 != source);
+
+        for message in pending_messages {
+            self.emit_msg(Destination::Broadcast, message)
+        }
+    }
+
+    /// Locks authority of the chunk once it changes hands too often, see `max_authority_changes`.
+    fn record_authority_change(&mut self, chunk: ChunkCoord) {
+        let Some(max) = self.max_authority_changes else {
+            return;
+        };
+        let changes = self.authority_changes.entry(chunk).or_default();
+        changes.push_back(self.current_update);
+        while changes
+            .front()
+            .is_some_and(|&at| self.current_update >= at + AUTHORITY_CHANGE_WINDOW)
+        {
+            changes.pop_front();
+        }
+        if changes.len() > max as usize {
+            warn!(
+                "Authority of {chunk:?} changed {} times recently, locking it",
+                changes.len()
+            );
+            self.authority_changes.remove(&chunk);
+            self.authority_locks
+                .insert(chunk, self.current_update + AUTHORITY_LOCK_COOLDOWN);
+        }
+    }
+
+    fn expire_pending_listeners(&mut self) {
+        let now = self.current_update;
+        self.pending_listeners.retain(|chunk, listeners| {
+            listeners.retain(|peer, &mut since| {
+                let waiting = now < since.saturating_add(PENDING_LISTENER_TIMEOUT);
+                if !waiting {
+                    debug!("Replica {peer} stopped waiting for {chunk:?}");
+                }
+                waiting
+            });
+            !listeners.is_empty()
+        });
+    }
+
+    fn expire_authority_locks(&mut self) {
+        let now = self.current_update;
+        self.authority_locks.retain(|chunk, until| {
+            let locked = now < *until;
+            if !locked {
+                debug!("Unlocking authority of {chunk:?}");
+            }
+            locked
+        });
+        self.authority_changes.retain(|_, changes| {
+            changes
+                .back()
+                .is_some_and(|&at| now < at + AUTHORITY_CHANGE_WINDOW)
+        });
+    }
+
+    /// Releases authority of departed peers that didn't come back in time.
+    fn expire_orphaned_authority(&mut self) {
+        let expired: Vec<OmniPeerId> = self
+            .orphaning_peers
+            .iter()
+            .filter(|(_, until)| self.current_update >= **until)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in expired {
+            info!("{peer} didn't come back in time");
+            self.release_authority_of(peer);
+        }
+    }
+
+    /// Starts saving chunk storage on another thread, without copying all of it at once.
+    /// Chunks are handed over to the writer a few at a time, each one as it is at that moment.
+    pub(crate) fn start_background_save(&mut self) {
+        if !self.is_host || self.background_save.is_some() {
+            return;
+        }
+        let Some(mut save) = self
+            .save_state
+            .start_streaming_save::<(ChunkCoord, SavedChunkData, u64)>()
+        else {
+            return;
+        };
+        let (sender, receiver) =
+            mpsc::sync_channel::<Option<(ChunkCoord, ChunkData, u64)>>(BACKGROUND_SAVE_QUEUE);
+        let stats = self.stats_sink();
+        let writer = thread::spawn(move || {
+            // Channel is closed without None if save got cancelled.
+            while let Ok(entry) = receiver.recv() {
+                let result = match entry {
+                    Some((chunk, data, modified_at)) => {
+                        save.write(&(chunk, saved_chunk_data(data, stats.as_deref()), modified_at))
+                    }
+                    None => {
+                        return save.finish().unwrap_or_else(|err| {
+                            warn!("Could not finish background save: {err}")
+                        });
+                    }
+                };
+                if let Err(err) = result {
+                    warn!("Could not write background save: {err}");
+                    return;
+                }
+            }
+        });
+        self.background_save = Some(BackgroundSave {
+            pending: self.chunk_storage.keys().copied().collect(),
+            sender: Some(sender),
+            writer,
+        });
+    }
+
+    /// Hands over pending chunks to background save writer, until its queue is full.
+    fn advance_background_save(&mut self) {
+        let Some(save) = &mut self.background_save else {
+            return;
+        };
+        let Some(sender) = &save.sender else {
+            if save.writer.is_finished() {
+                self.background_save = None;
+            }
+            return;
+        };
+        while let Some(&chunk) = save.pending.last() {
+            // Chunk could've been taken by some authority since the save started.
+            let Some(data) = self.chunk_storage.get(&chunk) else {
+                save.pending.pop();
+                continue;
+            };
+            let modified_at = self
+                .chunk_modified
+                .get(&chunk)
+                .copied()
+                .unwrap_or(UNKNOWN_MODIFICATION_TIME);
+            match sender.try_send(Some((chunk, data.into_owned(), modified_at))) {
+                Ok(()) => {
+                    save.pending.pop();
+                }
+                Err(TrySendError::Full(_)) => return,
+                Err(TrySendError::Disconnected(_)) => {
+                    warn!("Background save writer stopped");
+                    self.background_save = None;
+                    return;
+                }
+            }
+        }
+        if sender.try_send(None).is_ok() {
+            save.sender = None;
+        }
+    }
+
+    fn save_chunk_storage(&mut self) {
+        if let Some(mut save) = self.background_save.take() {
+            // Whole storage is saved anyway, so there is no need to finish it.
+            save.sender = None;
+            save.writer.join().ok();
+        }
+        let Some(mut save) = self.save_state.start_streaming_save() else {
+            return;
+        };
+        let stats = self.stats_sink();
+        for (chunk, data) in self.chunk_storage.iter() {
+            let modified_at = self.chunk_modified_at(*chunk).unwrap_or_default();
+            let data = saved_chunk_data(data.into_owned(), stats.as_deref());
+            if let Err(err) = save.write(&(*chunk, data, modified_at)) {
+                warn!("Could not save chunk data: {err}");
+                return;
+            }
+        }
+        if let Err(err) = save.finish() {
+            warn!("Could not save chunk data: {err}");
+        }
+    }
+
+    /// Whether we have any use for the chunk: it has some state other than being unloaded,
+    /// was updated locally at some point or got prefetched.
+    fn is_chunk_tracked(&self, chunk: ChunkCoord) -> bool {
+        self.chunk_state
+            .get(&chunk)
+            .is_some_and(|state| !matches!(state, ChunkState::UnloadPending))
+            || self.chunk_last_update.contains_key(&chunk)
+            || self.prefetched_chunks.contains_key(&chunk)
+    }
+
+    /// Counts a rejected RelinquishAuthority. These happen during races every now and then,
+    /// but a lot of them means someone is trying to free chunks it doesn't own.
+    fn reject_relinquish(&mut self, source: OmniPeerId) {
+        let rejected = self.rejected_relinquishes.entry(source).or_default();
+        *rejected += 1;
+        if *rejected == self.relinquish_rejection_limit {
+            warn!(
+                "{source} keeps relinquishing authority it doesn't have, {rejected} times so far"
+            );
+        }
+    }
+
+    /// Rough size of the bigger world collections, doesn't account for hash map overhead.
+    pub(crate) fn memory_estimate(&self) -> WorldMemoryStats {
+        let listeners: usize = self
+            .chunk_state
+            .values()
+            .map(|state| match state {
+                ChunkState::Authority { listeners, .. } => listeners.len(),
+                _ => 0,
+            })
+            .sum();
+        WorldMemoryStats {
+            chunk_storage: self.chunk_storage.memory_estimate(),
+            inbound_model: self.inbound_model.memory_estimate(),
+            outbound_model: self.outbound_model.memory_estimate(),
+            authority_map: self.authority_map.len() * size_of::<(ChunkCoord, (OmniPeerId, u8))>(),
+            chunk_state: self.chunk_state.len() * size_of::<(ChunkCoord, ChunkState)>()
+                + listeners * size_of::<OmniPeerId>(),
+        }
+    }
+
+    /// Peers that had at least `relinquish_rejection_limit` relinquishes rejected.
+    pub(crate) fn suspicious_peers(&self) -> Vec<(OmniPeerId, u32)> {
+        self.rejected_relinquishes
+            .iter()
+            .filter(|(_, rejected)| **rejected >= self.relinquish_rejection_limit)
+            .map(|(peer, rejected)| (*peer, *rejected))
+            .collect()
+    }
+
+    /// Read-only audit of authority bookkeeping, for debugging.
+    /// Only our own chunk states are known, so the host can't check other peers' claims,
+    /// and non-hosts don't have an authority map to check theirs against.
+    pub(crate) fn validate_consistency(&self) -> Vec<ConsistencyIssue> {
+        let mut issues = Vec::new();
+        for (&chunk, state) in &self.chunk_state {
+            let ChunkState::Authority { listeners, .. } = state else {
+                continue;
+            };
+            if self.is_host {
+                let listed = self.authority_map.get(&chunk).map(|(peer, _)| *peer);
+                if listed != Some(self.my_peer_id) {
+                    issues.push(ConsistencyIssue::UnlistedAuthority { chunk, listed });
+                }
+            }
+            for &listener in listeners {
+                if self.departed_peers.contains(&listener) {
+                    issues.push(ConsistencyIssue::DepartedListener { chunk, listener });
+                }
+            }
+        }
+        for (&chunk, &(authority, _)) in &self.authority_map {
+            let unclaimed = if authority == self.my_peer_id {
+                !matches!(
+                    self.chunk_state.get(&chunk),
+                    Some(ChunkState::Authority { .. })
+                )
+            } else {
+                self.departed_peers.contains(&authority)
+                    && !self.orphaning_peers.contains_key(&authority)
+            };
+            if unclaimed {
+                issues.push(ConsistencyIssue::UnclaimedAuthority { chunk, authority });
+            }
+        }
+        issues
+    }
+
+    /// Applies chunk data received from `source` to both models. Returns false if there was no
+    /// data, or if it was corrupt, in which case models are left as they were.
+    fn apply_received_chunk_data(
+        &mut self,
+        source: OmniPeerId,
+        chunk: ChunkCoord,
+        chunk_data: Option<&ChunkData>,
+    ) -> bool {
+        let Some(chunk_data) = chunk_data else {
+            return false;
+        };
+        if let Err(err) = self.inbound_model.apply_chunk_data(chunk, chunk_data) {
+            warn!(
+                "Corrupt chunk data for {chunk:?} from {source}, covers {} pixels",
+                err.covered
+            );
+            return false;
+        }
+        let _ = self.outbound_model.apply_chunk_data(chunk, chunk_data);
+        true
+    }
+
+    /// Transfer didn't happen, so authority is requested from host normally after a delay.
+    fn transfer_failed(&mut self, chunk: ChunkCoord) {
+        let (attempts, next_try) = self.transfer_backoff.entry(chunk).or_default();
+        *attempts += 1;
+        if *attempts > self.max_transfer_retries {
+            debug!("Transfer of {chunk:?} failed {attempts} times, will only listen");
+            *next_try = self.current_update;
+        } else {
+            let delay = 1u64
+                .checked_shl(*attempts - 1)
+                .and_then(|factor| self.transfer_retry_delay.checked_mul(factor))
+                .unwrap_or(MAX_TRANSFER_RETRY_DELAY)
+                .min(MAX_TRANSFER_RETRY_DELAY);
+            debug!("Transfer failed, requesting authority normally in {delay} updates");
+            *next_try = self.current_update.saturating_add(delay);
+        }
+        let priority = self
+            .last_request_priority
+            .get(&chunk)
+            .copied()
+            .unwrap_or(255);
+        self.chunk_state.insert(
+            chunk,
+            ChunkState::RequestAuthority {
+                priority,
+                can_wait: true,
+            },
+        );
+        self.emit_msg(
+            Destination::Host,
+            WorldNetMessage::RelinquishAuthority {
+                chunk,
+                chunk_data: None,
+                world_num: self.world_num,
+            },
+        );
+    }
+
+    /// Forcibly removes a peer from all chunk state, even if it's still connected.
+    /// Host only. Safe to call several times for the same peer.
+    pub(crate) fn evict_peer(&mut self, peer: OmniPeerId) {
+        if !self.is_host {
+            warn!("Only host can evict peers");
+            return;
+        }
+        info!("Evicting peer {peer}");
+        self.chunk_state.retain(
+            |_, state| !matches!(state, ChunkState::Transfer { authority } if *authority == peer),
+        );
+        self.handle_peer_left(peer);
+        self.release_authority_of(peer);
+        let authority_map = &self.authority_map;
+        self.speculative_chunks
+            .retain(|chunk, _| authority_map.contains_key(chunk));
+    }
+
+    /// Chunks we're authority of are cut in models as well when `nice_terraforming` is set,
+    /// so that Noita and listeners get the change on the next update.
+    pub(crate) fn cut_through_world(&mut self, x: i32, y_min: i32, y_max: i32, radius: i32) {
+        let (y_min, y_max) = match self.max_cut_depth {
+            Some(max) if y_max as i64 - y_min as i64 > max as i64 => {
+                let clamped = match self.cut_clamp {
+                    CutClamp::FromTop => (y_min, y_min.saturating_add(max)),
+                    CutClamp::Centered => {
+                        let middle = ((y_min as i64 + y_max as i64) / 2) as i32;
+                        (middle - max / 2, middle - max / 2 + max)
+                    }
+                };
+                warn!(
+                    "Cut at {x} from {y_min} to {y_max} is deeper than {max}, clamping it to {} {}",
+                    clamped.0, clamped.1
+                );
+                clamped
+            }
+            _ => (y_min, y_max),
+        };
+        let max_wiggle = 5;
+        let interval = 300.0;
+
+        let min_cx = (x - radius - max_wiggle).div_euclid(CHUNK_SIZE as i32);
+        let max_cx = (x + radius + max_wiggle).div_euclid(CHUNK_SIZE as i32);
+        let max_cy = y_max.div_euclid(CHUNK_SIZE as i32);
+        let min_cy = y_min.div_euclid(CHUNK_SIZE as i32);
+        let Some(((min_cx, max_cx), (min_cy, max_cy))) =
+            self.clamp_chunk_range((min_cx, max_cx), (min_cy, max_cy))
+        else {
+            return;
+        };
+        let start = x - radius;
+        let end = x + radius;
+
+        let air_pixel = RawPixel {
+            flags: PixelFlags::Normal,
+            material: 0,
+        };
+        let in_range = |coord: &ChunkCoord| {
+            min_cx <= coord.0
+                && max_cx >= coord.0
+                && coord.1 <= max_cy
+                && coord.1 >= min_cy
+                && !self.protected_chunks.contains(coord)
+        };
+        let cut = |chunk_coord: ChunkCoord, chunk_encoded: &ChunkData| {
+            let chunk_start_x = chunk_coord.0 * CHUNK_SIZE as i32;
+            let chunk_end_x = chunk_start_x + CHUNK_SIZE as i32;
+            let chunk_start_y = chunk_coord.1 * CHUNK_SIZE as i32;
+            let mut chunk = Chunk::default();
+            chunk_encoded.apply_to_chunk(&mut chunk);
+            for in_chunk_y in 0..(CHUNK_SIZE as i32) {
+                let global_y = in_chunk_y + chunk_start_y;
+                let wiggle = -(global_y as f32 / interval * TAU).cos() * max_wiggle as f32;
+                let wiggle = wiggle.round() as i32;
+                let in_chunk_x_range = ((start + wiggle).clamp(chunk_start_x, chunk_end_x)
+                    - chunk_start_x)
+                    ..((end + wiggle).clamp(chunk_start_x, chunk_end_x) - chunk_start_x);
+                for in_chunk_x in in_chunk_x_range {
+                    chunk.set_pixel(
+                        (in_chunk_y as usize) * CHUNK_SIZE + (in_chunk_x as usize),
+                        air_pixel,
+                    );
+                }
+            }
+            chunk.to_chunk_data()
+        };
+        let chunk_storage: Vec<(ChunkCoord, ChunkData)> = self
+            .chunk_storage
+            .to_map()
+            .into_par_iter()
+            .filter(|(coord, _)| in_range(coord))
+            .map(|(chunk_coord, chunk_encoded)| (chunk_coord, cut(chunk_coord, &chunk_encoded)))
+            .collect();
+        let owned: Vec<(ChunkCoord, ChunkData)> = if self.nice_terraforming {
+            self.chunk_state
+                .iter()
+                .filter(|(coord, state)| {
+                    matches!(state, ChunkState::Authority { .. }) && in_range(coord)
+                })
+                .filter_map(|(&coord, _)| {
+                    let data = self.outbound_model.get_chunk_data(coord)?;
+                    Some((coord, cut(coord, &data)))
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        for entry in chunk_storage.into_iter() {
+            self.chunk_storage.insert(entry.0, entry.1);
+            self.mark_modified(entry.0);
+        }
+        for (chunk, data) in owned {
+            // Cut data is always whole, so this can't fail.
+            let _ = self.outbound_model.apply_chunk_data(chunk, &data);
+            let _ = self.inbound_model.apply_chunk_data(chunk, &data);
+        }
+    }
+    /// Pixels with durability above `power` are left as they are.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn cut_through_world_line(
+        &mut self,
+        x: i32,
+        y: i32,
+        lx: i32,
+        ly: i32,
+        r: i32,
+        chance: u8,
+        seed: u64,
+        power: Option<u32>,
+    ) {
+        let cut = self.line_cut(x, y, lx, ly, r, chance, seed, power);
+        self.store_cut(cut);
+    }
+
+    /// Pixels with durability above `power` are left as they are, both when clearing and filling.
+    /// With `mat`, only `density` percent of the pixels get it and the rest become air, picked
+    /// by hashing pixel coordinates with `seed`, so every peer sprinkles the same pixels.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn cut_through_world_circle(
+        &mut self,
+        x: i32,
+        y: i32,
+        r: i32,
+        mat: Option<u16>,
+        density: u8,
+        chance: u8,
+        seed: u64,
+        power: Option<u32>,
+    ) {
+        let cut = self.circle_cut(x, y, r, mat, density, chance, seed, power);
+        self.store_cut(cut);
+    }
+
+    /// Chunks a cut would change, without changing anything.
+    /// Only affected chunks are copied, so it's fine to call it every frame to show a preview.
+    /// Returns None for operations that can't be previewed, like explosions.
+    pub(crate) fn preview_cut(&self, op: &TerraformOp) -> Option<FxHashMap<ChunkCoord, ChunkData>> {
+        let cut = match *op {
+            TerraformOp::Circle {
+                x,
+                y,
+                r,
+                mat,
+                density,
+                chance,
+                seed,
+                power,
+            } => self.circle_cut(x, y, r, mat, density, chance, seed, power),
+            TerraformOp::Line {
+                x,
+                y,
+                lx,
+                ly,
+                r,
+                chance,
+                seed,
+                power,
+            } => self.line_cut(x, y, lx, ly, r, chance, seed, power),
+            TerraformOp::Rect { .. } | TerraformOp::Explosion(_) => return None,
+        };
+        Some(
+            cut.into_iter()
+                .map(|(chunk, data, _)| (chunk, data))
+                .collect(),
+        )
+    }
+
+    /// Explosion stats of a material as rays see them, None for materials we know nothing about,
+    /// which rays pass through for free.
+    pub(crate) fn effective_durability(
+        &self,
+        material: u16,
+        damage_type: u32,
+        mult: f32,
+    ) -> Option<EffectiveDurability> {
+        let &(durability, hardness, _, _, vulnerable) = self.materials.get(&material)?;
+        Some(EffectiveDurability {
+            durability,
+            ray_cost: (hardness as f64 * mult as f64) as u64,
+            vulnerable: vulnerable & damage_type != 0,
+        })
+    }
+
+    /// Whether a pixel can't be cut by a tool of this power, like explosions can't go through materials
+    /// with durability above theirs. Everything can be cut without power.
+    fn too_hard(&self, pixel: RawPixel, power: Option<u32>) -> bool {
+        power.is_some_and(|power| {
+            pixel.flags != PixelFlags::Unknown
+                && self
+                    .materials
+                    .get(&pixel.material)
+                    .is_some_and(|(durability, _, _, _, _)| *durability > power)
+        })
+    }
+
+    fn store_cut(&mut self, cut: Vec<(ChunkCoord, ChunkData, CutBase)>) {
+        for (chunk, data, base) in cut {
+            if base == CutBase::Synthetic {
+                self.synthetic_chunks.insert(chunk, data);
+                continue;
+            }
+            self.chunk_storage.insert(chunk, data);
+            self.mark_modified(chunk);
+            if base == CutBase::Model {
+                self.is_storage_recent.insert(chunk);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn line_cut(
+        &self,
+        x: i32,
+        y: i32,
+        lx: i32,
+        ly: i32,
+        r: i32,
+        chance: u8,
+        seed: u64,
+        power: Option<u32>,
+    ) -> Vec<(ChunkCoord, ChunkData, CutBase)> {
+        if chance == 0 {
+            return Vec::new();
+        }
+        let (min_cx, max_cx) = if x < lx {
+            (
+                (x - r).div_euclid(CHUNK_SIZE as i32),
+                (lx + r).div_euclid(CHUNK_SIZE as i32),
+            )
+        } else {
+            (
+                (lx - r).div_euclid(CHUNK_SIZE as i32),
+                (x + r).div_euclid(CHUNK_SIZE as i32),
+            )
+        };
+        let (min_cy, max_cy) = if y < ly {
+            (
+                (y - r).div_euclid(CHUNK_SIZE as i32),
+                (ly + r).div_euclid(CHUNK_SIZE as i32),
+            )
+        } else {
+            (
+                (ly - r).div_euclid(CHUNK_SIZE as i32),
+                (y + r).div_euclid(CHUNK_SIZE as i32),
+            )
+        };
+
+        let Some(((min_cx, max_cx), (min_cy, max_cy))) =
+            self.clamp_chunk_range((min_cx, max_cx), (min_cy, max_cy))
+        else {
+            return Vec::new();
+        };
+
+        let dmx = lx - x;
+        let dmy = ly - y;
+        if dmx == 0 && dmy == 0 {
+            return self.circle_cut(x, y, r, None, 100, chance, seed, power);
+        }
+        if dmx.abs() > 8 * 512 || dmy.abs() > 8 * 512 {
+            warn!("unusually large cut, {} {} {} {} {}", x, y, lx, ly, r)
+        }
+        let dm2 = ((dmx.unsigned_abs() as u64 * dmx.unsigned_abs() as u64
+            + dmy.unsigned_abs() as u64 * dmy.unsigned_abs() as u64) as f64)
+            .recip();
+        let air_pixel = RawPixel {
+            flags: PixelFlags::Normal,
+            material: 0,
+        };
+        let close_check = max_cx == min_cx || max_cy == min_cy;
+        let iter_check = [
+            (x + r, y),
+            (x - r, y),
+            (x, y + r),
+            (x, y - r),
+            (lx + r, ly),
+            (lx - r, ly),
+            (lx, ly + r),
+            (lx, ly - r),
+        ]
+        .into_iter();
+        let r = r as u64 * r as u64;
+        (min_cx..=max_cx)
+            .into_par_iter()
+            .flat_map(|chunk_x| {
+                (min_cy..=max_cy)
+                    .into_par_iter()
+                    .map(move |chunk_y| (chunk_x, chunk_y))
+            })
+            .filter(|&(chunk_x, chunk_y)| {
+                !self
+                    .protected_chunks
+                    .contains(&ChunkCoord(chunk_x, chunk_y))
+            })
+            .filter(|&(chunk_x, chunk_y)| {
+                let chunk_start_x = chunk_x * CHUNK_SIZE as i32;
+                let chunk_start_y = chunk_y * CHUNK_SIZE as i32;
+                close_check
+                    || [
+                        (chunk_start_x, chunk_start_y),
+                        (
+                            chunk_start_x + CHUNK_SIZE as i32 - 1,
+                            chunk_start_y + CHUNK_SIZE as i32 - 1,
+                        ),
+                        (chunk_start_x + CHUNK_SIZE as i32 - 1, chunk_start_y),
+                        (chunk_start_x, chunk_start_y + CHUNK_SIZE as i32 - 1),
+                    ]
+                    .iter()
+                    .any(|(cx, cy)| {
+                        let dcx = cx - x;
+                        let dcy = cy - y;
+                        let m = ((dcx.unsigned_abs() as u64 * dmx.unsigned_abs() as u64
+                            + dcy.unsigned_abs() as u64 * dmy.unsigned_abs() as u64)
+                            as f64
+                            * dm2)
+                            .clamp(0.0, 1.0);
+                        let dx = dcx.abs_diff((m * dmx as f64) as i32) as u64;
+                        let dy = dcy.abs_diff((m * dmy as f64) as i32) as u64;
+                        dx * dx + dy * dy <= r
+                    })
+                    || {
+                        let (end_x, end_y) = (
+                            chunk_start_x + CHUNK_SIZE as i32 - 1,
+                            chunk_start_y + CHUNK_SIZE as i32 - 1,
+                        );
+                        iter_check.clone().any(|(x, y)| {
+                            end_x >= x && x >= chunk_start_x && end_y >= y && y >= chunk_start_y
+                        })
+                    }
+            })
+            .filter_map(|(chunk_x, chunk_y)| {
+                let chunk_start_x = chunk_x * CHUNK_SIZE as i32;
+                let chunk_start_y = chunk_y * CHUNK_SIZE as i32;
+                let mut chunk = Chunk::default();
+                let coord = ChunkCoord(chunk_x, chunk_y);
+                let mut base = CutBase::Storage;
+                let mut no_info = false;
+                if self.is_storage_recent.contains(&coord) {
+                    if let Some(chunk_encoded) = self.chunk_storage.get(&coord) {
+                        chunk_encoded.apply_to_chunk(&mut chunk)
+                    }
+                } else if let Some(chunk_encoded) = self
+                    .outbound_model
+                    .get_chunk_data(coord)
+                    .or(self.inbound_model.get_chunk_data(coord))
+                {
+                    base = CutBase::Model;
+                    chunk_encoded.apply_to_chunk(&mut chunk);
+                } else if let Some(chunk_encoded) = self.chunk_storage.get(&coord) {
+                    chunk_encoded.apply_to_chunk(&mut chunk)
+                } else if let Some(default) = self
+                    .default_chunk
+                    .as_ref()
+                    .map(|default| self.synthetic_chunks.get(&coord).unwrap_or(default))
+                {
+                    base = CutBase::Synthetic;
+                    default.apply_to_chunk(&mut chunk)
+                } else if !self.nice_terraforming {
+                    return None;
+                } else {
+                    no_info = true;
+                }
+                let mut changed = false;
+                for icx in 0..CHUNK_SIZE as i32 {
+                    let cx = chunk_start_x + icx;
+                    let dcx = cx - x;
+                    let dx2 = dcx.unsigned_abs() as u64 * dmx.unsigned_abs() as u64;
+                    for icy in 0..CHUNK_SIZE as i32 {
+                        let cy = chunk_start_y + icy;
+                        let dcy = cy - y;
+                        let m = ((dx2 + dcy.unsigned_abs() as u64 * dmy.unsigned_abs() as u64)
+                            as f64
+                            * dm2)
+                            .clamp(0.0, 1.0);
+                        let dx = dcx.abs_diff((m * dmx as f64) as i32) as u64;
+                        let dy = dcy.abs_diff((m * dmy as f64) as i32) as u64;
+                        if dx * dx + dy * dy <= r {
+                            let px = icy as usize * CHUNK_SIZE + icx as usize;
+                            if (no_info
+                                || chunk.pixel(px).flags == PixelFlags::Unknown
+                                || self
+                                    .materials
+                                    .get(&chunk.pixel(px).material)
+                                    .map(|(_, _, cell, _, _)| cell.can_remove(true, false))
+                                    .unwrap_or(true))
+                                && !self.too_hard(chunk.pixel(px), power)
+                                && seeded_chance(seed, cx, cy, chance)
+                            {
+                                changed = true;
+                                chunk.set_pixel(px, air_pixel);
+                            }
+                        }
+                    }
+                }
+                if changed {
+                    Some((coord, chunk.to_chunk_data(), base))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn circle_cut(
+        &self,
+        x: i32,
+        y: i32,
+        r: i32,
+        mat: Option<u16>,
+        density: u8,
+        chance: u8,
+        seed: u64,
+        power: Option<u32>,
+    ) -> Vec<(ChunkCoord, ChunkData, CutBase)> {
+        if chance == 0 {
+            return Vec::new();
+        }
+        let (min_cx, max_cx) = (
+            (x - r).div_euclid(CHUNK_SIZE as i32),
+            (x + r).div_euclid(CHUNK_SIZE as i32),
+        );
+        let (min_cy, max_cy) = (
+            (y - r).div_euclid(CHUNK_SIZE as i32),
+            (y + r).div_euclid(CHUNK_SIZE as i32),
+        );
+        let Some(((min_cx, max_cx), (min_cy, max_cy))) =
+            self.clamp_chunk_range((min_cx, max_cx), (min_cy, max_cy))
+        else {
+            return Vec::new();
+        };
+        let air_pixel = RawPixel {
+            flags: PixelFlags::Normal,
+            material: mat.unwrap_or(0),
+        };
+        let carved_pixel = RawPixel {
+            flags: PixelFlags::Normal,
+            material: 0,
+        };
+        // Rolled separately from `chance`, which decides whether a pixel is touched at all.
+        let density_seed = seeded_hash(seed, DENSITY_SALT);
+        let (chunkx, chunky) = (
+            x.div_euclid(CHUNK_SIZE as i32),
+            y.div_euclid(CHUNK_SIZE as i32),
+        );
+        let do_continue = mat.unwrap_or(0) != 0;
+        let rs = r as u64 * r as u64;
+        (min_cx..=max_cx)
+            .into_par_iter()
+            .flat_map(|chunk_x| {
+                (min_cy..=max_cy)
+                    .into_par_iter()
+                    .map(move |chunk_y| (chunk_x, chunk_y))
+            })
+            .filter(|&(chunk_x, chunk_y)| {
+                !self
+                    .protected_chunks
+                    .contains(&ChunkCoord(chunk_x, chunk_y))
+                    && (r <= CHUNK_SIZE as i32
+                        || min_dist(x, y, chunkx, chunky, chunk_x, chunk_y) <= rs)
+            })
+            .filter_map(|(chunk_x, chunk_y)| {
+                let coord = ChunkCoord(chunk_x, chunk_y);
+                let chunk_start_x = chunk_x * CHUNK_SIZE as i32;
+                let chunk_start_y = chunk_y * CHUNK_SIZE as i32;
+                let mut chunk = Chunk::default();
+                let mut base = CutBase::Storage;
+                let mut no_info = false;
+                if self.is_storage_recent.contains(&coord) {
+                    if let Some(chunk_encoded) = self.chunk_storage.get(&coord) {
+                        chunk_encoded.apply_to_chunk(&mut chunk)
+                    }
+                } else if let Some(chunk_encoded) = self
+                    .outbound_model
+                    .get_chunk_data(coord)
+                    .or(self.inbound_model.get_chunk_data(coord))
+                {
+                    base = CutBase::Model;
+                    chunk_encoded.apply_to_chunk(&mut chunk);
+                } else if let Some(chunk_encoded) = self.chunk_storage.get(&coord) {
+                    chunk_encoded.apply_to_chunk(&mut chunk)
+                } else if let Some(default) = self
+                    .default_chunk
+                    .as_ref()
+                    .map(|default| self.synthetic_chunks.get(&coord).unwrap_or(default))
+                {
+                    base = CutBase::Synthetic;
+                    default.apply_to_chunk(&mut chunk)
+                } else if do_continue || !self.nice_terraforming {
+                    return None;
+                } else {
+                    no_info = true;
+                }
+                let mut changed = false;
+                for icx in 0..CHUNK_SIZE as i32 {
+                    let cx = chunk_start_x + icx;
+                    let dx = cx.abs_diff(x) as u64;
+                    let dd = dx * dx;
+                    for icy in 0..CHUNK_SIZE as i32 {
+                        let cy = chunk_start_y + icy;
+                        let dy = cy.abs_diff(y) as u64;
+                        if dd + dy * dy <= rs {
+                            let px = icy as usize * CHUNK_SIZE + icx as usize;
+                            if (no_info
+                                || chunk.pixel(px).flags == PixelFlags::Unknown
+                                || self
+                                    .materials
+                                    .get(&chunk.pixel(px).material)
+                                    .map(|(_, _, cell, _, _)| cell.can_remove(true, false))
+                                    .unwrap_or(true))
+                                && !self.too_hard(chunk.pixel(px), power)
+                                && seeded_chance(seed, cx, cy, chance)
+                            {
+                                changed = true;
+                                if seeded_chance(density_seed, cx, cy, density) {
+                                    chunk.set_pixel(px, air_pixel);
+                                } else {
+                                    chunk.set_pixel(px, carved_pixel);
+                                }
+                            }
+                        }
+                    }
+                }
+                if changed {
+                    Some((coord, chunk.to_chunk_data(), base))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::type_complexity)]
+    fn do_ray(
+        &self,
+        mut x: i32,
+        mut y: i32,
+        end_x: i32,
+        end_y: i32,
+        mut ray: u64,
+        d: u32,
+        damage_type: u32,
+        mult: f32,
+    ) -> (Option<(i32, i32)>, u64, Option<ChunkCoord>) {
+        //Bresenham's line algorithm
+        let dx = (end_x - x).abs();
+        let dy = (end_y - y).abs();
+        if (dx == 0 && dy == 0) || ray == 0 {
+            return (None, 0, None);
+        }
+        let sx = if x < end_x { 1 } else { -1 };
+        let sy = if y < end_y { 1 } else { -1 };
+        let mut err = if dx > dy { dx } else { -dy } / 2;
+        let mut e2;
+        let mut working_chunk = Chunk::default();
+        let mut last_co = ChunkCoord(
+            x.div_euclid(CHUNK_SIZE as i32),
+            y.div_euclid(CHUNK_SIZE as i32),
+        );
+        if self.is_storage_recent.contains(&last_co) {
+            if let Some(c) = self.chunk_storage.get(&last_co) {
+                c.apply_to_chunk(&mut working_chunk);
+            } else {
+                return (None, 0, None);
+            }
+        } else if let Some(c) = self
+            .outbound_model
+            .get_chunk_data(last_co)
+            .or(self.inbound_model.get_chunk_data(last_co))
+        {
+            c.apply_to_chunk(&mut working_chunk);
+        } else if let Some(c) = self.chunk_storage.get(&last_co) {
+            c.apply_to_chunk(&mut working_chunk);
+        } else {
+            return (None, ray, None);
+        };
+        let mut last_coord = None;
+        let mut ret = 0;
+        while x != end_x || y != end_y {
+            if ret == 0 {
+                let co = ChunkCoord(
+                    x.div_euclid(CHUNK_SIZE as i32),
+                    y.div_euclid(CHUNK_SIZE as i32),
+                );
+                if co != last_co {
+                    if self.is_storage_recent.contains(&co) {
+                        if let Some(c) = self.chunk_storage.get(&co) {
+                            c.apply_to_chunk(&mut working_chunk);
+                        } else {
+                            return (None, 0, None);
+                        }
+                    } else if let Some(c) = self
+                        .outbound_model
+                        .get_chunk_data(co)
+                        .or(self.inbound_model.get_chunk_data(co))
+                    {
+                        c.apply_to_chunk(&mut working_chunk)
+                    } else if let Some(c) = self.chunk_storage.get(&co) {
+                        c.apply_to_chunk(&mut working_chunk)
+                    } else {
+                        ret = 17;
+                        continue;
+                    };
+                    last_co = co;
+                }
+                let icx = x.rem_euclid(CHUNK_SIZE as i32);
+                let icy = y.rem_euclid(CHUNK_SIZE as i32);
+                let px = icy as usize * CHUNK_SIZE + icx as usize;
+                let pixel = working_chunk.pixel(px);
+                if let Some(stats) = self.effective_durability(pixel.material, damage_type, mult) {
+                    if stats.stops(d, ray) {
+                        return (last_coord, 0, None);
+                    }
+                    ray = ray.saturating_sub(stats.ray_cost);
+                }
+                last_coord = Some((x, y));
+            } else if ret != 1 {
+                ret -= 1;
+            } else {
+                return (Some((x, y)), ray, Some(last_co));
+            }
+
+            e2 = err;
+            if e2 > -dx {
+                err -= dy;
+                x += sx;
+            }
+            if e2 < dy {
+                err += dx;
+                y += sy;
+            }
+        }
+        (Some((x, y)), 0, None)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn interior_iter(&self, ex: ExplosionData, fan: &[(i32, i32, f32)]) -> (Vec<ExRet>, Vec<u64>) {
+        let ExplosionData {
+            x,
+            y,
+            r,
+            d,
+            ray,
+            hole,
+            liquid,
+            mat,
+            prob,
+            seed,
+            jitter: _,
+            damage_type,
+        } = ex;
+        let rays = fan.len() as u64;
+        let cast = |&(dx, dy, mult): &(i32, i32, f32)| {
+            let (u, v, c) = self.do_ray(x, y, x + dx, y + dy, ray, d, damage_type, mult);
+            (
+                if let Some((ex, ey)) = u {
+                    let dx = ex.abs_diff(x) as u64;
+                    let dy = ey.abs_diff(y) as u64;
+                    if dx != 0 || dy != 0 {
+                        dx * dx + dy * dy
+                    } else {
+                        0
+                    }
+                } else {
+                    0
+                },
+                v,
+                c,
+            )
+        };
+        // Both collect in fan order, so results don't depend on which one is used.
+        let results: Vec<(u64, u64, Option<ChunkCoord>)> = if r <= self.serial_explosion_radius {
+            fan.iter().map(cast).collect()
+        } else {
+            fan.par_iter().map(cast).collect()
+        };
+        let lst = results.iter().map(|(_, b, _)| *b).collect();
+        (
+            self.cut_through_world_explosion_list(
+                x,
+                y,
+                d,
+                damage_type,
+                rays,
+                results,
+                hole,
+                liquid,
+                mat,
+                prob,
+                seed,
+                r,
+            ),
+            lst,
+        )
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn cut_through_world_explosion(&mut self, exp: Vec<ExplosionData>) {
+        for ex in &exp {
+            self.log_terraform(&TerraformOp::Explosion(*ex));
+        }
+        self.warm_explosion_range(&exp);
+        let resres: Vec<((Vec<ExRet>, Vec<u64>), ExplosionData)> = exp
+            .into_par_iter()
+            .map(|ex| {
+                let fan = ray_fan(ex.r, self.max_explosion_rays, ex.seed, ex.jitter);
+                (self.interior_iter(ex, &fan), ex)
+            })
+            .collect();
+        self.apply_explosion_results(resres);
+    }
+
+    /// Decompresses cold stored chunks within reach of explosions once, instead of in every ray
+    /// that goes through them.
+    fn warm_explosion_range(&mut self, exp: &[ExplosionData]) {
+        let size = CHUNK_SIZE as i64;
+        let ranges: Vec<((i64, i64), (i64, i64))> = exp
+            .iter()
+            .map(|ex| {
+                let (x, y, r) = (ex.x as i64, ex.y as i64, ex.r.min(i32::MAX as u64) as i64);
+                (
+                    ((x - r).div_euclid(size), (x + r).div_euclid(size)),
+                    ((y - r).div_euclid(size), (y + r).div_euclid(size)),
+                )
+            })
+            .collect();
+        self.chunk_storage.warm(|chunk| {
+            ranges.iter().any(|((min_x, max_x), (min_y, max_y))| {
+                (*min_x..=*max_x).contains(&(chunk.0 as i64))
+                    && (*min_y..=*max_y).contains(&(chunk.1 as i64))
+            })
+        });
+    }
+
+    /// Stamps a precomputed explosion at a location, see `compute_explosion_mask`.
+    pub(crate) fn apply_explosion_mask(&mut self, x: i32, y: i32, mask: &ExplosionMask) {
+        let ex = ExplosionData {
+            x,
+            y,
+            ..mask.explosion
+        };
+        // Mask fan is the same one a plain explosion would use.
+        self.log_terraform(&TerraformOp::Explosion(ex));
+        self.warm_explosion_range(&[ex]);
+        let result = self.interior_iter(ex, &mask.fan);
+        self.apply_explosion_results(vec![(result, ex)]);
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn apply_explosion_results(&mut self, resres: Vec<((Vec<ExRet>, Vec<u64>), ExplosionData)>) {
+        for ((chunks, raydata), ex) in resres {
+            let m = self.explosion_heap.len();
+            self.explosion_heap.push(ex);
+            let mut data = FxHashMap::default();
+            let mut exists = false;
+            for entry in chunks {
+                if let Some(entry) = entry.loaded {
+                    if entry.3 {
+                        self.chunk_storage.insert(entry.0, entry.1);
+                    } else {
+                        if let Some(c) = self.chunk_storage.get_mut(&entry.0) {
+                            c.apply_delta(entry.1)
+                        }
+                    }
+                    self.mark_modified(entry.0);
+                    if entry.2 {
+                        self.is_storage_recent.insert(entry.0);
+                    }
+                }
+                if let Some((coord, rays)) = entry.unloaded
+                    && self.nice_terraforming
+                {
+                    exists = true;
+                    let lst = rays
+                        .iter()
+                        .filter_map(|i| {
+                            if raydata[*i] == 0 {
+                                None
+                            } else if let Some(n) = data.get(i) {
+                                Some(*n)
+                            } else {
+                                let n = self.explosion_data.len();
+                                self.explosion_data
+                                    .push((m, *i, ExTarget::Ray(raydata[*i]), 0));
+                                data.insert(*i, n);
+                                Some(n)
+                            }
+                        })
+                        .collect::<Vec<usize>>();
+                    if !lst.is_empty() {
+                        self.explosion_pointer.entry(coord).or_default().extend(lst)
+                    }
+                }
+            }
+            if !exists {
+                self.explosion_heap.pop();
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn cut_through_world_explosion_list(
+        &self,
+        x: i32,
+        y: i32,
+        d: u32,
+        damage_type: u32,
+        rays: u64,
+        list: Vec<(u64, u64, Option<ChunkCoord>)>,
+        hole: bool,
+        liquid: bool,
+        mat: RawPixel,
+        prob: u8,
+        seed: u64,
+        mut r: u64,
+    ) -> Vec<ExRet> {
+        let mut list = list;
+        if let Some(cap) = self.max_affected_chunks {
+            let affected = |r: u64| {
+                let span = |c: i32| {
+                    let (min, max) = (c as i64 - r as i64, c as i64 + r as i64);
+                    (max.div_euclid(CHUNK_SIZE as i64) - min.div_euclid(CHUNK_SIZE as i64) + 1)
+                        as u64
+                };
+                span(x).saturating_mul(span(y))
+            };
+            let original = r;
+            while r > 0 && affected(r) > cap as u64 {
+                r = r * 3 / 4;
+            }
+            if r != original {
+                warn!(
+                    "Explosion at {x} {y} would affect {} chunks, clamping radius from {original} to {r}",
+                    affected(original)
+                );
+                // Rays are cut short, and don't go on in chunks that aren't loaded yet.
+                let limit = r * r;
+                for (dist, ray, _) in &mut list {
+                    if *dist > limit {
+                        *dist = limit;
+                        *ray = 0;
+                    }
+                }
+            }
+        }
+        let rads = list.iter().map(|(a, _, _)| *a).collect::<Vec<u64>>();
+        let rs = *rads.iter().max().unwrap_or(&0);
+        if r == 0 {
+            return Vec::new();
+        }
+        let (min_cx, max_cx) = (
+            (x - r as i32).div_euclid(CHUNK_SIZE as i32),
+            (x + r as i32).div_euclid(CHUNK_SIZE as i32),
+        );
+        let (min_cy, max_cy) = (
+            (y - r as i32).div_euclid(CHUNK_SIZE as i32),
+            (y + r as i32).div_euclid(CHUNK_SIZE as i32),
+        );
+        let Some(((min_cx, max_cx), (min_cy, max_cy))) =
+            self.clamp_chunk_range((min_cx, max_cx), (min_cy, max_cy))
+        else {
+            return Vec::new();
+        };
+        let air_pixel = RawPixel {
+            flags: PixelFlags::Normal,
+            material: 0,
+        };
+        let (chunkx, chunky) = (
+            x.div_euclid(CHUNK_SIZE as i32),
+            y.div_euclid(CHUNK_SIZE as i32),
+        );
+        fn is_contained(sx: i32, sy: i32, ix: i32, iy: i32, cx: i32, cy: i32) -> bool {
+            if ix == cx && iy == cy {
+                false
+            } else {
+                let a = if ix > sx { cx >= ix } else { cx <= ix };
+                let b = if iy > sy { cy >= iy } else { cy <= iy };
+                a && b
+            }
+        }
+        (min_cx..=max_cx)
+            .into_par_iter()
+            .flat_map(|chunk_x| {
+                (min_cy..=max_cy)
+                    .into_par_iter()
+                    .map(move |chunk_y| (chunk_x, chunk_y))
+            })
+            .filter(|&(chunk_x, chunk_y)| {
+                !self
+                    .protected_chunks
+                    .contains(&ChunkCoord(chunk_x, chunk_y))
+            })
+            .filter_map(|(chunk_x, chunk_y)| {
+                let coord = ChunkCoord(chunk_x, chunk_y);
+                let storage = if let Some(s) = self.chunk_storage.get(&coord) {
+                    s
+                } else if !self.nice_terraforming
+                    || min_dist(x, y, chunkx, chunky, chunk_x, chunk_y) > r * r
+                {
+                    return None;
+                } else {
+                    let lst: Vec<usize> = if (chunkx, chunky) == (chunk_x, chunk_y) {
+                        (0..list.len()).collect()
+                    } else {
+                        let (i, j) = find_rays(x, y, chunkx, chunky, chunk_x, chunk_y, rays);
+                        list.iter()
+                            .enumerate()
+                            .filter_map(|(n, (_, r, _))| {
+                                if (i..=j).contains(&n) && r != &0 {
+                                    Some(n)
+                                } else {
+                                    None
+                                }
+                            })
+                            .collect()
+                    };
+                    return if lst.is_empty() {
+                        None
+                    } else {
+                        Some(ExRet {
+                            loaded: None,
+                            unloaded: Some((coord, lst)),
+                        })
+                    };
+                };
+                if !should_process_chunk(chunk_x, chunk_y, x, y, r, &rads, chunkx, chunky, rays, rs)
+                {
+                    return if !self.nice_terraforming {
+                        None
+                    } else {
+                        let (i, j) = find_rays(x, y, chunkx, chunky, chunk_x, chunk_y, rays);
+                        let lst: Vec<usize> = list
+                            .iter()
+                            .enumerate()
+                            .filter_map(|(n, (_, r, _))| {
+                                if (i..=j).contains(&n) && r != &0 {
+                                    Some(n)
+                                } else {
+                                    None
+                                }
+                            })
+                            .collect();
+                        if lst.is_empty() {
+                            None
+                        } else {
+                            Some(ExRet {
+                                loaded: None,
+                                unloaded: Some((coord, lst)),
+                            })
+                        }
+                    };
+                }
+                let unloaded = if self.nice_terraforming && (chunkx, chunky) != (chunk_x, chunk_y) {
+                    let (i, j) = find_rays(x, y, chunkx, chunky, chunk_x, chunk_y, rays);
+                    let lst: Vec<usize> = list
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(n, (_, r, c))| {
+                            if let Some(c) = c {
+                                if (i..=j).contains(&n)
+                                    && r != &0
+                                    && is_contained(chunkx, chunky, c.0, c.1, chunk_x, chunk_y)
+                                {
+                                    Some(n)
+                                } else {
+                                    None
+                                }
+                            } else {
+                                None
+                            }
+                        })
+                        .collect();
+                    if lst.is_empty() {
+                        None
+                    } else {
+                        Some((coord, lst))
+                    }
+                } else {
+                    None
+                };
+                let mut chunk = Chunk::default();
+                let mut chunk_delta = Chunk::default();
+                let mut del = false;
+                if self.is_storage_recent.contains(&coord) {
+                    storage.apply_to_chunk(&mut chunk);
+                } else if let Some(chunk_encoded) = self
+                    .outbound_model
+                    .get_chunk_data(coord)
+                    .or(self.inbound_model.get_chunk_data(coord))
+                {
+                    del = true;
+                    chunk_encoded.apply_to_chunk(&mut chunk);
+                } else {
+                    storage.apply_to_chunk(&mut chunk);
+                }
+                let chunk_start_x = chunk_x * CHUNK_SIZE as i32;
+                let chunk_start_y = chunk_y * CHUNK_SIZE as i32;
+                let mut all = true;
+                let mut none = true;
+                let atan: Vec<f32> = compute_atans(chunk_start_x, chunk_start_y, rays as f32, x, y);
+                for icx in 0..CHUNK_SIZE as i32 {
+                    let cx = chunk_start_x + icx;
+                    let dx = cx.abs_diff(x) as u64;
+                    let dd = dx * dx;
+                    for icy in 0..CHUNK_SIZE as i32 {
+                        let cy = chunk_start_y + icy;
+                        let dy = cy.abs_diff(y) as u64;
+                        let px = icy as usize * CHUNK_SIZE + icx as usize;
+                        if (dx == 0 && dy == 0) || {
+                            let i = (atan[px] % rays as f32) as usize;
+                            dd + dy * dy <= list[i].0
+                        } {
+                            if self
+                                .materials
+                                .get(&chunk.pixel(px).material)
+                                .map(|(dur, _, cell, _, vulnerable)| {
+                                    (*dur <= d || vulnerable & damage_type != 0)
+                                        && cell.can_remove(hole, liquid)
+                                })
+                                .unwrap_or(true)
+                            {
+                                if seeded_chance(seed, cx, cy, prob) {
+                                    chunk_delta.set_pixel(px, mat);
+                                } else {
+                                    chunk_delta.set_pixel(px, air_pixel);
+                                }
+                                none = false;
+                            } else {
+                                all = false
+                            }
+                        } else {
+                            all = false
+                        }
+                    }
+                }
+                if none {
+                    unloaded.map(|unloaded| ExRet {
+                        loaded: None,
+                        unloaded: Some(unloaded),
+                    })
+                } else {
+                    Some(ExRet {
+                        loaded: Some((coord, chunk_delta.to_chunk_data(), del, all)),
+                        unloaded,
+                    })
+                }
+            })
+            .collect()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn interior_iter_chunk(
+        &self,
+        ex: ExplosionData,
+        data: (usize, usize, ExTarget, u64),
+        chunk: ChunkCoord,
+        a: f32,
+    ) -> Option<(Option<u64>, ExTarget, u64)> {
+        let ExplosionData {
+            x,
+            y,
+            mut r,
+            d,
+            ray: _,
+            hole: _,
+            liquid: _,
+            mat: _,
+            prob: _,
+            seed,
+            jitter,
+            damage_type,
+        } = ex;
+        let rays = get_ray(r, self.max_explosion_rays);
+        if let ExTarget::Radius(p) = data.2 {
+            r = p
+        } else if let ExTarget::RayRad((_, p)) = data.2 {
+            r = p
+        }
+        if r == 0 {
+            return None;
+        }
+        let t = TAU / rays as f32;
+        let theta = t * (data.1 as f32 + a + ray_offset(seed, jitter, data.1 as u64));
+        let end_x = x + (r as f64 * theta.cos() as f64) as i32;
+        let end_y = y + (r as f64 * theta.sin() as f64) as i32;
+        let mult = (((theta + TAU / 8.0) % (TAU / 4.0)) - TAU / 8.0)
+            .cos()
+            .recip();
+        if let Some((enx, eny, ur, dd)) = self.do_ray_chunk(
+            x,
+            y,
+            end_x,
+            end_y,
+            data.2,
+            d,
+            damage_type,
+            mult,
+            chunk,
+            data.3,
+            r,
+        ) {
+            let dx = enx.abs_diff(x) as u64;
+            let dy = eny.abs_diff(y) as u64;
+            if dx != 0 || dy != 0 {
+                Some((Some(dx * dx + dy * dy), ur, dd))
+            } else {
+                Some((None, ur, dd))
+            }
+        } else if a != 0.5 || (end_x.abs_diff(x) == 0 && end_y.abs_diff(y) == 0) {
+            None
+        } else {
+            self.interior_iter_chunk(ex, data, chunk, 1.0)
+                .or(self.interior_iter_chunk(ex, data, chunk, 0.0))
+                .or(self.interior_iter_chunk(ex, data, chunk, 0.75))
+                .or(self.interior_iter_chunk(ex, data, chunk, 0.25))
+                .or(Some((None, data.2, 0)))
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn cut_through_world_explosion_chunk(&mut self, chunk: ChunkCoord) {
+        if self.protected_chunks.contains(&chunk) {
+            self.explosion_pointer.remove(&chunk);
+            return;
+        }
+        let exp: Vec<(usize, (usize, usize, ExTarget, u64))> = self
+            .explosion_pointer
+            .remove(&chunk)
+            .unwrap_or_default()
+            .iter()
+            .map(|i| (*i, self.explosion_data[*i]))
+            .collect();
+        let data: Vec<(usize, Option<(Option<u64>, ExTarget, u64)>)> = exp
+            .into_par_iter()
+            .map(|ex| {
+                (
+                    ex.0,
+                    self.interior_iter_chunk(self.explosion_heap[ex.1.0], ex.1, chunk, 0.5),
+                )
+            })
+            .collect();
+        let ch = self.explosion_chunk(&data, chunk);
+        if let Some(ch) = ch {
+            if ch.1 {
+                self.chunk_storage.insert(chunk, ch.0);
+            } else {
+                if let Some(c) = self.chunk_storage.get_mut(&chunk) {
+                    c.apply_delta(ch.0)
+                }
+            }
+            self.mark_modified(chunk);
+            self.is_storage_recent.insert(chunk);
+        }
+        for (i, ch) in data {
+            if let Some((_, u, dd)) = ch {
+                self.explosion_data[i].2 = u;
+                if dd > self.explosion_data[i].3 {
+                    self.explosion_data[i].3 = dd
+                }
+            } else {
+                self.explosion_data[i].2 = ExTarget::Radius(0)
+            }
+        }
+    }
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn explosion_chunk(
+        &self,
+        data: &[(usize, Option<(Option<u64>, ExTarget, u64)>)],
+        coord: ChunkCoord,
+    ) -> Option<(ChunkData, bool)> {
+        let data: Vec<(usize, usize, u64)> = data
+            .iter()
+            .filter_map(|(i, a)| {
+                if let Some(a) = a {
+                    if let Some(b) = a.0 {
+                        let ex = self.explosion_data[*i];
+                        Some((ex.0, ex.1, b))
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let mut grouped: HashMap<usize, Vec<(usize, u64)>> = HashMap::new();
+        for (key, a, b) in data {
+            grouped.entry(key).or_default().push((a, b));
+        }
+        let data: Vec<(usize, Vec<(usize, u64)>)> = grouped.into_iter().collect();
+        let air_pixel = RawPixel {
+            flags: PixelFlags::Normal,
+            material: 0,
+        };
+        let mut chunk = Chunk::default();
+        let mut chunk_delta = Chunk::default();
+        self.chunk_storage.get(&coord)?.apply_to_chunk(&mut chunk);
+        let chunk_start_x = coord.0 * CHUNK_SIZE as i32;
+        let chunk_start_y = coord.1 * CHUNK_SIZE as i32;
+        let mut all = true;
+        let mut none = true;
+        let data: Vec<(usize, &Vec<(usize, u64)>, Vec<f32>)> = data
+            .iter()
+            .map(|(i, data)| {
+                let ex = self.explosion_heap[*i];
+                let ExplosionData {
+                    x,
+                    y,
+                    r,
+                    d: _,
+                    ray: _,
+                    hole: _,
+                    liquid: _,
+                    mat: _,
+                    prob: _,
+                    seed: _,
+                    jitter: _,
+                    damage_type: _,
+                } = ex;
+                let rays = get_ray(r, self.max_explosion_rays);
+                (
+                    *i,
+                    data,
+                    compute_atans(chunk_start_x, chunk_start_y, rays as f32, x, y),
+                )
+            })
+            .collect();
+        for icx in 0..CHUNK_SIZE as i32 {
+            let cx = chunk_start_x + icx;
+            'up: for icy in 0..CHUNK_SIZE as i32 {
+                let cy = chunk_start_y + icy;
+                let px = icy as usize * CHUNK_SIZE + icx as usize;
+                for (i, data, atan) in &data {
+                    let ex = self.explosion_heap[*i];
+                    let ExplosionData {
+                        x,
+                        y,
+                        r,
+                        d,
+                        ray: _,
+                        hole,
+                        liquid,
+                        mat,
+                        prob,
+                        seed,
+                        jitter: _,
+                        damage_type,
+                    } = ex;
+                    let dx = cx.abs_diff(x) as u64;
+                    let dy = cy.abs_diff(y) as u64;
+                    if ((dx == 0 && dy == 0) || {
+                        let rays = get_ray(r, self.max_explosion_rays);
+                        let j = (atan[px] % rays as f32) as usize;
+                        let dd = dx * dx + dy * dy;
+                        data.iter().any(|(i, r)| j == *i && dd <= *r)
+                    }) && self
+                        .materials
+                        .get(&chunk.pixel(px).material)
+                        .map(|(dur, _, cell, _, vulnerable)| {
+                            (*dur <= d || vulnerable & damage_type != 0)
+                                && cell.can_remove(hole, liquid)
+                        })
+                        .unwrap_or(true)
+                    {
+                        if seeded_chance(seed, cx, cy, prob) {
+                            chunk_delta.set_pixel(px, mat);
+                        } else {
+                            chunk_delta.set_pixel(px, air_pixel);
+                        }
+                        none = false;
+                        continue 'up;
+                    }
+                }
+                all = false
+            }
+        }
+        if none {
+            None
+        } else {
+            Some((chunk_delta.to_chunk_data(), all))
+        }
+    }
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::type_complexity)]
+    fn do_ray_chunk(
+        &self,
+        stx: i32,
+        sty: i32,
+        end_x: i32,
+        end_y: i32,
+        rayn: ExTarget,
+        d: u32,
+        damage_type: u32,
+        mult: f32,
+        chunk: ChunkCoord,
+        sd: u64,
+        r: u64,
+    ) -> Option<(i32, i32, ExTarget, u64)> {
+        //Bresenham's line algorithm
+        if r == 0
+            || min_dist(
+                stx,
+                sty,
+                stx.div_euclid(CHUNK_SIZE as i32),
+                sty.div_euclid(CHUNK_SIZE as i32),
+                chunk.0,
+                chunk.1,
+            ) > r * r
+        {
+            return None;
+        }
+        let mut ray = match rayn {
+            ExTarget::Ray(ray) => ray,
+            ExTarget::Radius(_) => u64::MAX,
+            ExTarget::RayRad((ray, _)) => ray,
+        };
+        if ray == 0 {
+            return None;
+        }
+        let mut x = stx;
+        let mut y = sty;
+        let dx = end_x.abs_diff(x) as i32;
+        let dy = end_y.abs_diff(y) as i32;
+        if (dx == 0 && dy == 0) || ray == 0 {
+            return None;
+        }
+        let sx = if x < end_x { 1 } else { -1 };
+        let sy = if y < end_y { 1 } else { -1 };
+        let mut err = if dx > dy { dx } else { -dy } / 2;
+        let mut e2;
+        let mut working_chunk = Chunk::default();
+        self.chunk_storage
+            .get(&chunk)?
+            .apply_to_chunk(&mut working_chunk);
+        let mut count = 0;
+        let mut avg = 0;
+        let mut count2 = 0;
+        let mut last_dd = None;
+        while x != end_x || y != end_y {
+            let co = ChunkCoord(
+                x.div_euclid(CHUNK_SIZE as i32),
+                y.div_euclid(CHUNK_SIZE as i32),
+            );
+            if co == chunk {
+                let dx = stx.abs_diff(x) as u64;
+                let dy = sty.abs_diff(y) as u64;
+                let dd = dx * dx + dy * dy;
+                if sd < dd {
+                    let icx = x.rem_euclid(CHUNK_SIZE as i32);
+                    let icy = y.rem_euclid(CHUNK_SIZE as i32);
+                    let px = icy as usize * CHUNK_SIZE + icx as usize;
+                    let pixel = working_chunk.pixel(px);
+                    if let Some(stats) =
+                        self.effective_durability(pixel.material, damage_type, mult)
+                    {
+                        let h = stats.ray_cost;
+                        avg += h;
+                        count2 += 1;
+                        if stats.resists(d) || ray < h + ((count * avg) / count2) {
+                            let nr = (dx as f64).hypot(dy as f64) as u64;
+                            return if count2 == 1 {
+                                Some((0, 0, ExTarget::RayRad((ray, nr)), 0))
+                            } else {
+                                Some((x, y, ExTarget::Radius(nr), dd))
+                            };
+                        };
+                        ray = ray.saturating_sub(h);
+                    }
+                }
+                last_dd = Some(dd)
+            } else if let Some(dd) = last_dd {
+                return if let ExTarget::Ray(_) = rayn {
+                    Some((
+                        i32::MAX / 4,
+                        i32::MAX / 4,
+                        ExTarget::Ray(ray.saturating_sub((count * avg) / count2.max(1))),
+                        dd,
+                    ))
+                } else if let ExTarget::RayRad((_, r)) = rayn {
+                    Some((
+                        i32::MAX / 4,
+                        i32::MAX / 4,
+                        ExTarget::RayRad((ray.saturating_sub((count * avg) / count2.max(1)), r)),
+                        dd,
+                    ))
+                } else {
+                    Some((i32::MAX / 4, i32::MAX / 4, rayn, dd))
+                };
+            } else if !self.chunk_storage.contains_key(&co) {
+                let dx = stx.abs_diff(x) as u64;
+                let dy = sty.abs_diff(y) as u64;
+                let dd = dx * dx + dy * dy;
+                if sd < dd {
+                    count += 1
+                }
+            }
+            e2 = err;
+            if e2 > -dx {
+                err -= dy;
+                x += sx;
+            }
+            if e2 < dy {
+                err += dx;
+                y += sy;
+            }
+        }
+        if let Some(dd) = last_dd {
+            if let ExTarget::Ray(_) = rayn {
+                Some((
+                    x,
+                    y,
+                    ExTarget::Ray(ray.saturating_sub((count * avg) / count2.max(1))),
+                    dd,
+                ))
+            } else if let ExTarget::RayRad((_, r)) = rayn {
+                Some((
+                    x,
+                    y,
+                    ExTarget::RayRad((ray.saturating_sub((count * avg) / count2.max(1)), r)),
+                    dd,
+                ))
+            } else {
+                Some((x, y, rayn, dd))
+            }
+        } else {
+            None
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn _create_image(&self, image: &mut image::GrayImage, w: u32) {
+        let mut working_chunk = Chunk::default();
+        let mut last_co = ChunkCoord(i32::MIN, i32::MIN);
+        for (i, px) in image.pixels_mut().enumerate() {
+            let x = i % w as usize;
+            let x = x as i32 - (w as i32 / 2);
+            let y = i / w as usize;
+            let y = y as i32 - (w as i32 / 2);
+            let coord = ChunkCoord(
+                x.div_euclid(CHUNK_SIZE as i32),
+                y.div_euclid(CHUNK_SIZE as i32),
+            );
+            let icx = x.rem_euclid(CHUNK_SIZE as i32);
+            let icy = y.rem_euclid(CHUNK_SIZE as i32);
+            if last_co != coord {
+                if let Some(c) = self.outbound_model.get_chunk_data(coord) {
+                    c.apply_to_chunk(&mut working_chunk)
+                } else if let Some(c) = self.chunk_storage.get(&coord) {
+                    c.apply_to_chunk(&mut working_chunk)
+                }
+                last_co = coord
+            }
+            let p = icy as usize * CHUNK_SIZE + icx as usize;
+            *px = image::Luma([
+                ((working_chunk.pixel(p).material * 255) as usize / self.materials.len()) as u8
+            ])
+        }
+    }
+}
+fn create_image(chunk: ChunkData, materials: &FxHashMap<u16, u32>) -> RgbaImage {
+    let mut working_chunk = Chunk::default();
+    chunk.apply_to_chunk(&mut working_chunk);
+    let w = 128;
+    let mut image = RgbaImage::new(w as u32, w as u32);
+    for (i, px) in image.pixels_mut().enumerate() {
+        let x = i % w as usize;
+        let y = i / w as usize;
+        let p = y * CHUNK_SIZE + x;
+        if let Some(color) = pixel_color(working_chunk.pixel(p), materials) {
+            *px = color
+        }
+    }
+    image
+}
+
+/// None for unknown pixels and materials without a color.
+fn pixel_color(pixel: RawPixel, materials: &FxHashMap<u16, u32>) -> Option<image::Rgba<u8>> {
+    if pixel.flags == PixelFlags::Unknown {
+        return None;
+    }
+    let c = materials.get(&pixel.material)?;
+    let a = (c >> 24) & 0xFFu32;
+    let r = (c >> 16) & 0xFFu32;
+    let g = (c >> 8) & 0xFFu32;
+    let b = c & 0xFF;
+    Some(image::Rgba([r as u8, g as u8, b as u8, a as u8]))
+}
+#[allow(clippy::too_many_arguments)]
+fn should_process_chunk(
+    chunk_x: i32,
+    chunk_y: i32,
+    x: i32,
+    y: i32,
+    r: u64,
+    list: &[u64],
+    chunkx: i32,
+    chunky: i32,
+    rays: u64,
+    rs: u64,
+) -> bool {
+    r <= CHUNK_SIZE as u64 || (chunkx, chunky) == (chunk_x, chunk_y) || {
+        {
+            let d = min_dist(x, y, chunkx, chunky, chunk_x, chunk_y);
+            d <= rs && {
+                let (i, j) = find_rays(x, y, chunkx, chunky, chunk_x, chunk_y, rays);
+                let r = list[i..=j].iter().max().unwrap_or(&0);
+                d <= *r
+            }
+        }
+    }
+}
+impl Drop for WorldManager {
+    fn drop(&mut self) {
+        if self.is_host {
+            self.save_chunk_storage();
+            info!("Saved chunk data");
+        }
+    }
+}
+impl SaveStateEntry for FxHashMap<ChunkCoord, ChunkData> {
+    const FILENAME: &'static str = "world_chunks";
+}
+impl StreamedSaveEntry for (ChunkCoord, SavedChunkData) {
+    const FILENAME: &'static str = "world_chunks_streamed";
+}
+/// Chunks along with their modification times.
+impl StreamedSaveEntry for (ChunkCoord, SavedChunkData, u64) {
+    const FILENAME: &'static str = "world_chunks_timed";
+}
+impl SaveStateEntry for MessageTrace {
+    const FILENAME: &'static str = "message_trace";
+}
+impl SaveStateEntry for ExportedRegion {
+    const FILENAME: &'static str = "exported_region";
+}
+/// Deltas don't carry sequence numbers, both authority and listener count them instead,
+/// starting from when the listener got the whole chunk.
+#[derive(Default)]
+struct SentDeltas {
+    count: u32,
+    /// Sequence numbers of deltas that weren't acknowledged yet, with updates they were sent in.
+    unacked: VecDeque<(u32, u64)>,
+}
+
+struct PriorityBoost {
+    /// Boosted priorities, and updates until which they last.
+    boosts: Vec<(u8, u64)>,
+    /// Priority to go back to once boosts are over.
+    base: Option<u8>,
+}
+
+impl PriorityBoost {
+    fn strongest(&self) -> u8 {
+        self.boosts
+            .iter()
+            .map(|(priority, _)| *priority)
+            .min()
+            .unwrap_or(u8::MAX)
+    }
+}
+
+struct BackgroundSave {
+    /// Chunks that weren't handed over to the writer yet.
+    pending: Vec<ChunkCoord>,
+    /// None once every chunk was handed over.
+    sender: Option<SyncSender<Option<(ChunkCoord, ChunkData, u64)>>>,
+    writer: thread::JoinHandle<()>,
+}
+pub(crate) struct ExRet {
+    loaded: Option<(ChunkCoord, ChunkData, bool, bool)>,
+    unloaded: Option<(ChunkCoord, Vec<usize>)>,
+}
+/// Arguments of `WorldSyncToProxy::End`.
+type TransitionEnd = (Option<(i32, i32, i32, i32, bool)>, u8, u8);
+
+/// Mixed into the seed of a cut to roll fill density independently of its chance.
+const DENSITY_SALT: u64 = 0x6465_6e73_6974_7921;
+
+/// Deterministic replacement for a random roll with `chance` percent of succeeding,
+/// so that every peer terraforming with the same seed removes the same pixels.
+fn seeded_chance(seed: u64, x: i32, y: i32, chance: u8) -> bool {
+    if chance >= 100 {
+        return true;
+    }
+    seeded_hash(seed, (x as u32 as u64) << 32 | y as u32 as u64) % 100 < chance as u64
+}
+
+/// splitmix64 finalizer, to spread nearby values over the whole range.
+fn seeded_hash(seed: u64, value: u64) -> u64 {
+    let mut h = seed ^ value.wrapping_mul(0x9e3779b97f4a7c15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+/// Deterministic angular offset of explosion ray `n`, as a fraction of angle between rays.
+/// Ray stays inside of its own sector, as pixels are assigned to rays by their angle.
+fn ray_offset(seed: u64, jitter: f32, n: u64) -> f32 {
+    if jitter <= 0.0 {
+        return 0.0;
+    }
+    let unit = seeded_hash(seed, n) as f64 / u64::MAX as f64 - 0.5;
+    (unit * jitter.min(MAX_RAY_JITTER) as f64) as f32
+}
+
+/// Seed for terraforming operations that don't come with one, derived from operation's parameters.
+/// Every peer computing the same operation gets the same seed.
+pub(crate) fn operation_seed(operation: impl Hash) -> u64 {
+    let mut hasher = FxHasher::default();
+    operation.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn find_rays(
+    x: i32,
+    y: i32,
+    chunkx: i32,
+    chunky: i32,
+    chunk_x: i32,
+    chunk_y: i32,
+    rays: u64,
+) -> (usize, usize) {
+    let (adj_x1, adj_x2) = (
+        chunk_x * CHUNK_SIZE as i32,
+        (chunk_x + 1) * CHUNK_SIZE as i32 - 1,
+    );
+    let (adj_y1, adj_y2) = if (chunk_x < chunkx) == (chunk_y < chunky) {
+        (
+            (chunk_y + 1) * CHUNK_SIZE as i32 - 1,
+            chunk_y * CHUNK_SIZE as i32,
+        )
+    } else {
+        (
+            chunk_y * CHUNK_SIZE as i32,
+            (chunk_y + 1) * CHUNK_SIZE as i32 - 1,
+        )
+    };
+    let adj_dx = adj_x1 - x;
+    let adj_dy = adj_y1 - y;
+    let i =
+        ((rays as f32 * (1.0 + (adj_dy as f32).atan2(adj_dx as f32) / TAU)) % rays as f32) as usize;
+    let adj_dx = adj_x2 - x;
+    let adj_dy = adj_y2 - y;
+    let j =
+        ((rays as f32 * (1.0 + (adj_dy as f32).atan2(adj_dx as f32) / TAU)) % rays as f32) as usize;
+    (i.min(j), j.max(i))
+}
+fn min_dist(x: i32, y: i32, chunkx: i32, chunky: i32, chunk_x: i32, chunk_y: i32) -> u64 {
+    let close_x = match chunkx.cmp(&chunk_x) {
+        cmp::Ordering::Equal => x,
+        cmp::Ordering::Greater => (chunk_x + 1) * CHUNK_SIZE as i32 - 1,
+        cmp::Ordering::Less => chunk_x * CHUNK_SIZE as i32,
+    };
+    let close_y = match chunky.cmp(&chunk_y) {
+        cmp::Ordering::Equal => y,
+        cmp::Ordering::Greater => (chunk_y + 1) * CHUNK_SIZE as i32 - 1,
+        cmp::Ordering::Less => chunk_y * CHUNK_SIZE as i32,
+    };
+    let dx = close_x.abs_diff(x) as u64;
+    let dy = close_y.abs_diff(y) as u64;
+    dx * dx + dy * dy
+}
+fn compute_atans(
+    chunk_start_x: i32,
+    chunk_start_y: i32,
+    rays: f32,
+    x_offset: i32,
+    y_offset: i32,
+) -> Vec<f32> {
+    let mut result = Vec::with_capacity(CHUNK_SIZE * CHUNK_SIZE);
+    for icy in 0..CHUNK_SIZE as i32 {
+        let dy = chunk_start_y + icy - y_offset;
+        let y = f32x8::splat(dy as f32);
+        for icx in (0..CHUNK_SIZE as i32).step_by(8) {
+            let dx = chunk_start_x + icx - x_offset;
+            let x = f32x8::from([
+                dx as f32,
+                (dx + 1) as f32,
+                (dx + 2) as f32,
+                (dx + 3) as f32,
+                (dx + 4) as f32,
+                (dx + 5) as f32,
+                (dx + 6) as f32,
+                (dx + 7) as f32,
+            ]);
+            let atan_values = rays * (1.0 + y.atan2(x) / TAU);
+            result.extend_from_slice(&atan_values.to_array());
+        }
+    }
+    result
+}
+/// Ray ends relative to explosion center, along with durability multipliers of rays.
+fn ray_fan(r: u64, max_rays: Option<u64>, seed: u64, jitter: f32) -> Vec<(i32, i32, f32)> {
+    let rays = get_ray(r, max_rays);
+    let t = TAU / rays as f32;
+    (0..rays)
+        .map(|n| {
+            let theta = t * (n as f32 + 0.5 + ray_offset(seed, jitter, n));
+            let end_x = (r as f64 * theta.cos() as f64) as i32;
+            let end_y = (r as f64 * theta.sin() as f64) as i32;
+            let mult = (((theta + TAU / 8.0) % (TAU / 4.0)) - TAU / 8.0)
+                .cos()
+                .recip();
+            (end_x, end_y, mult)
+        })
+        .collect()
+}
+
+/// Explosion that can be applied at any position without recomputing its rays.
+/// Only the shape of the ray fan is reused, how far rays get is still decided by terrain
+/// where the mask is applied. Result is the same as of a regular explosion without jitter.
+#[derive(Clone)]
+pub(crate) struct ExplosionMask {
+    /// Position is ignored.
+    explosion: ExplosionData,
+    fan: Vec<(i32, i32, f32)>,
+}
+
+/// Explosion that removes anything with durability up to `d`, see `ExplosionMask`.
+/// `max_rays` should match `WorldManager::max_explosion_rays` of where the mask is applied.
+pub(crate) fn compute_explosion_mask(
+    r: u64,
+    d: u32,
+    ray: u64,
+    max_rays: Option<u64>,
+) -> ExplosionMask {
+    let explosion = ExplosionData {
+        x: 0,
+        y: 0,
+        r,
+        d,
+        ray,
+        hole: true,
+        liquid: true,
+        mat: RawPixel {
+            flags: PixelFlags::Normal,
+            material: 0,
+        },
+        prob: 0,
+        seed: 0,
+        jitter: 0.0,
+        damage_type: 0,
+    };
+    ExplosionMask {
+        explosion,
+        fan: ray_fan(r, max_rays, 0, 0.0),
+    }
+}
+
+fn get_ray(r: u64, max_rays: Option<u64>) -> u64 {
+    let c = r.saturating_mul(15708) / 10000; // tau/4
+    let rays = (c - c % 8).clamp(1 << 4, 1 << 11);
+    max_rays.map_or(rays, |max| rays.min(max.max(8)))
+}
+/*#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_img() {
+    let (mut world, _, _, _, _) = WorldManager::new(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+    );
+    world
+        .materials
+        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+    world
+        .materials
+        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+    let w = 48;
+    for i in -w..w {
+        for j in -w..w {
+            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
+                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            } else {
+                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+            }
+        }
+    }
+    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+    //let mut img = image::GrayImage::new(pixels, pixels);
+    //world._create_image(&mut img, pixels);
+    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
+
+    let timer = std::time::Instant::now();
+    world.cut_through_world_explosion(vec![
+        ExplosionData::new(
+            2 * CHUNK_SIZE as i32,
+            -2 * CHUNK_SIZE as i32,
+            1048,
+            12,
+            10_000_000,
+            true,
+            true,
+            1,
+            4,
+        ),
+        ExplosionData::new(
+            -2 * CHUNK_SIZE as i32,
+            2 * CHUNK_SIZE as i32,
+            1048,
+            12,
+            10_000_000,
+            true,
+            true,
+            1,
+            4,
+        ),
+        ExplosionData::new(
+            -4 * CHUNK_SIZE as i32,
+            2 * CHUNK_SIZE as i32,
+            1048,
+            12,
+            10_000_000,
+            true,
+            true,
+            1,
+            4,
+        ),
+        ExplosionData::new(
+            -5 * CHUNK_SIZE as i32,
+            -2 * CHUNK_SIZE as i32,
+            1048,
+            12,
+            10_000_000,
+            true,
+            true,
+            1,
+            4,
+        ),
+        ExplosionData::new(
+            -42 * CHUNK_SIZE as i32,
+            -42 * CHUNK_SIZE as i32,
+            1048,
+            12,
+            10_000_000,
+            true,
+            true,
+            1,
+            4,
+        ),
+    ]);
+    println!("total img micros {}", timer.elapsed().as_micros());
+
+    let mut img = image::GrayImage::new(pixels, pixels);
+    world._create_image(&mut img, pixels);
+    img.save("/tmp/ew_tmp_save/img_ex.png").unwrap();
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_img_big() {
+    let (mut world, _, _, _, _) = WorldManager::new(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+    );
+    world
+        .materials
+        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+    world
+        .materials
+        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+    let w = 20;
+    for i in -w..w {
+        for j in -w..w {
+            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
+                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
+                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+            } else {
+                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+            }
+        }
+    }
+    //let mut img = image::GrayImage::new(pixels, pixels);
+    //world._create_image(&mut img, pixels);
+    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
+
+    let timer = std::time::Instant::now();
+    world.cut_through_world_explosion(vec![ExplosionData::new(
+        0,
+        0,
+        4096,
+        15,
+        1_024_000_000,
+        true,
+        true,
+        1,
+        4,
+    )]);
+    let w = 48;
+    let mut rng = rng();
+    let mut iter = (-w..w)
+        .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
+        .collect::<Vec<(i32, i32)>>();
+    iter.shuffle(&mut rng);
+    for (i, j) in iter {
+        let c = ChunkCoord(i, j);
+        if let std::collections::hash_map::Entry::Vacant(e) = world.chunk_storage.entry(c) {
+            e.insert(_brickwork.clone());
+        }
+        if world.explosion_pointer.contains_key(&c) {
+            world.cut_through_world_explosion_chunk(c)
+        }
+    }
+    println!("total img micros {}", timer.elapsed().as_micros());
+
+    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+    let mut img = image::GrayImage::new(pixels, pixels);
+    world._create_image(&mut img, pixels);
+    img.save("/tmp/ew_tmp_save/img_ex_bigger.png").unwrap();
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_img_big_br() {
+    let (mut world, _, _, _, _) = WorldManager::new(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+    );
+    world
+        .materials
+        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+    world
+        .materials
+        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+    let w = 20;
+    for i in -w..w {
+        for j in -w..w {
+            if (4..=6).contains(&i) && (4..=6).contains(&j) {
+                continue;
+            }
+            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
+                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
+                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+            } else {
+                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+            }
+        }
+    }
+    //let mut img = image::GrayImage::new(pixels, pixels);
+    //world._create_image(&mut img, pixels);
+    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
+
+    let timer = std::time::Instant::now();
+    world.cut_through_world_explosion(vec![ExplosionData::new(
+        0,
+        0,
+        4096,
+        15,
+        256_000_000,
+        true,
+        true,
+        1,
+        4,
+    )]);
+    let w = 48;
+    let mut rng = rng();
+    let mut iter = (-w..w)
+        .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
+        .collect::<Vec<(i32, i32)>>();
+    iter.shuffle(&mut rng);
+    for (i, j) in iter {
+        let c = ChunkCoord(i, j);
+        if let std::collections::hash_map::Entry::Vacant(e) = world.chunk_storage.entry(c) {
+            e.insert(if rng.random_bool(0.2) {
+                _brickwork.clone()
+            } else {
+                _dirt.clone()
+            });
+        }
+        if world.explosion_pointer.contains_key(&c) {
+            world.cut_through_world_explosion_chunk(c)
+        }
+    }
+    println!("total img micros {}", timer.elapsed().as_micros());
+
+    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+    let mut img = image::GrayImage::new(pixels, pixels);
+    world._create_image(&mut img, pixels);
+    img.save("/tmp/ew_tmp_save/img_ex_bigger_br.png").unwrap();
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_img_big_empty() {
+    let (mut world, _, _, _, _) = WorldManager::new(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+    );
+    world
+        .materials
+        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+    world
+        .materials
+        .insert(2, (14, 2_000, CellType::Liquid(LiquidType::Static)));
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+
+    let timer = std::time::Instant::now();
+    world.cut_through_world_explosion(vec![ExplosionData::new(
+        0,
+        0,
+        4096,
+        15,
+        2_000_000_000,
+        true,
+        true,
+        1,
+        4,
+    )]);
+    let w = 48;
+    let mut rng = rng();
+    let mut iter = (-w..w)
+        .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
+        .collect::<Vec<(i32, i32)>>();
+    iter.shuffle(&mut rng);
+    for (i, j) in iter {
+        let c = ChunkCoord(i, j);
+        if let std::collections::hash_map::Entry::Vacant(e) = world.chunk_storage.entry(c) {
+            e.insert(if rng.random_bool(0.2) {
+                _brickwork.clone()
+            } else {
+                _dirt.clone()
+            });
+        }
+        if world.explosion_pointer.contains_key(&c) {
+            world.cut_through_world_explosion_chunk(c)
+        }
+    }
+    println!("total img micros {}", timer.elapsed().as_micros());
+
+    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+    let mut img = image::GrayImage::new(pixels, pixels);
+    world._create_image(&mut img, pixels);
+    img.save("/tmp/ew_tmp_save/img_ex_big.png").unwrap();
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_large() {
+    let (mut world, _, _, _, _) = WorldManager::new(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+    );
+    world.nice_terraforming = false;
+    world
+        .materials
+        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+    world
+        .materials
+        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+    let w = 1024;
+    for i in -w..w {
+        for j in -w..w {
+            world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+        }
+    }
+    //let mut img = image::GrayImage::new(pixels, pixels);
+    //world._create_image(&mut img, pixels);
+    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
+
+    let timer = std::time::Instant::now();
+    world.cut_through_world_explosion(vec![ExplosionData::new(
+        0,
+        0,
+        65536,
+        15,
+        2_000_000_000,
+        true,
+        true,
+        1,
+        4,
+    )]);
+    println!("total large ex milli {}", timer.elapsed().as_millis());
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_cut_img() {
+    let (mut world, _, _, _, _) = WorldManager::new(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+    );
+    world
+        .materials
+        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+    world
+        .materials
+        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+    let w = 48;
+    for i in -w..w {
+        for j in -w..w {
+            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
+                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            } else {
+                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+            }
+        }
+    }
+    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+
+    let timer = std::time::Instant::now();
+    world.cut_through_world(0, i32::MIN, 0, 128);
+    println!("total img micros {}", timer.elapsed().as_micros());
+
+    let mut img = image::GrayImage::new(pixels, pixels);
+    world._create_image(&mut img, pixels);
+    img.save("/tmp/ew_tmp_save/img_cut.png").unwrap();
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_line_img() {
+    let (mut world, _, _, _, _) = WorldManager::new(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+    );
+    world
+        .materials
+        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+    world
+        .materials
+        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+    let w = 24;
+    for i in -w..w {
+        for j in -w..w {
+            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
+                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            } else {
+                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+            }
+        }
+    }
+
+    let timer = std::time::Instant::now();
+    world.cut_through_world_line(
+        128 * -50,
+        128 * -50 + 512,
+        128 * 50,
+        128 * 50 + 512,
+        128,
+        98,
+        0,
+        None,
+    );
+    for i in 0..64 {
+        let sx = 128 * -50;
+        let sy = 128 * 50 + 512;
+        world.cut_through_world_line(
+            sx + (i - 1) * 100,
+            sy - (i - 1) * 100,
+            sx + i * 100,
+            sy - i * 100,
+            128,
+            98,
+            0,
+            None,
+        );
+    }
+    world.cut_through_world_line(0, 0, 126, 64, 20, 50, 0, None);
+    world.cut_through_world_line(100, 0, 226, -164, 20, 0, 0, None);
+    world.cut_through_world_line(-100, 0, 26, 164, 20, 100, 0, None);
+    println!("total img micros {}", timer.elapsed().as_micros());
+
+    let w = 48;
+    for i in -w..w {
+        for j in -w..w {
+            if (-24..24).contains(&j) && (-24..24).contains(&i) {
+                continue;
+            }
+            let c = ChunkCoord(i, j);
+            if let Some(ch) = world.chunk_storage.get(&c) {
+                world
+                    .outbound_model
+                    .apply_chunk_data(c, &_brickwork.clone()).unwrap();
+                world.outbound_model.apply_chunk_data(c, ch).unwrap();
+                world
+                    .chunk_storage
+                    .insert(c, world.outbound_model.get_chunk_data(c).unwrap().clone());
+                world.outbound_model.forget_chunk(c);
+            } else {
+                world.chunk_storage.insert(c, _brickwork.clone());
+            }
+        }
+    }
+    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+
+    let mut img = image::GrayImage::new(pixels, pixels);
+    world._create_image(&mut img, pixels);
+    img.save("/tmp/ew_tmp_save/img_line.png").unwrap();
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_circ_img() {
+    let (mut world, _, _, _, _) = WorldManager::new(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+    );
+    world
+        .materials
+        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+    world
+        .materials
+        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+    let w = 48;
+    for i in -w..w {
+        for j in -w..w {
+            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
+                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            } else {
+                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+            }
+        }
+    }
+    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+
+    let timer = std::time::Instant::now();
+    world.cut_through_world_circle(0, 0, 540, None, 100, 80, 0, None);
+    println!("total img micros {}", timer.elapsed().as_micros());
+
+    let mut img = image::GrayImage::new(pixels, pixels);
+    world._create_image(&mut img, pixels);
+    img.save("/tmp/ew_tmp_save/img_circ.png").unwrap();
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_img_big_many() {
+    let (mut world, _, _, _, _) = WorldManager::new(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+    );
+    world
+        .materials
+        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+    world
+        .materials
+        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+    let w = 20;
+    for i in -w..w {
+        for j in -w..w {
+            if i != -10 {
+                if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
+                    //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
+                    world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+                } else {
+                    world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+                }
+            }
+        }
+    }
+
+    //let mut img = image::GrayImage::new(pixels, pixels);
+    //world._create_image(&mut img, pixels);
+    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
+
+    let timer = std::time::Instant::now();
+    world.cut_through_world_explosion(vec![
+        ExplosionData::new(
+            0,
+            0,
+            128,
+            15,
+            100_000_000,
+            true,
+            true,
+            1,
+            4,
+        );
+        16
+    ]);
+    world.cut_through_world_explosion(vec![
+        ExplosionData::new(1000, 5000, 128, 15, 100_000_000, true, true, 1, 4),
+        ExplosionData::new(-1000, -5000, 128, 15, 100_000_000, true, true, 1, 4),
+    ]);
+    world.cut_through_world_explosion(vec![
+        ExplosionData::new(0, 0, 4096, 15, 256_000_000, true, true, 1, 4),
+        ExplosionData::new(128 * 48, 128 * 48, 4096, 15, 256_000_000, true, true, 1, 4),
+    ]);
+    world.cut_through_world_explosion(
+        (-48..48)
+            .flat_map(|a| (-48..48).map(|b| (a, b)).collect::<Vec<(i32, i32)>>())
+            .map(|(a, b)| {
+                ExplosionData::new(a * 128, b * 128, 64, 15, 256_000_000, true, true, 1, 4)
+            })
+            .collect(),
+    );
+    let w = 48;
+    let mut rng = rng();
+    let mut iter = (-w..w)
+        .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
+        .collect::<Vec<(i32, i32)>>();
+    iter.shuffle(&mut rng);
+    for (i, j) in iter {
+        let c = ChunkCoord(i, j);
+        if let std::collections::hash_map::Entry::Vacant(e) = world.chunk_storage.entry(c) {
+            e.insert(if rng.random_bool(0.2) {
+                _brickwork.clone()
+            } else {
+                _dirt.clone()
+            });
+        }
+        if world.explosion_pointer.contains_key(&c) {
+            world.cut_through_world_explosion_chunk(c)
+        }
+    }
+    println!("total img micros {}", timer.elapsed().as_micros());
+
+    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+    let mut img = image::GrayImage::new(pixels, pixels);
+    world._create_image(&mut img, pixels);
+    img.save("/tmp/ew_tmp_save/img_ex_bigger_test.png").unwrap();
+}*/
+#[cfg(test)]
+use crate::net::LiquidType;
+#[cfg(test)]
+use rand::seq::SliceRandom;
+#[cfg(test)]
+use rand::{Rng, rng};
+#[cfg(test)]
+use serial_test::serial;
+#[cfg(test)]
+use shared::world_sync::PixelRun;
+use shared::world_sync::{
+    CHUNK_SIZE, ChunkCoord, NoitaWorldUpdate, PixelFlags, RawPixel, WorldSyncToProxy,
+};
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_perf() {
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+
+    let mut total = 0;
+    let iters = 64;
+    for _ in 0..iters {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world
+            .materials
+            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0, 0));
+        world
+            .materials
+            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+        world.materials.insert(
+            2,
+            (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0, 0),
+        );
+        let w = 48;
+        for i in -w..w {
+            for j in -w..w {
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            }
+        }
+        let timer = std::time::Instant::now();
+        world.cut_through_world_explosion(vec![
+            ExplosionData::new(
+                0,
+                0,
+                8,
+                14,
+                2_000_000_000,
+                true,
+                true,
+                1,
+                50
+            );
+            16
+        ]);
+        total += timer.elapsed().as_micros();
+    }
+    println!("total micros: {}", total / iters);
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_perf_unloaded() {
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+
+    let mut total = 0;
+    let iters = 4;
+    let mut n = 0;
+    for _ in 0..iters {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world
+            .materials
+            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0, 0));
+        world
+            .materials
+            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+        world.materials.insert(
+            2,
+            (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0, 0),
+        );
+        let w = 4;
+        for i in -w..w {
+            for j in -w..w {
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            }
+        }
+        world.cut_through_world_explosion(vec![ExplosionData::new(
+            0,
+            0,
+            1024,
+            14,
+            2_000_000_000,
+            true,
+            true,
+            1,
+            50,
+        )]);
+        let w = 8;
+        let mut rng = rng();
+        let mut iter = (-w..w)
+            .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
+            .collect::<Vec<(i32, i32)>>();
+        iter.shuffle(&mut rng);
+        for (i, j) in iter {
+            let c = ChunkCoord(i, j);
+            if !world.chunk_storage.contains_key(&c) {
+                world.chunk_storage.insert(
+                    c,
+                    if rng.random_bool(0.2) {
+                        _brickwork.clone()
+                    } else {
+                        _dirt.clone()
+                    },
+                );
+            }
+            if world.explosion_pointer.contains_key(&c) {
+                let timer = std::time::Instant::now();
+                world.cut_through_world_explosion_chunk(c);
+                total += timer.elapsed().as_micros();
+                n += 1;
+            }
+        }
+    }
+    println!("total micros: {}", total / n);
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_perf_large() {
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+
+    let mut total = 0;
+    let iters = 16;
+    for _ in 0..iters {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world
+            .materials
+            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0, 0));
+        world
+            .materials
+            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+        world.materials.insert(
+            2,
+            (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0, 0),
+        );
+        let w = 48;
+        for i in -w..w {
+            for j in -w..w {
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            }
+        }
+        let timer = std::time::Instant::now();
+        world.cut_through_world_explosion(vec![
+            ExplosionData::new(
+                0,
+                0,
+                380,
+                14,
+                2_000_000_000,
+                true,
+                true,
+                1,
+                50
+            );
+            4
+        ]);
+        total += timer.elapsed().as_micros();
+    }
+    println!("total micros: {}", total / iters);
+}
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_line_perf() {
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+
+    let mut total = 0;
+    let iters = 64;
+    for _ in 0..iters {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world
+            .materials
+            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0, 0));
+        world
+            .materials
+            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+        world.materials.insert(
+            2,
+            (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0, 0),
+        );
+        let w = 48;
+        for i in -w..w {
+            for j in -w..w {
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            }
+        }
+        let timer = std::time::Instant::now();
+        world.cut_through_world_line(0, 0, 64, 64, 64, 50, 0, None);
+        total += timer.elapsed().as_micros();
+    }
+    println!("total micros: {}", total / iters);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_circle_perf() {
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+
+    let mut total = 0;
+    let iters = 64;
+    for _ in 0..iters {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world
+            .materials
+            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0, 0));
+        world
+            .materials
+            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+        world.materials.insert(
+            2,
+            (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0, 0),
+        );
+        let w = 48;
+        for i in -w..w {
+            for j in -w..w {
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            }
+        }
+        let timer = std::time::Instant::now();
+        world.cut_through_world_circle(0, 0, 512, None, 100, 80, 0, None);
+        total += timer.elapsed().as_micros();
+    }
+    println!("total micros: {}", total / iters);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_cut_perf() {
+    let _dirt = ChunkData::new(1);
+    let _brickwork = ChunkData::new(2);
+
+    let mut total = 0;
+    let iters = 64;
+    for _ in 0..iters {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world
+            .materials
+            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0, 0));
+        world
+            .materials
+            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+        world.materials.insert(
+            2,
+            (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0, 0),
+        );
+        let w = 48;
+        for i in -w..w {
+            for j in -w..w {
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            }
+        }
+        let timer = std::time::Instant::now();
+        world.cut_through_world(0, i32::MIN, i32::MAX, 128);
+        total += timer.elapsed().as_micros();
+    }
+    println!("total micros: {}", total / iters);
+}
+
+impl WorldManager {
+    pub fn handle_noita_msg(&mut self, _: OmniPeerId, msg: WorldSyncToProxy) {
+        self.log_noita_update(&msg);
+        match msg {
+            WorldSyncToProxy::Updates(updates) => {
+                for update in updates {
+                    self.outbound_model
+                        .apply_noita_update(update, &mut self.is_storage_recent)
+                }
+            }
+            WorldSyncToProxy::End(pos, priority, world_num) => {
+                if let Some(last_end) = &mut self.transition {
+                    // Changes keep piling up in the model until the transition ends.
+                    *last_end = Some((pos, priority, world_num));
+                    if self.tick_duration.is_none() {
+                        self.current_update += 1;
+                    }
+                    return;
+                }
+                self.handle_frame_end(pos, priority, world_num);
+            }
+        }
+    }
+
+    fn handle_frame_end(
+        &mut self,
+        pos: Option<(i32, i32, i32, i32, bool)>,
+        priority: u8,
+        world_num: u8,
+    ) {
+        // Suspended chunks keep collecting changes, as long as we're still their authority.
+        self.suspended_sending.retain(|chunk| {
+            matches!(
+                self.chunk_state.get(chunk),
+                Some(ChunkState::Authority { .. })
+            )
+        });
+        let (suspended, mut updated_chunks): (Vec<_>, Vec<_>) = self
+            .outbound_model
+            .updated_chunks()
+            .iter()
+            .partition(|chunk| self.suspended_sending.contains(chunk));
+        if self.tick_duration.is_none() {
+            self.current_update += 1;
+        }
+        let start = Instant::now();
+        let budget = self.end_time_budget;
+        let over_budget = || budget.is_some_and(|budget| start.elapsed() > budget);
+        let mut chunk_packet: HashMap<OmniPeerId, Vec<(ChunkDelta, u8)>> = HashMap::new();
+        let mut processed = 0;
+        for chunk in updated_chunks.iter() {
+            if processed > 0 && over_budget() {
+                break;
+            }
+            processed += 1;
+            let who_sending = self.chunk_updated_locally(*chunk, priority, pos, world_num);
+            let Some(delta) = self.outbound_model.get_chunk_delta(*chunk, false) else {
+                self.send_full_chunk(*chunk, &who_sending);
+                continue;
+            };
+            for (peer, pri) in who_sending {
+                chunk_packet
+                    .entry(peer)
+                    .or_default()
+                    .push((delta.clone(), pri));
+            }
+        }
+        let mut deferred: FxHashSet<ChunkCoord> = updated_chunks.drain(processed..).collect();
+        if !deferred.is_empty() {
+            debug!("Deferring {} chunk updates to next frame", deferred.len());
+        }
+        deferred.extend(suspended);
+        for chunk in &updated_chunks {
+            if let Some(ChunkState::Authority { .. }) = self.chunk_state.get(chunk) {
+                let changed = self.outbound_model.changed_pixel_count(*chunk);
+                self.unpushed_changes
+                    .entry(*chunk)
+                    .or_insert((0, self.current_update))
+                    .0 += changed;
+            }
+        }
+        self.push_changed_storage();
+        let mut emit_queue = Vec::new();
+        let stats = self.stats_sink();
+        let packets: Vec<_> = mem::take(&mut self.unsent_packets)
+            .into_iter()
+            .chain(chunk_packet)
+            .collect();
+        for (peer, chunkpacket) in packets {
+            if !emit_queue.is_empty() && over_budget() {
+                self.unsent_packets.push((peer, chunkpacket));
+                continue;
+            }
+            let original = stats.as_ref().map(|_| {
+                chunkpacket
+                    .iter()
+                    .map(|delta| bitcode::encode(delta).len())
+                    .sum::<usize>()
+            });
+            let packet = build_chunk_packet(chunkpacket);
+            if let (Some(stats), Some(original)) = (&stats, original) {
+                let packed = bitcode::encode(&packet).len();
+                stats.lock().unwrap().record(original, packed);
+            }
+            emit_queue.push((Destination::Peer(peer), packet));
+        }
+        if !self.unsent_packets.is_empty() {
+            debug!(
+                "Deferring {} chunk packets to next frame",
+                self.unsent_packets.len()
+            );
+        }
+        for (dst, msg) in emit_queue {
+            self.emit_msg(dst, msg)
+        }
+        self.outbound_model.reset_change_tracking_except(&deferred);
+    }
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_set_position_unload() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(10, 10);
+    world.chunk_state.insert(
+        chunk,
+        ChunkState::Listening {
+            authority: OmniPeerId(1),
+            priority: 0,
+        },
+    );
+    world.set_position((10, 10), (10, 10), false);
+    world.update(Duration::ZERO);
+    assert!(world.chunk_state.contains_key(&chunk));
+    world.set_position((-10, -10), (-10, -10), false);
+    world.update(Duration::ZERO);
+    assert!(!world.chunk_state.contains_key(&chunk));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_out_of_bounds_chunk() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(i32::MAX / 2, 0);
+    world.handle_msg(
+        OmniPeerId(1),
+        WorldNetMessage::RequestAuthority {
+            chunk,
+            priority: 0,
+            can_wait: true,
+            hint: None,
+        },
+    );
+    assert!(world.authority_map.is_empty());
+    assert!(
+        world
+            .get_emitted_msgs()
+            .iter()
+            .all(|request| matches!(request.msg, WorldNetMessage::UnloadChunk { .. }))
+    );
+    world.nice_terraforming = true;
+    world.cut_through_world_circle(i32::MAX / 4, 0, 1000, None, 100, 100, 0, None);
+    assert!(world.chunk_storage.is_empty());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_message_sink() {
+    let (sink, rx) = mpsc::sync_channel(1);
+    let (mut world, _, _, _, _) = WorldManager::new_with_sink(
+        true,
+        OmniPeerId(0),
+        SaveState::new("/tmp/ew_tmp_save"),
+        Some(sink),
+    );
+    for i in 0..2 {
+        world.handle_msg(
+            OmniPeerId(1),
+            WorldNetMessage::ListenRequest {
+                chunk: ChunkCoord(i, 0),
+            },
+        );
+    }
+    let msg = rx.try_recv().unwrap();
+    assert!(msg.dst == Destination::Peer(OmniPeerId(1)));
+    assert!(matches!(
+        msg.msg,
+        WorldNetMessage::UnloadChunk {
+            chunk: ChunkCoord(0, 0)
+        }
+    ));
+    // Channel was full, second message got buffered.
+    let buffered = world.get_emitted_msgs();
+    assert_eq!(buffered.len(), 1);
+    assert!(matches!(
+        buffered[0].msg,
+        WorldNetMessage::UnloadChunk {
+            chunk: ChunkCoord(1, 0)
+        }
+    ));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_protected_chunk() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let dirt = ChunkData::new(1);
+    for i in -2..2 {
+        for j in -2..2 {
+            world.chunk_storage.insert(ChunkCoord(i, j), dirt.clone());
+        }
+    }
+    world.protect_region(ChunkCoord(0, 0), ChunkCoord(0, 0));
+    world.cut_through_world_circle(0, 0, 200, None, 100, 100, 0, None);
+    assert!(world.chunk_storage.get(&ChunkCoord(0, 0)).unwrap().runs == dirt.runs);
+    assert!(world.chunk_storage.get(&ChunkCoord(-1, -1)).unwrap().runs != dirt.runs);
+    world.unprotect_region(ChunkCoord(-1, -1), ChunkCoord(1, 1));
+    world.cut_through_world_circle(0, 0, 200, None, 100, 100, 0, None);
+    assert!(world.chunk_storage.get(&ChunkCoord(0, 0)).unwrap().runs != dirt.runs);
+
+    // Huge regions are capped instead of going through every chunk.
+    world.world_bounds = None;
+    world.protect_region(
+        ChunkCoord(i32::MIN, i32::MIN),
+        ChunkCoord(i32::MAX, i32::MAX),
+    );
+    assert_eq!(world.protected_chunks.len(), MAX_PROTECTED_CHUNKS);
+    world.unprotect_region(
+        ChunkCoord(i32::MIN, i32::MIN),
+        ChunkCoord(i32::MAX, i32::MAX),
+    );
+    world.world_bounds = Some((ChunkCoord(-1, -1), ChunkCoord(1, 1)));
+    world.protect_region(
+        ChunkCoord(i32::MIN, i32::MIN),
+        ChunkCoord(i32::MAX, i32::MAX),
+    );
+    assert_eq!(world.protected_chunks.len(), 9);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_end_time_budget() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.end_time_budget = Some(Duration::ZERO);
+    let chunks = [ChunkCoord(0, 0), ChunkCoord(1, 0), ChunkCoord(2, 0)];
+    for chunk in chunks {
+        let mut state = ChunkState::authority(0);
+        if let ChunkState::Authority { listeners, .. } = &mut state {
+            listeners.extend([OmniPeerId(1), OmniPeerId(2)]);
+        }
+        world.chunk_state.insert(chunk, state);
+    }
+    world.handle_noita_msg(
+        OmniPeerId(0),
+        WorldSyncToProxy::Updates(
+            chunks
+                .iter()
+                .map(|&coord| NoitaWorldUpdate {
+                    coord,
+                    runs: vec![PixelRun {
+                        length: 1,
+                        data: RawPixel {
+                            material: 1,
+                            flags: PixelFlags::Normal,
+                        },
+                    }],
+                })
+                .collect(),
+        ),
+    );
+    // Each frame gets one chunk ready for both listeners, but only encodes one packet.
+    let mut sent: FxHashMap<OmniPeerId, Vec<ChunkCoord>> = FxHashMap::default();
+    for _ in 0..7 {
+        world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::End(None, 0, 0));
+        let msgs = world.get_emitted_msgs();
+        assert!(msgs.len() <= 1);
+        for msg in msgs {
+            let (Destination::Peer(peer), WorldNetMessage::ChunkPacket { chunkpacket, .. }) =
+                (msg.dst, msg.msg)
+            else {
+                panic!("expected a chunk packet");
+            };
+            assert_eq!(chunkpacket.len(), 1);
+            sent.entry(peer)
+                .or_default()
+                .extend(chunkpacket.iter().map(|(delta, _)| delta.chunk_coord));
+        }
+    }
+    assert!(world.unsent_packets.is_empty());
+    assert_eq!(sent.len(), 2);
+    for sent in sent.values_mut() {
+        sent.sort_by_key(|c| c.0);
+        assert!(*sent == chunks);
+    }
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_compression_dict() {
+    use world_model::compression::train_dictionary;
+    let samples: Vec<ChunkData> = (0..4).map(ChunkData::new).collect();
+    let dict = CompressionDict::new(train_dictionary(&samples));
+    let chunk = ChunkData::new(2);
+    let plain = chunk.compress(None, CompressionLevel::Balanced);
+    let with_dict = chunk.compress(Some(&dict), CompressionLevel::Balanced);
+    assert_eq!(plain.dict_version, None);
+    assert_eq!(with_dict.dict_version, Some(dict.version()));
+    assert!(with_dict.decompress(Some(&dict)).unwrap().runs == chunk.runs);
+    assert!(plain.decompress(Some(&dict)).unwrap().runs == chunk.runs);
+    // Peer doesn't have the dictionary.
+    assert!(with_dict.decompress(None).is_none());
+
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    host.compression_dict = Some(dict.clone());
+    peer.compression_dict = Some(dict.clone());
+    host.update(Duration::ZERO);
+    for msg in host.get_emitted_msgs() {
+        peer.handle_msg(OmniPeerId(0), msg.msg);
+    }
+    assert_eq!(
+        peer.peer_dict_versions.get(&OmniPeerId(0)),
+        Some(&dict.version())
+    );
+    for msg in peer.get_emitted_msgs() {
+        host.handle_msg(OmniPeerId(1), msg.msg);
+    }
+    assert_eq!(
+        host.peer_dict_versions.get(&OmniPeerId(1)),
+        Some(&dict.version())
+    );
+    assert_eq!(
+        host.compress_for(OmniPeerId(1), &chunk).dict_version,
+        Some(dict.version())
+    );
+    assert_eq!(host.compress_for(OmniPeerId(2), &chunk).dict_version, None);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_known_peers() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    assert!(host.known_peers().is_empty());
+
+    host.authority_map
+        .insert(ChunkCoord(0, 0), (OmniPeerId(1), 0));
+    host.chunk_state.insert(
+        ChunkCoord(1, 0),
+        ChunkState::Listening {
+            authority: OmniPeerId(2),
+            priority: 0,
+        },
+    );
+    let mut authority = ChunkState::authority(0);
+    if let ChunkState::Authority { listeners, .. } = &mut authority {
+        listeners.insert(OmniPeerId(3));
+        listeners.insert(OmniPeerId(1));
+    }
+    host.chunk_state.insert(ChunkCoord(2, 0), authority);
+
+    let expected: FxHashSet<_> = [1, 2, 3].into_iter().map(OmniPeerId).collect();
+    assert_eq!(host.known_peers(), expected);
+
+    host.handle_peer_left(OmniPeerId(2));
+    host.handle_peer_left(OmniPeerId(3));
+    let expected: FxHashSet<_> = [OmniPeerId(1)].into_iter().collect();
+    assert_eq!(host.known_peers(), expected);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_check_missing_peers() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let (stays, leaves) = (OmniPeerId(1), OmniPeerId(2));
+    host.authority_map.insert(ChunkCoord(0, 0), (stays, 0));
+    host.authority_map.insert(ChunkCoord(1, 0), (leaves, 0));
+    let in_lobby = [OmniPeerId(0), stays];
+    host.check_missing_peers(&in_lobby);
+    host.current_update += MISSING_PEER_GRACE * 2;
+    host.check_missing_peers(&in_lobby);
+    assert_eq!(host.known_peers().len(), 2);
+
+    host.drop_missing_peers = true;
+    host.check_missing_peers(&in_lobby);
+    // Coming back in time resets the grace period.
+    host.current_update += MISSING_PEER_GRACE - 1;
+    host.check_missing_peers(&[OmniPeerId(0), stays, leaves]);
+    host.current_update += MISSING_PEER_CHECK_INTERVAL;
+    host.check_missing_peers(&in_lobby);
+    host.current_update += MISSING_PEER_GRACE - 1;
+    host.check_missing_peers(&in_lobby);
+    assert_eq!(host.known_peers().len(), 2);
+    // Checks are rate limited.
+    host.current_update += 1;
+    host.check_missing_peers(&in_lobby);
+    assert_eq!(host.known_peers().len(), 2);
+    host.current_update += MISSING_PEER_CHECK_INTERVAL;
+    host.check_missing_peers(&in_lobby);
+    assert_eq!(host.known_peers(), [stays].into_iter().collect());
+}
+
+#[cfg(test)]
+#[test]
+fn test_make_random_seeded() {
+    let a = ChunkData::make_random_seeded(12345);
+    let b = ChunkData::make_random_seeded(12345);
+    let c = ChunkData::make_random_seeded(54321);
+    assert_eq!(bitcode::encode(&a), bitcode::encode(&b));
+    assert_ne!(bitcode::encode(&a), bitcode::encode(&c));
+    assert!(a.runs.len() > 1);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_replicate_only() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let replica = OmniPeerId(1);
+    let chunk = ChunkCoord(0, 0);
+    host.chunk_storage.insert(chunk, ChunkData::new(1));
+    host.handle_msg(
+        replica,
+        WorldNetMessage::RegisterReplicaOnly {
+            replicate_only: true,
+        },
+    );
+
+    // Nobody has authority yet, but replica still shouldn't get it.
+    // It waits for whoever gets authority instead.
+    host.handle_msg(
+        replica,
+        WorldNetMessage::RequestAuthority {
+            chunk,
+            priority: 0,
+            can_wait: false,
+            hint: None,
+        },
+    );
+    assert!(!host.authority_map.contains_key(&chunk));
+    assert!(host.get_emitted_msgs().is_empty());
+
+    // Host takes authority and sends the chunk to waiting replica right away.
+    host.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::RequestAuthority {
+            chunk,
+            priority: 10,
+            can_wait: true,
+            hint: None,
+        },
+    );
+    let Some(ChunkState::Authority { listeners, .. }) = host.chunk_state.get(&chunk) else {
+        panic!("host didn't get authority");
+    };
+    assert!(listeners.contains(&replica));
+    let msgs = host.get_emitted_msgs();
+    assert!(msgs.iter().any(|m| matches!(
+        m,
+        MessageRequest {
+            msg: WorldNetMessage::ListenInitialResponse {
+                chunk_data: Some(_),
+                ..
+            },
+            dst: Destination::Peer(peer),
+            ..
+        } if *peer == replica
+    )));
+
+    // Replica gets told to listen instead, even with a better priority.
+    host.handle_msg(
+        replica,
+        WorldNetMessage::RequestAuthority {
+            chunk,
+            priority: 0,
+            can_wait: false,
+            hint: None,
+        },
+    );
+    assert_eq!(host.authority_map.get(&chunk), Some(&(OmniPeerId(0), 10)));
+    let msgs = host.get_emitted_msgs();
+    assert!(msgs.iter().any(|m| matches!(
+        m.msg,
+        WorldNetMessage::AuthorityAlreadyTaken {
+            authority: OmniPeerId(0),
+            ..
+        }
+    )));
+
+    host.handle_msg(replica, WorldNetMessage::ListenRequest { chunk });
+    let Some(ChunkState::Authority { listeners, .. }) = host.chunk_state.get(&chunk) else {
+        panic!("host lost authority");
+    };
+    assert!(listeners.contains(&replica));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_pending_listener_expiry() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let (stale, leaving) = (OmniPeerId(1), OmniPeerId(2));
+    let (chunk, other) = (ChunkCoord(0, 0), ChunkCoord(1, 0));
+    for replica in [stale, leaving] {
+        host.handle_msg(
+            replica,
+            WorldNetMessage::RegisterReplicaOnly {
+                replicate_only: true,
+            },
+        );
+    }
+    let request = |chunk| WorldNetMessage::RequestAuthority {
+        chunk,
+        priority: 0,
+        can_wait: false,
+        hint: None,
+    };
+    host.handle_msg(stale, request(chunk));
+    host.handle_msg(leaving, request(other));
+    assert_eq!(host.pending_listeners.len(), 2);
+
+    // Departed peers stop waiting right away.
+    host.handle_peer_left(leaving);
+    assert!(!host.pending_listeners.contains_key(&other));
+    assert!(host.pending_listeners.contains_key(&chunk));
+
+    // Others give up after a while.
+    host.current_update += PENDING_LISTENER_TIMEOUT - 1;
+    host.update(Duration::ZERO);
+    assert!(host.pending_listeners.contains_key(&chunk));
+    host.current_update += 1;
+    host.update(Duration::ZERO);
+    assert!(host.pending_listeners.is_empty());
+    host.get_emitted_msgs();
+
+    // Whoever gets authority later doesn't get the stale replica as a listener.
+    host.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::RequestAuthority {
+            chunk,
+            priority: 10,
+            can_wait: true,
+            hint: None,
+        },
+    );
+    let Some(ChunkState::Authority { listeners, .. }) = host.chunk_state.get(&chunk) else {
+        panic!("host didn't get authority");
+    };
+    assert!(!listeners.contains(&stale));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_import_region_offset() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.chunk_storage.clear();
+    host.chunk_storage
+        .insert(ChunkCoord(1, 1), ChunkData::new(5));
+    host.chunk_storage
+        .insert(ChunkCoord(2, 1), ChunkData::new(6));
+    host.chunk_storage
+        .insert(ChunkCoord(3, 1), ChunkData::new(7));
+
+    let region = host.export_region(ChunkCoord(1, 1), ChunkCoord(2, 1));
+    assert_eq!(region.chunks.len(), 2);
+
+    host.chunk_storage
+        .insert(ChunkCoord(11, -3), ChunkData::new(8));
+    let imported = host.import_region(&region, ChunkCoord(10, -3), ImportPolicy::SkipExisting);
+    assert_eq!(imported, 1);
+    let encoded =
+        |host: &WorldManager, chunk| bitcode::encode(&*host.chunk_storage.get(&chunk).unwrap());
+    assert_eq!(
+        encoded(&host, ChunkCoord(10, -3)),
+        bitcode::encode(&ChunkData::new(5))
+    );
+    assert_eq!(
+        encoded(&host, ChunkCoord(11, -3)),
+        bitcode::encode(&ChunkData::new(8))
+    );
+
+    let imported = host.import_region(&region, ChunkCoord(10, -3), ImportPolicy::Overwrite);
+    assert_eq!(imported, 2);
+    assert_eq!(
+        encoded(&host, ChunkCoord(11, -3)),
+        bitcode::encode(&ChunkData::new(6))
+    );
+    assert_eq!(host.chunk_storage.len(), 5);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_authority_of() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let mine = ChunkCoord(0, 0);
+    let listening = ChunkCoord(1, 0);
+    let transferring = ChunkCoord(2, 0);
+    let unknown = ChunkCoord(3, 0);
+    peer.chunk_state.insert(mine, ChunkState::authority(0));
+    peer.chunk_state.insert(
+        listening,
+        ChunkState::Listening {
+            authority: OmniPeerId(2),
+            priority: 0,
+        },
+    );
+    peer.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::GetAuthorityFrom {
+            chunk: transferring,
+            current_authority: OmniPeerId(3),
+        },
+    );
+
+    assert!(peer.is_authority(mine));
+    assert!(!peer.is_authority(listening));
+    assert!(!peer.is_authority(transferring));
+    assert!(!peer.is_authority(unknown));
+    assert_eq!(peer.authority_of(mine), Some(OmniPeerId(1)));
+    assert_eq!(peer.authority_of(listening), Some(OmniPeerId(2)));
+    assert_eq!(peer.authority_of(transferring), Some(OmniPeerId(3)));
+    assert_eq!(peer.authority_of(unknown), None);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_apply_delta_to_storage() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.chunk_storage.clear();
+    let chunk = ChunkCoord(0, 0);
+    host.chunk_storage.insert(chunk, ChunkData::new(5));
+
+    host.fill_rect((0, 0), (3, 0), 7);
+    let mut model = WorldModel::default();
+    model
+        .apply_chunk_data(chunk, &host.chunk_storage.get(&chunk).unwrap())
+        .unwrap();
+    let pixels = model.get_chunk_data(chunk).unwrap().runs;
+    assert_eq!(pixels[0].length, 4);
+    assert_eq!(
+        pixels[0].data,
+        RawPixel {
+            material: 7,
+            flags: PixelFlags::Normal,
+        }
+        .to_compact()
+    );
+    assert_eq!(pixels[1].data, ChunkData::new(5).runs[0].data);
+
+    // Partial delta can't create a chunk.
+    let other = ChunkCoord(1, 0);
+    host.fill_rect((CHUNK_SIZE as i32, 0), (CHUNK_SIZE as i32, 0), 7);
+    assert!(!host.chunk_storage.contains_key(&other));
+
+    // Authority gets the edit too.
+    host.authority_map.insert(chunk, (OmniPeerId(1), 0));
+    host.fill_rect((0, 1), (0, 1), 8);
+    let msgs = host.get_emitted_msgs();
+    assert!(matches!(
+        msgs.as_slice(),
+        [MessageRequest {
+            dst: Destination::Peer(OmniPeerId(1)),
+            msg: WorldNetMessage::ApplyDelta { .. },
+            ..
+        }]
+    ));
+
+    // Authority takes edits from host only.
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    peer.set_expected_host(OmniPeerId(0));
+    peer.chunk_state.insert(chunk, ChunkState::authority(0));
+    let edit = || WorldNetMessage::ApplyDelta {
+        delta: ChunkDelta::new(chunk, [Some(ChunkData::new(3).runs[0].data)]),
+    };
+    peer.handle_msg(OmniPeerId(2), edit());
+    assert!(peer.outbound_model.get_chunk_data(chunk).is_none());
+    peer.handle_msg(OmniPeerId(0), edit());
+    assert!(peer.outbound_model.get_chunk_data(chunk).is_some());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_corrupt_save_backed_up() {
+    let path = std::path::Path::new("/tmp/ew_tmp_save_corrupt");
+    std::fs::remove_dir_all(path).ok();
+    std::fs::create_dir_all(path).unwrap();
+    let save = path.join("world_chunks.bit");
+    let garbage = lz4_flex::compress_prepend_size(&[0xff; 16]);
+    std::fs::write(&save, &garbage).unwrap();
+
+    let (host, _, _, _, _) = WorldManager::new(true, OmniPeerId(0), SaveState::new(path));
+    assert!(host.chunk_storage.is_empty());
+    assert!(!save.exists());
+    assert_eq!(
+        std::fs::read(path.join("world_chunks.bit.corrupt")).unwrap(),
+        garbage
+    );
+
+    // Older save isn't loaded in place of a newer one that is corrupt.
+    let legacy = FxHashMap::from_iter([(ChunkCoord(0, 0), ChunkData::new(1))]);
+    std::fs::write(
+        &save,
+        lz4_flex::compress_prepend_size(&bitcode::encode(&legacy)),
+    )
+    .unwrap();
+    let timed = path.join("world_chunks_timed.bit");
+    std::fs::write(&timed, &garbage).unwrap();
+    let (host, _, _, _, _) = WorldManager::new(true, OmniPeerId(0), SaveState::new(path));
+    assert!(host.chunk_storage.is_empty());
+    assert!(!timed.exists());
+    assert!(path.join("world_chunks_timed.bit.corrupt").exists());
+    assert!(save.exists());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_emitted_caps() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.emitted_soft_cap = 5;
+    world.emitted_hard_cap = 10;
+    let delta = |priority| WorldNetMessage::ListenUpdate {
+        delta: ChunkDelta::new(ChunkCoord(0, 0), [None]),
+        priority,
+        take_auth: false,
+    };
+    for i in 0..8 {
+        world.emit_msg(Destination::Peer(OmniPeerId(1)), delta(i));
+    }
+    for _ in 0..8 {
+        world.emit_msg(
+            Destination::Peer(OmniPeerId(1)),
+            WorldNetMessage::ListenRequest {
+                chunk: ChunkCoord(0, 0),
+            },
+        );
+    }
+    assert!(world.last_emitted_cap_warning.is_some());
+    let msgs = world.get_emitted_msgs();
+    // All control messages are kept, deltas with best priority are kept when possible.
+    assert_eq!(msgs.len(), 10);
+    let priorities: Vec<u8> = msgs
+        .iter()
+        .filter_map(|request| request.msg.delta_priority())
+        .collect();
+    assert_eq!(priorities, vec![0, 1]);
+
+    for _ in 0..20 {
+        world.emit_msg(
+            Destination::Peer(OmniPeerId(1)),
+            WorldNetMessage::ListenRequest {
+                chunk: ChunkCoord(0, 0),
+            },
+        );
+    }
+    assert_eq!(world.get_emitted_msgs().len(), 20);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_movement_hint() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    peer.set_movement_hint((10, 0));
+    peer.chunk_state.insert(
+        ChunkCoord(0, 0),
+        ChunkState::RequestAuthority {
+            priority: 0,
+            can_wait: true,
+        },
+    );
+    peer.update(Duration::ZERO);
+    let Some(MessageRequest {
+        msg: request @ WorldNetMessage::RequestAuthority { hint, .. },
+        ..
+    }) = peer
+        .get_emitted_msgs()
+        .into_iter()
+        .find(|m| matches!(m.msg, WorldNetMessage::RequestAuthority { .. }))
+    else {
+        panic!("no authority request")
+    };
+    assert_eq!(hint, Some((10, 0)));
+
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.chunk_storage.clear();
+    host.handle_msg(OmniPeerId(1), request);
+    for x in 0..=SPECULATIVE_DISTANCE {
+        assert_eq!(
+            host.authority_map.get(&ChunkCoord(x, 0)).map(|a| a.0),
+            Some(OmniPeerId(1))
+        );
+    }
+    assert_eq!(
+        host.authority_map.get(&ChunkCoord(1, 0)),
+        Some(&(OmniPeerId(1), SPECULATIVE_PRIORITY))
+    );
+    assert!(!host.authority_map.contains_key(&ChunkCoord(0, 1)));
+
+    // Speculative grants never get in the way of actual requests.
+    host.handle_msg(
+        OmniPeerId(2),
+        WorldNetMessage::RequestAuthority {
+            chunk: ChunkCoord(1, 0),
+            priority: 10,
+            can_wait: true,
+            hint: None,
+        },
+    );
+    assert_eq!(
+        host.authority_map.get(&ChunkCoord(1, 0)),
+        Some(&(OmniPeerId(2), 10))
+    );
+
+    // Peer keeps pre-granted chunks for a while, even if they're out of range.
+    peer.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::GotAuthority {
+            chunk: ChunkCoord(2, 0),
+            chunk_data: None,
+            priority: SPECULATIVE_PRIORITY,
+            listeners: Default::default(),
+        },
+    );
+    peer.update(Duration::ZERO);
+    assert!(peer.is_authority(ChunkCoord(2, 0)));
+    peer.current_update += SPECULATIVE_TIMEOUT;
+    peer.update(Duration::ZERO);
+    assert!(!peer.is_authority(ChunkCoord(2, 0)));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_seeded_terraform() {
+    let terraform = |seed| {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
+        for i in -2..2 {
+            for j in -2..2 {
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), ChunkData::new(1));
+            }
+        }
+        world.cut_through_world_line(-100, -100, 100, 50, 30, 50, seed, None);
+        world.cut_through_world_circle(50, 50, 100, None, 100, 30, seed, None);
+        let mut chunks: Vec<_> = world
+            .chunk_storage
+            .iter()
+            .map(|(coord, data)| (*coord, bitcode::encode(&*data)))
+            .collect();
+        chunks.sort_by_key(|(coord, _)| (coord.0, coord.1));
+        chunks
+    };
+    assert_eq!(terraform(7), terraform(7));
+    assert_ne!(terraform(7), terraform(8));
+
+    let new_world = || {
+        let (world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world
+    };
+    let (mut a, mut b) = (new_world(), new_world());
+    let seed = operation_seed((10, 20, 30));
+    assert_eq!(a.peek_operation_seed(seed), seed);
+    let repeats: Vec<u64> = (0..3).map(|_| a.vary_operation_seed(seed)).collect();
+    assert_eq!(repeats[0], seed);
+    assert_ne!(repeats[1], repeats[0]);
+    assert_ne!(repeats[2], repeats[1]);
+    assert_eq!(
+        (0..3)
+            .map(|_| b.vary_operation_seed(seed))
+            .collect::<Vec<_>>(),
+        repeats
+    );
+    assert_eq!(a.peek_operation_seed(seed), a.vary_operation_seed(seed));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_message_history() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    world.handle_msg(
+        OmniPeerId(2),
+        WorldNetMessage::ListenStopRequest {
+            chunk: ChunkCoord(0, 0),
+        },
+    );
+    assert!(world.recent_messages().is_empty());
+
+    world.record_history = Some(3);
+    for i in 0..4 {
+        world.handle_msg(
+            OmniPeerId(2),
+            WorldNetMessage::ListenStopRequest {
+                chunk: ChunkCoord(i, 0),
+            },
+        );
+    }
+    world.handle_msg(
+        OmniPeerId(2),
+        WorldNetMessage::ListenRequest {
+            chunk: ChunkCoord(5, 0),
+        },
+    );
+    // Not an authority, so this gets a reply.
+    let recent: Vec<_> = world
+        .recent_messages()
+        .iter()
+        .map(|m| match m {
+            RecordedMessage::Handled { msg, .. } => ("handled", msg.chunk()),
+            RecordedMessage::Emitted { msg, .. } => ("emitted", msg.chunk()),
+        })
+        .collect();
+    assert_eq!(
+        recent,
+        vec![
+            ("handled", Some(ChunkCoord(3, 0))),
+            ("handled", Some(ChunkCoord(5, 0))),
+            ("emitted", Some(ChunkCoord(5, 0))),
+        ]
+    );
+    assert!(world.history.len() <= 6);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_split_brain() {
+    let (mut a, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut b, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(2), SaveState::new("/tmp/ew_tmp_save"));
+    a.host_epoch = 1;
+    b.host_epoch = 2;
+    peer.set_expected_host(OmniPeerId(0));
+
+    let assertions = |world: &mut WorldManager| -> Vec<WorldNetMessage> {
+        world.update(Duration::ZERO);
+        world
+            .get_emitted_msgs()
+            .into_iter()
+            .filter(|m| matches!(m.msg, WorldNetMessage::HostAssertion { .. }))
+            .map(|m| m.msg)
+            .collect()
+    };
+    let from_a = assertions(&mut a);
+    let from_b = assertions(&mut b);
+    assert_eq!(from_a.len(), 1);
+    assert_eq!(from_b.len(), 1);
+
+    b.handle_msg(OmniPeerId(0), from_a[0].clone());
+    assert!(b.is_host);
+    a.chunk_storage.insert(ChunkCoord(0, 0), ChunkData::new(1));
+    a.authority_map.insert(ChunkCoord(0, 0), (OmniPeerId(2), 0));
+    a.handle_msg(OmniPeerId(1), from_b[0].clone());
+    assert!(!a.is_host);
+    assert_eq!(a.expected_host, Some(OmniPeerId(1)));
+    // Stored chunks are still there to be served, authority is up to the new host.
+    assert!(a.chunk_storage.contains_key(&ChunkCoord(0, 0)));
+    assert!(a.authority_map.is_empty());
+
+    for msg in [from_a[0].clone(), from_b[0].clone()] {
+        let source = match msg {
+            WorldNetMessage::HostAssertion { host, .. } => host,
+            _ => unreachable!(),
+        };
+        peer.handle_msg(source, msg);
+    }
+    assert_eq!(peer.expected_host, Some(OmniPeerId(1)));
+
+    // Old host can't hand out authority anymore.
+    let chunk = ChunkCoord(0, 0);
+    peer.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::GotAuthority {
+            chunk,
+            chunk_data: None,
+            priority: 0,
+            listeners: Default::default(),
+        },
+    );
+    assert!(!peer.is_authority(chunk));
+    peer.handle_msg(
+        OmniPeerId(1),
+        WorldNetMessage::GotAuthority {
+            chunk,
+            chunk_data: None,
+            priority: 0,
+            listeners: Default::default(),
+        },
+    );
+    assert!(peer.is_authority(chunk));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_jitter() {
+    let explode = |seed| {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
+        world
+            .materials
+            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+        for i in -3..3 {
+            for j in -3..3 {
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), ChunkData::new(1));
+            }
+        }
+        let mut explosion = ExplosionData::new(10, -20, 200, 15, 1_000_000, true, true, 0, 0);
+        explosion.seed = seed;
+        explosion.jitter = 0.9;
+        world.cut_through_world_explosion(vec![explosion]);
+        world
+    };
+    let encoded = |world: &WorldManager| {
+        let mut chunks: Vec<_> = world
+            .chunk_storage
+            .iter()
+            .map(|(coord, data)| (*coord, bitcode::encode(&*data)))
+            .collect();
+        chunks.sort_by_key(|(coord, _)| (coord.0, coord.1));
+        chunks
+    };
+    let world = explode(5);
+    assert_eq!(encoded(&world), encoded(&explode(5)));
+    assert_ne!(encoded(&world), encoded(&explode(6)));
+
+    let mut chunk = Chunk::default();
+    for (coord, data) in world.chunk_storage.iter() {
+        data.apply_to_chunk(&mut chunk);
+        for icx in 0..CHUNK_SIZE as i32 {
+            for icy in 0..CHUNK_SIZE as i32 {
+                let dx = coord.0 * CHUNK_SIZE as i32 + icx - 10;
+                let dy = coord.1 * CHUNK_SIZE as i32 + icy + 20;
+                if dx * dx + dy * dy < 190 * 190 {
+                    let px = icy as usize * CHUNK_SIZE + icx as usize;
+                    assert_eq!(chunk.pixel(px).material, 0, "pixel at {dx} {dy} not carved");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_evict_peer() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let evicted = OmniPeerId(5);
+    let owned = ChunkCoord(1, 1);
+    let listened = ChunkCoord(2, 2);
+    world.authority_map.insert(owned, (evicted, 0));
+    world.authority_map.insert(listened, (OmniPeerId(0), 0));
+    world.chunk_state.insert(
+        owned,
+        ChunkState::Listening {
+            authority: evicted,
+            priority: 0,
+        },
+    );
+    let mut listeners = FxHashSet::default();
+    listeners.insert(evicted);
+    listeners.insert(OmniPeerId(6));
+    world.chunk_state.insert(
+        listened,
+        ChunkState::Authority {
+            listeners,
+            priority: 0,
+            new_authority: Some((evicted, 0)),
+            stop_sending: false,
+        },
+    );
+    world.evict_peer(evicted);
+    world.evict_peer(evicted);
+    assert!(!world.known_peers().contains(&evicted));
+    assert!(!matches!(
+        world.chunk_state.get(&owned),
+        Some(ChunkState::Listening { .. })
+    ));
+    assert!(!world.authority_map.contains_key(&owned));
+    let Some(ChunkState::Authority {
+        listeners,
+        new_authority,
+        ..
+    }) = world.chunk_state.get(&listened)
+    else {
+        panic!("authority over {listened:?} was lost");
+    };
+    assert_eq!(listeners.len(), 1);
+    assert!(new_authority.is_none());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_background_save() {
+    let save_state = SaveState::new("/tmp/ew_tmp_save_streamed");
+    save_state.reset();
+    save_state.mark_game_started();
+    let (mut world, _, _, _, _) = WorldManager::new(true, OmniPeerId(0), save_state.clone());
+    world.chunk_storage.clear();
+    for i in 0..300 {
+        world
+            .chunk_storage
+            .insert(ChunkCoord(i, 0), ChunkData::new(1));
+    }
+    world.start_background_save();
+    let mut i = 0;
+    while world.background_save.is_some() {
+        if i < 290 {
+            world
+                .chunk_storage
+                .insert(ChunkCoord(i, 0), ChunkData::new(2));
+            world.chunk_storage.remove(&ChunkCoord(i + 1, 0));
+            i += 7;
+        }
+        world.advance_background_save();
+        thread::sleep(Duration::from_millis(1));
+    }
+    let saved: Vec<_> = save_state
+        .load_streamed::<(ChunkCoord, SavedChunkData, u64)>()
+        .unwrap()
+        .into_iter()
+        .map(|(chunk, data, _)| (chunk, data.into_chunk_data()))
+        .collect();
+    let versions = [1, 2].map(|mat| bitcode::encode(&ChunkData::new(mat)));
+    for (chunk, data) in &saved {
+        assert!(
+            versions.contains(&bitcode::encode(data)),
+            "{chunk:?} was torn"
+        );
+    }
+    let saved: FxHashMap<_, _> = saved.into_iter().collect();
+    for chunk in world.chunk_storage.keys() {
+        assert!(saved.contains_key(chunk), "{chunk:?} is missing");
+    }
+    // Don't overwrite it on drop.
+    world.is_host = false;
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_compression_level() {
+    let chunk = ChunkData::make_random_seeded(3);
+    let fast = chunk.compress(None, CompressionLevel::Fast);
+    let balanced = chunk.compress(None, CompressionLevel::Balanced);
+    let max = chunk.compress(None, CompressionLevel::Max);
+    let size = |compressed: &CompressedChunkData| bitcode::encode(compressed).len();
+    assert_ne!(size(&fast), size(&balanced));
+    assert!(size(&max) <= size(&fast).min(size(&balanced)));
+    for compressed in [fast, balanced, max] {
+        assert_eq!(compressed.decompress(None).unwrap().runs, chunk.runs);
+    }
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_missing_delta_fallback() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(3, 3);
+    world.chunk_storage.insert(chunk, ChunkData::new(1));
+    world.chunk_state.insert(
+        chunk,
+        ChunkState::Authority {
+            listeners: [OmniPeerId(1)].into_iter().collect(),
+            priority: 0,
+            new_authority: None,
+            stop_sending: false,
+        },
+    );
+    world
+        .outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(2))
+        .unwrap();
+    world.outbound_model.lose_chunk_baseline(chunk);
+    world.get_emitted_msgs();
+    let sent_chunk = |world: &mut WorldManager| {
+        world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::End(None, 0, 0));
+        world
+            .get_emitted_msgs()
+            .into_iter()
+            .find_map(|msg| match msg {
+                MessageRequest {
+                    dst: Destination::Peer(OmniPeerId(1)),
+                    msg:
+                        WorldNetMessage::ListenInitialResponse {
+                            chunk: sent,
+                            chunk_data,
+                            ..
+                        },
+                    ..
+                } if sent == chunk => chunk_data,
+                _ => None,
+            })
+    };
+    // Storage might be older than what listeners already have.
+    assert!(sent_chunk(&mut world).is_none());
+
+    world
+        .outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(2))
+        .unwrap();
+    world.outbound_model.lose_chunk_baseline(chunk);
+    world.is_storage_recent.insert(chunk);
+    let sent = sent_chunk(&mut world).expect("listener didn't get the chunk");
+    let sent = world.decompress(&sent);
+    assert_eq!(sent.unwrap().runs, ChunkData::new(1).runs);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_storage_push() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    world.storage_push_interval = 10;
+    world.storage_push_min_pixels = 100;
+    let chunk = ChunkCoord(0, 0);
+    world.chunk_state.insert(
+        chunk,
+        ChunkState::Authority {
+            listeners: Default::default(),
+            priority: 0,
+            new_authority: None,
+            stop_sending: false,
+        },
+    );
+    let mut pushes = Vec::new();
+    for i in 0..15 {
+        let update = NoitaWorldUpdate {
+            coord: chunk,
+            runs: vec![PixelRun {
+                length: 30,
+                data: RawPixel {
+                    material: i % 2 + 1,
+                    flags: PixelFlags::Normal,
+                },
+            }],
+        };
+        world.handle_noita_msg(OmniPeerId(1), WorldSyncToProxy::Updates(vec![update]));
+        world.handle_noita_msg(OmniPeerId(1), WorldSyncToProxy::End(None, 0, 0));
+        for msg in world.get_emitted_msgs() {
+            if let WorldNetMessage::UpdateStorage { chunk_data, .. } = msg.msg {
+                assert!(chunk_data.is_some());
+                pushes.push(world.current_update);
+            }
+        }
+    }
+    // Enough pixels change in a few updates, but pushes are throttled.
+    assert_eq!(pushes, vec![11]);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_validate_consistency() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let mine = ChunkCoord(0, 0);
+    let stolen = ChunkCoord(1, 0);
+    for chunk in [mine, stolen] {
+        host.authority_map.insert(chunk, (OmniPeerId(0), 0));
+        host.chunk_state.insert(
+            chunk,
+            ChunkState::Authority {
+                listeners: [OmniPeerId(1)].into_iter().collect(),
+                priority: 0,
+                new_authority: None,
+                stop_sending: false,
+            },
+        );
+    }
+    assert!(host.validate_consistency().is_empty());
+
+    host.authority_map.insert(stolen, (OmniPeerId(2), 0));
+    host.departed_peers.insert(OmniPeerId(1));
+    host.departed_peers.insert(OmniPeerId(2));
+    let issues = host.validate_consistency();
+    assert_eq!(issues.len(), 4);
+    assert!(issues.contains(&ConsistencyIssue::UnlistedAuthority {
+        chunk: stolen,
+        listed: Some(OmniPeerId(2)),
+    }));
+    assert!(issues.contains(&ConsistencyIssue::UnclaimedAuthority {
+        chunk: stolen,
+        authority: OmniPeerId(2),
+    }));
+    assert!(issues.contains(&ConsistencyIssue::DepartedListener {
+        chunk: mine,
+        listener: OmniPeerId(1),
+    }));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_spoofed_relinquish() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.relinquish_rejection_limit = 3;
+    let chunk = ChunkCoord(0, 0);
+    host.authority_map.insert(chunk, (OmniPeerId(1), 0));
+    for _ in 0..3 {
+        host.handle_msg(
+            OmniPeerId(2),
+            WorldNetMessage::RelinquishAuthority {
+                chunk,
+                chunk_data: Some(ChunkData::new(1)),
+                world_num: 0,
+            },
+        );
+        assert_eq!(host.authority_of(chunk), Some(OmniPeerId(1)));
+    }
+    assert!(!host.chunk_storage.contains_key(&chunk));
+    assert_eq!(host.suspicious_peers(), vec![(OmniPeerId(2), 3)]);
+
+    host.handle_msg(
+        OmniPeerId(1),
+        WorldNetMessage::RelinquishAuthority {
+            chunk,
+            chunk_data: Some(ChunkData::new(1)),
+            world_num: 0,
+        },
+    );
+    assert_eq!(host.authority_of(chunk), None);
+    assert!(host.chunk_storage.contains_key(&chunk));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_prefetch_region() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    world.set_position((0, 0), (0, 0), false);
+    assert_eq!(
+        world.prefetch_region(ChunkCoord(100, 100), ChunkCoord(101, 102), 200),
+        6
+    );
+    world.update(Duration::ZERO);
+    let requested: FxHashSet<ChunkCoord> = world
+        .get_emitted_msgs()
+        .into_iter()
+        .filter_map(|msg| match msg.msg {
+            WorldNetMessage::RequestAuthority {
+                chunk, priority, ..
+            } => {
+                assert_eq!(priority, 200);
+                Some(chunk)
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(requested.len(), 6);
+    assert!(requested.contains(&ChunkCoord(101, 102)));
+    // Far away from the player, but kept for a while.
+    world.update(Duration::ZERO);
+    assert!(world.chunk_state.contains_key(&ChunkCoord(100, 100)));
+    world.current_update += PREFETCH_TIMEOUT;
+    world.update(Duration::ZERO);
+    assert!(!world.chunk_state.contains_key(&ChunkCoord(100, 100)));
+
+    let huge = world.prefetch_region(ChunkCoord(0, 0), ChunkCoord(1000, 1000), 200);
+    assert_eq!(huge, MAX_PREFETCH_CHUNKS);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_saved_chunk_template() {
+    let rock = RawPixel {
+        material: 1,
+        flags: PixelFlags::Normal,
+    };
+    let mut chunk = Chunk::default();
+    for i in 0..CHUNK_SIZE * CHUNK_SIZE {
+        chunk.set_pixel(i, rock);
+    }
+    // A few spots of something else.
+    for i in (0..CHUNK_SIZE * CHUNK_SIZE).step_by(97) {
+        chunk.set_pixel(
+            i,
+            RawPixel {
+                material: 2 + (i % 3) as u16,
+                flags: PixelFlags::Normal,
+            },
+        );
+    }
+    for chunk in [chunk.to_chunk_data(), ChunkData::new(1)] {
+        let saved = SavedChunkData::new(chunk.clone());
+        assert!(
+            bitcode::encode(&saved).len()
+                <= bitcode::encode(&SavedChunkData::Plain(chunk.clone())).len()
+        );
+        assert_eq!(
+            bitcode::encode(&saved.into_chunk_data()),
+            bitcode::encode(&chunk)
+        );
+    }
+    let spotted = SavedChunkData::new(chunk.to_chunk_data());
+    assert!(matches!(spotted, SavedChunkData::Template { .. }));
+    assert!(bitcode::encode(&spotted).len() < bitcode::encode(&chunk.to_chunk_data()).len());
+
+    let save_state = SaveState::new("/tmp/ew_tmp_save_template");
+    save_state.reset();
+    save_state.mark_game_started();
+    let (mut world, _, _, _, _) = WorldManager::new(true, OmniPeerId(0), save_state.clone());
+    world.chunk_storage.clear();
+    world
+        .chunk_storage
+        .insert(ChunkCoord(0, 0), chunk.to_chunk_data());
+    world
+        .chunk_storage
+        .insert(ChunkCoord(1, 0), ChunkData::new(3));
+    drop(world);
+    let (world, _, _, _, _) = WorldManager::new(false, OmniPeerId(0), save_state);
+    assert_eq!(
+        bitcode::encode(&*world.chunk_storage.get(&ChunkCoord(0, 0)).unwrap()),
+        bitcode::encode(&chunk.to_chunk_data())
+    );
+    assert_eq!(world.chunk_storage.len(), 2);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_boost_chunk_priority() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    world.set_position((0, 0), (0, 0), false);
+    let chunk = ChunkCoord(0, 0);
+    world.chunk_state.insert(
+        chunk,
+        ChunkState::Authority {
+            listeners: Default::default(),
+            priority: 10,
+            new_authority: None,
+            stop_sending: false,
+        },
+    );
+    let priority_changes = |world: &mut WorldManager| {
+        world
+            .get_emitted_msgs()
+            .into_iter()
+            .filter_map(|msg| match msg.msg {
+                WorldNetMessage::ChangePriority { priority, .. } => Some(priority),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    world.boost_chunk_priority(chunk, 4, 10);
+    world.boost_chunk_priority(chunk, 2, 5);
+    assert_eq!(priority_changes(&mut world), vec![4, 2]);
+
+    world.current_update += 5;
+    world.update(Duration::ZERO);
+    // Weaker boost is still there.
+    assert_eq!(priority_changes(&mut world), vec![4]);
+    world.chunk_updated_locally(chunk, 10, None, 0);
+    assert!(priority_changes(&mut world).is_empty());
+
+    world.current_update += 5;
+    world.update(Duration::ZERO);
+    assert_eq!(priority_changes(&mut world), vec![10]);
+    assert!(matches!(
+        world.chunk_state.get(&chunk),
+        Some(ChunkState::Authority { priority: 10, .. })
+    ));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_message_trace_replay() {
+    let new_host = || {
+        let (host, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        host
+    };
+    let mut host = new_host();
+    host.start_trace();
+    let request = |chunk, peer: u64| WorldNetMessage::RequestAuthority {
+        chunk,
+        priority: peer as u8,
+        can_wait: false,
+        hint: None,
+    };
+    host.handle_msg(OmniPeerId(1), request(ChunkCoord(0, 0), 1));
+    host.handle_msg(OmniPeerId(2), request(ChunkCoord(0, 0), 2));
+    host.handle_msg(OmniPeerId(2), request(ChunkCoord(1, 0), 2));
+    host.handle_msg(
+        OmniPeerId(1),
+        WorldNetMessage::RelinquishAuthority {
+            chunk: ChunkCoord(0, 0),
+            chunk_data: Some(ChunkData::new(1)),
+            world_num: 0,
+        },
+    );
+    let trace = host.take_trace().unwrap();
+    assert_eq!(trace.steps.len(), 4);
+    assert!(trace.steps.iter().all(|step| !step.emitted.is_empty()));
+    let trace: MessageTrace = bitcode::decode(&bitcode::encode(&trace)).unwrap();
+
+    assert_eq!(trace.replay(&mut new_host()), Ok(()));
+    // Not a fresh world anymore, so chunks are taken already.
+    assert_eq!(trace.replay(&mut host), Err(0));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_unload_per_update() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    world.unload_per_update = Some(30);
+    let chunks: Vec<ChunkCoord> = (0..100).map(|i| ChunkCoord(i, 50)).collect();
+    for &chunk in &chunks {
+        world
+            .outbound_model
+            .apply_chunk_data(chunk, &ChunkData::new(1))
+            .unwrap();
+        world.chunk_state.insert(chunk, ChunkState::UnloadPending);
+    }
+    world
+        .outbound_model
+        .reset_change_tracking_except(&Default::default());
+    let mut remaining = Vec::new();
+    for _ in 0..4 {
+        world.update(Duration::ZERO);
+        // Unloaded right away, only cleanup is spread out.
+        assert!(world.chunk_state.is_empty());
+        assert!(world.get_emitted_msgs().is_empty());
+        remaining.push(
+            chunks
+                .iter()
+                .filter(|chunk| world.outbound_model.get_chunk_data(**chunk).is_some())
+                .count(),
+        );
+    }
+    assert_eq!(remaining, vec![70, 40, 10, 0]);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_mask() {
+    let new_world = || {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
+        world
+            .materials
+            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+        world.materials.insert(
+            2,
+            (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0, 0),
+        );
+        for i in -2..4 {
+            for j in -2..2 {
+                let mat = if i == 2 { 2 } else { 1 };
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), ChunkData::new(mat));
+            }
+        }
+        world
+    };
+    let encoded = |world: &WorldManager| {
+        let mut chunks: Vec<_> = world
+            .chunk_storage
+            .iter()
+            .map(|(coord, data)| (*coord, bitcode::encode(&*data)))
+            .collect();
+        chunks.sort_by_key(|(coord, _)| (coord.0, coord.1));
+        chunks
+    };
+    let mask = compute_explosion_mask(60, 10, 1_000_000, None);
+    let mut masked = new_world();
+    masked.apply_explosion_mask(0, 0, &mask);
+    masked.apply_explosion_mask(300, 0, &mask);
+
+    let mut regular = new_world();
+    let explosion = |x| ExplosionData {
+        x,
+        ..mask.explosion
+    };
+    regular.cut_through_world_explosion(vec![explosion(0), explosion(300)]);
+    assert_eq!(encoded(&masked), encoded(&regular));
+
+    let material_at = |x: i32, y: i32| {
+        let mut chunk = Chunk::default();
+        let coord = ChunkCoord(
+            x.div_euclid(CHUNK_SIZE as i32),
+            y.div_euclid(CHUNK_SIZE as i32),
+        );
+        masked
+            .chunk_storage
+            .get(&coord)
+            .unwrap()
+            .apply_to_chunk(&mut chunk);
+        let px = y.rem_euclid(CHUNK_SIZE as i32) as usize * CHUNK_SIZE
+            + x.rem_euclid(CHUNK_SIZE as i32) as usize;
+        chunk.pixel(px).material
+    };
+    assert_eq!(material_at(20, 20), 0);
+    // Second one is in terrain that's too durable.
+    assert_eq!(material_at(310, 10), 2);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_chunk_modified_at() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    world.chunk_modified.clear();
+    world
+        .materials
+        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+    let chunk = ChunkCoord(0, 0);
+    world.chunk_storage.insert(chunk, ChunkData::new(1));
+    assert_eq!(
+        world.chunk_modified_at(chunk),
+        Some(UNKNOWN_MODIFICATION_TIME)
+    );
+    assert_eq!(world.chunk_modified_at(ChunkCoord(5, 5)), None);
+
+    world.cut_through_world_circle(20, 20, 5, None, 100, 100, 0, None);
+    let first = world.chunk_modified_at(chunk).unwrap();
+    assert!(first > UNKNOWN_MODIFICATION_TIME);
+
+    thread::sleep(Duration::from_millis(5));
+    world.cut_through_world_circle(40, 40, 5, None, 100, 100, 0, None);
+    assert!(world.chunk_modified_at(chunk).unwrap() > first);
+
+    let region = world.export_region(chunk, chunk);
+    world.import_region(&region, chunk, ImportPolicy::Overwrite);
+    assert_eq!(
+        world.chunk_modified_at(chunk),
+        Some(UNKNOWN_MODIFICATION_TIME)
+    );
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_untracked_take_auth() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let authority = OmniPeerId(2);
+    let update = |chunk| WorldNetMessage::ListenUpdate {
+        delta: ChunkDelta::new(chunk, [None]),
+        priority: 5,
+        take_auth: true,
+    };
+    let untracked = ChunkCoord(40, 40);
+    peer.handle_msg(authority, update(untracked));
+    assert!(matches!(
+        peer.get_emitted_msgs().as_slice(),
+        [MessageRequest {
+            msg: WorldNetMessage::LoseAuthority { chunk, new_authority, .. },
+            ..
+        }] if *chunk == untracked && *new_authority == authority
+    ));
+    assert!(!peer.chunk_state.contains_key(&untracked));
+
+    peer.untracked_take_auth = UntrackedTakeAuth::Unload;
+    peer.handle_msg(authority, update(untracked));
+    assert!(matches!(
+        peer.get_emitted_msgs().as_slice(),
+        [MessageRequest {
+            msg: WorldNetMessage::LoseAuthority { chunk: declined, .. },
+            ..
+        }, MessageRequest {
+            msg: WorldNetMessage::ListenStopRequest { chunk },
+            ..
+        }] if *chunk == untracked && *declined == untracked
+    ));
+
+    // Chunks we've updated ourselves still get taken over.
+    let tracked = ChunkCoord(0, 0);
+    peer.chunk_last_update.insert(tracked, 0);
+    peer.handle_msg(authority, update(tracked));
+    assert!(matches!(
+        peer.get_emitted_msgs().as_slice(),
+        [MessageRequest {
+            msg: WorldNetMessage::LoseAuthority { chunk, .. },
+            ..
+        }] if *chunk == tracked
+    ));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_declined_take_auth_resumes_updates() {
+    let (mut authority, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut untracked, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(2), SaveState::new("/tmp/ew_tmp_save"));
+    let listener = OmniPeerId(3);
+    let chunk = ChunkCoord(0, 0);
+    authority
+        .outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+    authority
+        .outbound_model
+        .reset_change_tracking_except(&Default::default());
+    let change = |authority: &mut WorldManager, material| {
+        let runs = vec![PixelRun {
+            length: 1,
+            data: RawPixel {
+                material,
+                flags: PixelFlags::Normal,
+            },
+        }];
+        authority.handle_noita_msg(
+            OmniPeerId(1),
+            WorldSyncToProxy::Updates(vec![NoitaWorldUpdate { coord: chunk, runs }]),
+        );
+        authority.handle_noita_msg(OmniPeerId(1), WorldSyncToProxy::End(None, 10, 0));
+    };
+    let sent_to_listener = |authority: &mut WorldManager| {
+        authority
+            .get_emitted_msgs()
+            .into_iter()
+            .filter(|request| request.dst == Destination::Peer(listener))
+            .count()
+    };
+    for untracked_take_auth in [UntrackedTakeAuth::Ignore, UntrackedTakeAuth::Unload] {
+        untracked.untracked_take_auth = untracked_take_auth;
+        authority.chunk_state.insert(
+            chunk,
+            ChunkState::Authority {
+                listeners: [untracked.my_peer_id, listener].into_iter().collect(),
+                priority: 10,
+                new_authority: Some((untracked.my_peer_id, 0)),
+                stop_sending: false,
+            },
+        );
+        change(&mut authority, 4);
+        for request in authority.get_emitted_msgs() {
+            if request.dst == Destination::Peer(untracked.my_peer_id) {
+                untracked.handle_msg(authority.my_peer_id, request.msg);
+            }
+        }
+        assert!(!untracked.chunk_state.contains_key(&chunk));
+        for request in untracked.get_emitted_msgs() {
+            authority.handle_msg(untracked.my_peer_id, request.msg);
+        }
+        change(&mut authority, 5);
+        assert!(
+            sent_to_listener(&mut authority) > 0,
+            "{untracked_take_auth:?} left listeners without updates"
+        );
+    }
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_cold_storage() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    world.set_cold_storage_after(Some(10));
+    let (cold, hot) = (ChunkCoord(0, 0), ChunkCoord(1, 0));
+    let chunk = ChunkData::make_random_seeded(1);
+    world.chunk_storage.insert(cold, chunk.clone());
+    world.chunk_storage.insert(hot, ChunkData::new(1));
+    for _ in 0..10 {
+        world.current_update += 1;
+        world.chunk_storage.get_mut(&hot);
+        world.update(Duration::ZERO);
+    }
+    assert!(world.chunk_storage.is_compressed(&cold));
+    assert!(!world.chunk_storage.is_compressed(&hot));
+    assert_eq!(
+        bitcode::encode(&*world.chunk_storage.get(&cold).unwrap()),
+        bitcode::encode(&chunk)
+    );
+    assert_eq!(world.chunk_storage.len(), 2);
+
+    // Changing it makes it hot again.
+    world.chunk_storage.get_mut(&cold);
+    assert!(!world.chunk_storage.is_compressed(&cold));
+    assert_eq!(
+        bitcode::encode(&world.get_chunks()[&cold]),
+        bitcode::encode(&chunk)
+    );
+
+    // Explosions decompress chunks in their reach before casting rays.
+    let far = ChunkCoord(10, 10);
+    world.chunk_storage.insert(far, ChunkData::new(1));
+    for _ in 0..10 {
+        world.current_update += 1;
+        world.update(Duration::ZERO);
+    }
+    assert!(world.chunk_storage.is_compressed(&cold));
+    assert!(world.chunk_storage.is_compressed(&far));
+    world.cut_through_world_explosion(vec![ExplosionData::new(
+        CHUNK_SIZE as i32 / 2,
+        CHUNK_SIZE as i32 / 2,
+        8,
+        0,
+        0,
+        true,
+        false,
+        0,
+        100,
+    )]);
+    assert!(!world.chunk_storage.is_compressed(&cold));
+    assert!(world.chunk_storage.is_compressed(&far));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_peer_left_grace() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.peer_left_grace = 10;
+    let peer = OmniPeerId(1);
+    let request = |chunk| WorldNetMessage::RequestAuthority {
+        chunk,
+        priority: 0,
+        can_wait: true,
+        hint: None,
+    };
+    let (a, b) = (ChunkCoord(0, 0), ChunkCoord(1, 0));
+    host.handle_msg(peer, request(a));
+    host.handle_msg(peer, request(b));
+
+    // Comes back in time.
+    host.handle_peer_left(peer);
+    host.current_update += 5;
+    host.update(Duration::ZERO);
+    assert_eq!(host.authority_map.get(&a).map(|(p, _)| *p), Some(peer));
+    host.handle_msg(
+        peer,
+        WorldNetMessage::ChangePriority {
+            chunk: a,
+            priority: 0,
+        },
+    );
+    host.current_update += 20;
+    host.update(Duration::ZERO);
+    assert_eq!(host.authority_map.get(&a).map(|(p, _)| *p), Some(peer));
+
+    // Someone else doesn't have to wait for the grace period.
+    host.handle_peer_left(peer);
+    host.handle_msg(OmniPeerId(2), request(b));
+    assert_eq!(
+        host.authority_map.get(&b).map(|(p, _)| *p),
+        Some(OmniPeerId(2))
+    );
+    assert_eq!(host.authority_map.get(&a).map(|(p, _)| *p), Some(peer));
+
+    // Doesn't come back.
+    host.current_update += 10;
+    host.update(Duration::ZERO);
+    assert!(!host.authority_map.contains_key(&a));
+    assert!(host.orphaning_peers.is_empty());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_terraform_batch_undo() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    for i in 0..3 {
+        world
+            .chunk_storage
+            .insert(ChunkCoord(i, 0), ChunkData::new(1));
+    }
+    let encoded = |world: &WorldManager| {
+        let mut chunks: Vec<_> = world
+            .chunk_storage
+            .iter()
+            .map(|(coord, data)| (*coord, bitcode::encode(&*data)))
+            .collect();
+        chunks.sort_by_key(|(coord, _)| (coord.0, coord.1));
+        chunks
+    };
+    let original = encoded(&world);
+    let undo = world.terraform_batch(&[
+        TerraformOp::Circle {
+            x: 20,
+            y: 20,
+            r: 10,
+            mat: None,
+            density: 100,
+            chance: 100,
+            seed: 0,
+            power: None,
+        },
+        TerraformOp::Rect {
+            min: (CHUNK_SIZE as i32 * 2, 0),
+            max: (CHUNK_SIZE as i32 * 2 + 10, 10),
+            material: 2,
+        },
+    ]);
+    assert_eq!(undo.chunks.len(), 2);
+    let changed = encoded(&world);
+    assert_ne!(changed[0], original[0]);
+    assert_eq!(changed[1], original[1]);
+    assert_ne!(changed[2], original[2]);
+
+    world.undo_terraform(undo);
+    assert_eq!(encoded(&world), original);
+
+    // Chunks changed after the batch keep their changes.
+    let undo = world.terraform_batch(&[TerraformOp::Rect {
+        min: (0, 0),
+        max: (CHUNK_SIZE as i32 * 2 + 10, 10),
+        material: 2,
+    }]);
+    world.fill_rect((0, 20), (10, 30), 3);
+    let later = encoded(&world);
+    world.undo_terraform(undo);
+    let undone = encoded(&world);
+    assert_eq!(undone[0], later[0]);
+    assert_eq!(undone[1], original[1]);
+    assert_eq!(undone[2], original[2]);
+
+    // Huge operations neither overflow nor go through every chunk in the world.
+    let undo = world.terraform_batch(&[TerraformOp::Line {
+        x: i32::MAX,
+        y: i32::MIN,
+        lx: i32::MIN,
+        ly: i32::MAX,
+        r: i32::MAX,
+        chance: 0,
+        seed: 0,
+        power: None,
+    }]);
+    assert!(undo.chunks.is_empty());
+    assert_eq!(encoded(&world), undone);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_templated_chunk_packet() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let band: Vec<ChunkCoord> = (0..4).map(|i| ChunkCoord(i, 0)).collect();
+    let other = ChunkCoord(0, 1);
+    for &chunk in band.iter().chain([&other]) {
+        let mut state = ChunkState::authority(0);
+        if let ChunkState::Authority { listeners, .. } = &mut state {
+            listeners.insert(OmniPeerId(1));
+        }
+        world.chunk_state.insert(chunk, state);
+    }
+    let update = |coord, length| NoitaWorldUpdate {
+        coord,
+        runs: vec![PixelRun {
+            length,
+            data: RawPixel {
+                material: 1,
+                flags: PixelFlags::Normal,
+            },
+        }],
+    };
+    let mut updates: Vec<_> = band
+        .iter()
+        .map(|&coord| update(coord, CHUNK_SIZE as u16 * 8))
+        .collect();
+    updates.push(update(other, 3));
+    world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::Updates(updates));
+    world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::End(None, 0, 0));
+    let msgs = world.get_emitted_msgs();
+    let [
+        MessageRequest {
+            msg:
+                msg @ WorldNetMessage::ChunkPacket {
+                    chunkpacket,
+                    templated,
+                },
+            ..
+        },
+    ] = msgs.as_slice()
+    else {
+        panic!("expected a single chunk packet")
+    };
+    assert_eq!(chunkpacket.len(), 1);
+    assert_eq!(chunkpacket[0].0.chunk_coord, other);
+    assert_eq!(templated.len(), 1);
+    let mut templated_chunks: Vec<_> = templated[0].chunks.iter().map(|(c, _)| *c).collect();
+    templated_chunks.sort_by_key(|c| c.0);
+    assert_eq!(templated_chunks, band);
+
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    for &chunk in band.iter().chain([&other]) {
+        peer.chunk_state.insert(
+            chunk,
+            ChunkState::Listening {
+                authority: OmniPeerId(0),
+                priority: 0,
+            },
+        );
+    }
+    peer.handle_msg(OmniPeerId(0), msg.clone());
+    for &chunk in &band {
+        assert_eq!(
+            bitcode::encode(&peer.inbound_model.get_chunk_data(chunk).unwrap()),
+            bitcode::encode(&world.outbound_model.get_chunk_data(chunk).unwrap())
+        );
+    }
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_min_takeover_priority_gap() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    peer.min_takeover_priority_gap = 2;
+    let chunk = ChunkCoord(0, 0);
+    let authority = OmniPeerId(2);
+    peer.chunk_state.insert(
+        chunk,
+        ChunkState::Listening {
+            authority,
+            priority: 5,
+        },
+    );
+    let update = |peer: &mut WorldManager, priority| {
+        peer.handle_noita_msg(
+            OmniPeerId(1),
+            WorldSyncToProxy::Updates(vec![NoitaWorldUpdate {
+                coord: chunk,
+                runs: vec![PixelRun {
+                    length: 1,
+                    data: RawPixel {
+                        material: priority as u16,
+                        flags: PixelFlags::Normal,
+                    },
+                }],
+            }]),
+        );
+        peer.handle_noita_msg(OmniPeerId(1), WorldSyncToProxy::End(None, priority, 0));
+    };
+    update(&mut peer, 4);
+    assert_eq!(
+        peer.chunk_state.get(&chunk),
+        Some(&ChunkState::Listening {
+            authority,
+            priority: 5
+        })
+    );
+    assert!(
+        !peer
+            .get_emitted_msgs()
+            .iter()
+            .any(|m| matches!(m.msg, WorldNetMessage::LoseAuthority { .. }))
+    );
+
+    update(&mut peer, 3);
+    assert!(matches!(
+        peer.chunk_state.get(&chunk),
+        Some(ChunkState::WantToGetAuth { .. })
+    ));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_active_sync_bounds() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    for (my_pos, cam_pos, is_notplayer) in [
+        ((0, 0), (0, 0), false),
+        ((5, -3), (6, -2), true),
+        ((0, 0), (10, 10), false),
+    ] {
+        world.set_position(my_pos, cam_pos, is_notplayer);
+        let (min, max) = world.active_sync_bounds();
+        let camera_far = my_pos.0.abs_diff(cam_pos.0) > 2 || my_pos.1.abs_diff(cam_pos.1) > 2;
+        for cx in -8..=16 {
+            for cy in -8..=16 {
+                let in_bounds = (min.0..=max.0).contains(&cx) && (min.1..=max.1).contains(&cy);
+                let kill = should_kill(my_pos, cam_pos, cx, cy, world.player_sync_radius());
+                if in_bounds {
+                    assert!(
+                        !kill,
+                        "({cx}, {cy}) is in sync bounds but would be unloaded"
+                    );
+                } else if !camera_far {
+                    assert!(
+                        kill,
+                        "({cx}, {cy}) is outside of sync bounds but would be kept"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_save_checksums() {
+    let save_state = SaveState::new("/tmp/ew_tmp_save_checksums");
+    save_state.reset();
+    save_state.mark_game_started();
+    let (mut world, _, _, _, _) = WorldManager::new(true, OmniPeerId(0), save_state.clone());
+    world.chunk_storage.clear();
+    for i in 0..3 {
+        world
+            .chunk_storage
+            .insert(ChunkCoord(i, 0), ChunkData::new(i as u16 + 1));
+    }
+    drop(world);
+
+    let path = std::path::Path::new("/tmp/ew_tmp_save_checksums/world_chunks_timed.bit");
+    let mut save = std::fs::read(path).unwrap();
+    // Skip header and the first entry, then damage the second one.
+    let entry_len = |at: usize| u32::from_le_bytes(save[at..at + 4].try_into().unwrap()) as usize;
+    let second = 8 + 8 + entry_len(8);
+    let payload = second + 8..second + 8 + entry_len(second);
+    let (damaged, _, _): (ChunkCoord, SavedChunkData, u64) =
+        bitcode::decode(&lz4_flex::decompress_size_prepended(&save[payload.clone()]).unwrap())
+            .unwrap();
+    save[payload.end - 1] ^= 0xff;
+    std::fs::write(path, &save).unwrap();
+
+    let (world, _, _, _, _) = WorldManager::new(true, OmniPeerId(0), save_state.clone());
+    assert_eq!(world.chunk_storage.len(), 2);
+    assert!(!world.chunk_storage.contains_key(&damaged));
+    assert!(path.with_extension("bit.corrupt").exists());
+    drop(world);
+
+    // Saves from before checksums are loaded without them.
+    let mut old_save = Vec::new();
+    for i in 0..2 {
+        let entry = (
+            ChunkCoord(i, 0),
+            SavedChunkData::new(ChunkData::new(1)),
+            0u64,
+        );
+        let compressed = lz4_flex::compress_prepend_size(&bitcode::encode(&entry));
+        old_save.extend((compressed.len() as u32).to_le_bytes());
+        old_save.extend(compressed);
+    }
+    std::fs::write(path, &old_save).unwrap();
+    let (world, _, _, _, _) = WorldManager::new(true, OmniPeerId(0), save_state);
+    assert_eq!(world.chunk_storage.len(), 2);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_max_requests_per_update() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    world.set_position((0, 0), (0, 0), false);
+    world.max_requests_per_update = Some(4);
+    for cx in -3..=3 {
+        for cy in -3..=3 {
+            world.chunk_state.insert(
+                ChunkCoord(cx, cy),
+                ChunkState::RequestAuthority {
+                    priority: 0,
+                    can_wait: true,
+                },
+            );
         }
     }
-
-    #[cfg(test)]
-    pub(crate) fn _create_image(&self, image: &mut image::GrayImage, w: u32) {
-        let mut working_chunk = Chunk::default();
-        let mut last_co = ChunkCoord(i32::MIN, i32::MIN);
-        for (i, px) in image.pixels_mut().enumerate() {
-            let x = i % w as usize;
-            let x = x as i32 - (w as i32 / 2);
-            let y = i / w as usize;
-            let y = y as i32 - (w as i32 / 2);
-            let coord = ChunkCoord(
-                x.div_euclid(CHUNK_SIZE as i32),
-                y.div_euclid(CHUNK_SIZE as i32),
-            );
-            let icx = x.rem_euclid(CHUNK_SIZE as i32);
-            let icy = y.rem_euclid(CHUNK_SIZE as i32);
-            if last_co != coord {
-                if let Some(c) = self.outbound_model.get_chunk_data(coord) {
-                    c.apply_to_chunk(&mut working_chunk)
-                } else if let Some(c) = self.chunk_storage.get(&coord) {
-                    c.apply_to_chunk(&mut working_chunk)
-                }
-                last_co = coord
+    let mut requested = Vec::new();
+    for tick in 0..13 {
+        world.update(Duration::ZERO);
+        let before = requested.len();
+        for msg in world.get_emitted_msgs() {
+            if let WorldNetMessage::RequestAuthority { chunk, .. } = msg.msg {
+                requested.push(chunk);
             }
-            let p = icy as usize * CHUNK_SIZE + icx as usize;
-            *px = image::Luma([
-                ((working_chunk.pixel(p).material * 255) as usize / self.materials.len()) as u8
-            ])
+        }
+        assert!(requested.len() - before <= 4);
+        if tick == 0 {
+            let mut first: Vec<_> = requested.clone();
+            first.sort_by_key(|c| (c.0, c.1));
+            assert_eq!(
+                first,
+                [
+                    ChunkCoord(-1, 0),
+                    ChunkCoord(0, -1),
+                    ChunkCoord(0, 0),
+                    ChunkCoord(0, 1)
+                ]
+            );
         }
     }
+    assert_eq!(requested.len(), 49);
 }
-fn create_image(chunk: ChunkData, materials: &FxHashMap<u16, u32>) -> RgbaImage {
-    let mut working_chunk = Chunk::default();
-    chunk.apply_to_chunk(&mut working_chunk);
-    let w = 128;
-    let mut image = RgbaImage::new(w as u32, w as u32);
-    for (i, px) in image.pixels_mut().enumerate() {
-        let x = i % w as usize;
-        let y = i / w as usize;
-        let p = y * CHUNK_SIZE + x;
-        let m = working_chunk.pixel(p);
-        if m.flags != PixelFlags::Unknown
-            && let Some(c) = materials.get(&m.material)
-        {
-            let a = (c >> 24) & 0xFFu32;
-            let r = (c >> 16) & 0xFFu32;
-            let g = (c >> 8) & 0xFFu32;
-            let b = c & 0xFF;
-            *px = image::Rgba([r as u8, g as u8, b as u8, a as u8])
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_preview_cut() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    for cx in -2..=2 {
+        for cy in -2..=2 {
+            world
+                .chunk_storage
+                .insert(ChunkCoord(cx, cy), ChunkData::new(1));
         }
     }
-    image
+    let before = world.get_chunks();
+    let op = TerraformOp::Circle {
+        x: 0,
+        y: 0,
+        r: 10,
+        mat: None,
+        density: 100,
+        chance: 100,
+        seed: 0,
+        power: None,
+    };
+    let preview = world.preview_cut(&op).unwrap();
+    let mut previewed: Vec<_> = preview.keys().copied().collect();
+    previewed.sort_by_key(|c| (c.0, c.1));
+    assert_eq!(
+        previewed,
+        [
+            ChunkCoord(-1, -1),
+            ChunkCoord(-1, 0),
+            ChunkCoord(0, -1),
+            ChunkCoord(0, 0)
+        ]
+    );
+    let mut carved = Chunk::default();
+    preview[&ChunkCoord(0, 0)].apply_to_chunk(&mut carved);
+    assert_eq!(carved.pixel(0).material, 0);
+    assert_eq!(carved.pixel(CHUNK_SIZE * 20 + 20).material, 1);
+
+    for (chunk, data) in world.get_chunks() {
+        assert_eq!(bitcode::encode(&data), bitcode::encode(&before[&chunk]));
+    }
+    assert!(world.chunk_modified.is_empty());
+
+    world.apply_terraform(&op);
+    for (chunk, data) in &preview {
+        assert_eq!(
+            bitcode::encode(&*world.chunk_storage.get(chunk).unwrap()),
+            bitcode::encode(data)
+        );
+    }
 }
-#[allow(clippy::too_many_arguments)]
-fn should_process_chunk(
-    chunk_x: i32,
-    chunk_y: i32,
-    x: i32,
-    y: i32,
-    r: u64,
-    list: &[u64],
-    chunkx: i32,
-    chunky: i32,
-    rays: u64,
-    rs: u64,
-) -> bool {
-    r <= CHUNK_SIZE as u64 || (chunkx, chunky) == (chunk_x, chunk_y) || {
-        {
-            let d = min_dist(x, y, chunkx, chunky, chunk_x, chunk_y);
-            d <= rs && {
-                let (i, j) = find_rays(x, y, chunkx, chunky, chunk_x, chunk_y, rays);
-                let r = list[i..=j].iter().max().unwrap_or(&0);
-                d <= *r
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_self_listen_ignored() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(0, 0);
+    world.chunk_state.insert(chunk, ChunkState::authority(0));
+    world.handle_msg(OmniPeerId(1), WorldNetMessage::ListenRequest { chunk });
+    let Some(ChunkState::Authority { listeners, .. }) = world.chunk_state.get(&chunk) else {
+        panic!("Lost authority of {chunk:?}");
+    };
+    assert!(listeners.is_empty());
+    assert!(world.get_emitted_msgs().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_cut_power() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    world.materials.insert(1, (6, 2000, CellType::Solid, 0, 0));
+    world
+        .materials
+        .insert(2, (14, 1_000_000, CellType::Solid, 0, 0));
+    let bedrock = (0..CHUNK_SIZE as i32).map(|x| (x, 10));
+    world.fill_rect((0, 0), (CHUNK_SIZE as i32 - 1, CHUNK_SIZE as i32 - 1), 1);
+    world.fill_rect((0, 10), (CHUNK_SIZE as i32 - 1, 10), 2);
+    let material_at = |world: &WorldManager, (x, y): (i32, i32)| {
+        let mut chunk = Chunk::default();
+        world
+            .chunk_storage
+            .get(&ChunkCoord(0, 0))
+            .unwrap()
+            .apply_to_chunk(&mut chunk);
+        chunk.pixel(y as usize * CHUNK_SIZE + x as usize).material
+    };
+
+    world.cut_through_world_line(20, 0, 20, 20, 3, 100, 0, Some(10));
+    assert_eq!(material_at(&world, (20, 5)), 0);
+    assert_eq!(material_at(&world, (20, 15)), 0);
+    for pos in bedrock.clone() {
+        assert_eq!(material_at(&world, pos), 2);
+    }
+
+    // Filling is blocked by hard pixels as well.
+    world.cut_through_world_circle(40, 10, 5, Some(3), 100, 100, 0, Some(10));
+    assert_eq!(material_at(&world, (40, 8)), 3);
+    assert_eq!(material_at(&world, (40, 10)), 2);
+
+    world.cut_through_world_circle(40, 10, 5, None, 100, 100, 0, Some(20));
+    assert_eq!(material_at(&world, (40, 10)), 0);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_suspend_sending() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(0, 0);
+    let mut state = ChunkState::authority(0);
+    if let ChunkState::Authority { listeners, .. } = &mut state {
+        listeners.insert(OmniPeerId(1));
+    }
+    world.chunk_state.insert(chunk, state);
+    assert!(world.suspend_sending(chunk));
+    assert!(!world.suspend_sending(ChunkCoord(1, 0)));
+
+    let pixel = |material| PixelRun {
+        length: 1,
+        data: RawPixel {
+            material,
+            flags: PixelFlags::Normal,
+        },
+    };
+    for runs in [vec![pixel(1)], vec![pixel(1), pixel(2)]] {
+        world.handle_noita_msg(
+            OmniPeerId(0),
+            WorldSyncToProxy::Updates(vec![NoitaWorldUpdate { coord: chunk, runs }]),
+        );
+        world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::End(None, 0, 0));
+        assert!(world.get_emitted_msgs().is_empty());
+    }
+
+    world.resume_sending(chunk);
+    world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::End(None, 0, 0));
+    let msgs = world.get_emitted_msgs();
+    let [
+        MessageRequest {
+            msg: msg @ WorldNetMessage::ChunkPacket { chunkpacket, .. },
+            dst: Destination::Peer(OmniPeerId(1)),
+            ..
+        },
+    ] = msgs.as_slice()
+    else {
+        panic!("expected a single chunk packet")
+    };
+    assert_eq!(chunkpacket.len(), 1);
+
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    peer.chunk_state.insert(
+        chunk,
+        ChunkState::Listening {
+            authority: OmniPeerId(0),
+            priority: 0,
+        },
+    );
+    peer.handle_msg(OmniPeerId(0), msg.clone());
+    assert_eq!(
+        bitcode::encode(&peer.inbound_model.get_chunk_data(chunk).unwrap()),
+        bitcode::encode(&world.outbound_model.get_chunk_data(chunk).unwrap())
+    );
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_transfer_backoff() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    peer.set_position((0, 0), (0, 0), false);
+    peer.transfer_retry_delay = 2;
+    peer.max_transfer_retries = 3;
+    let chunk = ChunkCoord(0, 0);
+    let authority = OmniPeerId(2);
+    let mut intervals = Vec::new();
+    for _ in 0..4 {
+        peer.chunk_state
+            .insert(chunk, ChunkState::Transfer { authority });
+        peer.handle_msg(authority, WorldNetMessage::TransferFailed { chunk });
+        peer.get_emitted_msgs();
+        let mut waited = 0;
+        loop {
+            peer.update(Duration::ZERO);
+            let requested = peer.get_emitted_msgs().iter().any(|m| {
+                matches!(m.msg, WorldNetMessage::RequestAuthority { chunk: c, .. } if c == chunk)
+            });
+            if requested {
+                break;
             }
+            waited += 1;
+            peer.current_update += 1;
         }
+        intervals.push(waited);
+    }
+    assert_eq!(intervals, [2, 4, 8, 0]);
+
+    // Gave up, so a better priority doesn't lead to another transfer.
+    peer.chunk_state.insert(
+        chunk,
+        ChunkState::Listening {
+            authority,
+            priority: 10,
+        },
+    );
+    peer.chunk_updated_locally(chunk, 0, None, 0);
+    assert!(matches!(
+        peer.chunk_state.get(&chunk),
+        Some(ChunkState::Listening { .. })
+    ));
+
+    peer.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::GotAuthority {
+            chunk,
+            chunk_data: None,
+            priority: 0,
+            listeners: Default::default(),
+        },
+    );
+    assert!(peer.transfer_backoff.is_empty());
+
+    // Delay stops growing instead of overflowing.
+    peer.transfer_retry_delay = u64::MAX / 2;
+    peer.max_transfer_retries = u32::MAX;
+    for _ in 0..70 {
+        peer.transfer_failed(chunk);
     }
+    assert_eq!(
+        peer.transfer_backoff[&chunk].1,
+        peer.current_update + MAX_TRANSFER_RETRY_DELAY
+    );
 }
-impl Drop for WorldManager {
-    fn drop(&mut self) {
-        if self.is_host {
-            self.save_state.save(&self.chunk_storage);
-            info!("Saved chunk data");
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_render_region() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    world
+        .chunk_storage
+        .insert(ChunkCoord(0, 0), ChunkData::new(1));
+    world
+        .outbound_model
+        .apply_chunk_data(ChunkCoord(1, 0), &ChunkData::new(2))
+        .unwrap();
+    let colors: FxHashMap<u16, u32> = [(1, 0xff102030), (2, 0x80405060)].into_iter().collect();
+    let image = world
+        .render_region(ChunkCoord(0, 0), ChunkCoord(1, 1), &colors)
+        .unwrap();
+    assert_eq!(
+        image.dimensions(),
+        (2 * CHUNK_SIZE as u32, 2 * CHUNK_SIZE as u32)
+    );
+    let size = CHUNK_SIZE as u32;
+    assert_eq!(
+        image.get_pixel(5, 5),
+        &image::Rgba([0x10, 0x20, 0x30, 0xff])
+    );
+    assert_eq!(
+        image.get_pixel(size + 5, 5),
+        &image::Rgba([0x40, 0x50, 0x60, 0x80])
+    );
+    assert_eq!(image.get_pixel(5, size + 5), &UNKNOWN_CHUNK_COLOR);
+    assert_eq!(image.get_pixel(size + 5, size + 5), &UNKNOWN_CHUNK_COLOR);
+
+    assert!(
+        world
+            .render_region(ChunkCoord(0, 0), ChunkCoord(1000, 1000), &colors)
+            .is_none()
+    );
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_tick_duration() {
+    let chunk = ChunkCoord(0, 0);
+    let retried_after = |dt: Duration| {
+        let (mut peer, _, _, _, _) =
+            WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+        peer.set_position((0, 0), (0, 0), false);
+        peer.tick_duration = Some(Duration::from_millis(10));
+        peer.transfer_retry_delay = 5;
+        peer.chunk_state.insert(
+            chunk,
+            ChunkState::Transfer {
+                authority: OmniPeerId(2),
+            },
+        );
+        peer.handle_msg(OmniPeerId(2), WorldNetMessage::TransferFailed { chunk });
+        peer.get_emitted_msgs();
+        let mut elapsed = Duration::ZERO;
+        loop {
+            elapsed += dt;
+            peer.update(dt);
+            let retried = peer
+                .get_emitted_msgs()
+                .iter()
+                .any(|m| matches!(m.msg, WorldNetMessage::RequestAuthority { .. }));
+            if retried {
+                return elapsed;
+            }
         }
-    }
+    };
+    assert_eq!(
+        retried_after(Duration::from_millis(5)),
+        Duration::from_millis(50)
+    );
+    assert_eq!(
+        retried_after(Duration::from_millis(25)),
+        Duration::from_millis(50)
+    );
 }
-impl SaveStateEntry for FxHashMap<ChunkCoord, ChunkData> {
-    const FILENAME: &'static str = "world_chunks";
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_pending_by_destination() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let listen = |x| WorldNetMessage::ListenRequest {
+        chunk: ChunkCoord(x, 0),
+    };
+    world.emit_msg(Destination::Peer(OmniPeerId(1)), listen(0));
+    world.emit_msg(Destination::Peer(OmniPeerId(1)), listen(1));
+    world.emit_msg(Destination::Peer(OmniPeerId(2)), listen(2));
+    world.emit_msg(Destination::Broadcast, listen(3));
+    let pending = world.pending_by_destination();
+    assert_eq!(pending.len(), 3);
+    let single = bitcode::encode(&listen(0)).len();
+    assert_eq!(pending[&Destination::Peer(OmniPeerId(1))], (2, 2 * single));
+    assert_eq!(pending[&Destination::Peer(OmniPeerId(2))], (1, single));
+    assert_eq!(pending[&Destination::Broadcast], (1, single));
+    assert_eq!(world.get_emitted_msgs().len(), 4);
+    assert!(world.pending_by_destination().is_empty());
 }
-pub(crate) struct ExRet {
-    loaded: Option<(ChunkCoord, ChunkData, bool, bool)>,
-    unloaded: Option<(ChunkCoord, Vec<usize>)>,
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_got_authority_without_data_clears_models() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(0, 0);
+    peer.inbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+    peer.outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(2))
+        .unwrap();
+    peer.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::GotAuthority {
+            chunk,
+            chunk_data: None,
+            priority: 0,
+            listeners: Default::default(),
+        },
+    );
+    assert!(matches!(
+        peer.chunk_state.get(&chunk),
+        Some(ChunkState::Authority { .. })
+    ));
+    assert!(peer.inbound_model.get_chunk_data(chunk).is_none());
+    assert!(peer.outbound_model.get_chunk_data(chunk).is_none());
 }
-fn find_rays(
-    x: i32,
-    y: i32,
-    chunkx: i32,
-    chunky: i32,
-    chunk_x: i32,
-    chunk_y: i32,
-    rays: u64,
-) -> (usize, usize) {
-    let (adj_x1, adj_x2) = (
-        chunk_x * CHUNK_SIZE as i32,
-        (chunk_x + 1) * CHUNK_SIZE as i32 - 1,
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_unknown_world_message_skipped() {
+    use crate::net::messages::NetMsg;
+
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    // Something a newer version might send, with a variant this one doesn't have.
+    let mut payload = vec![KNOWN_WORLD_MESSAGE_TAGS];
+    payload.extend_from_slice(&[1, 2, 3, 4]);
+    for mandatory in [false, true] {
+        let encoded = bitcode::encode(&NetMsg::WorldMessage(WorldEnvelope {
+            tag: KNOWN_WORLD_MESSAGE_TAGS,
+            mandatory,
+            payload: payload.clone(),
+        }));
+        let Ok(NetMsg::WorldMessage(envelope)) = bitcode::decode::<NetMsg>(&encoded) else {
+            panic!("envelope with unknown tag should still decode")
+        };
+        assert!(envelope.open(OmniPeerId(1)).is_none());
+        world.handle_envelope(OmniPeerId(1), envelope);
+        assert!(world.get_emitted_msgs().is_empty());
+    }
+
+    let msg = WorldNetMessage::ListenRequest {
+        chunk: ChunkCoord(0, 0),
+    };
+    let envelope = WorldEnvelope::wrap(&msg);
+    assert!(!envelope.mandatory);
+    assert!(matches!(
+        envelope.open(OmniPeerId(1)),
+        Some(WorldNetMessage::ListenRequest {
+            chunk: ChunkCoord(0, 0)
+        })
+    ));
+    assert!(
+        WorldEnvelope::wrap(&WorldNetMessage::TransferFailed {
+            chunk: ChunkCoord(0, 0)
+        })
+        .mandatory
     );
-    let (adj_y1, adj_y2) = if (chunk_x < chunkx) == (chunk_y < chunky) {
-        (
-            (chunk_y + 1) * CHUNK_SIZE as i32 - 1,
-            chunk_y * CHUNK_SIZE as i32,
-        )
-    } else {
-        (
-            chunk_y * CHUNK_SIZE as i32,
-            (chunk_y + 1) * CHUNK_SIZE as i32 - 1,
-        )
+    assert_eq!(
+        WorldNetMessage::ConfirmAuthority {
+            chunk: ChunkCoord(0, 0)
+        }
+        .tag(),
+        KNOWN_WORLD_MESSAGE_TAGS - 1
+    );
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_memory_estimate() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    let empty = world.memory_estimate();
+    assert_eq!(empty.total(), 0);
+
+    let populate = |world: &mut WorldManager, range: std::ops::Range<i32>| {
+        for x in range {
+            let chunk = ChunkCoord(x, 0);
+            world.chunk_storage.insert(chunk, ChunkData::new(1));
+            world
+                .inbound_model
+                .apply_chunk_data(chunk, &ChunkData::new(1))
+                .unwrap();
+            world
+                .outbound_model
+                .apply_chunk_data(chunk, &ChunkData::new(1))
+                .unwrap();
+            world.authority_map.insert(chunk, (OmniPeerId(1), 0));
+            world.chunk_state.insert(chunk, ChunkState::authority(0));
+        }
     };
-    let adj_dx = adj_x1 - x;
-    let adj_dy = adj_y1 - y;
-    let i =
-        ((rays as f32 * (1.0 + (adj_dy as f32).atan2(adj_dx as f32) / TAU)) % rays as f32) as usize;
-    let adj_dx = adj_x2 - x;
-    let adj_dy = adj_y2 - y;
-    let j =
-        ((rays as f32 * (1.0 + (adj_dy as f32).atan2(adj_dx as f32) / TAU)) % rays as f32) as usize;
-    (i.min(j), j.max(i))
+    populate(&mut world, 0..10);
+    let ten = world.memory_estimate();
+    populate(&mut world, 10..20);
+    let twenty = world.memory_estimate();
+    assert!(ten.chunk_storage > 0 && ten.inbound_model > 0 && ten.outbound_model > 0);
+    assert_eq!(twenty.chunk_storage, 2 * ten.chunk_storage);
+    assert_eq!(twenty.inbound_model, 2 * ten.inbound_model);
+    assert_eq!(twenty.outbound_model, 2 * ten.outbound_model);
+    assert_eq!(twenty.authority_map, 2 * ten.authority_map);
+    assert_eq!(twenty.chunk_state, 2 * ten.chunk_state);
+    assert_eq!(twenty.total(), 2 * ten.total());
 }
-fn min_dist(x: i32, y: i32, chunkx: i32, chunky: i32, chunk_x: i32, chunk_y: i32) -> u64 {
-    let close_x = match chunkx.cmp(&chunk_x) {
-        cmp::Ordering::Equal => x,
-        cmp::Ordering::Greater => (chunk_x + 1) * CHUNK_SIZE as i32 - 1,
-        cmp::Ordering::Less => chunk_x * CHUNK_SIZE as i32,
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_replay_terraform_log() {
+    let path = "/tmp/ew_tmp_terraform_log.bin";
+    let fresh = || {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
+        world.materials.insert(1, (6, 2000, CellType::Solid, 0, 0));
+        world.fill_rect(
+            (0, 0),
+            (3 * CHUNK_SIZE as i32 - 1, 2 * CHUNK_SIZE as i32 - 1),
+            1,
+        );
+        world
     };
-    let close_y = match chunky.cmp(&chunk_y) {
-        cmp::Ordering::Equal => y,
-        cmp::Ordering::Greater => (chunk_y + 1) * CHUNK_SIZE as i32 - 1,
-        cmp::Ordering::Less => chunk_y * CHUNK_SIZE as i32,
+
+    let mut world = fresh();
+    world.start_terraform_log(path).unwrap();
+    world.apply_terraform(&TerraformOp::Circle {
+        x: 40,
+        y: 40,
+        r: 20,
+        mat: None,
+        density: 100,
+        chance: 50,
+        seed: 1,
+        power: None,
+    });
+    world.apply_terraform(&TerraformOp::Line {
+        x: 0,
+        y: 100,
+        lx: 150,
+        ly: 20,
+        r: 4,
+        chance: 70,
+        seed: 2,
+        power: None,
+    });
+    world.apply_terraform(&TerraformOp::Rect {
+        min: (100, 10),
+        max: (120, 30),
+        material: 3,
+    });
+    let mut explosion = ExplosionData::new(90, 60, 25, 1000, 200, true, false, 0, 60);
+    explosion.jitter = 0.5;
+    world.cut_through_world_explosion(vec![explosion]);
+    world.apply_explosion_mask(150, 80, &compute_explosion_mask(10, 1000, 64, None));
+    world.stop_terraform_log();
+    let logged = std::fs::metadata(path).unwrap().len();
+    world.apply_terraform(&TerraformOp::Rect {
+        min: (0, 0),
+        max: (5, 5),
+        material: 1,
+    });
+    assert_eq!(std::fs::metadata(path).unwrap().len(), logged);
+
+    let mut replayed = fresh();
+    let untouched = replayed.chunk_storage.to_map();
+    replayed.replay_terraform_log(path).unwrap();
+    let encode = |map: FxHashMap<ChunkCoord, ChunkData>| {
+        let mut chunks: Vec<_> = map
+            .into_iter()
+            .map(|(chunk, data)| (chunk, bitcode::encode(&data)))
+            .collect();
+        chunks.sort_unstable_by_key(|(chunk, _)| (chunk.0, chunk.1));
+        chunks
     };
-    let dx = close_x.abs_diff(x) as u64;
-    let dy = close_y.abs_diff(y) as u64;
-    dx * dx + dy * dy
+    let replayed_chunks = encode(replayed.chunk_storage.to_map());
+    assert_ne!(replayed_chunks, encode(untouched));
+    assert_eq!(replayed_chunks, encode(world.chunk_storage.to_map()));
+
+    let data = std::fs::read(path).unwrap();
+    std::fs::write(path, &data[..data.len() - 1]).unwrap();
+    assert_eq!(
+        fresh().replay_terraform_log(path).unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_default_chunk() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    world.nice_terraforming = false;
+    world.materials.insert(1, (6, 2000, CellType::Solid, 0, 0));
+    let chunk = ChunkCoord(0, 0);
+    let material_at = |data: &ChunkData, (x, y): (i32, i32)| {
+        let mut pixels = Chunk::default();
+        data.apply_to_chunk(&mut pixels);
+        pixels.pixel(y as usize * CHUNK_SIZE + x as usize).material
+    };
+
+    world.cut_through_world_circle(20, 20, 5, None, 100, 100, 0, None);
+    assert!(world.synthetic_chunks.is_empty());
+
+    world.default_chunk = Some(ChunkData::new(1));
+    world.cut_through_world_circle(20, 20, 5, None, 100, 100, 0, None);
+    world.cut_through_world_circle(30, 20, 5, None, 100, 100, 0, None);
+    let synthetic = &world.synthetic_chunks[&chunk];
+    assert_eq!(material_at(synthetic, (20, 20)), 0);
+    assert_eq!(material_at(synthetic, (30, 20)), 0);
+    assert_eq!(material_at(synthetic, (40, 40)), 1);
+    // Made up data isn't given to anyone.
+    assert!(world.chunk_storage.is_empty());
+    world.handle_msg(
+        OmniPeerId(1),
+        WorldNetMessage::RequestAuthority {
+            chunk,
+            priority: 0,
+            can_wait: true,
+            hint: None,
+        },
+    );
+    assert!(matches!(
+        world.get_emitted_msgs().as_slice(),
+        [MessageRequest {
+            msg: WorldNetMessage::GotAuthority {
+                chunk_data: None,
+                ..
+            },
+            ..
+        }]
+    ));
+
+    // Real data replaces the default entirely.
+    world.handle_msg(
+        OmniPeerId(1),
+        WorldNetMessage::UpdateStorage {
+            chunk,
+            chunk_data: Some(ChunkData::new(2)),
+            world_num: world.world_num,
+            priority: None,
+        },
+    );
+    assert!(world.synthetic_chunks.is_empty());
+    let stored = world.chunk_storage.get(&chunk).unwrap().into_owned();
+    assert_eq!(material_at(&stored, (20, 20)), 2);
+    assert_eq!(material_at(&stored, (40, 40)), 2);
+    world.cut_through_world_circle(40, 40, 5, None, 100, 100, 0, None);
+    let stored = world.chunk_storage.get(&chunk).unwrap().into_owned();
+    assert_eq!(material_at(&stored, (20, 20)), 2);
+    assert_eq!(material_at(&stored, (40, 40)), 0);
 }
-fn compute_atans(
-    chunk_start_x: i32,
-    chunk_start_y: i32,
-    rays: f32,
-    x_offset: i32,
-    y_offset: i32,
-) -> Vec<f32> {
-    let mut result = Vec::with_capacity(CHUNK_SIZE * CHUNK_SIZE);
-    for icy in 0..CHUNK_SIZE as i32 {
-        let dy = chunk_start_y + icy - y_offset;
-        let y = f32x8::splat(dy as f32);
-        for icx in (0..CHUNK_SIZE as i32).step_by(8) {
-            let dx = chunk_start_x + icx - x_offset;
-            let x = f32x8::from([
-                dx as f32,
-                (dx + 1) as f32,
-                (dx + 2) as f32,
-                (dx + 3) as f32,
-                (dx + 4) as f32,
-                (dx + 5) as f32,
-                (dx + 6) as f32,
-                (dx + 7) as f32,
-            ]);
-            let atan_values = rays * (1.0 + y.atan2(x) / TAU);
-            result.extend_from_slice(&atan_values.to_array());
-        }
-    }
-    result
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_compression_stats() {
+    let mut stats = CompressionStats::default();
+    stats.record(100, 25);
+    stats.record(100, 75);
+    stats.record(0, 10);
+    assert_eq!(stats.samples, 2);
+    assert_eq!(stats.mean_ratio(), 0.5);
+    assert_eq!(stats.worst_ratio, 0.75);
+    assert_eq!(stats.bytes_saved, 100);
+
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkData::new(1);
+    world.compress_for(OmniPeerId(1), &chunk);
+    assert_eq!(world.compression_stats().samples, 0);
+
+    world.record_compression_stats = true;
+    let compressed = world.compress_for(OmniPeerId(1), &chunk);
+    let original = bitcode::encode(&chunk).len();
+    let stats = world.compression_stats();
+    assert_eq!(stats.samples, 1);
+    assert_eq!(
+        stats.mean_ratio(),
+        compressed.len() as f64 / original as f64
+    );
+    assert_eq!(stats.worst_ratio, stats.mean_ratio());
+    assert_eq!(stats.bytes_saved, original as i64 - compressed.len() as i64);
+
+    world.reset_compression_stats();
+    assert_eq!(world.compression_stats(), CompressionStats::default());
 }
-fn get_ray(r: u64) -> u64 {
-    let c = r.saturating_mul(15708) / 10000; // tau/4
-    (c - c % 8).clamp(1 << 4, 1 << 11)
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_import_merge_non_air() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.chunk_storage.clear();
+    let size = CHUNK_SIZE as i32;
+    // Prefab is a square of material 5 surrounded by air.
+    host.fill_rect((0, 0), (size - 1, size - 1), 0);
+    host.fill_rect((0, 0), (19, 19), 5);
+    let prefab = host.export_region(ChunkCoord(0, 0), ChunkCoord(0, 0));
+    // Terrain has a wall on the left, air on the right.
+    for cx in [5, 6] {
+        host.fill_rect((cx * size, 0), (cx * size + size - 1, size - 1), 0);
+        host.fill_rect((cx * size, 0), (cx * size + 9, size - 1), 1);
+    }
+    let material_at = |host: &WorldManager, (x, y): (i32, i32)| {
+        let mut chunk = Chunk::default();
+        host.chunk_storage
+            .get(&ChunkCoord(x.div_euclid(size), 0))
+            .unwrap()
+            .apply_to_chunk(&mut chunk);
+        chunk
+            .pixel((y * size + x.rem_euclid(size)) as usize)
+            .material
+    };
+
+    let imported = host.import_region(&prefab, ChunkCoord(5, 0), ImportPolicy::MergeNonAir);
+    assert_eq!(imported, 1);
+    let x = 5 * size;
+    assert_eq!(material_at(&host, (x + 5, 5)), 1);
+    assert_eq!(material_at(&host, (x + 15, 5)), 5);
+    assert_eq!(material_at(&host, (x + 5, 30)), 1);
+    assert_eq!(material_at(&host, (x + 30, 30)), 0);
+
+    // Authority would overwrite storage later, so it gets only the filled pixels as well.
+    host.authority_map
+        .insert(ChunkCoord(6, 0), (OmniPeerId(2), 0));
+    host.get_emitted_msgs();
+    host.import_region(&prefab, ChunkCoord(6, 0), ImportPolicy::MergeNonAir);
+    let msgs = host.get_emitted_msgs();
+    let [
+        MessageRequest {
+            msg: WorldNetMessage::ApplyDelta { delta },
+            dst: Destination::Peer(OmniPeerId(2)),
+            ..
+        },
+    ] = msgs.as_slice()
+    else {
+        panic!("expected a delta for the authority")
+    };
+    assert!(delta.to_chunk_data().is_none());
+    let x = 6 * size;
+    assert_eq!(material_at(&host, (x + 5, 5)), 1);
+    assert_eq!(material_at(&host, (x + 15, 5)), 5);
+    assert_eq!(material_at(&host, (x + 30, 30)), 0);
 }
-/*#[cfg(test)]
+
+#[cfg(test)]
 #[test]
 #[serial]
-fn test_explosion_img() {
-    let (mut world, _, _, _, _) = WorldManager::new(
-        true,
+fn test_lost_transfer_recovers() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    peer.set_position((0, 0), (0, 0), false);
+    let chunk = ChunkCoord(0, 0);
+    peer.last_request_priority.insert(chunk, 3);
+    peer.handle_msg(
         OmniPeerId(0),
-        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+        WorldNetMessage::GetAuthorityFrom {
+            chunk,
+            current_authority: OmniPeerId(2),
+        },
+    );
+    assert!(
+        peer.get_emitted_msgs()
+            .iter()
+            .any(|m| matches!(m.msg, WorldNetMessage::RequestAuthorityTransfer { .. }))
+    );
+
+    // Reply never arrives.
+    peer.current_update += TRANSFER_TIMEOUT - 1;
+    peer.update(Duration::ZERO);
+    assert!(matches!(
+        peer.chunk_state.get(&chunk),
+        Some(ChunkState::Transfer { .. })
+    ));
+    assert!(peer.get_emitted_msgs().is_empty());
+
+    peer.current_update += 1;
+    peer.update(Duration::ZERO);
+    assert_eq!(
+        peer.chunk_state.get(&chunk),
+        Some(&ChunkState::RequestAuthority {
+            priority: 3,
+            can_wait: true
+        })
+    );
+    assert!(peer.transfer_started.is_empty());
+    assert!(
+        peer.get_emitted_msgs()
+            .iter()
+            .any(|m| matches!(m.msg, WorldNetMessage::RelinquishAuthority { .. }))
     );
+
+    peer.current_update += peer.transfer_retry_delay;
+    peer.update(Duration::ZERO);
+    assert!(
+        peer.get_emitted_msgs()
+            .iter()
+            .any(|m| matches!(m.msg, WorldNetMessage::RequestAuthority { priority: 3, .. }))
+    );
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_neighbors_loaded() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    let center = ChunkCoord(4, 7);
+    assert_eq!(world.neighbors_loaded(center), [false; 8]);
+
     world
-        .materials
-        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+        .chunk_storage
+        .insert(ChunkCoord(3, 6), ChunkData::new(1));
     world
-        .materials
-        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+        .outbound_model
+        .apply_chunk_data(ChunkCoord(5, 7), &ChunkData::new(1))
+        .unwrap();
     world
-        .materials
-        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
-    let w = 48;
-    for i in -w..w {
-        for j in -w..w {
-            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
-                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
+        .inbound_model
+        .apply_chunk_data(ChunkCoord(4, 8), &ChunkData::new(1))
+        .unwrap();
+    // Center itself and chunks further away don't count.
+    world.chunk_storage.insert(center, ChunkData::new(1));
+    world
+        .chunk_storage
+        .insert(ChunkCoord(6, 7), ChunkData::new(1));
+    assert_eq!(
+        world.neighbors_loaded(center),
+        [true, false, false, false, true, false, true, false]
+    );
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_explosion_damage_type() {
+    const PHYSICAL: u32 = 1;
+    const FIRE: u32 = 2;
+    let new_world = || {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
+        // Tough, but flammable.
+        world
+            .materials
+            .insert(1, (14, 10, CellType::Solid, 0, FIRE));
+        for i in -1..=1 {
+            for j in -1..=1 {
                 world
                     .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
-            } else {
-                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+                    .insert(ChunkCoord(i, j), ChunkData::new(1));
             }
         }
+        world
+    };
+    let material_at = |world: &WorldManager, x: i32, y: i32| {
+        let mut chunk = Chunk::default();
+        world
+            .chunk_storage
+            .get(&ChunkCoord(
+                x.div_euclid(CHUNK_SIZE as i32),
+                y.div_euclid(CHUNK_SIZE as i32),
+            ))
+            .unwrap()
+            .apply_to_chunk(&mut chunk);
+        let px = y.rem_euclid(CHUNK_SIZE as i32) as usize * CHUNK_SIZE
+            + x.rem_euclid(CHUNK_SIZE as i32) as usize;
+        chunk.pixel(px).material
+    };
+    let explode = |damage_type, d| {
+        let mut world = new_world();
+        let mut explosion = ExplosionData::new(32, 32, 10, d, 1000, true, true, 0, 100);
+        explosion.damage_type = damage_type;
+        world.cut_through_world_explosion(vec![explosion]);
+        world
+    };
+
+    let physical = explode(PHYSICAL, 6);
+    assert_eq!(material_at(&physical, 32, 32), 1);
+    assert_eq!(material_at(&physical, 35, 32), 1);
+
+    let fire = explode(FIRE, 6);
+    assert_eq!(material_at(&fire, 32, 32), 0);
+    assert_eq!(material_at(&fire, 35, 32), 0);
+    assert_eq!(material_at(&fire, 32, 28), 0);
+
+    // Plain durability threshold still works without any damage type.
+    let strong = explode(0, 20);
+    assert_eq!(material_at(&strong, 35, 32), 0);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_sync_equal() {
+    let new_peer = |id| {
+        let (mut peer, _, _, _, _) =
+            WorldManager::new(false, OmniPeerId(id), SaveState::new("/tmp/ew_tmp_save"));
+        peer.chunk_storage.clear();
+        peer
+    };
+    let mut authority = new_peer(1);
+    let mut listener = new_peer(2);
+    let chunk = ChunkCoord(0, 0);
+    let mut state = ChunkState::authority(0);
+    if let ChunkState::Authority { listeners, .. } = &mut state {
+        listeners.insert(OmniPeerId(2));
     }
-    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
-    //let mut img = image::GrayImage::new(pixels, pixels);
-    //world._create_image(&mut img, pixels);
-    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
+    authority.chunk_state.insert(chunk, state);
+    listener.chunk_state.insert(
+        chunk,
+        ChunkState::Listening {
+            authority: OmniPeerId(1),
+            priority: 0,
+        },
+    );
+    authority
+        .outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+    listener
+        .inbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+    // Only one of them knows about this one, so it doesn't count.
+    authority
+        .chunk_storage
+        .insert(ChunkCoord(5, 5), ChunkData::new(3));
+    assert!(authority.sync_equal(&listener));
 
-    let timer = std::time::Instant::now();
-    world.cut_through_world_explosion(vec![
-        ExplosionData::new(
-            2 * CHUNK_SIZE as i32,
-            -2 * CHUNK_SIZE as i32,
-            1048,
-            12,
-            10_000_000,
-            true,
-            true,
-            1,
-            4,
-        ),
-        ExplosionData::new(
-            -2 * CHUNK_SIZE as i32,
-            2 * CHUNK_SIZE as i32,
-            1048,
-            12,
-            10_000_000,
-            true,
-            true,
-            1,
-            4,
-        ),
-        ExplosionData::new(
-            -4 * CHUNK_SIZE as i32,
-            2 * CHUNK_SIZE as i32,
-            1048,
-            12,
-            10_000_000,
-            true,
-            true,
-            1,
-            4,
-        ),
-        ExplosionData::new(
-            -5 * CHUNK_SIZE as i32,
-            -2 * CHUNK_SIZE as i32,
-            1048,
-            12,
-            10_000_000,
-            true,
-            true,
-            1,
-            4,
-        ),
-        ExplosionData::new(
-            -42 * CHUNK_SIZE as i32,
-            -42 * CHUNK_SIZE as i32,
-            1048,
-            12,
-            10_000_000,
-            true,
-            true,
+    authority.handle_noita_msg(
+        OmniPeerId(1),
+        WorldSyncToProxy::Updates(vec![NoitaWorldUpdate {
+            coord: chunk,
+            runs: vec![PixelRun {
+                length: 10,
+                data: RawPixel {
+                    material: 2,
+                    flags: PixelFlags::Normal,
+                },
+            }],
+        }]),
+    );
+    authority.handle_noita_msg(OmniPeerId(1), WorldSyncToProxy::End(None, 0, 0));
+    assert_eq!(authority.sync_diff(&listener), vec![chunk]);
+    assert!(!listener.sync_equal(&authority));
+
+    for request in authority.get_emitted_msgs() {
+        assert_eq!(request.dst, Destination::Peer(OmniPeerId(2)));
+        listener.handle_msg(OmniPeerId(1), request.msg);
+    }
+    assert!(authority.sync_equal(&listener));
+    assert!(listener.sync_equal(&authority));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_replay_noita_updates() {
+    let path = "/tmp/ew_tmp_noita_updates.bin";
+    let fresh = || {
+        let (world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world
+    };
+    let update = |coord, material, length| NoitaWorldUpdate {
+        coord,
+        runs: vec![PixelRun {
+            length,
+            data: RawPixel {
+                material,
+                flags: PixelFlags::Normal,
+            },
+        }],
+    };
+
+    let mut world = fresh();
+    world.start_noita_update_log(path).unwrap();
+    world.handle_noita_msg(
+        OmniPeerId(0),
+        WorldSyncToProxy::Updates(vec![
+            update(ChunkCoord(0, 0), 1, CHUNK_SIZE as u16 * 4),
+            update(ChunkCoord(1, 0), 2, 100),
+        ]),
+    );
+    world.handle_noita_msg(
+        OmniPeerId(0),
+        WorldSyncToProxy::End(Some((10, 10, 0, 0, false)), 3, 0),
+    );
+    world.handle_noita_msg(
+        OmniPeerId(0),
+        WorldSyncToProxy::Updates(vec![update(ChunkCoord(0, 0), 5, 7)]),
+    );
+    world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::End(None, 3, 0));
+    world.stop_noita_update_log();
+    let logged = std::fs::metadata(path).unwrap().len();
+    world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::End(None, 3, 0));
+    assert_eq!(std::fs::metadata(path).unwrap().len(), logged);
+
+    let mut replayed = fresh();
+    replayed.replay_noita_updates(path).unwrap();
+    for chunk in [ChunkCoord(0, 0), ChunkCoord(1, 0)] {
+        let expected = world.outbound_model.get_chunk_data(chunk).unwrap();
+        let got = replayed.outbound_model.get_chunk_data(chunk).unwrap();
+        assert_eq!(bitcode::encode(&got), bitcode::encode(&expected));
+    }
+
+    let data = std::fs::read(path).unwrap();
+    std::fs::write(path, &data[..data.len() - 1]).unwrap();
+    assert_eq!(
+        fresh().replay_noita_updates(path).unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_corrupt_chunk_data_recovers() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let mut corrupt = ChunkData::new(1);
+    corrupt.runs[0].length += 100;
+    assert!(corrupt.check().is_err());
+    let compressed = peer.compress_for(peer.my_peer_id, &corrupt);
+
+    let chunk = ChunkCoord(0, 0);
+    peer.outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(2))
+        .unwrap();
+    peer.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::GotAuthority {
+            chunk,
+            chunk_data: Some(compressed.clone()),
+            priority: 0,
+            listeners: Default::default(),
+        },
+    );
+    assert!(matches!(
+        peer.chunk_state.get(&chunk),
+        Some(ChunkState::Authority { .. })
+    ));
+    assert!(peer.inbound_model.get_chunk_data(chunk).is_none());
+    assert!(peer.outbound_model.get_chunk_data(chunk).is_none());
+
+    let chunk = ChunkCoord(1, 0);
+    peer.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::ListenInitialResponse {
+            chunk,
+            chunk_data: Some(compressed),
+            priority: 0,
+        },
+    );
+    assert!(!peer.chunk_state.contains_key(&chunk));
+    assert!(peer.inbound_model.get_chunk_data(chunk).is_none());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_coalesced_terraform() {
+    let fresh = || {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
+        world.fill_rect(
+            (0, 0),
+            (3 * CHUNK_SIZE as i32 - 1, 2 * CHUNK_SIZE as i32 - 1),
             1,
-            4,
-        ),
-    ]);
-    println!("total img micros {}", timer.elapsed().as_micros());
+        );
+        world
+    };
+    let ops: Vec<_> = (0..4)
+        .map(|i| TerraformOp::Circle {
+            x: CHUNK_SIZE as i32 - 20 + i * 15,
+            y: CHUNK_SIZE as i32 - 10,
+            r: 30,
+            mat: Some(2 + i as u16),
+            density: 100,
+            chance: 100,
+            seed: 0,
+            power: None,
+        })
+        .collect();
 
-    let mut img = image::GrayImage::new(pixels, pixels);
-    world._create_image(&mut img, pixels);
-    img.save("/tmp/ew_tmp_save/img_ex.png").unwrap();
+    let mut individual = fresh();
+    for op in &ops {
+        individual.apply_terraform(op);
+    }
+
+    let mut world = fresh();
+    world.coalesce_terraform = true;
+    let (tx, rx) = mpsc::channel();
+    world.tx = tx;
+    let untouched = world.chunk_storage.to_map();
+    for op in &ops {
+        world.queue_terraform(*op);
+    }
+    for (chunk, data) in &untouched {
+        let stored = world.chunk_storage.get(chunk).unwrap();
+        assert_eq!(bitcode::encode(&*stored), bitcode::encode(data));
+    }
+    assert!(rx.try_recv().is_err());
+    world.update(Duration::ZERO);
+
+    let synced: Vec<_> = rx.try_iter().collect();
+    let mut chunks: Vec<_> = synced.iter().map(|(chunk, _)| *chunk).collect();
+    chunks.sort_unstable_by_key(|chunk| (chunk.0, chunk.1));
+    chunks.dedup();
+    assert_eq!(chunks.len(), synced.len(), "each chunk is synced once");
+    assert_eq!(chunks.len(), 4);
+    for (chunk, data) in synced {
+        let expected = individual.chunk_storage.get(&chunk).unwrap();
+        assert_eq!(bitcode::encode(&data), bitcode::encode(&*expected));
+        assert_ne!(bitcode::encode(&data), bitcode::encode(&untouched[&chunk]));
+    }
+    world.update(Duration::ZERO);
+    assert!(rx.try_recv().is_err());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_vertical_cut_reaches_listeners() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(0, 0);
+    let mut state = ChunkState::authority(0);
+    if let ChunkState::Authority { listeners, .. } = &mut state {
+        listeners.insert(OmniPeerId(1));
+    }
+    world.chunk_state.insert(chunk, state);
+    world
+        .outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+    world
+        .outbound_model
+        .reset_change_tracking_except(&Default::default());
+    peer.chunk_state.insert(
+        chunk,
+        ChunkState::Listening {
+            authority: OmniPeerId(0),
+            priority: 0,
+        },
+    );
+    peer.inbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+
+    world.nice_terraforming = false;
+    world.cut_through_world(64, 0, CHUNK_SIZE as i32 - 1, 10);
+    assert!(world.outbound_model.updated_chunks().is_empty());
+
+    world.nice_terraforming = true;
+    world.cut_through_world(64, 0, CHUNK_SIZE as i32 - 1, 10);
+    assert_eq!(
+        bitcode::encode(&world.inbound_model.get_chunk_data(chunk).unwrap()),
+        bitcode::encode(&world.outbound_model.get_chunk_data(chunk).unwrap())
+    );
+    world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::End(None, 0, 0));
+    let msgs = world.get_emitted_msgs();
+    assert!(!msgs.is_empty());
+    for msg in msgs {
+        if msg.dst == Destination::Peer(OmniPeerId(1)) {
+            peer.handle_msg(OmniPeerId(0), msg.msg);
+        }
+    }
+    let synced = peer.inbound_model.get_chunk_data(chunk).unwrap();
+    assert_ne!(
+        bitcode::encode(&synced),
+        bitcode::encode(&ChunkData::new(1))
+    );
+    assert_eq!(
+        bitcode::encode(&synced),
+        bitcode::encode(&world.outbound_model.get_chunk_data(chunk).unwrap())
+    );
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_explosion_img_big() {
-    let (mut world, _, _, _, _) = WorldManager::new(
-        true,
-        OmniPeerId(0),
-        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
-    );
-    world
-        .materials
-        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
+fn test_effective_durability() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
     world
         .materials
-        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
+        .insert(1, (6, 2000, CellType::Solid, 0, 0b10));
+    assert_eq!(world.effective_durability(2, 0, 1.0), None);
+
+    let stats = world.effective_durability(1, 0b01, 1.5).unwrap();
+    assert_eq!(
+        stats,
+        EffectiveDurability {
+            durability: 6,
+            ray_cost: 3000,
+            vulnerable: false,
+        }
+    );
+    assert!(stats.resists(5));
+    assert!(!stats.stops(6, 3000));
+    assert!(stats.stops(6, 2999));
+
+    let stats = world.effective_durability(1, 0b11, 0.5).unwrap();
+    assert_eq!(stats.ray_cost, 1000);
+    assert!(stats.vulnerable);
+    assert!(!stats.resists(0));
+    assert!(!stats.stops(0, 1000));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_minimap_tile() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(0, 0);
+    assert!(!world.set_minimap_block(48));
+    assert!(world.set_minimap_block(32));
     world
-        .materials
-        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
-    let w = 20;
-    for i in -w..w {
-        for j in -w..w {
-            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
-                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
-                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
-            } else {
-                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
-            }
+        .inbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+    assert_eq!(world.minimap_tile(chunk).unwrap(), vec![1; 16]);
+
+    let mut pixels = vec![None; CHUNK_SIZE * CHUNK_SIZE];
+    // Most of the second block of the first row.
+    for y in 0..20 {
+        for x in 32..64 {
+            pixels[y * CHUNK_SIZE + x] = Some(
+                RawPixel {
+                    material: 5,
+                    flags: PixelFlags::Normal,
+                }
+                .to_compact(),
+            );
         }
     }
-    //let mut img = image::GrayImage::new(pixels, pixels);
-    //world._create_image(&mut img, pixels);
-    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
+    world
+        .inbound_model
+        .apply_chunk_delta(&ChunkDelta::new(chunk, pixels));
+    let mut expected = vec![1; 16];
+    expected[1] = 5;
+    assert_eq!(world.minimap_tile(chunk).unwrap(), expected);
 
-    let timer = std::time::Instant::now();
-    world.cut_through_world_explosion(vec![ExplosionData::new(
-        0,
-        0,
-        4096,
-        15,
-        1_024_000_000,
-        true,
-        true,
-        1,
-        4,
-    )]);
-    let w = 48;
-    let mut rng = rng();
-    let mut iter = (-w..w)
-        .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
-        .collect::<Vec<(i32, i32)>>();
-    iter.shuffle(&mut rng);
-    for (i, j) in iter {
-        let c = ChunkCoord(i, j);
-        if let std::collections::hash_map::Entry::Vacant(e) = world.chunk_storage.entry(c) {
-            e.insert(_brickwork.clone());
-        }
-        if world.explosion_pointer.contains_key(&c) {
-            world.cut_through_world_explosion_chunk(c)
-        }
+    world.handle_noita_msg(
+        OmniPeerId(0),
+        WorldSyncToProxy::Updates(vec![NoitaWorldUpdate {
+            coord: chunk,
+            runs: vec![PixelRun {
+                length: CHUNK_SIZE as u16 * 32,
+                data: RawPixel {
+                    material: 7,
+                    flags: PixelFlags::Normal,
+                },
+            }],
+        }]),
+    );
+    // Outbound model has the game's view, which wins over what we heard from others.
+    assert_eq!(world.minimap_tile(chunk).unwrap()[..4], [7; 4]);
+
+    world.set_minimap_block(0);
+    assert!(world.minimap_tile(chunk).is_none());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_chunks_changed_since() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let update = |coord, material| NoitaWorldUpdate {
+        coord,
+        runs: vec![PixelRun {
+            length: 1,
+            data: RawPixel {
+                material,
+                flags: PixelFlags::Normal,
+            },
+        }],
+    };
+    let (a, b, c) = (ChunkCoord(0, 0), ChunkCoord(2, 0), ChunkCoord(4, 0));
+    for (material, chunks) in [vec![a, b], vec![c], vec![a]].into_iter().enumerate() {
+        let updates = chunks
+            .into_iter()
+            .map(|chunk| update(chunk, material as u16 + 1))
+            .collect();
+        world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::Updates(updates));
+        world.handle_noita_msg(OmniPeerId(0), WorldSyncToProxy::End(None, 0, 0));
     }
-    println!("total img micros {}", timer.elapsed().as_micros());
+    assert_eq!(world.current_update(), 3);
+    let sorted = |mut chunks: Vec<ChunkCoord>| {
+        chunks.sort_unstable_by_key(|chunk| (chunk.0, chunk.1));
+        chunks
+    };
+    assert_eq!(sorted(world.chunks_changed_since(0, false)), vec![a, b, c]);
+    assert_eq!(sorted(world.chunks_changed_since(1, false)), vec![a, c]);
+    assert_eq!(world.chunks_changed_since(2, false), vec![a]);
+    assert!(world.chunks_changed_since(3, false).is_empty());
 
-    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
-    let mut img = image::GrayImage::new(pixels, pixels);
-    world._create_image(&mut img, pixels);
-    img.save("/tmp/ew_tmp_save/img_ex_bigger.png").unwrap();
+    world.chunk_state.remove(&c);
+    assert_eq!(world.chunks_changed_since(1, false), vec![a]);
+    assert_eq!(sorted(world.chunks_changed_since(1, true)), vec![a, c]);
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_explosion_img_big_br() {
-    let (mut world, _, _, _, _) = WorldManager::new(
-        true,
-        OmniPeerId(0),
-        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+fn test_prioritize_control() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let (a, b, c) = (ChunkCoord(0, 0), ChunkCoord(1, 0), ChunkCoord(2, 0));
+    let delta = |chunk| WorldNetMessage::ListenUpdate {
+        delta: ChunkDelta::new(chunk, [None]),
+        priority: 0,
+        take_auth: false,
+    };
+    let relinquish = |chunk| WorldNetMessage::RelinquishAuthority {
+        chunk,
+        chunk_data: None,
+        world_num: 0,
+    };
+    let msgs = [
+        (Destination::Peer(OmniPeerId(2)), delta(a)),
+        (Destination::Peer(OmniPeerId(2)), delta(b)),
+        (Destination::Host, relinquish(a)),
+        (
+            Destination::Host,
+            WorldNetMessage::RequestAuthority {
+                chunk: c,
+                priority: 0,
+                can_wait: true,
+                hint: None,
+            },
+        ),
+        (Destination::Peer(OmniPeerId(2)), delta(c)),
+        (Destination::Host, relinquish(c)),
+    ];
+    let describe = |msgs: Vec<MessageRequest<WorldNetMessage>>| -> Vec<(&str, ChunkCoord)> {
+        msgs.iter()
+            .map(|request| (request.msg.name(), request.msg.chunk().unwrap()))
+            .collect()
+    };
+
+    for (dst, msg) in msgs.clone() {
+        world.emit_msg(dst, msg);
+    }
+    let fifo = describe(world.get_emitted_msgs());
+    assert_eq!(fifo[2], ("RelinquishAuthority", a));
+
+    world.prioritize_control = true;
+    for (dst, msg) in msgs {
+        world.emit_msg(dst, msg);
+    }
+    assert_eq!(
+        describe(world.get_emitted_msgs()),
+        vec![
+            ("RequestAuthority", c),
+            ("ListenUpdate", a),
+            ("ListenUpdate", b),
+            // Both stay behind deltas for their chunks.
+            ("RelinquishAuthority", a),
+            ("ListenUpdate", c),
+            ("RelinquishAuthority", c),
+        ]
     );
-    world
-        .materials
-        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
-    world
-        .materials
-        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
-    world
-        .materials
-        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
-    let w = 20;
-    for i in -w..w {
-        for j in -w..w {
-            if (4..=6).contains(&i) && (4..=6).contains(&j) {
-                continue;
-            }
-            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
-                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
-                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
-            } else {
-                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_max_affected_chunks() {
+    let explode = |cap| {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
+        world.max_affected_chunks = cap;
+        world.materials.insert(1, (0, 1, CellType::Solid, 0, 0));
+        for i in -8..=8 {
+            for j in -8..=8 {
+                world
+                    .chunk_storage
+                    .insert(ChunkCoord(i, j), ChunkData::new(1));
             }
         }
+        let explosion = ExplosionData::new(64, 64, 1000, 1, u64::MAX / 2, true, true, 0, 100);
+        world.cut_through_world_explosion(vec![explosion]);
+        let untouched = bitcode::encode(&ChunkData::new(1));
+        world
+            .chunk_storage
+            .to_map()
+            .values()
+            .filter(|data| bitcode::encode(*data) != untouched)
+            .count()
+    };
+    assert!(explode(None) > 100);
+    let capped = explode(Some(9));
+    assert!((1..=9).contains(&capped), "{capped} chunks affected");
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_idle_unload() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    peer.set_position((0, 0), (0, 0), false);
+    let (idle, boosted, standing) = (ChunkCoord(-1, 0), ChunkCoord(1, 0), ChunkCoord(0, 0));
+    for chunk in [idle, boosted, standing] {
+        peer.chunk_state.insert(
+            chunk,
+            ChunkState::Listening {
+                authority: OmniPeerId(0),
+                priority: 0,
+            },
+        );
+        peer.chunk_last_update.insert(chunk, 0);
     }
-    //let mut img = image::GrayImage::new(pixels, pixels);
-    //world._create_image(&mut img, pixels);
-    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
+    peer.boost_chunk_priority(boosted, 0, 1000);
+    peer.current_update = 50;
+    peer.update(Duration::ZERO);
+    assert!(
+        peer.chunk_state.contains_key(&idle),
+        "close chunks are kept"
+    );
 
-    let timer = std::time::Instant::now();
-    world.cut_through_world_explosion(vec![ExplosionData::new(
-        0,
-        0,
-        4096,
-        15,
-        256_000_000,
-        true,
-        true,
+    peer.idle_unload_after = Some(60);
+    peer.update(Duration::ZERO);
+    assert!(peer.chunk_state.contains_key(&idle));
+
+    peer.current_update = 60;
+    peer.get_emitted_msgs();
+    peer.update(Duration::ZERO);
+    assert!(!peer.chunk_state.contains_key(&idle));
+    assert!(peer.chunk_state.contains_key(&boosted));
+    assert!(peer.chunk_state.contains_key(&standing));
+    assert!(peer.get_emitted_msgs().iter().any(|request| matches!(
+        request.msg,
+        WorldNetMessage::ListenStopRequest { chunk } if chunk == idle
+    )));
+
+    // Game changing it again gets it requested like any new chunk.
+    peer.handle_noita_msg(
+        OmniPeerId(1),
+        WorldSyncToProxy::Updates(vec![NoitaWorldUpdate {
+            coord: idle,
+            runs: vec![PixelRun {
+                length: 1,
+                data: RawPixel {
+                    material: 3,
+                    flags: PixelFlags::Normal,
+                },
+            }],
+        }]),
+    );
+    peer.handle_noita_msg(OmniPeerId(1), WorldSyncToProxy::End(None, 0, 0));
+    assert!(peer.chunk_state.contains_key(&idle));
+    assert_eq!(peer.chunk_last_update[&idle], peer.current_update);
+
+    // Huge timeouts don't overflow into unloading everything.
+    peer.idle_unload_after = Some(u64::MAX);
+    peer.current_update += 1;
+    peer.update(Duration::ZERO);
+    assert!(peer.chunk_state.contains_key(&boosted));
+    assert!(peer.chunk_state.contains_key(&idle));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_world_hash() {
+    let new_peer = |id| {
+        let (mut peer, _, _, _, _) =
+            WorldManager::new(false, OmniPeerId(id), SaveState::new("/tmp/ew_tmp_save"));
+        peer.chunk_storage.clear();
+        peer
+    };
+    let (a, b) = (ChunkCoord(0, 0), ChunkCoord(3, -2));
+    let mut first = new_peer(1);
+    first.chunk_storage.insert(a, ChunkData::new(1));
+    first
+        .outbound_model
+        .apply_chunk_data(b, &ChunkData::new(2))
+        .unwrap();
+    // Same content, kept in different places and encoded differently.
+    let mut second = new_peer(2);
+    second
+        .inbound_model
+        .apply_chunk_data(b, &ChunkData::new(2))
+        .unwrap();
+    let mut split = ChunkData::new(1);
+    let run = split.runs[0];
+    split.runs[0].length = 1;
+    split.runs.insert(
         1,
-        4,
-    )]);
-    let w = 48;
-    let mut rng = rng();
-    let mut iter = (-w..w)
-        .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
-        .collect::<Vec<(i32, i32)>>();
-    iter.shuffle(&mut rng);
-    for (i, j) in iter {
-        let c = ChunkCoord(i, j);
-        if let std::collections::hash_map::Entry::Vacant(e) = world.chunk_storage.entry(c) {
-            e.insert(if rng.random_bool(0.2) {
-                _brickwork.clone()
-            } else {
-                _dirt.clone()
-            });
-        }
-        if world.explosion_pointer.contains_key(&c) {
-            world.cut_through_world_explosion_chunk(c)
-        }
-    }
-    println!("total img micros {}", timer.elapsed().as_micros());
+        PixelRun {
+            length: run.length - 1,
+            data: run.data,
+        },
+    );
+    second.chunk_storage.insert(a, split);
+    assert_eq!(first.world_hash(), second.world_hash());
+    assert_ne!(first.world_hash(), new_peer(3).world_hash());
 
-    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
-    let mut img = image::GrayImage::new(pixels, pixels);
-    world._create_image(&mut img, pixels);
-    img.save("/tmp/ew_tmp_save/img_ex_bigger_br.png").unwrap();
+    second.handle_noita_msg(
+        OmniPeerId(2),
+        WorldSyncToProxy::Updates(vec![NoitaWorldUpdate {
+            coord: b,
+            runs: vec![PixelRun {
+                length: 1,
+                data: RawPixel {
+                    material: 7,
+                    flags: PixelFlags::Normal,
+                },
+            }],
+        }]),
+    );
+    assert_ne!(first.world_hash(), second.world_hash());
+    assert_eq!(first.chunk_checksum(a), second.chunk_checksum(a));
+    assert_ne!(first.chunk_checksum(b), second.chunk_checksum(b));
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_explosion_img_big_empty() {
-    let (mut world, _, _, _, _) = WorldManager::new(
-        true,
-        OmniPeerId(0),
-        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+fn test_storage_resent_until_acked() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(0, 0);
+    peer.outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(4))
+        .unwrap();
+    peer.chunk_state.insert(
+        chunk,
+        ChunkState::Authority {
+            listeners: Default::default(),
+            priority: 0,
+            new_authority: None,
+            stop_sending: false,
+        },
     );
-    world
-        .materials
-        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
-    world
-        .materials
-        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
-    world
-        .materials
-        .insert(2, (14, 2_000, CellType::Liquid(LiquidType::Static)));
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
+    peer.handle_msg(
+        OmniPeerId(2),
+        WorldNetMessage::RequestAuthorityTransfer { chunk },
+    );
+    let is_storage = |msg: &WorldNetMessage| matches!(msg, WorldNetMessage::UpdateStorage { chunk: c, .. } if *c == chunk);
+    // First UpdateStorage gets dropped.
+    assert!(peer.get_emitted_msgs().iter().any(|m| is_storage(&m.msg)));
 
-    let timer = std::time::Instant::now();
-    world.cut_through_world_explosion(vec![ExplosionData::new(
-        0,
-        0,
-        4096,
-        15,
-        2_000_000_000,
-        true,
-        true,
-        1,
-        4,
-    )]);
-    let w = 48;
-    let mut rng = rng();
-    let mut iter = (-w..w)
-        .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
-        .collect::<Vec<(i32, i32)>>();
-    iter.shuffle(&mut rng);
-    for (i, j) in iter {
-        let c = ChunkCoord(i, j);
-        if let std::collections::hash_map::Entry::Vacant(e) = world.chunk_storage.entry(c) {
-            e.insert(if rng.random_bool(0.2) {
-                _brickwork.clone()
-            } else {
-                _dirt.clone()
-            });
-        }
-        if world.explosion_pointer.contains_key(&c) {
-            world.cut_through_world_explosion_chunk(c)
-        }
-    }
-    println!("total img micros {}", timer.elapsed().as_micros());
+    peer.update(Duration::ZERO);
+    assert!(!peer.get_emitted_msgs().iter().any(|m| is_storage(&m.msg)));
+    peer.current_update += STORAGE_ACK_TIMEOUT;
+    peer.update(Duration::ZERO);
+    let resent: Vec<_> = peer
+        .get_emitted_msgs()
+        .into_iter()
+        .filter(|m| is_storage(&m.msg))
+        .collect();
+    assert_eq!(resent.len(), 1);
+
+    host.get_emitted_msgs();
+    host.handle_msg(OmniPeerId(1), resent[0].msg.clone());
+    assert!(host.chunk_storage.get(&chunk).is_some());
+    let ack = host
+        .get_emitted_msgs()
+        .into_iter()
+        .find(|m| matches!(m.msg, WorldNetMessage::StorageAck { .. }))
+        .unwrap();
+    assert!(matches!(ack.dst, Destination::Peer(OmniPeerId(1))));
+
+    // Ack from an older world doesn't count.
+    peer.handle_msg(
+        OmniPeerId(0),
+        WorldNetMessage::StorageAck {
+            chunk,
+            world_num: peer.world_num.wrapping_sub(1),
+        },
+    );
+    assert!(peer.unacked_storage.contains_key(&chunk));
 
-    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
-    let mut img = image::GrayImage::new(pixels, pixels);
-    world._create_image(&mut img, pixels);
-    img.save("/tmp/ew_tmp_save/img_ex_big.png").unwrap();
+    peer.handle_msg(OmniPeerId(0), ack.msg);
+    peer.current_update += STORAGE_ACK_TIMEOUT;
+    peer.update(Duration::ZERO);
+    assert!(!peer.get_emitted_msgs().iter().any(|m| is_storage(&m.msg)));
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_explosion_large() {
-    let (mut world, _, _, _, _) = WorldManager::new(
-        true,
+fn test_stale_storage_retry() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.chunk_storage.clear();
+    let chunk = ChunkCoord(0, 0);
+    let (old, new) = (OmniPeerId(1), OmniPeerId(2));
+    let world_num = host.world_num;
+    let storage = |mat| WorldNetMessage::UpdateStorage {
+        chunk,
+        chunk_data: Some(ChunkData::new(mat)),
+        world_num,
+        priority: None,
+    };
+    let stored = |host: &WorldManager, mat| {
+        bitcode::encode(&*host.chunk_storage.get(&chunk).unwrap())
+            == bitcode::encode(&ChunkData::new(mat))
+    };
+    host.authority_map.insert(chunk, (old, 0));
+    host.emit_transfer_authority(chunk, new, 0, old);
+    // Storage of the previous authority is still taken until the new one stores anything.
+    host.handle_msg(old, storage(1));
+    assert!(stored(&host, 1));
+    host.handle_msg(new, storage(2));
+    assert!(stored(&host, 2));
+    host.get_emitted_msgs();
+    host.handle_msg(old, storage(1));
+    assert!(stored(&host, 2));
+    // Still acknowledged, so that it isn't sent again.
+    assert!(matches!(
+        host.get_emitted_msgs().as_slice(),
+        [MessageRequest {
+            msg: WorldNetMessage::StorageAck { .. },
+            dst: Destination::Peer(peer),
+            ..
+        }] if *peer == old
+    ));
+
+    // Peer doesn't retry storage once it has the chunk again.
+    let (mut peer, _, _, _, _) = WorldManager::new(false, old, SaveState::new("/tmp/ew_tmp_save"));
+    peer.outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+    peer.send_storage(chunk, Some(ChunkData::new(1)));
+    peer.handle_msg(
         OmniPeerId(0),
-        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+        WorldNetMessage::GotAuthority {
+            chunk,
+            chunk_data: None,
+            priority: 0,
+            listeners: Default::default(),
+        },
+    );
+    peer.get_emitted_msgs();
+    peer.current_update += STORAGE_ACK_TIMEOUT;
+    peer.update(Duration::ZERO);
+    assert!(
+        !peer
+            .get_emitted_msgs()
+            .iter()
+            .any(|request| matches!(request.msg, WorldNetMessage::UpdateStorage { .. }))
     );
-    world.nice_terraforming = false;
-    world
-        .materials
-        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
-    world
-        .materials
-        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
-    world
-        .materials
-        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
-    let w = 1024;
-    for i in -w..w {
-        for j in -w..w {
-            world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
-        }
-    }
-    //let mut img = image::GrayImage::new(pixels, pixels);
-    //world._create_image(&mut img, pixels);
-    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
-
-    let timer = std::time::Instant::now();
-    world.cut_through_world_explosion(vec![ExplosionData::new(
-        0,
-        0,
-        65536,
-        15,
-        2_000_000_000,
-        true,
-        true,
-        1,
-        4,
-    )]);
-    println!("total large ex milli {}", timer.elapsed().as_millis());
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_cut_img() {
-    let (mut world, _, _, _, _) = WorldManager::new(
-        true,
-        OmniPeerId(0),
-        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+fn test_apply_chunk_delta_transformed() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let (chunk, target) = (ChunkCoord(0, 0), ChunkCoord(2, 0));
+    world.chunk_storage.insert(target, ChunkData::new(1));
+    let pixel = |material| {
+        Some(
+            RawPixel {
+                material,
+                flags: PixelFlags::Normal,
+            }
+            .to_compact(),
+        )
+    };
+    // Left column and a single pixel in the second row.
+    let delta = ChunkDelta::new(
+        chunk,
+        (0..CHUNK_SIZE * CHUNK_SIZE).map(|i| match (i % CHUNK_SIZE, i / CHUNK_SIZE) {
+            (0, _) => pixel(5),
+            (3, 1) => pixel(6),
+            _ => None,
+        }),
     );
+    assert!(world.apply_chunk_delta_transformed(
+        &delta,
+        ChunkTransform {
+            offset: (2, 0),
+            mirror_x: true,
+            mirror_y: false,
+        },
+    ));
+    assert!(!world.chunk_storage.contains_key(&chunk));
+    let mut result = Chunk::default();
     world
-        .materials
-        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
-    world
-        .materials
-        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
-    world
-        .materials
-        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
-    let w = 48;
-    for i in -w..w {
-        for j in -w..w {
-            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
-                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
-                world
-                    .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
-            } else {
-                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
-            }
-        }
+        .chunk_storage
+        .get(&target)
+        .unwrap()
+        .apply_to_chunk(&mut result);
+    let material = |x: usize, y: usize| result.pixel(x + y * CHUNK_SIZE).material;
+    for y in 0..CHUNK_SIZE {
+        assert_eq!(material(CHUNK_SIZE - 1, y), 5);
+        assert_eq!(material(0, y), 1);
     }
-    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+    assert_eq!(material(CHUNK_SIZE - 4, 1), 6);
+    assert_eq!(material(3, 1), 1);
+    assert_eq!(material(CHUNK_SIZE - 4, 2), 1);
 
-    let timer = std::time::Instant::now();
-    world.cut_through_world(0, i32::MIN, 0, 128);
-    println!("total img micros {}", timer.elapsed().as_micros());
+    let flipped = delta.transformed(ChunkTransform {
+        mirror_y: true,
+        ..Default::default()
+    });
+    assert_eq!(flipped.chunk_coord, chunk);
+    assert!(
+        flipped
+            .transformed(ChunkTransform {
+                mirror_y: true,
+                ..Default::default()
+            })
+            .same_pixels(&delta)
+    );
+}
 
-    let mut img = image::GrayImage::new(pixels, pixels);
-    world._create_image(&mut img, pixels);
-    img.save("/tmp/ew_tmp_save/img_cut.png").unwrap();
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_notplayer_sync_radius() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let edge = ChunkCoord(PLAYER_SYNC_RADIUS, 0);
+    let listen = |peer: &mut WorldManager| {
+        peer.chunk_state.insert(
+            edge,
+            ChunkState::Listening {
+                authority: OmniPeerId(0),
+                priority: 0,
+            },
+        );
+    };
+    peer.notplayer_sync_radius = None;
+    listen(&mut peer);
+    peer.set_position((0, 0), (0, 0), true);
+    peer.update(Duration::ZERO);
+    assert!(peer.chunk_state.contains_key(&edge));
+
+    peer.notplayer_sync_radius = Some(PLAYER_SYNC_RADIUS);
+    peer.set_position((0, 0), (0, 0), false);
+    peer.update(Duration::ZERO);
+    peer.set_position((0, 0), (0, 0), true);
+    peer.update(Duration::ZERO);
+    assert!(peer.chunk_state.contains_key(&edge));
+    assert!(
+        !peer
+            .get_emitted_msgs()
+            .iter()
+            .any(|request| matches!(request.msg, WorldNetMessage::ListenStopRequest { .. }))
+    );
+
+    peer.notplayer_sync_radius = Some(PLAYER_SYNC_RADIUS - 1);
+    peer.update(Duration::ZERO);
+    assert!(!peer.chunk_state.contains_key(&edge));
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_line_img() {
-    let (mut world, _, _, _, _) = WorldManager::new(
-        true,
-        OmniPeerId(0),
-        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+fn test_get_chunks_data() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    let (outbound, inbound, stored, unknown) = (
+        ChunkCoord(0, 0),
+        ChunkCoord(1, 0),
+        ChunkCoord(2, 0),
+        ChunkCoord(3, 0),
     );
+    for chunk in [outbound, inbound, stored] {
+        world.chunk_storage.insert(chunk, ChunkData::new(3));
+    }
+    for chunk in [outbound, inbound] {
+        world
+            .inbound_model
+            .apply_chunk_data(chunk, &ChunkData::new(2))
+            .unwrap();
+    }
     world
-        .materials
-        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
-    world
-        .materials
-        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
-    world
-        .materials
-        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
-    let w = 24;
-    for i in -w..w {
-        for j in -w..w {
-            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
-                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
-                world
-                    .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
-            } else {
-                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+        .outbound_model
+        .apply_chunk_data(outbound, &ChunkData::new(1))
+        .unwrap();
+    let material = |data: &Option<ChunkData>| data.as_ref().map(|data| data.runs[0].data.raw());
+    let expected = |chunk: ChunkCoord| {
+        let data = world.latest_chunk_data(chunk);
+        assert_eq!(
+            material(&data),
+            material(&world.get_chunks_data(&[chunk])[0].1)
+        );
+        material(&data)
+    };
+    let single: Vec<_> = [outbound, inbound, stored, unknown]
+        .into_iter()
+        .map(expected)
+        .collect();
+    let raw = |mat| {
+        Some(
+            RawPixel {
+                material: mat,
+                flags: PixelFlags::Normal,
             }
-        }
-    }
+            .to_compact()
+            .raw(),
+        )
+    };
+    assert_eq!(single, vec![raw(1), raw(2), raw(3), None]);
 
-    let timer = std::time::Instant::now();
-    world.cut_through_world_line(
-        128 * -50,
-        128 * -50 + 512,
-        128 * 50,
-        128 * 50 + 512,
-        128,
-        98,
-    );
-    for i in 0..64 {
-        let sx = 128 * -50;
-        let sy = 128 * 50 + 512;
-        world.cut_through_world_line(
-            sx + (i - 1) * 100,
-            sy - (i - 1) * 100,
-            sx + i * 100,
-            sy - i * 100,
-            128,
-            98,
-        );
+    // Big enough to be resolved in parallel.
+    let coords: Vec<ChunkCoord> = (0..PARALLEL_QUERY_MIN_CHUNKS * 2)
+        .map(|i| ChunkCoord(i as i32 % 4, 0))
+        .collect();
+    let batch = world.get_chunks_data(&coords);
+    assert_eq!(batch.len(), coords.len());
+    for ((chunk, data), expected_chunk) in batch.iter().zip(&coords) {
+        assert_eq!(chunk, expected_chunk);
+        assert_eq!(material(data), single[chunk.0 as usize]);
     }
-    world.cut_through_world_line(0, 0, 126, 64, 20, 50);
-    world.cut_through_world_line(100, 0, 226, -164, 20, 0);
-    world.cut_through_world_line(-100, 0, 26, 164, 20, 100);
-    println!("total img micros {}", timer.elapsed().as_micros());
+}
 
-    let w = 48;
-    for i in -w..w {
-        for j in -w..w {
-            if (-24..24).contains(&j) && (-24..24).contains(&i) {
-                continue;
-            }
-            let c = ChunkCoord(i, j);
-            if let Some(ch) = world.chunk_storage.get(&c) {
-                world
-                    .outbound_model
-                    .apply_chunk_data(c, &_brickwork.clone());
-                world.outbound_model.apply_chunk_data(c, ch);
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_small_explosion_perf() {
+    let new_world = |serial_explosion_radius| {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.serial_explosion_radius = serial_explosion_radius;
+        world
+            .materials
+            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0, 0));
+        world
+            .materials
+            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0, 0));
+        world.chunk_storage.clear();
+        for i in -2..2 {
+            for j in -2..2 {
                 world
                     .chunk_storage
-                    .insert(c, world.outbound_model.get_chunk_data(c).unwrap().clone());
-                world.outbound_model.forget_chunk(c);
-            } else {
-                world.chunk_storage.insert(c, _brickwork.clone());
+                    .insert(ChunkCoord(i, j), ChunkData::new(1));
             }
         }
+        world
+    };
+    let explosions = |world: &mut WorldManager| {
+        let timer = std::time::Instant::now();
+        for i in 0..256 {
+            let mut ex = ExplosionData::new(i % 64 - 32, i / 8 - 16, 4, 8, 100, true, true, 1, 4);
+            ex.seed = i as u64;
+            ex.jitter = 0.5;
+            world.cut_through_world_explosion(vec![ex]);
+        }
+        timer.elapsed().as_micros()
+    };
+    let mut parallel = new_world(0);
+    let mut serial = new_world(16);
+    let parallel_micros = explosions(&mut parallel);
+    let serial_micros = explosions(&mut serial);
+    println!("parallel micros: {parallel_micros}, serial micros: {serial_micros}");
+    assert_eq!(parallel.world_hash(), serial.world_hash());
+    assert_ne!(parallel.world_hash(), new_world(16).world_hash());
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_listeners_of() {
+    let (mut peer, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let (mine, listening) = (ChunkCoord(0, 0), ChunkCoord(1, 0));
+    peer.chunk_state.insert(mine, ChunkState::authority(0));
+    peer.chunk_state.insert(
+        listening,
+        ChunkState::Listening {
+            authority: OmniPeerId(2),
+            priority: 0,
+        },
+    );
+    assert_eq!(peer.listeners_of(mine), Some(Vec::new()));
+    for listener in [OmniPeerId(4), OmniPeerId(2), OmniPeerId(4)] {
+        peer.handle_msg(listener, WorldNetMessage::ListenRequest { chunk: mine });
     }
-    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+    peer.handle_msg(
+        OmniPeerId(3),
+        WorldNetMessage::ListenRequest { chunk: listening },
+    );
+    assert_eq!(
+        peer.listeners_of(mine),
+        Some(vec![OmniPeerId(2), OmniPeerId(4)])
+    );
+    assert_eq!(peer.listeners_of(listening), None);
+    assert_eq!(peer.listeners_of(ChunkCoord(2, 0)), None);
 
-    let mut img = image::GrayImage::new(pixels, pixels);
-    world._create_image(&mut img, pixels);
-    img.save("/tmp/ew_tmp_save/img_line.png").unwrap();
+    peer.handle_msg(
+        OmniPeerId(2),
+        WorldNetMessage::ListenStopRequest { chunk: mine },
+    );
+    assert_eq!(peer.listeners_of(mine), Some(vec![OmniPeerId(4)]));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_max_cut_depth() {
+    let rows = -4..4;
+    let cut_rows = |cut_clamp, y_min, y_max| {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
+        for cy in rows.clone() {
+            world
+                .chunk_storage
+                .insert(ChunkCoord(0, cy), ChunkData::new(1));
+        }
+        world.max_cut_depth = Some(CHUNK_SIZE as i32 * 2);
+        world.cut_clamp = cut_clamp;
+        world.cut_through_world(64, y_min, y_max, 10);
+        rows.clone()
+            .filter(|&cy| {
+                let data = world.chunk_storage.get(&ChunkCoord(0, cy)).unwrap();
+                bitcode::encode(&*data) != bitcode::encode(&ChunkData::new(1))
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        cut_rows(CutClamp::FromTop, -2 * CHUNK_SIZE as i32, i32::MAX),
+        vec![-2, -1, 0]
+    );
+    assert_eq!(
+        cut_rows(CutClamp::Centered, -1_000_000_000, 1_000_000_000),
+        vec![-1, 0, 1]
+    );
+    // Cuts within the limit aren't changed.
+    assert_eq!(
+        cut_rows(
+            CutClamp::Centered,
+            -(CHUNK_SIZE as i32),
+            CHUNK_SIZE as i32 - 1
+        ),
+        vec![-1, 0]
+    );
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_merge_storage() {
+    let (shared, ours, theirs) = (ChunkCoord(0, 0), ChunkCoord(1, 0), ChunkCoord(2, 0));
+    let air_with_stone = {
+        let mut chunk = Chunk::default();
+        ChunkData::new(0).apply_to_chunk(&mut chunk);
+        chunk.set_pixel(
+            0,
+            RawPixel {
+                material: 5,
+                flags: PixelFlags::Normal,
+            },
+        );
+        chunk.to_chunk_data()
+    };
+    let other: FxHashMap<ChunkCoord, ChunkData> =
+        [(shared, ChunkData::new(2)), (theirs, ChunkData::new(3))]
+            .into_iter()
+            .collect();
+    let merged = |policy| {
+        let (mut world, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
+        world.chunk_storage.insert(shared, air_with_stone.clone());
+        world.chunk_storage.insert(ours, ChunkData::new(1));
+        world.merge_storage(other.clone(), policy);
+        let pixel = |chunk, offset| {
+            let mut pixels = Chunk::default();
+            world
+                .chunk_storage
+                .get(&chunk)
+                .unwrap()
+                .apply_to_chunk(&mut pixels);
+            pixels.pixel(offset).material
+        };
+        [
+            pixel(shared, 0),
+            pixel(shared, 1),
+            pixel(ours, 0),
+            pixel(theirs, 0),
+        ]
+    };
+    assert_eq!(merged(ImportPolicy::SkipExisting), [5, 0, 1, 3]);
+    assert_eq!(merged(ImportPolicy::Overwrite), [2, 2, 1, 3]);
+    assert_eq!(merged(ImportPolicy::MergeNonAir), [5, 2, 1, 3]);
+}
+
+/// Checks that messages we send to ourselves leave the world the same whether they are handled
+/// right away, or like ones that came over the network. Catches handlers that only work because
+/// they run in the middle of whatever sent the message, or that mishandle their own peer id.
+#[cfg(test)]
+fn assert_self_handling_matches(
+    new_world: impl Fn() -> WorldManager,
+    action: impl Fn(&mut WorldManager),
+) {
+    let outcome = |mut world: WorldManager| {
+        let mut chunk_state: Vec<_> = world
+            .chunk_state
+            .iter()
+            .map(|(chunk, state)| format!("{chunk:?} {state:?}"))
+            .collect();
+        chunk_state.sort();
+        let mut authority_map: Vec<_> = world
+            .authority_map
+            .iter()
+            .map(|(chunk, authority)| format!("{chunk:?} {authority:?}"))
+            .collect();
+        authority_map.sort();
+        let emitted: Vec<_> = world
+            .get_emitted_msgs()
+            .into_iter()
+            .map(|request| format!("{:?} {:?}", request.dst, request.msg))
+            .collect();
+        (chunk_state, authority_map, emitted, world.world_hash())
+    };
+    let mut local = new_world();
+    action(&mut local);
+    let mut remote = new_world();
+    remote.loopback = Some(Vec::new());
+    action(&mut remote);
+    remote.deliver_loopback();
+    assert_eq!(outcome(local), outcome(remote));
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_self_handling_matches_remote() {
+    let chunk = ChunkCoord(0, 0);
+    let new_host = || {
+        let (mut host, _, _, _, _) =
+            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        host.chunk_storage.clear();
+        host.chunk_storage.insert(chunk, ChunkData::new(1));
+        host
+    };
+    let request = |host: &mut WorldManager| {
+        host.chunk_state.insert(
+            chunk,
+            ChunkState::RequestAuthority {
+                priority: 0,
+                can_wait: true,
+            },
+        );
+        host.update(Duration::ZERO);
+    };
+    assert_self_handling_matches(new_host, request);
+
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    request(&mut host);
+    assert!(host.is_authority(chunk));
+
+    // Someone else has it already.
+    assert_self_handling_matches(
+        || {
+            let mut host = new_host();
+            host.authority_map.insert(chunk, (OmniPeerId(1), 0));
+            host
+        },
+        request,
+    );
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_circ_img() {
-    let (mut world, _, _, _, _) = WorldManager::new(
-        true,
-        OmniPeerId(0),
-        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
-    );
-    world
-        .materials
-        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
-    world
-        .materials
-        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
-    world
-        .materials
-        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
-    let w = 48;
-    for i in -w..w {
-        for j in -w..w {
-            if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
-                //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
-                world
-                    .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
-            } else {
-                world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
+fn test_delta_ack_resends_chunk() {
+    let (mut authority, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut listener, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(2), SaveState::new("/tmp/ew_tmp_save"));
+    listener.delta_ack_interval = Some(1);
+    listener.set_position((0, 0), (0, 0), false);
+    let chunk = ChunkCoord(0, 0);
+    authority
+        .outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+    authority
+        .outbound_model
+        .reset_change_tracking_except(&Default::default());
+    authority
+        .chunk_state
+        .insert(chunk, ChunkState::authority(0));
+    let deliver = |from: &mut WorldManager, to: &mut WorldManager| {
+        for request in from.get_emitted_msgs() {
+            if request.dst == Destination::Peer(to.my_peer_id) {
+                to.handle_msg(from.my_peer_id, request.msg);
             }
         }
+    };
+    // Each change keeps pixels of the previous ones, and sets one more.
+    let change = |authority: &mut WorldManager, materials: &[u16]| {
+        let runs = materials
+            .iter()
+            .map(|&material| PixelRun {
+                length: 1,
+                data: RawPixel {
+                    material,
+                    flags: PixelFlags::Normal,
+                },
+            })
+            .collect();
+        authority.handle_noita_msg(
+            OmniPeerId(1),
+            WorldSyncToProxy::Updates(vec![NoitaWorldUpdate { coord: chunk, runs }]),
+        );
+        authority.handle_noita_msg(OmniPeerId(1), WorldSyncToProxy::End(None, 0, 0));
+    };
+    let synced = |authority: &WorldManager, listener: &WorldManager| {
+        bitcode::encode(&authority.outbound_model.get_chunk_data(chunk).unwrap())
+            == bitcode::encode(&listener.inbound_model.get_chunk_data(chunk).unwrap())
+    };
+    authority.handle_msg(OmniPeerId(2), WorldNetMessage::ListenRequest { chunk });
+    deliver(&mut authority, &mut listener);
+    assert!(synced(&authority, &listener));
+
+    // First delta is lost.
+    change(&mut authority, &[5]);
+    assert!(
+        authority
+            .get_emitted_msgs()
+            .iter()
+            .any(|request| matches!(request.msg, WorldNetMessage::ChunkPacket { .. }))
+    );
+    change(&mut authority, &[5, 6]);
+    deliver(&mut authority, &mut listener);
+    assert!(!synced(&authority, &listener));
+
+    listener.current_update += 1;
+    listener.update(Duration::ZERO);
+    let acks: Vec<_> = listener
+        .get_emitted_msgs()
+        .into_iter()
+        .filter(|request| matches!(request.msg, WorldNetMessage::DeltaAck { seq: 1, .. }))
+        .collect();
+    assert_eq!(acks.len(), 1);
+    assert_eq!(acks[0].dst, Destination::Peer(OmniPeerId(1)));
+
+    // Might just be in flight.
+    authority.handle_msg(OmniPeerId(2), acks[0].msg.clone());
+    assert!(authority.get_emitted_msgs().is_empty());
+
+    authority.current_update += DELTA_ACK_TIMEOUT;
+    authority.handle_msg(OmniPeerId(2), acks[0].msg.clone());
+    let resent = authority.get_emitted_msgs();
+    assert!(
+        resent
+            .iter()
+            .any(|request| matches!(request.msg, WorldNetMessage::ListenInitialResponse { .. }))
+    );
+    for request in resent {
+        listener.handle_msg(OmniPeerId(1), request.msg);
     }
-    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
+    assert!(synced(&authority, &listener));
 
-    let timer = std::time::Instant::now();
-    world.cut_through_world_circle(0, 0, 540, None, 80);
-    println!("total img micros {}", timer.elapsed().as_micros());
+    // Nothing is missing after that.
+    change(&mut authority, &[5, 6, 7]);
+    deliver(&mut authority, &mut listener);
+    assert!(synced(&authority, &listener));
+    listener.current_update += 1;
+    listener.update(Duration::ZERO);
+    let acks = listener.get_emitted_msgs();
+    assert!(
+        acks.iter()
+            .any(|request| matches!(request.msg, WorldNetMessage::DeltaAck { seq: 1, .. }))
+    );
+    authority.current_update += DELTA_ACK_TIMEOUT;
+    for request in acks {
+        authority.handle_msg(OmniPeerId(2), request.msg);
+    }
+    assert!(
+        !authority
+            .get_emitted_msgs()
+            .iter()
+            .any(|request| matches!(request.msg, WorldNetMessage::ListenInitialResponse { .. }))
+    );
 
-    let mut img = image::GrayImage::new(pixels, pixels);
-    world._create_image(&mut img, pixels);
-    img.save("/tmp/ew_tmp_save/img_circ.png").unwrap();
+    // Acks keep working with whoever the authority got transferred to.
+    let (mut new_authority, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(3), SaveState::new("/tmp/ew_tmp_save"));
+    new_authority
+        .outbound_model
+        .apply_chunk_data(
+            chunk,
+            &authority.outbound_model.get_chunk_data(chunk).unwrap(),
+        )
+        .unwrap();
+    new_authority
+        .outbound_model
+        .reset_change_tracking_except(&Default::default());
+    let mut state = ChunkState::authority(0);
+    if let ChunkState::Authority { listeners, .. } = &mut state {
+        listeners.insert(OmniPeerId(2));
+    }
+    new_authority.chunk_state.insert(chunk, state);
+    listener.handle_msg(OmniPeerId(3), WorldNetMessage::NotifyNewAuthority { chunk });
+    change(&mut new_authority, &[5, 6, 7, 8]);
+    deliver(&mut new_authority, &mut listener);
+    assert!(synced(&new_authority, &listener));
+    listener.current_update += 1;
+    listener.update(Duration::ZERO);
+    for request in listener.get_emitted_msgs() {
+        assert_eq!(request.dst, Destination::Peer(OmniPeerId(3)));
+        new_authority.handle_msg(OmniPeerId(2), request.msg);
+    }
+    assert!(
+        new_authority.deltas_sent[&(chunk, OmniPeerId(2))]
+            .unacked
+            .is_empty()
+    );
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_explosion_img_big_many() {
-    let (mut world, _, _, _, _) = WorldManager::new(
-        true,
-        OmniPeerId(0),
-        SaveState::new("/tmp/ew_tmp_save".parse().unwrap()),
+fn test_warm_up_priority() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.chunk_storage.clear();
+    let background: Vec<ChunkCoord> = (0..8).map(|x| ChunkCoord(x, 5)).collect();
+    let (warm, pinned) = (ChunkCoord(0, 0), ChunkCoord(1, 0));
+    for &chunk in background.iter().chain([&warm, &pinned]) {
+        host.chunk_storage.insert(chunk, ChunkData::new(1));
+    }
+    let request = |host: &mut WorldManager, peer, chunk, priority| {
+        host.handle_msg(
+            OmniPeerId(peer),
+            WorldNetMessage::RequestAuthority {
+                chunk,
+                priority,
+                can_wait: true,
+                hint: None,
+            },
+        );
+    };
+    host.warm_up_priority = Some(2);
+    for &chunk in &background {
+        request(&mut host, 1, chunk, 10);
+    }
+    host.handle_msg(
+        OmniPeerId(2),
+        WorldNetMessage::ListenRequest { chunk: pinned },
     );
-    world
-        .materials
-        .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid)));
-    world
-        .materials
-        .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static)));
-    world
-        .materials
-        .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static)));
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
-    let w = 20;
-    for i in -w..w {
-        for j in -w..w {
-            if i != -10 {
-                if (-4..=-3).contains(&i) && (-4..=4).contains(&j) {
-                    //world.outbound_model.apply_chunk_data(ChunkCoord(i, j), &_brickwork.clone());
-                    world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
-                } else {
-                    world.chunk_storage.insert(ChunkCoord(i, j), _dirt.clone());
-                }
-            }
-        }
+    request(&mut host, 2, warm, 0);
+    request(&mut host, 3, pinned, 0);
+    let emitted = host.get_emitted_msgs();
+    let got_authority = |request: &MessageRequest<WorldNetMessage>| match request.msg {
+        WorldNetMessage::GotAuthority { chunk, .. } => Some(chunk),
+        _ => None,
+    };
+    assert_eq!(got_authority(&emitted[0]), Some(warm));
+    // Background work still goes out, in the same order.
+    let rest: Vec<ChunkCoord> = emitted[1..].iter().filter_map(got_authority).collect();
+    assert_eq!(rest[..background.len()], background[..]);
+    // Doesn't overtake what was already sent about the chunk.
+    let position = |chunk| {
+        emitted
+            .iter()
+            .position(|request| request.msg.chunk() == Some(chunk))
+            .unwrap()
+    };
+    assert!(!matches!(
+        emitted[position(pinned)].msg,
+        WorldNetMessage::GotAuthority { .. }
+    ));
+    assert!(position(pinned) > 0);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_arena_world_bounds() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.chunk_storage.clear();
+    let (inside, outside) = (ChunkCoord(3, 0), ChunkCoord(4, 0));
+    for chunk in [inside, outside] {
+        host.chunk_storage.insert(chunk, ChunkData::new(1));
     }
+    host.set_world_bounds(Some((ChunkCoord(0, 0), ChunkCoord(3, 3))));
+    assert!(host.chunk_storage.contains_key(&inside));
+    assert!(!host.chunk_storage.contains_key(&outside));
 
-    //let mut img = image::GrayImage::new(pixels, pixels);
-    //world._create_image(&mut img, pixels);
-    //img.save("/tmp/ew_tmp_save/img1.png").unwrap();
+    host.handle_msg(
+        OmniPeerId(1),
+        WorldNetMessage::RequestAuthority {
+            chunk: outside,
+            priority: 0,
+            can_wait: true,
+            hint: None,
+        },
+    );
+    assert!(!host.authority_map.contains_key(&outside));
+    let emitted = host.get_emitted_msgs();
+    assert_eq!(emitted.len(), 1);
+    assert_eq!(emitted[0].dst, Destination::Peer(OmniPeerId(1)));
+    assert!(matches!(
+        emitted[0].msg,
+        WorldNetMessage::UnloadChunk { chunk } if chunk == outside
+    ));
+    host.handle_msg(
+        OmniPeerId(1),
+        WorldNetMessage::UpdateStorage {
+            chunk: outside,
+            chunk_data: Some(ChunkData::new(2)),
+            world_num: host.world_num,
+            priority: None,
+        },
+    );
+    assert!(!host.chunk_storage.contains_key(&outside));
 
-    let timer = std::time::Instant::now();
-    world.cut_through_world_explosion(vec![
-        ExplosionData::new(
-            0,
-            0,
-            128,
-            15,
-            100_000_000,
-            true,
-            true,
-            1,
-            4,
-        );
-        16
-    ]);
-    world.cut_through_world_explosion(vec![
-        ExplosionData::new(1000, 5000, 128, 15, 100_000_000, true, true, 1, 4),
-        ExplosionData::new(-1000, -5000, 128, 15, 100_000_000, true, true, 1, 4),
-    ]);
-    world.cut_through_world_explosion(vec![
-        ExplosionData::new(0, 0, 4096, 15, 256_000_000, true, true, 1, 4),
-        ExplosionData::new(128 * 48, 128 * 48, 4096, 15, 256_000_000, true, true, 1, 4),
-    ]);
-    world.cut_through_world_explosion(
-        (-48..48)
-            .flat_map(|a| (-48..48).map(|b| (a, b)).collect::<Vec<(i32, i32)>>())
-            .map(|(a, b)| {
-                ExplosionData::new(a * 128, b * 128, 64, 15, 256_000_000, true, true, 1, 4)
-            })
-            .collect(),
+    // Cuts reaching past the arena are clipped to it.
+    host.cut_through_world(4 * CHUNK_SIZE as i32, 0, CHUNK_SIZE as i32 - 1, 20);
+    assert!(!host.chunk_storage.contains_key(&outside));
+    assert_ne!(
+        bitcode::encode(&*host.chunk_storage.get(&inside).unwrap()),
+        bitcode::encode(&ChunkData::new(1))
     );
-    let w = 48;
-    let mut rng = rng();
-    let mut iter = (-w..w)
-        .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
-        .collect::<Vec<(i32, i32)>>();
-    iter.shuffle(&mut rng);
-    for (i, j) in iter {
-        let c = ChunkCoord(i, j);
-        if let std::collections::hash_map::Entry::Vacant(e) = world.chunk_storage.entry(c) {
-            e.insert(if rng.random_bool(0.2) {
-                _brickwork.clone()
-            } else {
-                _dirt.clone()
-            });
-        }
-        if world.explosion_pointer.contains_key(&c) {
-            world.cut_through_world_explosion_chunk(c)
-        }
-    }
-    println!("total img micros {}", timer.elapsed().as_micros());
+}
 
-    let pixels = (w * 2 * CHUNK_SIZE as i32) as u32;
-    let mut img = image::GrayImage::new(pixels, pixels);
-    world._create_image(&mut img, pixels);
-    img.save("/tmp/ew_tmp_save/img_ex_bigger_test.png").unwrap();
-}*/
-#[cfg(test)]
-use crate::net::LiquidType;
-#[cfg(test)]
-use rand::seq::SliceRandom;
-#[cfg(test)]
-use serial_test::serial;
-use shared::world_sync::{
-    CHUNK_SIZE, ChunkCoord, NoitaWorldUpdate, PixelFlags, RawPixel, WorldSyncToProxy,
-};
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_explosion_perf() {
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
-
-    let mut total = 0;
-    let iters = 64;
-    for _ in 0..iters {
+fn test_fill_density() {
+    let fill = |density| {
         let (mut world, _, _, _, _) =
             WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+        world.chunk_storage.clear();
         world
-            .materials
-            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0));
-        world
-            .materials
-            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0));
+            .chunk_storage
+            .insert(ChunkCoord(0, 0), ChunkData::new(1));
+        world.cut_through_world_circle(60, 60, 40, Some(5), density, 100, 7, None);
+        let mut chunk = Chunk::default();
         world
-            .materials
-            .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0));
-        let w = 48;
-        for i in -w..w {
-            for j in -w..w {
-                world
-                    .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
+            .chunk_storage
+            .get(&ChunkCoord(0, 0))
+            .unwrap()
+            .apply_to_chunk(&mut chunk);
+        let mut counts = (0, 0);
+        for x in 20..=100 {
+            for y in 20..=100 {
+                if (x - 60) * (x - 60) + (y - 60) * (y - 60) > 40 * 40 {
+                    continue;
+                }
+                match chunk.pixel(y as usize * CHUNK_SIZE + x as usize).material {
+                    5 => counts.0 += 1,
+                    0 => counts.1 += 1,
+                    _ => panic!("pixel inside the cut kept its material"),
+                }
             }
         }
-        let timer = std::time::Instant::now();
-        world.cut_through_world_explosion(vec![
-            ExplosionData::new(
-                0,
-                0,
-                8,
-                14,
-                2_000_000_000,
-                true,
-                true,
-                1,
-                50
-            );
-            16
-        ]);
-        total += timer.elapsed().as_micros();
+        counts
+    };
+    let (filled, carved) = fill(50);
+    let ratio = filled as f64 / (filled + carved) as f64;
+    assert!((0.45..0.55).contains(&ratio), "{ratio}");
+    assert_eq!(fill(50), (filled, carved));
+    assert_eq!(fill(100).1, 0);
+    assert_eq!(fill(0).0, 0);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_transition_syncs_final_state() {
+    let (mut authority, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut listener, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(2), SaveState::new("/tmp/ew_tmp_save"));
+    let chunk = ChunkCoord(0, 0);
+    let new_chunk = ChunkCoord(1, 0);
+    authority
+        .outbound_model
+        .apply_chunk_data(chunk, &ChunkData::new(1))
+        .unwrap();
+    authority
+        .outbound_model
+        .reset_change_tracking_except(&Default::default());
+    authority
+        .chunk_state
+        .insert(chunk, ChunkState::authority(0));
+    authority.handle_msg(OmniPeerId(2), WorldNetMessage::ListenRequest { chunk });
+    for request in authority.get_emitted_msgs() {
+        listener.handle_msg(OmniPeerId(1), request.msg);
+    }
+    let frame = |authority: &mut WorldManager, material: u16| {
+        let runs = |length| {
+            vec![PixelRun {
+                length,
+                data: RawPixel {
+                    material,
+                    flags: PixelFlags::Normal,
+                },
+            }]
+        };
+        authority.handle_noita_msg(
+            OmniPeerId(1),
+            WorldSyncToProxy::Updates(vec![
+                NoitaWorldUpdate {
+                    coord: chunk,
+                    runs: runs(CHUNK_SIZE as u16 * 4),
+                },
+                NoitaWorldUpdate {
+                    coord: new_chunk,
+                    runs: runs(1),
+                },
+            ]),
+        );
+        authority.handle_noita_msg(
+            OmniPeerId(1),
+            WorldSyncToProxy::End(Some((0, 0, 0, 0, false)), 0, 0),
+        );
+        authority.update(Duration::ZERO);
+    };
+
+    authority.begin_transition();
+    for material in 2..40 {
+        frame(&mut authority, material);
+        assert!(authority.get_emitted_msgs().is_empty());
+    }
+    assert!(!authority.chunk_state.contains_key(&new_chunk));
+    authority.end_transition();
+    authority.update(Duration::ZERO);
+    let emitted = authority.get_emitted_msgs();
+    let packets: Vec<_> = emitted
+        .iter()
+        .filter(|request| matches!(request.msg, WorldNetMessage::ChunkPacket { .. }))
+        .collect();
+    assert_eq!(packets.len(), 1);
+    assert!(emitted.iter().any(|request| matches!(
+        request.msg,
+        WorldNetMessage::RequestAuthority { chunk, .. } if chunk == new_chunk
+    )));
+    for request in emitted {
+        if request.dst == Destination::Peer(OmniPeerId(2)) {
+            listener.handle_msg(OmniPeerId(1), request.msg);
+        }
+    }
+    let listened = listener.inbound_model.get_chunk_data(chunk).unwrap();
+    assert_eq!(
+        bitcode::encode(&listened),
+        bitcode::encode(&authority.outbound_model.get_chunk_data(chunk).unwrap())
+    );
+    let mut pixels = Chunk::default();
+    listened.apply_to_chunk(&mut pixels);
+    assert_eq!(pixels.pixel(0).material, 39);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_reacquire_relinquished() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(2), SaveState::new("/tmp/ew_tmp_save"));
+    let near = ChunkCoord(0, 0);
+    let far = ChunkCoord(20, 20);
+    let listen = |world: &mut WorldManager, chunk| {
+        world.chunk_state.insert(
+            chunk,
+            ChunkState::Listening {
+                authority: OmniPeerId(1),
+                priority: 0,
+            },
+        );
+        world.chunk_updated_locally(chunk, 5, Some((0, 0, 0, 0, false)), 0);
+    };
+    let relinquish = |world: &mut WorldManager, chunk| {
+        world.handle_msg(
+            OmniPeerId(1),
+            WorldNetMessage::ListenAuthorityRelinquished { chunk },
+        );
+    };
+
+    listen(&mut world, near);
+    relinquish(&mut world, near);
+    assert_eq!(
+        world.chunk_state.get(&near),
+        Some(&ChunkState::UnloadPending)
+    );
+
+    world.reacquire_relinquished = true;
+    listen(&mut world, near);
+    listen(&mut world, far);
+    relinquish(&mut world, near);
+    relinquish(&mut world, far);
+    assert_eq!(
+        world.chunk_state.get(&near),
+        Some(&ChunkState::RequestAuthority {
+            priority: 5,
+            can_wait: true,
+        })
+    );
+    assert_eq!(
+        world.chunk_state.get(&far),
+        Some(&ChunkState::UnloadPending)
+    );
+    world.get_emitted_msgs();
+    world.update(Duration::ZERO);
+    let requests: Vec<_> = world
+        .get_emitted_msgs()
+        .into_iter()
+        .filter_map(|request| match request.msg {
+            WorldNetMessage::RequestAuthority {
+                chunk, priority, ..
+            } => Some((request.dst, chunk, priority)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(requests, vec![(Destination::Host, near, 5)]);
+}
+
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_authority_priorities() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    for (chunk, peer, priority) in [
+        (ChunkCoord(1, 0), 2, 7),
+        (ChunkCoord(-1, 3), 1, 0),
+        (ChunkCoord(1, -2), 0, 255),
+    ] {
+        host.handle_msg(
+            OmniPeerId(peer),
+            WorldNetMessage::RequestAuthority {
+                chunk,
+                priority,
+                can_wait: true,
+                hint: None,
+            },
+        );
     }
-    println!("total micros: {}", total / iters);
+    assert_eq!(
+        host.authority_priorities(),
+        vec![
+            (ChunkCoord(-1, 3), OmniPeerId(1), 0),
+            (ChunkCoord(1, -2), OmniPeerId(0), 255),
+            (ChunkCoord(1, 0), OmniPeerId(2), 7),
+        ]
+    );
+    host.handle_msg(
+        OmniPeerId(2),
+        WorldNetMessage::ChangePriority {
+            chunk: ChunkCoord(1, 0),
+            priority: 3,
+        },
+    );
+    assert_eq!(
+        host.authority_priorities()[2],
+        (ChunkCoord(1, 0), OmniPeerId(2), 3)
+    );
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_explosion_perf_unloaded() {
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
+fn test_replicate_terraform() {
+    let chunk = ChunkCoord(0, 0);
+    let carve = |chance: u8| TerraformOp::Circle {
+        x: 30,
+        y: 30,
+        r: 10,
+        mat: None,
+        density: 100,
+        chance,
+        seed: 0,
+        power: None,
+    };
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.chunk_storage.clear();
+    host.chunk_storage.insert(chunk, ChunkData::new(1));
+    host.is_storage_recent.insert(chunk);
+    host.replicate_terraform = true;
+    host.handle_msg(
+        OmniPeerId(1),
+        WorldNetMessage::RequestAuthority {
+            chunk,
+            priority: 0,
+            can_wait: true,
+            hint: None,
+        },
+    );
+    host.get_emitted_msgs();
 
-    let mut total = 0;
-    let iters = 4;
-    let mut n = 0;
-    for _ in 0..iters {
-        let (mut world, _, _, _, _) =
-            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
-        world
-            .materials
-            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0));
-        world
-            .materials
-            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0));
-        world
-            .materials
-            .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0));
-        let w = 4;
-        for i in -w..w {
-            for j in -w..w {
-                world
-                    .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
-            }
-        }
-        world.cut_through_world_explosion(vec![ExplosionData::new(
-            0,
-            0,
-            1024,
-            14,
-            2_000_000_000,
-            true,
-            true,
-            1,
-            50,
-        )]);
-        let w = 8;
-        let mut rng = rng();
-        let mut iter = (-w..w)
-            .flat_map(|i| (-w..w).map(|j| (i, j)).collect::<Vec<(i32, i32)>>())
-            .collect::<Vec<(i32, i32)>>();
-        iter.shuffle(&mut rng);
-        for (i, j) in iter {
-            let c = ChunkCoord(i, j);
-            if let std::collections::hash_map::Entry::Vacant(e) = world.chunk_storage.entry(c) {
-                e.insert(if rng.random_bool(0.2) {
-                    _brickwork.clone()
-                } else {
-                    _dirt.clone()
-                });
-            }
-            if world.explosion_pointer.contains_key(&c) {
-                let timer = std::time::Instant::now();
-                world.cut_through_world_explosion_chunk(c);
-                total += timer.elapsed().as_micros();
-                n += 1;
-            }
-        }
+    // Client has changed a pixel near the circle since, and only carved some of it.
+    let (mut client, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let live = 21 * CHUNK_SIZE + 21;
+    let mut pixels = Chunk::default();
+    ChunkData::new(1).apply_to_chunk(&mut pixels);
+    pixels.set_pixel(
+        live,
+        RawPixel {
+            material: 2,
+            flags: PixelFlags::Normal,
+        },
+    );
+    client.chunk_storage.insert(chunk, pixels.to_chunk_data());
+    client.terraform_batch(&[carve(50)]);
+    let predicted = client.chunk_storage.get(&chunk).unwrap().into_owned();
+    client
+        .outbound_model
+        .apply_chunk_data(chunk, &predicted)
+        .unwrap();
+    client.chunk_state.insert(chunk, ChunkState::authority(0));
+
+    host.terraform_batch(&[carve(100)]);
+    let mut expected = Chunk::default();
+    host.chunk_storage
+        .get(&chunk)
+        .unwrap()
+        .apply_to_chunk(&mut expected);
+    assert_ne!(
+        bitcode::encode(&predicted),
+        bitcode::encode(&expected.to_chunk_data())
+    );
+    let emitted = host.get_emitted_msgs();
+    assert_eq!(emitted.len(), 1);
+    assert_eq!(emitted[0].dst, Destination::Peer(OmniPeerId(1)));
+    for request in emitted {
+        client.handle_msg(OmniPeerId(0), request.msg);
     }
-    println!("total micros: {}", total / n);
+    expected.set_pixel(live, pixels.pixel(live));
+    assert_eq!(
+        bitcode::encode(&client.outbound_model.get_chunk_data(chunk).unwrap()),
+        bitcode::encode(&expected.to_chunk_data())
+    );
+    assert!(!client.inbound_model.get_all_noita_updates().is_empty());
+
+    // Host's storage might be outdated, nothing to send.
+    host.is_storage_recent.remove(&chunk);
+    host.chunk_storage.insert(chunk, ChunkData::new(1));
+    host.terraform_batch(&[carve(100)]);
+    assert!(host.get_emitted_msgs().is_empty());
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_explosion_perf_large() {
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
+fn test_max_explosion_rays() {
+    for r in [1, 10, 100, 500, 1000, 10_000, u64::MAX] {
+        assert!(get_ray(r, Some(64)) <= 64);
+        assert_eq!(get_ray(r, None), get_ray(r, Some(u64::MAX)));
+        assert_eq!(
+            compute_explosion_mask(r.min(100_000), 10, 1_000_000, Some(64))
+                .fan
+                .len() as u64,
+            get_ray(r.min(100_000), Some(64))
+        );
+    }
+    assert_eq!(get_ray(1000, Some(0)), 8);
 
-    let mut total = 0;
-    let iters = 16;
-    for _ in 0..iters {
+    let explode = |max_rays| {
         let (mut world, _, _, _, _) =
             WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
-        world
-            .materials
-            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0));
-        world
-            .materials
-            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0));
-        world
-            .materials
-            .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0));
-        let w = 48;
-        for i in -w..w {
-            for j in -w..w {
+        world.chunk_storage.clear();
+        for cx in -2..2 {
+            for cy in -2..2 {
                 world
                     .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
+                    .insert(ChunkCoord(cx, cy), ChunkData::new(1));
             }
         }
-        let timer = std::time::Instant::now();
-        world.cut_through_world_explosion(vec![
-            ExplosionData::new(
-                0,
-                0,
-                380,
-                14,
-                2_000_000_000,
-                true,
-                true,
-                1,
-                50
-            );
-            4
-        ]);
-        total += timer.elapsed().as_micros();
-    }
-    println!("total micros: {}", total / iters);
+        world.max_explosion_rays = max_rays;
+        world.cut_through_world_explosion(vec![ExplosionData::new(
+            0, 0, 200, 15, 1_000_000, true, true, 0, 0,
+        )]);
+        world.world_hash()
+    };
+    let uncapped = explode(None);
+    assert_ne!(explode(Some(64)), uncapped);
+    assert_eq!(explode(Some(get_ray(200, None))), uncapped);
 }
+
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_line_perf() {
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
+fn test_rebroadcast_authority_state() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut authority, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let (mut listener, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(2), SaveState::new("/tmp/ew_tmp_save"));
+    let (owned, forgotten) = (ChunkCoord(0, 0), ChunkCoord(1, 0));
+    for chunk in [owned, forgotten] {
+        host.handle_msg(
+            OmniPeerId(1),
+            WorldNetMessage::RequestAuthority {
+                chunk,
+                priority: 0,
+                can_wait: true,
+                hint: None,
+            },
+        );
+    }
+    host.get_emitted_msgs();
+    authority
+        .chunk_state
+        .insert(owned, ChunkState::authority(0));
+    authority.handle_msg(
+        OmniPeerId(2),
+        WorldNetMessage::ListenRequest { chunk: owned },
+    );
+    authority.get_emitted_msgs();
+    // Listener missed a NotifyNewAuthority.
+    listener.chunk_state.insert(
+        owned,
+        ChunkState::Listening {
+            authority: OmniPeerId(3),
+            priority: 0,
+        },
+    );
 
-    let mut total = 0;
-    let iters = 64;
-    for _ in 0..iters {
-        let (mut world, _, _, _, _) =
-            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
-        world
-            .materials
-            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0));
-        world
-            .materials
-            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0));
-        world
-            .materials
-            .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0));
-        let w = 48;
-        for i in -w..w {
-            for j in -w..w {
-                world
-                    .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
-            }
+    assert!(host.rebroadcast_authority_state());
+    for request in host.get_emitted_msgs() {
+        assert_eq!(request.dst, Destination::Peer(OmniPeerId(1)));
+        authority.handle_msg(OmniPeerId(0), request.msg);
+    }
+    for request in authority.get_emitted_msgs() {
+        match request.dst {
+            Destination::Host => host.handle_msg(OmniPeerId(1), request.msg),
+            Destination::Peer(OmniPeerId(2)) => listener.handle_msg(OmniPeerId(1), request.msg),
+            dst => panic!("unexpected destination {dst:?}"),
         }
-        let timer = std::time::Instant::now();
-        world.cut_through_world_line(0, 0, 64, 64, 64, 50);
-        total += timer.elapsed().as_micros();
     }
-    println!("total micros: {}", total / iters);
+    assert_eq!(
+        listener.chunk_state.get(&owned),
+        Some(&ChunkState::Listening {
+            authority: OmniPeerId(1),
+            priority: 0,
+        })
+    );
+    assert_eq!(host.authority_of(owned), Some(OmniPeerId(1)));
+    assert_eq!(host.authority_of(forgotten), None);
+
+    assert!(!host.rebroadcast_authority_state());
+    host.current_update += AUTHORITY_REBROADCAST_INTERVAL;
+    assert!(host.rebroadcast_authority_state());
 }
 
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_circle_perf() {
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
+fn test_can_terraform() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    world.chunk_storage.clear();
+    world.materials.insert(1, (5, 1000, CellType::Solid, 0, 0));
+    world
+        .materials
+        .insert(2, (100, 1000, CellType::Solid, 0, 0));
+    world
+        .materials
+        .insert(3, (5, 1000, CellType::Liquid(LiquidType::Liquid), 0, 0));
+    world
+        .chunk_storage
+        .insert(ChunkCoord(0, 0), ChunkData::new(1));
+    world
+        .chunk_storage
+        .insert(ChunkCoord(1, 0), ChunkData::new(1));
+    world.fill_rect((10, 10), (10, 10), 2);
+    world.fill_rect((20, 10), (20, 10), 3);
+    world.protect_region(ChunkCoord(1, 0), ChunkCoord(1, 0));
+    world.world_bounds = Some((ChunkCoord(-4, -4), ChunkCoord(4, 4)));
 
-    let mut total = 0;
-    let iters = 64;
-    for _ in 0..iters {
-        let (mut world, _, _, _, _) =
-            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
-        world
-            .materials
-            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0));
-        world
-            .materials
-            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0));
-        world
-            .materials
-            .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0));
-        let w = 48;
-        for i in -w..w {
-            for j in -w..w {
-                world
-                    .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
-            }
-        }
-        let timer = std::time::Instant::now();
-        world.cut_through_world_circle(0, 0, 512, None, 80);
-        total += timer.elapsed().as_micros();
-    }
-    println!("total micros: {}", total / iters);
+    assert_eq!(
+        world.can_terraform(5, 5, Some(50)),
+        TerraformPermission::Allowed
+    );
+    assert_eq!(
+        world.can_terraform(10, 10, Some(50)),
+        TerraformPermission::TooHard
+    );
+    assert_eq!(
+        world.can_terraform(10, 10, None),
+        TerraformPermission::Allowed
+    );
+    assert_eq!(
+        world.can_terraform(20, 10, None),
+        TerraformPermission::TooHard
+    );
+    assert_eq!(
+        world.can_terraform(CHUNK_SIZE as i32 + 5, 5, None),
+        TerraformPermission::Protected
+    );
+    assert_eq!(
+        world.can_terraform(-100 * CHUNK_SIZE as i32, 5, None),
+        TerraformPermission::OutOfBounds
+    );
+    // Nothing known there yet.
+    assert_eq!(
+        world.can_terraform(-(CHUNK_SIZE as i32), 5, Some(0)),
+        TerraformPermission::Allowed
+    );
 }
 
 #[cfg(test)]
 #[test]
 #[serial]
-fn test_cut_perf() {
-    let _dirt = ChunkData::new(1);
-    let _brickwork = ChunkData::new(2);
+fn test_initial_priority_fn() {
+    let (mut world, _, _, _, _) =
+        WorldManager::new(false, OmniPeerId(1), SaveState::new("/tmp/ew_tmp_save"));
+    let (near, far) = (ChunkCoord(1, 0), ChunkCoord(-2, 2));
+    let initial_priority = |world: &mut WorldManager, chunk| {
+        world.chunk_state.remove(&chunk);
+        world.chunk_updated_locally(chunk, 4, Some((0, 0, 0, 0, false)), 0);
+        match world.chunk_state.get(&chunk) {
+            Some(ChunkState::RequestAuthority { priority, .. }) => *priority,
+            _ => panic!("no authority request for {chunk:?}"),
+        }
+    };
+    assert_eq!(initial_priority(&mut world, near), 4);
+    assert_eq!(initial_priority(&mut world, far), 4);
 
-    let mut total = 0;
-    let iters = 64;
-    for _ in 0..iters {
-        let (mut world, _, _, _, _) =
-            WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
-        world
-            .materials
-            .insert(0, (0, 100, CellType::Liquid(LiquidType::Liquid), 0));
-        world
-            .materials
-            .insert(1, (6, 2000, CellType::Liquid(LiquidType::Static), 0));
-        world
-            .materials
-            .insert(2, (14, 1_000_000, CellType::Liquid(LiquidType::Static), 0));
-        let w = 48;
-        for i in -w..w {
-            for j in -w..w {
-                world
-                    .chunk_storage
-                    .insert(ChunkCoord(i, j), _brickwork.clone());
-            }
+    world.initial_priority_fn = Some(|chunk, (x, y), priority| {
+        if chunk.0.abs_diff(x) + chunk.1.abs_diff(y) > 2 {
+            priority + 10
+        } else {
+            priority
         }
-        let timer = std::time::Instant::now();
-        world.cut_through_world(0, i32::MIN, i32::MAX, 128);
-        total += timer.elapsed().as_micros();
-    }
-    println!("total micros: {}", total / iters);
+    });
+    assert_eq!(initial_priority(&mut world, near), 4);
+    assert_eq!(initial_priority(&mut world, far), 14);
+
+    world.initial_priority_fn = Some(distance_initial_priority);
+    assert_eq!(initial_priority(&mut world, near), 5);
+    assert_eq!(initial_priority(&mut world, far), 6);
+    // Only new entries are affected.
+    world.chunk_updated_locally(far, 4, Some((-2, 2, -2, 2, false)), 0);
+    assert!(matches!(
+        world.chunk_state.get(&far),
+        Some(ChunkState::RequestAuthority { priority: 6, .. })
+    ));
 }
 
-impl WorldManager {
-    pub fn handle_noita_msg(&mut self, _: OmniPeerId, msg: WorldSyncToProxy) {
-        match msg {
-            WorldSyncToProxy::Updates(updates) => {
-                for update in updates {
-                    self.outbound_model
-                        .apply_noita_update(update, &mut self.is_storage_recent)
-                }
-            }
-            WorldSyncToProxy::End(pos, priority, world_num) => {
-                let updated_chunks = self
-                    .outbound_model
-                    .updated_chunks()
-                    .iter()
-                    .copied()
-                    .collect::<Vec<_>>();
-                self.current_update += 1;
-                let chunks_to_send: Vec<Vec<(OmniPeerId, u8)>> = updated_chunks
-                    .iter()
-                    .map(|chunk| self.chunk_updated_locally(*chunk, priority, pos, world_num))
-                    .collect();
-                let mut chunk_packet: HashMap<OmniPeerId, Vec<(ChunkDelta, u8)>> = HashMap::new();
-                for (chunk, who_sending) in updated_chunks.iter().zip(chunks_to_send.iter()) {
-                    let Some(delta) = self.outbound_model.get_chunk_delta(*chunk, false) else {
-                        continue;
-                    };
-                    for (peer, pri) in who_sending {
-                        chunk_packet
-                            .entry(*peer)
-                            .or_default()
-                            .push((delta.clone(), *pri));
-                    }
-                }
-                let mut emit_queue = Vec::new();
-                for (peer, chunkpacket) in chunk_packet {
-                    emit_queue.push((
-                        Destination::Peer(peer),
-                        WorldNetMessage::ChunkPacket { chunkpacket },
-                    ));
-                }
-                for (dst, msg) in emit_queue {
-                    self.emit_msg(dst, msg)
-                }
-                self.outbound_model.reset_change_tracking();
-            }
+#[cfg(test)]
+#[test]
+#[serial]
+fn test_authority_cycle_lock() {
+    let (mut host, _, _, _, _) =
+        WorldManager::new(true, OmniPeerId(0), SaveState::new("/tmp/ew_tmp_save"));
+    host.max_authority_changes = Some(4);
+    let chunk = ChunkCoord(0, 0);
+    let request = |host: &mut WorldManager, peer: u64| {
+        host.handle_msg(
+            OmniPeerId(peer),
+            WorldNetMessage::RequestAuthority {
+                chunk,
+                priority: 5,
+                can_wait: false,
+                hint: None,
+            },
+        );
+        host.get_emitted_msgs()
+    };
+    request(&mut host, 1);
+    // Whoever has the chunk ends up with the worst priority, so the next peer takes it over.
+    let rotate = |host: &mut WorldManager, peer: u64| {
+        let authority = host.authority_of(chunk).unwrap();
+        host.handle_msg(
+            authority,
+            WorldNetMessage::ChangePriority {
+                chunk,
+                priority: 10,
+            },
+        );
+        request(host, peer)
+    };
+    for i in 0..5 {
+        let peer = 2 + i % 3;
+        let emitted = rotate(&mut host, peer);
+        assert!(matches!(
+            emitted[0].msg,
+            WorldNetMessage::GetAuthorityFrom { .. }
+        ));
+        assert_eq!(host.authority_of(chunk), Some(OmniPeerId(peer)));
+        host.current_update += 10;
+    }
+    assert!(host.authority_locks.contains_key(&chunk));
+    let locked_to = host.authority_of(chunk);
+    for peer in [1, 2, 4, 1, 2, 4] {
+        if Some(OmniPeerId(peer)) == locked_to {
+            continue;
         }
+        let emitted = rotate(&mut host, peer);
+        assert!(matches!(
+            emitted[0].msg,
+            WorldNetMessage::AuthorityAlreadyTaken { .. }
+        ));
+        assert_eq!(host.authority_of(chunk), locked_to);
     }
+
+    host.current_update += AUTHORITY_LOCK_COOLDOWN;
+    host.update(Duration::ZERO);
+    host.get_emitted_msgs();
+    assert!(host.authority_locks.is_empty());
+    let peer = if locked_to == Some(OmniPeerId(1)) {
+        2
+    } else {
+        1
+    };
+    assert!(matches!(
+        rotate(&mut host, peer)[0].msg,
+        WorldNetMessage::GetAuthorityFrom { .. }
+    ));
 }
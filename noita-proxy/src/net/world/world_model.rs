@@ -1,3 +1,5 @@
+use std::collections::hash_map::Entry;
+use std::iter;
 use std::num::NonZeroU16;
 use std::sync::Arc;
 
@@ -10,7 +12,9 @@ use shared::world_sync::{
 };
 use tracing::info;
 pub(crate) mod chunk;
+pub(crate) mod compression;
 pub mod encoding;
+pub(crate) mod storage;
 
 #[derive(Default)]
 pub(crate) struct WorldModel {
@@ -18,6 +22,10 @@ pub(crate) struct WorldModel {
     /// Tracks chunks which we written to.
     /// This includes any write, not just those that actually changed at least one pixel.
     updated_chunks: FxHashSet<ChunkCoord>,
+    /// Side length of minimap blocks in pixels, no minimap is kept if 0.
+    minimap_block: usize,
+    /// Most common material of each block of a chunk, row by row.
+    minimap: FxHashMap<ChunkCoord, Vec<u16>>,
 }
 
 /// Contains full info abount a chunk, RLE encoded.
@@ -27,6 +35,13 @@ pub(crate) struct ChunkData {
     pub runs: Vec<PixelRun<CompactPixel>>,
 }
 
+/// ChunkData whose runs don't cover exactly one chunk, e.g. from a misbehaving peer.
+#[derive(Debug)]
+pub(crate) struct CorruptChunkData {
+    /// Amount of pixels the runs add up to.
+    pub covered: usize,
+}
+
 /// Contains a diff, only pixels that were updated, for a given chunk.
 #[derive(Debug, Encode, Decode, Clone)]
 pub(crate) struct ChunkDelta {
@@ -34,6 +49,31 @@ pub(crate) struct ChunkDelta {
     runs: Arc<Vec<PixelRun<Option<CompactPixel>>>>,
 }
 
+/// Where a delta ends up when applied transformed, see `ChunkDelta::transformed`.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ChunkTransform {
+    /// Shift in whole chunks.
+    pub offset: (i32, i32),
+    /// Mirror pixels left to right within the chunk.
+    pub mirror_x: bool,
+    /// Mirror pixels top to bottom within the chunk.
+    pub mirror_y: bool,
+}
+
+impl ChunkTransform {
+    /// Offset within the chunk that a pixel at `offset` is moved to.
+    fn pixel_offset(self, offset: usize) -> usize {
+        let (mut x, mut y) = (offset % CHUNK_SIZE, offset / CHUNK_SIZE);
+        if self.mirror_x {
+            x = CHUNK_SIZE - 1 - x;
+        }
+        if self.mirror_y {
+            y = CHUNK_SIZE - 1 - y;
+        }
+        x + y * CHUNK_SIZE
+    }
+}
+
 impl ChunkData {
     /*pub(crate) fn make_random() -> Self {
         let mut runner = PixelRunner::new();
@@ -50,7 +90,31 @@ impl ChunkData {
         ChunkData { runs }
     }*/
 
+    /// Deterministic pseudo-random chunk content, so that sync issues can be reproduced from a seed.
     #[cfg(test)]
+    pub(crate) fn make_random_seeded(seed: u64) -> Self {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut runner = PixelRunner::new();
+        let mut material = 0;
+        for _ in 0..CHUNK_SIZE * CHUNK_SIZE {
+            // Keep some runs around, as actual chunks are mostly made of those.
+            if rng.random_ratio(1, 8) {
+                material = rng.random_range(0..512);
+            }
+            runner.put_pixel(
+                RawPixel {
+                    flags: shared::world_sync::PixelFlags::Normal,
+                    material,
+                }
+                .to_compact(),
+            )
+        }
+        let runs = runner.build();
+        ChunkData { runs }
+    }
+
+    /// Chunk filled with a single material.
     pub(crate) fn new(mat: u16) -> Self {
         let mut runner = PixelRunner::new();
         for _ in 0..CHUNK_SIZE * CHUNK_SIZE {
@@ -66,6 +130,17 @@ impl ChunkData {
         ChunkData { runs }
     }
 
+    /// Checks that runs cover every pixel of a chunk and nothing past it.
+    pub(crate) fn check(&self) -> Result<(), CorruptChunkData> {
+        let covered = self.runs.iter().map(|run| run.length as usize).sum();
+        if covered == CHUNK_SIZE * CHUNK_SIZE {
+            Ok(())
+        } else {
+            Err(CorruptChunkData { covered })
+        }
+    }
+
+    /// Assumes the data is valid, see `check` for data that came from elsewhere.
     pub(crate) fn apply_to_chunk(&self, chunk: &mut Chunk) {
         let nil = CompactPixel(NonZeroU16::new(4095).unwrap());
         let mut offset = 0;
@@ -98,6 +173,89 @@ impl ChunkData {
         }
         *self = chunk.to_chunk_data()
     }
+
+    pub(crate) fn apply_chunk_delta(&mut self, delta: &ChunkDelta) {
+        let mut chunk = Chunk::default();
+        self.apply_to_chunk(&mut chunk);
+        delta.apply_to_chunk(&mut chunk);
+        *self = chunk.to_chunk_data()
+    }
+}
+
+impl ChunkDelta {
+    /// Builds a delta out of all pixels of a chunk, None for pixels that don't change.
+    pub(crate) fn new(
+        chunk_coord: ChunkCoord,
+        pixels: impl IntoIterator<Item = Option<CompactPixel>>,
+    ) -> Self {
+        let mut runner = PixelRunner::new();
+        for pixel in pixels.into_iter().take(CHUNK_SIZE * CHUNK_SIZE) {
+            runner.put_pixel(pixel)
+        }
+        ChunkDelta {
+            chunk_coord,
+            runs: runner.build().into(),
+        }
+    }
+
+    fn apply_to_chunk(&self, chunk: &mut Chunk) {
+        let mut offset = 0;
+        for run in self.runs.iter() {
+            if let Some(pixel) = run.data {
+                for _ in 0..run.length {
+                    chunk.set_compact_pixel(offset, pixel);
+                    offset += 1;
+                }
+            } else {
+                offset += run.length as usize
+            }
+        }
+    }
+
+    /// Full chunk data, if this delta covers every pixel of the chunk.
+    pub(crate) fn to_chunk_data(&self) -> Option<ChunkData> {
+        let runs = self
+            .runs
+            .iter()
+            .map(|run| {
+                Some(PixelRun {
+                    length: run.length,
+                    data: run.data?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(ChunkData { runs })
+    }
+
+    /// Same pixel changes, but for another chunk.
+    pub(crate) fn moved_to(&self, chunk_coord: ChunkCoord) -> Self {
+        ChunkDelta {
+            chunk_coord,
+            runs: self.runs.clone(),
+        }
+    }
+
+    /// Same pixel changes, moved by whole chunks and mirrored within the chunk.
+    pub(crate) fn transformed(&self, transform: ChunkTransform) -> Self {
+        let mut pixels = vec![None; CHUNK_SIZE * CHUNK_SIZE];
+        let changes = self
+            .runs
+            .iter()
+            .flat_map(|run| iter::repeat_n(run.data, run.length as usize));
+        for (offset, pixel) in changes.take(CHUNK_SIZE * CHUNK_SIZE).enumerate() {
+            pixels[transform.pixel_offset(offset)] = pixel;
+        }
+        let ChunkCoord(x, y) = self.chunk_coord;
+        ChunkDelta::new(
+            ChunkCoord(x + transform.offset.0, y + transform.offset.1),
+            pixels,
+        )
+    }
+
+    /// Whether both deltas change the same pixels in the same way, wherever their chunks are.
+    pub(crate) fn same_pixels(&self, other: &ChunkDelta) -> bool {
+        Arc::ptr_eq(&self.runs, &other.runs) || self.runs == other.runs
+    }
 }
 
 impl WorldModel {
@@ -150,6 +308,7 @@ impl WorldModel {
         );
         let mut chunk_coord = update.coord;
         let mut chunk = self.chunks.entry(update.coord).or_default();
+        let mut touched = vec![chunk_coord];
         for run in update.runs {
             for _ in 0..run.length {
                 let xs = start_x + x;
@@ -158,6 +317,9 @@ impl WorldModel {
                 if chunk_coord != new_chunk_coord {
                     chunk_coord = new_chunk_coord;
                     chunk = self.chunks.entry(chunk_coord).or_default();
+                    if !touched.contains(&chunk_coord) {
+                        touched.push(chunk_coord);
+                    }
                 }
                 if set_pixel(
                     RawPixel {
@@ -180,6 +342,9 @@ impl WorldModel {
                 }
             }
         }
+        for chunk in touched {
+            self.refresh_minimap(chunk);
+        }
     }
 
     pub fn get_all_noita_updates(&mut self) -> Vec<NoitaWorldUpdate> {
@@ -205,17 +370,8 @@ impl WorldModel {
     pub(crate) fn apply_chunk_delta(&mut self, delta: &ChunkDelta) {
         self.updated_chunks.insert(delta.chunk_coord);
         let chunk = self.chunks.entry(delta.chunk_coord).or_default();
-        let mut offset = 0;
-        for run in delta.runs.iter() {
-            if let Some(pixel) = run.data {
-                for _ in 0..run.length {
-                    chunk.set_compact_pixel(offset, pixel);
-                    offset += 1;
-                }
-            } else {
-                offset += run.length as usize
-            }
-        }
+        delta.apply_to_chunk(chunk);
+        self.refresh_minimap(delta.chunk_coord);
     }
 
     pub(crate) fn get_chunk_delta(
@@ -232,29 +388,61 @@ impl WorldModel {
         Some(ChunkDelta { chunk_coord, runs })
     }
 
+    /// How many pixels of a chunk were changed since change tracking was last reset.
+    pub(crate) fn changed_pixel_count(&self, chunk_coord: ChunkCoord) -> usize {
+        self.chunks.get(&chunk_coord).map_or(0, |chunk| {
+            (0..CHUNK_SIZE * CHUNK_SIZE)
+                .filter(|&i| chunk.changed(i))
+                .count()
+        })
+    }
+
     pub fn updated_chunks(&self) -> &FxHashSet<ChunkCoord> {
         &self.updated_chunks
     }
 
-    pub fn reset_change_tracking(&mut self) {
-        for chunk_pos in &self.updated_chunks {
-            if let Some(chunk) = self.chunks.get_mut(chunk_pos) {
+    /// Clears change tracking of updated chunks, except for ones in `keep`.
+    pub fn reset_change_tracking_except(&mut self, keep: &FxHashSet<ChunkCoord>) {
+        let chunks = &mut self.chunks;
+        self.updated_chunks.retain(|chunk_pos| {
+            if keep.contains(chunk_pos) {
+                return true;
+            }
+            if let Some(chunk) = chunks.get_mut(chunk_pos) {
                 chunk.clear_changed();
             }
-        }
-        self.updated_chunks.clear();
+            false
+        });
     }
 
     pub fn reset(&mut self) {
         self.chunks.clear();
         self.updated_chunks.clear();
+        self.minimap.clear();
         info!("World model reset");
     }
 
-    pub(crate) fn apply_chunk_data(&mut self, chunk: ChunkCoord, chunk_data: &ChunkData) {
+    /// Leaves the model untouched if the data is corrupt.
+    pub(crate) fn apply_chunk_data(
+        &mut self,
+        chunk: ChunkCoord,
+        chunk_data: &ChunkData,
+    ) -> Result<(), CorruptChunkData> {
+        chunk_data.check()?;
         self.updated_chunks.insert(chunk);
+        let chunk_coord = chunk;
         let chunk = self.chunks.entry(chunk).or_default();
         chunk_data.apply_to_chunk(chunk);
+        self.refresh_minimap(chunk_coord);
+        Ok(())
+    }
+
+    pub(crate) fn chunk_coords(&self) -> impl Iterator<Item = &ChunkCoord> {
+        self.chunks.keys()
+    }
+
+    pub(crate) fn has_chunk(&self, chunk: ChunkCoord) -> bool {
+        self.chunks.contains_key(&chunk)
     }
 
     pub(crate) fn get_chunk_data(&self, chunk: ChunkCoord) -> Option<ChunkData> {
@@ -262,8 +450,76 @@ impl WorldModel {
         Some(chunk.to_chunk_data())
     }
 
+    /// Drops chunk contents, but keeps it marked as updated.
+    #[cfg(test)]
+    pub(crate) fn lose_chunk_baseline(&mut self, chunk: ChunkCoord) {
+        self.chunks.remove(&chunk);
+    }
+
+    /// Approximate size of chunks kept, in bytes.
+    pub(crate) fn memory_estimate(&self) -> usize {
+        self.chunks.len() * size_of::<(ChunkCoord, Chunk)>()
+            + self.updated_chunks.len() * size_of::<ChunkCoord>()
+    }
+
     pub(crate) fn forget_chunk(&mut self, chunk: ChunkCoord) {
         self.chunks.remove(&chunk);
         self.updated_chunks.remove(&chunk);
+        self.minimap.remove(&chunk);
+    }
+
+    /// Starts keeping a minimap with blocks of this side length, or stops if it's 0.
+    /// Block size has to divide CHUNK_SIZE.
+    pub(crate) fn set_minimap_block(&mut self, block: usize) {
+        self.minimap_block = block;
+        self.minimap.clear();
+        let chunks: Vec<ChunkCoord> = self.chunks.keys().copied().collect();
+        for chunk in chunks {
+            self.refresh_minimap(chunk);
+        }
+    }
+
+    pub(crate) fn minimap_tile(&self, chunk: ChunkCoord) -> Option<&[u16]> {
+        self.minimap.get(&chunk).map(Vec::as_slice)
+    }
+
+    /// Recomputes minimap blocks of a chunk that have changed pixels, or all of them if the chunk
+    /// didn't have a tile yet.
+    fn refresh_minimap(&mut self, chunk_coord: ChunkCoord) {
+        let block = self.minimap_block;
+        if block == 0 {
+            return;
+        }
+        let Some(chunk) = self.chunks.get(&chunk_coord) else {
+            return;
+        };
+        let blocks = CHUNK_SIZE / block;
+        let (tile, fresh) = match self.minimap.entry(chunk_coord) {
+            Entry::Occupied(entry) => (entry.into_mut(), false),
+            Entry::Vacant(entry) => (entry.insert(vec![0; blocks * blocks]), true),
+        };
+        let mut counts: Vec<(u16, usize)> = Vec::new();
+        for by in 0..blocks {
+            for bx in 0..blocks {
+                let offsets = (0..block).flat_map(|y| {
+                    (0..block).map(move |x| (by * block + y) * CHUNK_SIZE + bx * block + x)
+                });
+                if !fresh && !offsets.clone().any(|offset| chunk.changed(offset)) {
+                    continue;
+                }
+                counts.clear();
+                for offset in offsets {
+                    let material = chunk.pixel(offset).material;
+                    match counts.iter_mut().find(|(mat, _)| *mat == material) {
+                        Some((_, count)) => *count += 1,
+                        None => counts.push((material, 1)),
+                    }
+                }
+                tile[by * blocks + bx] = counts
+                    .iter()
+                    .max_by_key(|(_, count)| *count)
+                    .map_or(0, |(mat, _)| *mat);
+            }
+        }
     }
 }
@@ -0,0 +1,229 @@
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use bitcode::{Decode, Encode};
+use rustc_hash::{FxHashMap, FxHasher};
+use shared::world_sync::{CompactPixel, PixelRun};
+use tracing::warn;
+
+use super::ChunkData;
+
+/// lz4 doesn't look further back than that, so bigger dictionaries don't help.
+const MAX_DICT_SIZE: usize = 64 * 1024;
+
+/// How much effort goes into compressing chunk data.
+/// Data compressed at any level is decompressed the same way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum CompressionLevel {
+    /// Data isn't compressed at all, for when cpu time matters more than bandwidth, like on LAN.
+    Fast,
+    #[default]
+    Balanced,
+    /// Tries every method and keeps the smallest result.
+    Max,
+}
+
+/// Dictionary used to compress chunk data sent between peers.
+/// Both sides need to have the exact same dictionary, which is checked using `version`.
+#[derive(Clone)]
+pub(crate) struct CompressionDict {
+    version: u64,
+    data: Arc<[u8]>,
+}
+
+impl CompressionDict {
+    pub(crate) fn new(mut data: Vec<u8>) -> Self {
+        if data.len() > MAX_DICT_SIZE {
+            data.drain(..data.len() - MAX_DICT_SIZE);
+        }
+        let mut hasher = FxHasher::default();
+        data.hash(&mut hasher);
+        Self {
+            version: hasher.finish(),
+            data: data.into(),
+        }
+    }
+
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+/// Builds a dictionary out of representative chunks.
+/// lz4 dictionary is just data that's treated as if it came right before the input,
+/// so the best we can do is to fill it with encoded samples, most important ones last.
+pub(crate) fn train_dictionary(samples: &[ChunkData]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for sample in samples {
+        data.extend_from_slice(&bitcode::encode(sample));
+    }
+    if data.len() > MAX_DICT_SIZE {
+        data.drain(..data.len() - MAX_DICT_SIZE);
+    }
+    data
+}
+
+/// Sizes of data before and after compression, to see whether compression is worth it.
+/// Ratio is compressed size divided by original size, so lower is better.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct CompressionStats {
+    pub(crate) samples: u64,
+    ratio_sum: f64,
+    /// Highest ratio seen.
+    pub(crate) worst_ratio: f64,
+    /// Negative if compression made data bigger overall.
+    pub(crate) bytes_saved: i64,
+}
+
+impl CompressionStats {
+    pub(crate) fn record(&mut self, original: usize, compressed: usize) {
+        if original == 0 {
+            return;
+        }
+        let ratio = compressed as f64 / original as f64;
+        self.samples += 1;
+        self.ratio_sum += ratio;
+        self.worst_ratio = self.worst_ratio.max(ratio);
+        self.bytes_saved += original as i64 - compressed as i64;
+    }
+
+    pub(crate) fn mean_ratio(&self) -> f64 {
+        if self.samples == 0 {
+            return 1.0;
+        }
+        self.ratio_sum / self.samples as f64
+    }
+}
+
+#[derive(Debug, Encode, Decode, Clone)]
+pub(crate) struct CompressedChunkData {
+    pub(crate) dict_version: Option<u64>,
+    /// Data is just encoded, without any compression.
+    stored: bool,
+    data: Vec<u8>,
+}
+
+impl ChunkData {
+    pub(crate) fn compress(
+        &self,
+        dict: Option<&CompressionDict>,
+        level: CompressionLevel,
+    ) -> CompressedChunkData {
+        let encoded = bitcode::encode(self);
+        let stored = || CompressedChunkData {
+            dict_version: None,
+            stored: true,
+            data: encoded.clone(),
+        };
+        let lz4 = || CompressedChunkData {
+            dict_version: None,
+            stored: false,
+            data: lz4_flex::compress_prepend_size(&encoded),
+        };
+        let lz4_dict = |dict: &CompressionDict| CompressedChunkData {
+            dict_version: Some(dict.version),
+            stored: false,
+            data: lz4_flex::block::compress_prepend_size_with_dict(&encoded, &dict.data),
+        };
+        match level {
+            CompressionLevel::Fast => stored(),
+            CompressionLevel::Balanced => dict.map_or_else(lz4, lz4_dict),
+            CompressionLevel::Max => [Some(stored()), Some(lz4()), dict.map(lz4_dict)]
+                .into_iter()
+                .flatten()
+                .min_by_key(|compressed| compressed.data.len())
+                .unwrap(),
+        }
+    }
+}
+
+impl CompressedChunkData {
+    /// Size of compressed data, without the header.
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns None if data is corrupt or was compressed with a dictionary we don't have.
+    pub(crate) fn decompress(&self, dict: Option<&CompressionDict>) -> Option<ChunkData> {
+        if self.stored {
+            return bitcode::decode(&self.data)
+                .inspect_err(|err| warn!("Could not decode chunk data: {err}"))
+                .ok();
+        }
+        let decompressed = match (self.dict_version, dict) {
+            (None, _) => lz4_flex::decompress_size_prepended(&self.data),
+            (Some(version), Some(dict)) if version == dict.version => {
+                lz4_flex::block::decompress_size_prepended_with_dict(&self.data, &dict.data)
+            }
+            (Some(version), _) => {
+                warn!("Chunk data compressed with unknown dictionary {version:x}");
+                return None;
+            }
+        }
+        .inspect_err(|err| warn!("Could not decompress chunk data: {err}"))
+        .ok()?;
+        bitcode::decode(&decompressed)
+            .inspect_err(|err| warn!("Could not decode chunk data: {err}"))
+            .ok()
+    }
+}
+
+/// Chunk data as stored in saves.
+#[derive(Debug, Encode, Decode, Clone)]
+pub(crate) enum SavedChunkData {
+    Plain(ChunkData),
+    /// Difference from a chunk filled with `template`, None for runs of `template` itself.
+    /// Smaller for chunks that are mostly made of one material.
+    Template {
+        template: CompactPixel,
+        runs: Vec<PixelRun<Option<CompactPixel>>>,
+    },
+}
+
+impl SavedChunkData {
+    /// Picks whichever encoding is smaller.
+    pub(crate) fn new(chunk: ChunkData) -> Self {
+        let mut pixel_counts: FxHashMap<u16, usize> = FxHashMap::default();
+        for run in &chunk.runs {
+            *pixel_counts.entry(run.data.raw()).or_default() += run.length as usize;
+        }
+        let Some(template) = pixel_counts
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(pixel, _)| CompactPixel::from_raw(pixel))
+        else {
+            return SavedChunkData::Plain(chunk);
+        };
+        // Maps runs one to one, so that decoding gets exactly the same runs back.
+        let runs = chunk
+            .runs
+            .iter()
+            .map(|run| PixelRun {
+                length: run.length,
+                data: (run.data != template).then_some(run.data),
+            })
+            .collect();
+        let templated = SavedChunkData::Template { template, runs };
+        let plain = SavedChunkData::Plain(chunk);
+        if bitcode::encode(&templated).len() < bitcode::encode(&plain).len() {
+            templated
+        } else {
+            plain
+        }
+    }
+
+    pub(crate) fn into_chunk_data(self) -> ChunkData {
+        match self {
+            SavedChunkData::Plain(chunk) => chunk,
+            SavedChunkData::Template { template, runs } => ChunkData {
+                runs: runs
+                    .into_iter()
+                    .map(|run| PixelRun {
+                        length: run.length,
+                        data: run.data.unwrap_or(template),
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::borrow::Cow;
+
+use rustc_hash::FxHashMap;
+use shared::world_sync::{ChunkCoord, CompactPixel, PixelRun};
+use tracing::warn;
+
+use super::ChunkData;
+
+/// How many hot chunks are looked at to get average chunk size.
+const MEMORY_ESTIMATE_SAMPLES: usize = 64;
+
+/// Chunks that aren't under any authority, as kept by host.
+/// Chunks that weren't written to for `cold_after` updates are kept lz4 compressed,
+/// and get decompressed whenever they're read. Writing to a chunk makes it hot again.
+#[derive(Default)]
+pub(crate) struct ChunkStorage {
+    hot: FxHashMap<ChunkCoord, ChunkData>,
+    cold: FxHashMap<ChunkCoord, Vec<u8>>,
+    /// Update in which hot chunks were last written to.
+    last_written: FxHashMap<ChunkCoord, u64>,
+    current_update: u64,
+    /// Nothing is compressed if None.
+    pub(crate) cold_after: Option<u64>,
+}
+
+impl ChunkStorage {
+    pub(crate) fn get(&self, chunk: &ChunkCoord) -> Option<Cow<'_, ChunkData>> {
+        if let Some(data) = self.hot.get(chunk) {
+            return Some(Cow::Borrowed(data));
+        }
+        self.cold
+            .get(chunk)
+            .and_then(|compressed| decompress(compressed))
+            .map(Cow::Owned)
+    }
+
+    pub(crate) fn get_mut(&mut self, chunk: &ChunkCoord) -> Option<&mut ChunkData> {
+        self.make_hot(chunk);
+        let data = self.hot.get_mut(chunk)?;
+        self.last_written.insert(*chunk, self.current_update);
+        Some(data)
+    }
+
+    /// Decompresses cold chunks ahead of many reads, like explosion rays going through them.
+    /// They go cold again after `cold_after` updates, same as written ones.
+    pub(crate) fn warm(&mut self, in_range: impl Fn(&ChunkCoord) -> bool) {
+        let chunks: Vec<ChunkCoord> = self.cold.keys().copied().filter(in_range).collect();
+        for chunk in chunks {
+            self.make_hot(&chunk);
+        }
+    }
+
+    /// Chunk stays cold if it can't be decompressed, so that it isn't lost.
+    fn make_hot(&mut self, chunk: &ChunkCoord) {
+        let Some(data) = self
+            .cold
+            .get(chunk)
+            .and_then(|compressed| decompress(compressed))
+        else {
+            return;
+        };
+        self.cold.remove(chunk);
+        self.last_written.insert(*chunk, self.current_update);
+        self.hot.insert(*chunk, data);
+    }
+
+    pub(crate) fn insert(&mut self, chunk: ChunkCoord, data: ChunkData) {
+        self.cold.remove(&chunk);
+        self.last_written.insert(chunk, self.current_update);
+        self.hot.insert(chunk, data);
+    }
+
+    pub(crate) fn remove(&mut self, chunk: &ChunkCoord) -> Option<ChunkData> {
+        self.last_written.remove(chunk);
+        self.hot
+            .remove(chunk)
+            .or_else(|| decompress(&self.cold.remove(chunk)?))
+    }
+
+    pub(crate) fn contains_key(&self, chunk: &ChunkCoord) -> bool {
+        self.hot.contains_key(chunk) || self.cold.contains_key(chunk)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_compressed(&self, chunk: &ChunkCoord) -> bool {
+        self.cold.contains_key(chunk)
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &ChunkCoord> {
+        self.hot.keys().chain(self.cold.keys())
+    }
+
+    /// Cold chunks are decompressed one by one, as they're iterated over.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&ChunkCoord, Cow<'_, ChunkData>)> {
+        self.hot
+            .iter()
+            .map(|(chunk, data)| (chunk, Cow::Borrowed(data)))
+            .chain(self.cold.iter().filter_map(|(chunk, compressed)| {
+                Some((chunk, Cow::Owned(decompress(compressed)?)))
+            }))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.hot.len() + self.cold.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hot.is_empty() && self.cold.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.hot.clear();
+        self.cold.clear();
+        self.last_written.clear();
+    }
+
+    /// Decompressed copy of every chunk.
+    pub(crate) fn to_map(&self) -> FxHashMap<ChunkCoord, ChunkData> {
+        self.iter()
+            .map(|(chunk, data)| (*chunk, data.into_owned()))
+            .collect()
+    }
+
+    /// Approximate size of stored chunks, in bytes.
+    /// Hot chunks are assumed to be of average size, cold ones are counted exactly.
+    pub(crate) fn memory_estimate(&self) -> usize {
+        let sampled = self.hot.len().min(MEMORY_ESTIMATE_SAMPLES);
+        let average_runs = self
+            .hot
+            .values()
+            .take(sampled)
+            .map(|data| data.runs.len())
+            .sum::<usize>()
+            .checked_div(sampled)
+            .unwrap_or(0);
+        let hot = self.hot.len()
+            * (size_of::<(ChunkCoord, ChunkData)>()
+                + average_runs * size_of::<PixelRun<CompactPixel>>());
+        let cold: usize = self
+            .cold
+            .values()
+            .map(|compressed| size_of::<(ChunkCoord, Vec<u8>)>() + compressed.len())
+            .sum();
+        hot + cold + self.last_written.len() * size_of::<(ChunkCoord, u64)>()
+    }
+
+    /// Compresses chunks that went cold, should be called once per update.
+    pub(crate) fn update(&mut self, current_update: u64) {
+        self.current_update = current_update;
+        let Some(cold_after) = self.cold_after else {
+            return;
+        };
+        let cold: Vec<ChunkCoord> = self
+            .last_written
+            .iter()
+            .filter(|(_, written)| current_update >= **written + cold_after)
+            .map(|(chunk, _)| *chunk)
+            .collect();
+        for chunk in cold {
+            self.last_written.remove(&chunk);
+            if let Some(data) = self.hot.remove(&chunk) {
+                let compressed = lz4_flex::compress_prepend_size(&bitcode::encode(&data));
+                self.cold.insert(chunk, compressed);
+            }
+        }
+    }
+}
+
+impl FromIterator<(ChunkCoord, ChunkData)> for ChunkStorage {
+    fn from_iter<T: IntoIterator<Item = (ChunkCoord, ChunkData)>>(iter: T) -> Self {
+        let mut storage = ChunkStorage::default();
+        for (chunk, data) in iter {
+            storage.insert(chunk, data);
+        }
+        storage
+    }
+}
+
+fn decompress(compressed: &[u8]) -> Option<ChunkData> {
+    let decompressed = lz4_flex::decompress_size_prepended(compressed)
+        .inspect_err(|err| warn!("Could not decompress stored chunk: {err}"))
+        .ok()?;
+    bitcode::decode(&decompressed)
+        .inspect_err(|err| warn!("Could not decode stored chunk: {err}"))
+        .ok()
+}
@@ -102,7 +102,7 @@ impl PeerId {
     }
 }
 
-#[derive(Encode, Decode, Debug, PartialEq, Eq)]
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Destination<PeerType> {
     Peers(Vec<PeerType>),
     Peer(PeerType),